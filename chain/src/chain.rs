@@ -17,10 +17,12 @@
 
 use crate::core::core::hash::{Hash, Hashed, ZERO_HASH};
 use crate::core::core::merkle_proof::MerkleProof;
+use crate::core::core::pmmr;
 use crate::core::core::verifier_cache::VerifierCache;
 use crate::core::core::{
 	Block, BlockHeader, BlockSums, BlockTokenSums, Committed, Output, OutputIdentifier,
-	TokenIssueProof, TokenOutput, TokenOutputIdentifier, TokenTxKernel, Transaction, TxKernel,
+	TokenIssueProof, TokenKey, TokenOutput, TokenOutputIdentifier, TokenTxKernel, Transaction,
+	TxKernel,
 };
 use crate::core::global;
 use crate::core::pow;
@@ -31,7 +33,8 @@ use crate::store;
 use crate::txhashset;
 use crate::txhashset::{PMMRHandle, TxHashSet};
 use crate::types::{
-	BlockStatus, ChainAdapter, CommitPos, NoStatus, Options, Tip, TxHashsetWriteStatus,
+	BlockStatus, ChainAdapter, CommitPos, NoStatus, Options, Tip, TokenSupply,
+	TxHashsetWriteStatus, ValidationConfig,
 };
 use crate::util::secp::pedersen::{Commitment, RangeProof};
 use crate::util::RwLock;
@@ -39,8 +42,8 @@ use grin_store::Error::NotFoundErr;
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::Read;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -50,6 +53,9 @@ pub const MAX_ORPHAN_SIZE: usize = 200;
 /// When evicting, very old orphans are evicted first
 const MAX_ORPHAN_AGE_SECS: u64 = 300;
 
+/// Default capacity of the header hash by height cache on the header and sync MMRs
+pub const HEADER_HASH_CACHE_SIZE: usize = 1_000;
+
 #[derive(Debug, Clone)]
 struct Orphan {
 	block: Block,
@@ -156,6 +162,12 @@ pub struct Chain {
 	pow_verifier: fn(&BlockHeader) -> Result<(), pow::Error>,
 	archive_mode: bool,
 	genesis: BlockHeader,
+	// seconds txhashset zips are retained for before being cleaned up
+	txhashset_zip_retention_secs: AtomicU64,
+	// max number of blocks a reorg can rewind when processing a block (None for no limit)
+	max_reorg_depth: RwLock<Option<u64>>,
+	// batch sizes used for rangeproof and kernel signature verification
+	validation_config: RwLock<ValidationConfig>,
 }
 
 impl Chain {
@@ -192,6 +204,9 @@ impl Chain {
 			None,
 		)?;
 
+		header_pmmr.set_header_hash_cache_capacity(HEADER_HASH_CACHE_SIZE);
+		sync_pmmr.set_header_hash_cache_capacity(HEADER_HASH_CACHE_SIZE);
+
 		setup_head(
 			&genesis,
 			&store,
@@ -207,6 +222,8 @@ impl Chain {
 			let batch = store.batch()?;
 			txhashset.init_output_pos_index(&header_pmmr, &batch)?;
 			txhashset.init_token_output_pos_index(&header_pmmr, &batch)?;
+			txhashset.init_kernel_height_index(&header_pmmr, &batch)?;
+			txhashset.init_token_kernel_height_index(&header_pmmr, &batch)?;
 			batch.commit()?;
 		}
 
@@ -222,6 +239,13 @@ impl Chain {
 			verifier_cache,
 			archive_mode,
 			genesis: genesis.header,
+			txhashset_zip_retention_secs: AtomicU64::new(txhashset::TXHASHSET_ZIP_RETENTION_SECS),
+			max_reorg_depth: RwLock::new(if archive_mode {
+				None
+			} else {
+				Some(global::cut_through_horizon() as u64)
+			}),
+			validation_config: RwLock::new(ValidationConfig::default()),
 		};
 
 		// DB migrations to be run prior to the chain being used.
@@ -240,6 +264,45 @@ impl Chain {
 		self.header_pmmr.clone()
 	}
 
+	/// Resize the header hash by height cache on both the header and sync MMRs.
+	/// A capacity of 0 disables the cache (for memory constrained nodes).
+	pub fn set_header_hash_cache_capacity(&self, capacity: usize) {
+		self.header_pmmr
+			.write()
+			.set_header_hash_cache_capacity(capacity);
+		self.sync_pmmr
+			.write()
+			.set_header_hash_cache_capacity(capacity);
+	}
+
+	/// Enable (or disable) the in-memory kernel excess index used to speed up
+	/// kernel lookups, see TxHashSet::set_kernel_pos_index. Disabled by default.
+	pub fn set_kernel_pos_index(&self, enabled: bool) {
+		self.txhashset.write().set_kernel_pos_index(enabled);
+	}
+
+	/// Set the number of seconds txhashset zips (see txhashset_read) are retained for
+	/// before being cleaned up. Defaults to TXHASHSET_ZIP_RETENTION_SECS.
+	/// Nodes serving many fast sync peers may want to retain zips for longer.
+	pub fn set_txhashset_zip_retention(&self, retention_secs: u64) {
+		self.txhashset_zip_retention_secs
+			.store(retention_secs, Ordering::Relaxed);
+	}
+
+	/// Set the max number of blocks a reorg can rewind when processing a block.
+	/// Defaults to the cut-through horizon (a non-archive node does not have the blocks
+	/// to reorg any deeper) and to None (no limit) in archive mode.
+	/// Other rewinds (merkle proofs, txhashset archives etc.) are never limited.
+	pub fn set_max_reorg_depth(&self, max_reorg_depth: Option<u64>) {
+		*self.max_reorg_depth.write() = max_reorg_depth;
+	}
+
+	/// Set the batch sizes used for rangeproof and kernel signature verification
+	/// when validating the txhashset (see validate() and txhashset_write()).
+	pub fn set_validation_config(&self, config: ValidationConfig) {
+		*self.validation_config.write() = config;
+	}
+
 	/// Return our shared txhashset instance.
 	pub fn txhashset(&self) -> Arc<RwLock<TxHashSet>> {
 		self.txhashset.clone()
@@ -444,6 +507,7 @@ impl Chain {
 			header_pmmr,
 			txhashset,
 			batch,
+			max_reorg_depth: *self.max_reorg_depth.read(),
 		})
 	}
 
@@ -535,6 +599,27 @@ impl Chain {
 		self.txhashset.read().get_token_unspent(output_ref)
 	}
 
+	/// Batch version of get_unspent(), results are aligned with the provided outputs.
+	pub fn get_unspent_batch(
+		&self,
+		output_refs: &[OutputIdentifier],
+	) -> Result<Vec<Option<CommitPos>>, Error> {
+		self.txhashset.read().get_unspent_batch(output_refs)
+	}
+
+	/// Batch version of get_token_unspent(), results are aligned with the provided outputs.
+	pub fn get_token_unspent_batch(
+		&self,
+		output_refs: &[TokenOutputIdentifier],
+	) -> Result<Vec<Option<CommitPos>>, Error> {
+		self.txhashset.read().get_token_unspent_batch(output_refs)
+	}
+
+	/// Supply of the given token type based on the current chain state.
+	pub fn token_supply(&self, token_type: &TokenKey) -> Result<TokenSupply, Error> {
+		self.txhashset.read().token_supply(token_type)
+	}
+
 	/// Retrieves an unspent output using its PMMR position
 	pub fn get_unspent_output_at(&self, pos: u64) -> Result<Output, Error> {
 		let header_pmmr = self.header_pmmr.read();
@@ -600,6 +685,7 @@ impl Chain {
 			return Ok(());
 		}
 
+		let config = *self.validation_config.read();
 		let mut header_pmmr = self.header_pmmr.write();
 		let mut txhashset = self.txhashset.write();
 
@@ -608,8 +694,14 @@ impl Chain {
 		// ensure the view is consistent.
 		txhashset::extending_readonly(&mut header_pmmr, &mut txhashset, |ext, batch| {
 			pipe::rewind_and_apply_fork(&header, ext, batch)?;
-			ext.extension
-				.validate(&self.genesis, fast_validation, &NoStatus, &header)?;
+			ext.extension.validate(
+				&self.genesis,
+				fast_validation,
+				false,
+				&config,
+				&NoStatus,
+				&header,
+			)?;
 			Ok(())
 		})
 	}
@@ -640,23 +732,11 @@ impl Chain {
 		// Set the output and kernel MMR sizes.
 		// Note: We need to do this *before* calculating the roots as the output_root
 		// depends on the output_mmr_size
-		{
-			// Carefully destructure these correctly...
-			let (
-				output_mmr_size,
-				_,
-				kernel_mmr_size,
-				token_output_mmr_size,
-				_,
-				token_issue_proof_mmr_size,
-				token_kernel_mmr_size,
-			) = sizes;
-			b.header.output_mmr_size = output_mmr_size;
-			b.header.kernel_mmr_size = kernel_mmr_size;
-			b.header.token_output_mmr_size = token_output_mmr_size;
-			b.header.token_issue_proof_mmr_size = token_issue_proof_mmr_size;
-			b.header.token_kernel_mmr_size = token_kernel_mmr_size;
-		}
+		b.header.output_mmr_size = sizes.output;
+		b.header.kernel_mmr_size = sizes.kernel;
+		b.header.token_output_mmr_size = sizes.token_output;
+		b.header.token_issue_proof_mmr_size = sizes.token_issue_proof;
+		b.header.token_kernel_mmr_size = sizes.token_kernel;
 
 		// Set the prev_root on the header.
 		b.header.prev_root = prev_root;
@@ -693,14 +773,14 @@ impl Chain {
 	/// Return a merkle proof valid for the current output pmmr state at the
 	/// given pos
 	pub fn get_merkle_proof_for_pos(&self, commit: Commitment) -> Result<MerkleProof, Error> {
-		let mut txhashset = self.txhashset.write();
+		let txhashset = self.txhashset.read();
 		txhashset.merkle_proof(commit)
 	}
 
 	/// Return a merkle proof valid for the current token output pmmr state at the
 	/// given pos
 	pub fn get_token_merkle_proof_for_pos(&self, commit: Commitment) -> Result<MerkleProof, Error> {
-		let mut txhashset = self.txhashset.write();
+		let txhashset = self.txhashset.read();
 		txhashset.token_merkle_proof(commit)
 	}
 
@@ -744,15 +824,16 @@ impl Chain {
 			ext.extension.snapshot(batch)?;
 
 			// prepare the zip
-			txhashset::zip_read(self.db_root.clone(), &header).map(|file| {
-				(
-					header.output_mmr_size,
-					header.kernel_mmr_size,
-					header.token_output_mmr_size,
-					header.token_issue_proof_mmr_size,
-					file,
-				)
-			})
+			let retention_secs = self.txhashset_zip_retention_secs.load(Ordering::Relaxed);
+			let file =
+				txhashset::zip_read_with_retention(self.db_root.clone(), &header, retention_secs)?;
+			Ok((
+				header.output_mmr_size,
+				header.kernel_mmr_size,
+				header.token_output_mmr_size,
+				header.token_issue_proof_mmr_size,
+				file,
+			))
 		})
 	}
 
@@ -1001,6 +1082,8 @@ impl Chain {
 		// Validate the full kernel history (kernel MMR root for every block header).
 		self.validate_kernel_history(&header, &txhashset)?;
 
+		let config = *self.validation_config.read();
+
 		// all good, prepare a new batch and update all the required records
 		debug!("txhashset_write: rewinding a 2nd time (writeable)");
 
@@ -1016,8 +1099,19 @@ impl Chain {
 
 				// Validate the extension, generating the utxo_sum and kernel_sum.
 				// Full validation, including rangeproofs and kernel signature verification.
-				let (utxo_sum, kernel_sum, block_token_sums) =
-					extension.validate(&self.genesis, false, status, &header)?;
+				// Verification progress is persisted (outside the sandbox, which is cleaned
+				// on every write) so a node restarted mid-validation of the same txhashset
+				// resumes rather than starting over.
+				let progress_path = txhashset::verification_progress_path(Path::new(&self.db_root));
+				let (utxo_sum, kernel_sum, block_token_sums) = extension.validate_with_progress(
+					&self.genesis,
+					false,
+					false,
+					&config,
+					status,
+					&header,
+					Some(&progress_path),
+				)?;
 
 				// Save the block_sums (utxo_sum, kernel_sum) to the db for use later.
 				batch.save_block_sums(
@@ -1049,6 +1143,10 @@ impl Chain {
 		txhashset.init_output_pos_index(&header_pmmr, &batch)?;
 		txhashset.init_token_output_pos_index(&header_pmmr, &batch)?;
 
+		// Index the kernels from the fresh kernel MMR by block height.
+		txhashset.init_kernel_height_index(&header_pmmr, &batch)?;
+		txhashset.init_token_kernel_height_index(&header_pmmr, &batch)?;
+
 		// Commit all the changes to the db.
 		batch.commit()?;
 
@@ -1091,6 +1189,8 @@ impl Chain {
 		header_pmmr: &txhashset::PMMRHandle<BlockHeader>,
 		batch: &store::Batch<'_>,
 	) -> Result<(), Error> {
+		// Note: in archive mode the output_created index is never cleaned up here either,
+		// it grows without bound (one entry per output ever created).
 		if self.archive_mode {
 			return Ok(());
 		}
@@ -1114,6 +1214,14 @@ impl Chain {
 			return Ok(());
 		}
 
+		// Output MMR sizes up to which the output_created index is no longer needed.
+		// Rewinding the tail block needs the entries for the outputs it created.
+		let old_tail_sizes = batch
+			.get_block_header(&tail.last_block_h)
+			.and_then(|x| batch.get_previous_header(&x))
+			.map(|x| (x.output_mmr_size, x.token_output_mmr_size))
+			.unwrap_or((0, 0));
+
 		let mut count = 0;
 		let tail_hash = header_pmmr.get_header_hash_by_height(head.height - horizon)?;
 		let tail = batch.get_block_header(&tail_hash)?;
@@ -1127,6 +1235,30 @@ impl Chain {
 			}
 		}
 
+		// Entries are missing for outputs created before the output_created index existed
+		// (or before a txhashset download), these are skipped.
+		if let Ok(prev) = batch.get_previous_header(&tail) {
+			let (from_pos, from_token_pos) = old_tail_sizes;
+			for pos in (from_pos + 1)..=prev.output_mmr_size {
+				if pmmr::is_leaf(pos) {
+					match batch.get_output_created(pos) {
+						Ok(_) => batch.delete_output_created(pos)?,
+						Err(NotFoundErr(_)) => {}
+						Err(e) => return Err(e.into()),
+					}
+				}
+			}
+			for pos in (from_token_pos + 1)..=prev.token_output_mmr_size {
+				if pmmr::is_leaf(pos) {
+					match batch.get_token_output_created(pos) {
+						Ok(_) => batch.delete_token_output_created(pos)?,
+						Err(NotFoundErr(_)) => {}
+						Err(e) => return Err(e.into()),
+					}
+				}
+			}
+		}
+
 		batch.save_body_tail(&Tip::from_header(&tail))?;
 
 		debug!(
@@ -1475,6 +1607,18 @@ impl Chain {
 		Ok(self.get_block_header(&hash)?)
 	}
 
+	/// Gets the height of the block containing the kernel with the given excess
+	/// via the kernel height index. Unlike get_kernel_height this does not scan.
+	pub fn kernel_height(&self, excess: &Commitment) -> Result<Option<u64>, Error> {
+		self.txhashset.read().kernel_height(excess)
+	}
+
+	/// Gets the height of the block containing the token kernel with the given excess
+	/// via the token kernel height index.
+	pub fn token_kernel_height(&self, excess: &Commitment) -> Result<Option<u64>, Error> {
+		self.txhashset.read().token_kernel_height(excess)
+	}
+
 	/// Gets the kernel with a given excess and the block height it is included in.
 	pub fn get_kernel_height(
 		&self,
@@ -1619,7 +1763,7 @@ impl Chain {
 		if chain_header.hash() == header.hash() {
 			Ok(())
 		} else {
-			Err(ErrorKind::Other("not on current chain".to_string()).into())
+			Err(ErrorKind::NotOnCurrentChain.into())
 		}
 	}
 
@@ -92,12 +92,19 @@ pub enum ErrorKind {
 	/// One of the inputs in the block has already been spent
 	#[fail(display = "Already Spent: {:?}", _0)]
 	AlreadySpent(Commitment),
+	/// One or more inputs in the block do not spend a currently unspent output
+	#[fail(display = "Unspendable Inputs: {:?}", _0)]
+	UnspendableInputs(Vec<Commitment>),
 	/// An output with that commitment already exists (should be unique)
 	#[fail(display = "Duplicate Commitment: {:?}", _0)]
 	DuplicateCommitment(Commitment),
 	/// An token_issue_output with that token_key already exists (should be unique)
 	#[fail(display = "Duplicate TokenKey: {:?}", _0)]
 	DuplicateTokenKey(TokenKey),
+	/// The same TokenKey appears more than once in the token issue proof MMR
+	/// (token type, first pos, duplicate pos)
+	#[fail(display = "Duplicate token issue proofs: {:?}", _0)]
+	DuplicateIssueProof(Vec<(TokenKey, u64, u64)>),
 	/// Attempt to spend a coinbase output before it sufficiently matures.
 	#[fail(display = "Attempt to spend immature coinbase")]
 	ImmatureCoinbase,
@@ -155,6 +162,142 @@ pub enum ErrorKind {
 	/// Internal Roaring Bitmap error
 	#[fail(display = "Roaring Bitmap error")]
 	Bitmap,
+	/// Output and rangeproof MMRs (or token equivalents) differ in size
+	#[fail(display = "MMR parity mismatch: {}", _0)]
+	MMRParityMismatch(String),
+	/// Failed to build the bitmap accumulator from the output MMR
+	#[fail(
+		display = "Bitmap accumulator init failed: {}, try rebuild_bitmap_accumulator",
+		_0
+	)]
+	BitmapAccumulatorInit(String),
+	/// The bitmap accumulator does not match the output MMR it was built from
+	#[fail(
+		display = "Bitmap accumulator mismatch: {}, try rebuild_bitmap_accumulator",
+		_0
+	)]
+	BitmapAccumulatorMismatch(String),
+	/// Failed to open the MMR backend in one of the txhashset subdirs
+	#[fail(
+		display = "Failed to open {} MMR (protocol version {}): {}",
+		subdir, version, reason
+	)]
+	OpenMMR {
+		/// The txhashset subdir of the MMR
+		subdir: String,
+		/// The (last) protocol version attempted
+		version: u32,
+		/// Why the open failed
+		reason: String,
+	},
+	/// Requested rewind is deeper than the max rewind depth of the extension
+	#[fail(display = "Rewind depth {} exceeds max rewind depth {}", _0, _1)]
+	RewindTooDeep(u64, u64),
+	/// One or more MMR roots do not match the expected roots
+	#[fail(display = "Roots mismatch: {:?}", _0)]
+	RootsMismatch(Vec<String>),
+	/// A file in the txhashset zip does not match the checksum in the zip manifest
+	#[fail(display = "TxHashSet zip checksum mismatch: {}", _0)]
+	ZipChecksumMismatch(String),
+	/// An entry in the txhashset zip has a path outside the txhashset dir
+	#[fail(display = "TxHashSet zip unsafe path: {}", _0)]
+	ZipUnsafePath(String),
+	/// An entry (or entries) in the txhashset zip exceeds the max size limits
+	#[fail(display = "TxHashSet zip too large: {}", _0)]
+	ZipTooLarge(String),
+	/// The txhashset zip is missing one or more of the required MMR files
+	#[fail(display = "TxHashSet zip missing required files: {}", _0)]
+	ZipMissingFiles(String),
+	/// The txhashset zip is being created by another process holding its lock file
+	#[fail(display = "TxHashSet zip locked by another process: {}", _0)]
+	ZipLocked(String),
+	/// A hash file in the txhashset zip has an implausible size for the MMR size in the header
+	#[fail(
+		display = "TxHashSet zip implausible size {} for {}, mmr size {}",
+		_0, _1, _2
+	)]
+	ZipImplausibleSize(u64, String, u64),
+	/// MMR backend path is not valid unicode
+	#[fail(display = "invalid file path")]
+	InvalidFilePath,
+	/// No header hash in the header MMR at the requested height
+	#[fail(display = "get header hash by height")]
+	HeaderHashByHeight,
+	/// No header in the header MMR at the requested height
+	#[fail(display = "get header by height")]
+	HeaderByHeight,
+	/// The MMR is empty so has no head
+	#[fail(display = "MMR empty, no head")]
+	EmptyMMR,
+	/// The head hash could not be found in the header MMR
+	#[fail(display = "failed to find head hash")]
+	HeadHashNotFound,
+	/// The header MMR size is inconsistent with the head height
+	#[fail(display = "header MMR size {} inconsistent with head at {}", _0, _1)]
+	HeaderMMRSizeMismatch(u64, u64),
+	/// No header matches the provided (output, kernel) MMR sizes
+	#[fail(
+		display = "no header matches content mmr sizes (output {}, kernel {})",
+		_0, _1
+	)]
+	NoHeaderForMMRSizes(u64, u64),
+	/// The utxo export is not sorted by commitment
+	#[fail(display = "utxo export is not sorted")]
+	UtxoExportNotSorted,
+	/// The header MMR does not cover every utxo when (re)building an output_pos index
+	#[fail(display = "{}", _0)]
+	OutputPosIndexUncovered(String),
+	/// The header is not on the current chain
+	#[fail(display = "not on current chain")]
+	NotOnCurrentChain,
+	/// The header is beyond the head of the current chain
+	#[fail(display = "not on current chain, out beyond")]
+	NotOnCurrentChainBeyondHead,
+	/// Requested rewind height is beyond the head
+	#[fail(display = "rewind to height {}, out beyond head {}", _0, _1)]
+	RewindBeyondHead(u64, u64),
+	/// Output and rangeproof MMRs differ in size after applying an output
+	#[fail(display = "output vs rproof MMRs different sizes")]
+	OutputRproofSizeMismatch,
+	/// Output and rangeproof were applied at different MMR positions
+	#[fail(display = "output vs rproof MMRs different pos")]
+	OutputRproofPosMismatch,
+	/// Token output and token rangeproof MMRs differ in size after applying a token output
+	#[fail(display = "token_output vs token_rproof MMRs different sizes")]
+	TokenOutputRproofSizeMismatch,
+	/// Token output and token rangeproof were applied at different MMR positions
+	#[fail(display = "token_output vs token_rproof MMRs different pos")]
+	TokenOutputRproofPosMismatch,
+	/// Token output applied as a token issue is not a token issue
+	#[fail(display = "token_output is not a token issue")]
+	NotTokenIssue,
+	/// Failed to snapshot an MMR (write the rewound leaf file)
+	#[fail(display = "MMR snapshot error: {}", _0)]
+	Snapshot(String),
+	/// The (first) header is not an ancestor of the (second) header
+	#[fail(
+		display = "validate_kernel_sums_incremental: {} is not an ancestor of {}",
+		_0, _1
+	)]
+	NotAncestor(String, String),
+	/// Outputs (or their rangeproofs) have been pruned within the horizon
+	#[fail(
+		display = "pruned within horizon at {}: output pos {:?}, token output pos {:?}",
+		_0, _1, _2
+	)]
+	PrunedWithinHorizon(u64, Vec<u64>, Vec<u64>),
+	/// A peak of the MMR at the requested size has been pruned
+	#[fail(display = "peak at pos {} for mmr size {} has been pruned", _0, _1)]
+	PrunedPeak(u64, u64),
+	/// The output in the output MMR does not match the output in the block
+	#[fail(display = "output at {} does not match block {}", _0, _1)]
+	OutputMismatch(u64, String),
+	/// Repairing the txhashset at the head header failed, see TxHashSet::verify_and_repair
+	#[fail(
+		display = "failed to repair mmrs at head {}: {}, txhashset needs to be resynced",
+		_0, _1
+	)]
+	RepairFailed(String, Box<ErrorKind>),
 	/// Block Aux data is invalid
 	#[fail(display = "Block Aux data error")]
 	BadAuxDataBlock,
@@ -204,7 +347,32 @@ impl Error {
 			| ErrorKind::StoreErr(_, _)
 			| ErrorKind::SerErr(_)
 			| ErrorKind::TxHashSetErr(_)
+			| ErrorKind::OpenMMR { .. }
 			| ErrorKind::GenesisBlockRequired
+			| ErrorKind::InvalidFilePath
+			| ErrorKind::HeaderHashByHeight
+			| ErrorKind::HeaderByHeight
+			| ErrorKind::EmptyMMR
+			| ErrorKind::HeadHashNotFound
+			| ErrorKind::HeaderMMRSizeMismatch(_, _)
+			| ErrorKind::NoHeaderForMMRSizes(_, _)
+			| ErrorKind::UtxoExportNotSorted
+			| ErrorKind::OutputPosIndexUncovered(_)
+			| ErrorKind::NotOnCurrentChain
+			| ErrorKind::NotOnCurrentChainBeyondHead
+			| ErrorKind::RewindBeyondHead(_, _)
+			| ErrorKind::OutputRproofSizeMismatch
+			| ErrorKind::OutputRproofPosMismatch
+			| ErrorKind::TokenOutputRproofSizeMismatch
+			| ErrorKind::TokenOutputRproofPosMismatch
+			| ErrorKind::NotTokenIssue
+			| ErrorKind::Snapshot(_)
+			| ErrorKind::NotAncestor(_, _)
+			| ErrorKind::ZipLocked(_)
+			| ErrorKind::PrunedWithinHorizon(_, _, _)
+			| ErrorKind::PrunedPeak(_, _)
+			| ErrorKind::OutputMismatch(_, _)
+			| ErrorKind::RepairFailed(_, _)
 			| ErrorKind::Other(_) => false,
 			_ => true,
 		}
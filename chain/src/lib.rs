@@ -42,9 +42,10 @@ pub mod types;
 
 // Re-export the base interface
 
-pub use crate::chain::{Chain, MAX_ORPHAN_SIZE};
+pub use crate::chain::{Chain, HEADER_HASH_CACHE_SIZE, MAX_ORPHAN_SIZE};
 pub use crate::error::{Error, ErrorKind};
 pub use crate::store::ChainStore;
 pub use crate::types::{
 	BlockStatus, ChainAdapter, Options, SyncState, SyncStatus, Tip, TxHashsetWriteStatus,
+	ValidationConfig,
 };
@@ -45,6 +45,8 @@ pub struct BlockContext<'a> {
 	pub batch: store::Batch<'a>,
 	/// The verifier cache (caching verifier for rangeproofs and kernel signatures)
 	pub verifier_cache: Arc<RwLock<dyn VerifierCache>>,
+	/// The max number of blocks we will rewind to process a block on a fork.
+	pub max_reorg_depth: Option<u64>,
 }
 
 fn validate_block_auxdata(
@@ -208,7 +210,10 @@ pub fn process_block(b: &Block, ctx: &mut BlockContext<'_>) -> Result<Option<Tip
 	let ref mut header_pmmr = &mut ctx.header_pmmr;
 	let ref mut txhashset = &mut ctx.txhashset;
 	let ref mut batch = &mut ctx.batch;
+	let max_reorg_depth = ctx.max_reorg_depth;
 	txhashset::extending(header_pmmr, txhashset, batch, |ext, batch| {
+		// Protect against pathological reorgs (a bug or a malicious peer).
+		ext.extension.set_max_rewind_depth(max_reorg_depth);
 		rewind_and_apply_fork(&prev, ext, batch)?;
 
 		// Check any coinbase being spent have matured sufficiently.
@@ -24,7 +24,7 @@ use crate::types::{CommitPos, Tip};
 use crate::util::secp::pedersen::Commitment;
 use croaring::Bitmap;
 use grin_store as store;
-use grin_store::{option_to_not_found, to_key, Error, SerIterator};
+use grin_store::{option_to_not_found, to_key, u64_to_key, Error, SerIterator};
 use std::convert::TryInto;
 use std::sync::Arc;
 
@@ -38,12 +38,16 @@ const OUTPUT_POS_PREFIX: u8 = b'p';
 const BLOCK_INPUT_BITMAP_PREFIX: u8 = b'B';
 const BLOCK_SUMS_PREFIX: u8 = b'M';
 const BLOCK_SPENT_PREFIX: u8 = b's';
+const OUTPUT_CREATED_PREFIX: u8 = b'q';
+const KERNEL_HEIGHT_PREFIX: u8 = b'k';
 
 const TOKEN_COMMIT_POS_PREFIX: u8 = b't';
 const TOKEN_ISSUE_PROOF_POS_PREFIX: u8 = b'P';
 const TOKEN_EXCESS_SUMS_PREFIX: u8 = b'S';
 const TOKEN_BLOCK_INPUT_BITMAP_PREFIX: u8 = b'C';
 const TOKEN_BLOCK_SPENT_PREFIX: u8 = b'Z';
+const TOKEN_OUTPUT_CREATED_PREFIX: u8 = b'Q';
+const TOKEN_KERNEL_HEIGHT_PREFIX: u8 = b'K';
 
 /// All chain-related database operations
 pub struct ChainStore {
@@ -173,6 +177,20 @@ impl ChainStore {
 		))
 	}
 
+	/// Get the height of the block containing the kernel with the given excess.
+	pub fn get_kernel_height(&self, excess: &Commitment) -> Result<Option<u64>, Error> {
+		self.db
+			.get_ser(&to_key(KERNEL_HEIGHT_PREFIX, &mut excess.as_ref().to_vec()))
+	}
+
+	/// Get the height of the block containing the token kernel with the given excess.
+	pub fn get_token_kernel_height(&self, excess: &Commitment) -> Result<Option<u64>, Error> {
+		self.db.get_ser(&to_key(
+			TOKEN_KERNEL_HEIGHT_PREFIX,
+			&mut excess.as_ref().to_vec(),
+		))
+	}
+
 	/// Builds a new batch to be used with this store.
 	pub fn batch(&self) -> Result<Batch<'_>, Error> {
 		Ok(Batch {
@@ -337,6 +355,111 @@ impl<'a> Batch<'a> {
 		))
 	}
 
+	/// Save the height of the block containing the kernel with the given excess.
+	/// A later kernel with the same excess replaces the entry.
+	pub fn save_kernel_height(&self, excess: &Commitment, height: u64) -> Result<(), Error> {
+		self.db.put_ser(
+			&to_key(KERNEL_HEIGHT_PREFIX, &mut excess.as_ref().to_vec())[..],
+			&height,
+		)
+	}
+
+	/// Save the height of the block containing the token kernel with the given excess.
+	pub fn save_token_kernel_height(&self, excess: &Commitment, height: u64) -> Result<(), Error> {
+		self.db.put_ser(
+			&to_key(TOKEN_KERNEL_HEIGHT_PREFIX, &mut excess.as_ref().to_vec())[..],
+			&height,
+		)
+	}
+
+	/// Get the height of the block containing the kernel with the given excess.
+	pub fn get_kernel_height(&self, excess: &Commitment) -> Result<Option<u64>, Error> {
+		self.db
+			.get_ser(&to_key(KERNEL_HEIGHT_PREFIX, &mut excess.as_ref().to_vec()))
+	}
+
+	/// Get the height of the block containing the token kernel with the given excess.
+	pub fn get_token_kernel_height(&self, excess: &Commitment) -> Result<Option<u64>, Error> {
+		self.db.get_ser(&to_key(
+			TOKEN_KERNEL_HEIGHT_PREFIX,
+			&mut excess.as_ref().to_vec(),
+		))
+	}
+
+	/// Delete the kernel height index entry for the given excess.
+	pub fn delete_kernel_height(&self, excess: &Commitment) -> Result<(), Error> {
+		self.db
+			.delete(&to_key(KERNEL_HEIGHT_PREFIX, &mut excess.as_ref().to_vec()))
+	}
+
+	/// Delete the token kernel height index entry for the given excess.
+	pub fn delete_token_kernel_height(&self, excess: &Commitment) -> Result<(), Error> {
+		self.db.delete(&to_key(
+			TOKEN_KERNEL_HEIGHT_PREFIX,
+			&mut excess.as_ref().to_vec(),
+		))
+	}
+
+	/// Save the commitment and block height of the output created at this
+	/// output MMR pos. Lets rewind clean up the output_pos index without the block.
+	/// Entries are removed on rewind and as blocks are removed beyond the horizon.
+	/// In archive mode blocks are never removed so this index grows without bound,
+	/// one entry (commitment and height) per output ever created.
+	pub fn save_output_created(
+		&self,
+		pos: u64,
+		commit: &Commitment,
+		height: u64,
+	) -> Result<(), Error> {
+		self.db.put_ser(
+			&u64_to_key(OUTPUT_CREATED_PREFIX, pos)[..],
+			&(*commit, height),
+		)
+	}
+
+	/// Save the commitment and block height of the token output created at this
+	/// token output MMR pos.
+	pub fn save_token_output_created(
+		&self,
+		pos: u64,
+		commit: &Commitment,
+		height: u64,
+	) -> Result<(), Error> {
+		self.db.put_ser(
+			&u64_to_key(TOKEN_OUTPUT_CREATED_PREFIX, pos)[..],
+			&(*commit, height),
+		)
+	}
+
+	/// Get the commitment and block height of the output created at this output MMR pos.
+	pub fn get_output_created(&self, pos: u64) -> Result<(Commitment, u64), Error> {
+		option_to_not_found(
+			self.db.get_ser(&u64_to_key(OUTPUT_CREATED_PREFIX, pos)),
+			|| format!("output created: {}", pos),
+		)
+	}
+
+	/// Get the commitment and block height of the token output created at this
+	/// token output MMR pos.
+	pub fn get_token_output_created(&self, pos: u64) -> Result<(Commitment, u64), Error> {
+		option_to_not_found(
+			self.db
+				.get_ser(&u64_to_key(TOKEN_OUTPUT_CREATED_PREFIX, pos)),
+			|| format!("token output created: {}", pos),
+		)
+	}
+
+	/// Delete the output created entry for this output MMR pos.
+	pub fn delete_output_created(&self, pos: u64) -> Result<(), Error> {
+		self.db.delete(&u64_to_key(OUTPUT_CREATED_PREFIX, pos))
+	}
+
+	/// Delete the token output created entry for this token output MMR pos.
+	pub fn delete_token_output_created(&self, pos: u64) -> Result<(), Error> {
+		self.db
+			.delete(&u64_to_key(TOKEN_OUTPUT_CREATED_PREFIX, pos))
+	}
+
 	/// When using the output_pos iterator we have access to the index keys but not the
 	/// original commitment that the key is constructed from. So we need a way of comparing
 	/// a key with another commitment without reconstructing the commitment from the key bytes.
@@ -559,6 +682,21 @@ impl<'a> Batch<'a> {
 		}
 	}
 
+	/// Save a legacy block input bitmap, as written by nodes prior to the spent index.
+	/// Only needed to reproduce a legacy db (see TxHashSet::migrate_spent_indexes).
+	pub fn save_legacy_input_bitmap(&self, bh: &Hash, bitmap: &Bitmap) -> Result<(), Error> {
+		self.db.put(
+			&to_key(BLOCK_INPUT_BITMAP_PREFIX, &mut bh.to_vec())[..],
+			&bitmap.serialize(),
+		)
+	}
+
+	/// Delete the "spent index" for the specified block, leaving it to be rewound via the
+	/// legacy input bitmap (see TxHashSet::migrate_spent_indexes).
+	pub fn delete_block_spent_index(&self, bh: &Hash) -> Result<(), Error> {
+		self.delete_spent_index(bh)
+	}
+
 	fn get_legacy_input_bitmap(&self, bh: &Hash) -> Result<Bitmap, Error> {
 		if let Ok(Some(bytes)) = self
 			.db
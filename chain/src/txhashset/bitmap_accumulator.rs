@@ -41,6 +41,9 @@ use crate::error::{Error, ErrorKind};
 /// both inclusion and location in the overall "accumulator" MMR. We plan to take advantage of
 /// this during fast sync, allowing for validation of partial data.
 ///
+/// The chunks themselves are retained in memory (one bit per output) so individual bits
+/// can be queried cheaply via is_set().
+///
 #[derive(Clone)]
 pub struct BitmapAccumulator {
 	backend: VecBackend<BitmapChunk>,
@@ -50,7 +53,7 @@ impl BitmapAccumulator {
 	/// Crate a new empty bitmap accumulator.
 	pub fn new() -> BitmapAccumulator {
 		BitmapAccumulator {
-			backend: VecBackend::new_hash_only(),
+			backend: VecBackend::new(),
 		}
 	}
 
@@ -174,6 +177,15 @@ impl BitmapAccumulator {
 			.map_err(|e| ErrorKind::Other(e).into())
 	}
 
+	/// Is the bit at the provided idx set in the bitmap accumulator?
+	/// Bits beyond the last chunk are considered unset.
+	pub fn is_set(&self, idx: u64) -> bool {
+		let chunk_pos = pmmr::insertion_to_pmmr_index(BitmapAccumulator::chunk_idx(idx) + 1);
+		ReadonlyPMMR::at(&self.backend, self.backend.size())
+			.get_data(chunk_pos)
+			.map_or(false, |chunk| chunk.get(idx % 1024))
+	}
+
 	/// The root hash of the bitmap accumulator MMR.
 	pub fn root(&self) -> Hash {
 		ReadonlyPMMR::at(&self.backend, self.backend.size()).root()
@@ -203,6 +215,15 @@ impl BitmapChunk {
 		self.0.set(idx, value)
 	}
 
+	/// Get a single bit in this chunk.
+	/// 0-indexed from start of chunk.
+	/// Panics if idx is outside the valid range of bits in a chunk.
+	pub fn get(&self, idx: u64) -> bool {
+		let idx = usize::try_from(idx).expect("usize from u64");
+		assert!(idx < Self::LEN_BITS);
+		self.0.get(idx).unwrap_or(false)
+	}
+
 	/// Does this bitmap chunk have any bits set to 1?
 	pub fn any(&self) -> bool {
 		self.0.any()
@@ -231,8 +252,8 @@ impl Writeable for BitmapChunk {
 
 impl Readable for BitmapChunk {
 	/// Reading is not currently supported, just return an empty one for now.
-	/// We store the underlying roaring bitmap externally for the bitmap accumulator
-	/// and the "hash only" backend means we never actually read these chunks.
+	/// Chunks are only ever held in memory by the vec backend of the bitmap accumulator
+	/// so we never actually deserialize these chunks.
 	fn read(_reader: &mut dyn Reader) -> Result<BitmapChunk, ser::Error> {
 		Ok(BitmapChunk::new())
 	}
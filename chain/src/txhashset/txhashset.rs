@@ -15,29 +15,42 @@
 //! Utility structs to handle the 3 MMRs (output, rangeproof,
 //! kernel) along the overall header MMR conveniently and transactionally.
 
-use crate::core::core::committed::Committed;
-use crate::core::core::hash::{Hash, Hashed};
+use crate::core::core::committed::{self, Committed};
+use crate::core::core::hash::{Hash, HashWriter, Hashed, ZERO_HASH};
 use crate::core::core::merkle_proof::MerkleProof;
-use crate::core::core::pmmr::{self, Backend, ReadonlyPMMR, RewindablePMMR, PMMR};
-use crate::core::core::{Block, BlockHeader, Input, Output, OutputIdentifier, TxKernel};
+use crate::core::core::pmmr::{self, Backend, ReadonlyPMMR, RewindablePMMR, VecBackend, PMMR};
+use crate::core::core::{Block, BlockHeader, BlockSums, Input, Output, OutputIdentifier, TxKernel};
 use crate::core::core::{
 	BlockTokenSums, TokenInput, TokenIssueProof, TokenKey, TokenOutput, TokenOutputIdentifier,
 	TokenTxKernel,
 };
-use crate::core::ser::{PMMRable, ProtocolVersion};
+use crate::core::global;
+use crate::core::ser::{self, PMMRIndexHashable, PMMRable, ProtocolVersion, Writer};
 use crate::error::{Error, ErrorKind};
 use crate::store::{Batch, ChainStore};
 use crate::txhashset::bitmap_accumulator::BitmapAccumulator;
 use crate::txhashset::{RewindableKernelView, UTXOView};
-use crate::types::{CommitPos, OutputRoots, Tip, TxHashSetRoots, TxHashsetWriteStatus};
+use crate::types::{
+	CommitPos, CommitmentSeen, CompactionStats, ExtensionSummary, IndexCheckReport,
+	KernelSumComponents, MmrPeakPositions, MmrSizes, OutputRoots, OutputStatus, PruneSnapshot,
+	RepairReport, Tip, TokenSupply, TxHashSetRoots, TxHashsetWriteStatus, UtxoDiff,
+	ValidationConfig, VerificationProgress,
+};
 use crate::util::secp::pedersen::{Commitment, RangeProof};
-use crate::util::{file, secp_static, zip};
+use crate::util::{secp_static, static_secp_instance, to_hex, zip, Mutex};
 use croaring::Bitmap;
+use failure::Fail;
+use fs2::FileExt;
 use grin_store;
 use grin_store::pmmr::{clean_files_by_prefix, PMMRBackend};
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::path::{Path, PathBuf};
+use lru_cache::LruCache;
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -53,17 +66,101 @@ const TOKEN_KERNEL_SUBDIR: &str = "tokenkernel";
 
 const TXHASHSET_ZIP: &str = "txhashset_snapshot";
 
+// Manifest of sha256 checksums of the files in the txhashset zip.
+const TXHASHSET_MANIFEST: &str = "txhashset_manifest.txt";
+
+// zstd compression level used for compressed txhashset archives.
+const TXHASHSET_ZSTD_LEVEL: i32 = 3;
+
+const VERIFICATION_PROGRESS_FILE: &str = "verification_progress.bin";
+
+/// A single failure found when verifying rangeproofs and kernel signatures
+/// with collect_all_failures enabled.
+/// The token flag indicates the failure was found in the corresponding token MMR.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerificationFailure {
+	/// Output missing at the given leaf pos.
+	OutputNotFound {
+		/// Failure found in the token MMRs.
+		token: bool,
+		/// MMR pos of the missing output.
+		pos: u64,
+	},
+	/// Rangeproof missing at the given leaf pos.
+	RangeproofNotFound {
+		/// Failure found in the token MMRs.
+		token: bool,
+		/// MMR pos of the missing rangeproof.
+		pos: u64,
+	},
+	/// Rangeproof failed to verify for the output commitment.
+	InvalidRangeproof {
+		/// Failure found in the token MMRs.
+		token: bool,
+		/// MMR pos of the output and rangeproof.
+		pos: u64,
+		/// Output commitment.
+		commit: Commitment,
+	},
+	/// Kernel missing at the given leaf pos.
+	KernelNotFound {
+		/// Failure found in the token MMRs.
+		token: bool,
+		/// MMR pos of the missing kernel.
+		pos: u64,
+	},
+	/// Kernel signature failed to verify.
+	InvalidKernelSignature {
+		/// Failure found in the token MMRs.
+		token: bool,
+		/// MMR pos of the kernel.
+		pos: u64,
+		/// Kernel excess commitment.
+		excess: Commitment,
+	},
+}
+
+/// Controls whether committing a txhashset extension fsyncs the MMR backend files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DurabilityMode {
+	/// Every commit is fsynced to disk (the default).
+	Durable,
+	/// Commits are written to the OS page cache but not fsynced.
+	/// Significantly faster during initial sync, but on an OS crash or power loss recent
+	/// commits may be lost or the MMR files left inconsistent with each other and with
+	/// the db. A node running in this mode must be prepared to discard its txhashset
+	/// and resync after such a crash. A process crash alone does not lose data.
+	Buffered,
+}
+
+impl Default for DurabilityMode {
+	fn default() -> DurabilityMode {
+		DurabilityMode::Durable
+	}
+}
+
 /// Convenience wrapper around a single prunable MMR backend.
 pub struct PMMRHandle<T: PMMRable> {
 	/// The backend storage for the MMR.
 	pub backend: PMMRBackend<T>,
 	/// The last position accessible via this MMR handle (backend may continue out beyond this).
 	pub last_pos: u64,
+	// optional LRU cache of header hashes by height, only used by the header MMR
+	header_hash_cache: Option<Mutex<LruCache<u64, Hash>>>,
 }
 
 impl<T: PMMRable> PMMRHandle<T> {
 	/// Constructor to create a PMMR handle from an existing directory structure on disk.
 	/// Creates the backend files as necessary if they do not already exist.
+	///
+	/// Note: reads (get_data/get_hash via ReadonlyPMMR) are always served from a memory
+	/// map of the underlying data and hash files (see AppendOnlyFile in the store crate),
+	/// so repeated reads hit the page cache without per-call syscalls. Only appends are
+	/// buffered. The memory map is only replaced when the backend is flushed, rewound
+	/// on disk or compacted, all of which require `&mut` access to the backend
+	/// (i.e. the txhashset write lock). Readers holding the read lock can therefore never
+	/// observe a file truncated underneath the map. Another process reading these files
+	/// must use open_readonly, which never maps them.
 	pub fn new(
 		root_dir: &str,
 		sub_dir: &str,
@@ -74,24 +171,75 @@ impl<T: PMMRable> PMMRHandle<T> {
 	) -> Result<PMMRHandle<T>, Error> {
 		let path = Path::new(root_dir).join(sub_dir).join(file_name);
 		fs::create_dir_all(path.clone())?;
-		let path_str = path
-			.to_str()
-			.ok_or_else(|| ErrorKind::Other("invalid file path".to_owned()))?;
+		let path_str = path.to_str().ok_or_else(|| ErrorKind::InvalidFilePath)?;
 		let backend = PMMRBackend::new(path_str.to_string(), prunable, version, header)?;
 		let last_pos = backend.unpruned_size();
-		Ok(PMMRHandle { backend, last_pos })
+		Ok(PMMRHandle {
+			backend,
+			last_pos,
+			header_hash_cache: None,
+		})
+	}
+
+	/// Constructor to create a read-only PMMR handle from an existing directory on disk.
+	/// Never creates or modifies any file, the backend rejects any attempt to sync or compact.
+	/// The files are read via pread (not memory mapped), bounded by their flushed size when
+	/// opened, so this is safe against files concurrently written by a running node.
+	pub fn open_readonly(
+		root_dir: &str,
+		sub_dir: &str,
+		file_name: &str,
+		prunable: bool,
+		version: ProtocolVersion,
+	) -> Result<PMMRHandle<T>, Error> {
+		let path = Path::new(root_dir).join(sub_dir).join(file_name);
+		let backend = PMMRBackend::open_readonly(path, prunable, version)?;
+		let last_pos = backend.unpruned_size();
+		Ok(PMMRHandle {
+			backend,
+			last_pos,
+			header_hash_cache: None,
+		})
 	}
 }
 
 impl PMMRHandle<BlockHeader> {
+	/// Enable (or resize) the LRU cache of header hashes by height used by
+	/// get_header_hash_by_height. A capacity of 0 disables the cache.
+	/// The cache is cleared whenever a header extension is committed.
+	pub fn set_header_hash_cache_capacity(&mut self, capacity: usize) {
+		self.header_hash_cache = if capacity == 0 {
+			None
+		} else {
+			Some(Mutex::new(LruCache::new(capacity)))
+		};
+	}
+
+	/// Clear the header hash cache (if enabled).
+	/// Must be called whenever the underlying header MMR changes.
+	pub fn clear_header_hash_cache(&self) {
+		if let Some(ref cache) = self.header_hash_cache {
+			cache.lock().clear();
+		}
+	}
+
 	/// Get the header hash at the specified height based on the current header MMR state.
 	pub fn get_header_hash_by_height(&self, height: u64) -> Result<Hash, Error> {
+		if let Some(ref cache) = self.header_hash_cache {
+			if let Some(hash) = cache.lock().get_mut(&height) {
+				return Ok(*hash);
+			}
+		}
 		let pos = pmmr::insertion_to_pmmr_index(height + 1);
 		let header_pmmr = ReadonlyPMMR::at(&self.backend, self.last_pos);
 		if let Some(entry) = header_pmmr.get_data(pos) {
-			Ok(entry.hash())
+			let hash = entry.hash();
+			if let Some(ref cache) = self.header_hash_cache {
+				cache.lock().insert(height, hash);
+			}
+			Ok(hash)
 		} else {
-			Err(ErrorKind::Other("get header hash by height".to_string()).into())
+			Err(ErrorKind::HeaderHashByHeight.into())
 		}
 	}
 
@@ -99,18 +247,96 @@ impl PMMRHandle<BlockHeader> {
 	/// Find the last leaf pos based on MMR size and return its header hash.
 	pub fn head_hash(&self) -> Result<Hash, Error> {
 		if self.last_pos == 0 {
-			return Err(ErrorKind::Other("MMR empty, no head".to_string()).into());
+			return Err(ErrorKind::EmptyMMR.into());
 		}
 		let header_pmmr = ReadonlyPMMR::at(&self.backend, self.last_pos);
 		let leaf_pos = pmmr::bintree_rightmost(self.last_pos);
 		if let Some(entry) = header_pmmr.get_data(leaf_pos) {
 			Ok(entry.hash())
 		} else {
-			Err(ErrorKind::Other("failed to find head hash".to_string()).into())
+			Err(ErrorKind::HeadHashNotFound.into())
+		}
+	}
+
+	/// Quick integrity check of the header MMR against the head header.
+	/// Each header commits to the root of the header MMR containing all previous headers
+	/// (prev_root), so we compute the root of the MMR at last_pos, excluding the head itself,
+	/// and confirm it matches the prev_root of the head header.
+	/// The head header must be the last leaf at last_pos.
+	pub fn validate_head_root(&self, batch: &Batch<'_>) -> Result<(), Error> {
+		let head = batch.get_block_header(&self.head_hash()?)?;
+		if head.height == 0 {
+			return Ok(());
+		}
+		if pmmr::insertion_to_pmmr_index(head.height + 1) != pmmr::bintree_rightmost(self.last_pos)
+		{
+			return Err(ErrorKind::HeaderMMRSizeMismatch(self.last_pos, head.height).into());
+		}
+		let prev_size = pmmr::insertion_to_pmmr_index(head.height + 1) - 1;
+		let root = ReadonlyPMMR::at(&self.backend, prev_size).root();
+		if root != head.prev_root {
+			return Err(ErrorKind::RootsMismatch(vec![format!(
+				"header_root: expected {}, got {}",
+				head.prev_root, root
+			)])
+			.into());
 		}
+		Ok(())
+	}
+
+	/// Iterate over the (height, hash) of headers from from_height to to_height (inclusive)
+	/// based on the current header MMR state.
+	/// The range is clamped to the current extent of the header MMR.
+	pub fn iter_header_hashes(
+		&self,
+		from_height: u64,
+		to_height: u64,
+	) -> impl Iterator<Item = (u64, Hash)> + '_ {
+		let header_pmmr = ReadonlyPMMR::at(&self.backend, self.last_pos);
+		let n_leaves = pmmr::n_leaves(self.last_pos);
+		let to_height = to_height.min(n_leaves.saturating_sub(1));
+		let heights = if n_leaves == 0 {
+			1..=0
+		} else {
+			from_height..=to_height
+		};
+		heights.filter_map(move |height| {
+			let pos = pmmr::insertion_to_pmmr_index(height + 1);
+			header_pmmr
+				.get_data(pos)
+				.map(|entry| (height, entry.hash()))
+		})
 	}
 }
 
+/// Readonly view of all the txhashset MMRs pinned to specific (past) sizes.
+/// See TxHashSet::readonly_view_at().
+pub struct ReadonlyView<'a> {
+	/// Output MMR.
+	pub output_pmmr: ReadonlyPMMR<'a, Output, PMMRBackend<Output>>,
+	/// Rangeproof MMR.
+	pub rproof_pmmr: ReadonlyPMMR<'a, RangeProof, PMMRBackend<RangeProof>>,
+	/// Kernel MMR.
+	pub kernel_pmmr: ReadonlyPMMR<'a, TxKernel, PMMRBackend<TxKernel>>,
+	/// Token output MMR.
+	pub token_output_pmmr: ReadonlyPMMR<'a, TokenOutput, PMMRBackend<TokenOutput>>,
+	/// Token rangeproof MMR.
+	pub token_rproof_pmmr: ReadonlyPMMR<'a, RangeProof, PMMRBackend<RangeProof>>,
+	/// Token issue proof MMR.
+	pub token_issue_proof_pmmr: ReadonlyPMMR<'a, TokenIssueProof, PMMRBackend<TokenIssueProof>>,
+	/// Token kernel MMR.
+	pub token_kernel_pmmr: ReadonlyPMMR<'a, TokenTxKernel, PMMRBackend<TokenTxKernel>>,
+}
+
+/// How strictly to treat an output/rangeproof MMR size mismatch found at open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParityCheck {
+	/// Log a warning and continue opening the txhashset.
+	Warn,
+	/// Fail to open the txhashset.
+	Error,
+}
+
 /// An easy to manipulate structure holding the 3 MMRs necessary to
 /// validate blocks and capturing the output set, associated rangeproofs and the
 /// kernels. Also handles the index of Commitments to positions in the
@@ -132,16 +358,51 @@ pub struct TxHashSet {
 
 	bitmap_accumulator: BitmapAccumulator,
 
+	// cached count of unspent outputs and token outputs
+	utxo_count: u64,
+	token_utxo_count: u64,
+
+	durability_mode: DurabilityMode,
+
+	// optional in-memory index of kernel excess to kernel MMR pos (None if disabled)
+	kernel_pos_index: Option<HashMap<Commitment, u64>>,
+
 	// chain store used as index of commitments to MMR positions
 	commit_index: Arc<ChainStore>,
 }
 
+/// A TxHashSet opened read-only (see TxHashSet::open_readonly).
+/// Only derefs to a shared `&TxHashSet` so none of the mutating operations
+/// (extending, compaction, rebuilding the bitmap accumulator...) can be called on it.
+pub struct ReadonlyTxHashSet(TxHashSet);
+
+impl Deref for ReadonlyTxHashSet {
+	type Target = TxHashSet;
+
+	fn deref(&self) -> &TxHashSet {
+		&self.0
+	}
+}
+
 impl TxHashSet {
 	/// Open an existing or new set of backends for the TxHashSet
 	pub fn open(
 		root_dir: String,
 		commit_index: Arc<ChainStore>,
 		header: Option<&BlockHeader>,
+	) -> Result<TxHashSet, Error> {
+		TxHashSet::open_with_parity_check(root_dir, commit_index, header, ParityCheck::Warn)
+	}
+
+	/// Open an existing or new set of backends for the TxHashSet, checking the output
+	/// and rangeproof MMRs (and token output and token rangeproof MMRs) have the same size.
+	/// These can differ after a crash between syncing the backends. The parity_check
+	/// determines whether a mismatch is logged as a warning or fails the open.
+	pub fn open_with_parity_check(
+		root_dir: String,
+		commit_index: Arc<ChainStore>,
+		header: Option<&BlockHeader>,
+		parity_check: ParityCheck,
 	) -> Result<TxHashSet, Error> {
 		let output_pmmr_h = PMMRHandle::new(
 			&root_dir,
@@ -150,7 +411,8 @@ impl TxHashSet {
 			true,
 			ProtocolVersion(1),
 			header,
-		)?;
+		)
+		.map_err(|e| open_mmr_error(OUTPUT_SUBDIR, ProtocolVersion(1), e))?;
 
 		let rproof_pmmr_h = PMMRHandle::new(
 			&root_dir,
@@ -159,7 +421,8 @@ impl TxHashSet {
 			true,
 			ProtocolVersion(1),
 			header,
-		)?;
+		)
+		.map_err(|e| open_mmr_error(RANGE_PROOF_SUBDIR, ProtocolVersion(1), e))?;
 		let token_output_pmmr_h = PMMRHandle::new(
 			&root_dir,
 			TXHASHSET_SUBDIR,
@@ -167,7 +430,8 @@ impl TxHashSet {
 			true,
 			ProtocolVersion(1),
 			header,
-		)?;
+		)
+		.map_err(|e| open_mmr_error(TOKEN_OUTPUT_SUBDIR, ProtocolVersion(1), e))?;
 		let token_rproof_pmmr_h = PMMRHandle::new(
 			&root_dir,
 			TXHASHSET_SUBDIR,
@@ -175,7 +439,8 @@ impl TxHashSet {
 			true,
 			ProtocolVersion(1),
 			header,
-		)?;
+		)
+		.map_err(|e| open_mmr_error(TOKEN_RANGE_PROOF_SUBDIR, ProtocolVersion(1), e))?;
 		let token_issue_proof_pmmr_h = PMMRHandle::new(
 			&root_dir,
 			TXHASHSET_SUBDIR,
@@ -183,7 +448,8 @@ impl TxHashSet {
 			false,
 			ProtocolVersion(1),
 			header,
-		)?;
+		)
+		.map_err(|e| open_mmr_error(TOKEN_ISSUE_PROOF_SUBDIR, ProtocolVersion(1), e))?;
 		let token_kernel_pmmr_h = PMMRHandle::new(
 			&root_dir,
 			TXHASHSET_SUBDIR,
@@ -191,67 +457,240 @@ impl TxHashSet {
 			false, // not prunable
 			ProtocolVersion(1),
 			None,
+		)
+		.map_err(|e| open_mmr_error(TOKEN_KERNEL_SUBDIR, ProtocolVersion(1), e))?;
+
+		check_mmr_parity(
+			"output",
+			output_pmmr_h.last_pos,
+			rproof_pmmr_h.last_pos,
+			parity_check,
+		)?;
+		check_mmr_parity(
+			"token_output",
+			token_output_pmmr_h.last_pos,
+			token_rproof_pmmr_h.last_pos,
+			parity_check,
 		)?;
 
 		// Initialize the bitmap accumulator from the current output PMMR.
-		let bitmap_accumulator = TxHashSet::bitmap_accumulator(&output_pmmr_h)?;
+		// Retry once before giving up as the failure may be transient.
+		let bitmap_accumulator = match TxHashSet::bitmap_accumulator(&output_pmmr_h) {
+			Ok(bitmap_accumulator) => bitmap_accumulator,
+			Err(e) => {
+				warn!("bitmap accumulator init failed: {}, retrying", e);
+				TxHashSet::bitmap_accumulator(&output_pmmr_h)
+					.map_err(|e| ErrorKind::BitmapAccumulatorInit(format!("{}", e.kind())))?
+			}
+		};
 
-		let mut maybe_kernel_handle: Option<PMMRHandle<TxKernel>> = None;
-		let versions = vec![ProtocolVersion(2), ProtocolVersion(1)];
-		for version in versions {
-			let handle = PMMRHandle::new(
+		let kernel_pmmr_h = TxHashSet::open_kernel_pmmr(|version| {
+			PMMRHandle::new(
 				&root_dir,
 				TXHASHSET_SUBDIR,
 				KERNEL_SUBDIR,
 				false, // not prunable
 				version,
 				None,
-			)?;
+			)
+		})?;
+
+		let utxo_count = output_pmmr_h.backend.n_unpruned_leaves();
+		let token_utxo_count = token_output_pmmr_h.backend.n_unpruned_leaves();
+		Ok(TxHashSet {
+			output_pmmr_h,
+			rproof_pmmr_h,
+			kernel_pmmr_h,
+			token_output_pmmr_h,
+			token_rproof_pmmr_h,
+			token_issue_proof_pmmr_h,
+			token_kernel_pmmr_h,
+			bitmap_accumulator,
+			utxo_count,
+			token_utxo_count,
+			durability_mode: DurabilityMode::default(),
+			kernel_pos_index: None,
+			commit_index,
+		})
+	}
+
+	/// Open an existing set of backends for the TxHashSet in read-only mode.
+	/// No file is created or modified (no leaf_set snapshot, no size file rebuild).
+	/// Safe to use against the live data dir of a running node. The files are never memory
+	/// mapped, reads use pread and are bounded by the size of each file (as indexed by its
+	/// size file) when opened, so data flushed by the node afterwards is not visible.
+	/// The view is a snapshot as of opening. If the node rewinds afterwards, reads of the
+	/// data it truncated fail (returning None) rather than crash. Compaction replaces the
+	/// files, so reads keep seeing the files as they were when opened.
+	/// Reopen to see the latest state. If the node commits while this is opening, the
+	/// MMRs may be captured at slightly different points, so check the roots against a
+	/// header before relying on them.
+	/// The returned ReadonlyTxHashSet only gives out a shared reference to the
+	/// underlying TxHashSet, so extensions, compaction and any other operation
+	/// requiring `&mut TxHashSet` are rejected at compile time.
+	pub fn open_readonly(
+		root_dir: String,
+		commit_index: Arc<ChainStore>,
+	) -> Result<ReadonlyTxHashSet, Error> {
+		let output_pmmr_h = PMMRHandle::open_readonly(
+			&root_dir,
+			TXHASHSET_SUBDIR,
+			OUTPUT_SUBDIR,
+			true,
+			ProtocolVersion(1),
+		)
+		.map_err(|e| open_mmr_error(OUTPUT_SUBDIR, ProtocolVersion(1), e))?;
+		let rproof_pmmr_h = PMMRHandle::open_readonly(
+			&root_dir,
+			TXHASHSET_SUBDIR,
+			RANGE_PROOF_SUBDIR,
+			true,
+			ProtocolVersion(1),
+		)
+		.map_err(|e| open_mmr_error(RANGE_PROOF_SUBDIR, ProtocolVersion(1), e))?;
+		let token_output_pmmr_h = PMMRHandle::open_readonly(
+			&root_dir,
+			TXHASHSET_SUBDIR,
+			TOKEN_OUTPUT_SUBDIR,
+			true,
+			ProtocolVersion(1),
+		)
+		.map_err(|e| open_mmr_error(TOKEN_OUTPUT_SUBDIR, ProtocolVersion(1), e))?;
+		let token_rproof_pmmr_h = PMMRHandle::open_readonly(
+			&root_dir,
+			TXHASHSET_SUBDIR,
+			TOKEN_RANGE_PROOF_SUBDIR,
+			true,
+			ProtocolVersion(1),
+		)
+		.map_err(|e| open_mmr_error(TOKEN_RANGE_PROOF_SUBDIR, ProtocolVersion(1), e))?;
+		let token_issue_proof_pmmr_h = PMMRHandle::open_readonly(
+			&root_dir,
+			TXHASHSET_SUBDIR,
+			TOKEN_ISSUE_PROOF_SUBDIR,
+			false,
+			ProtocolVersion(1),
+		)
+		.map_err(|e| open_mmr_error(TOKEN_ISSUE_PROOF_SUBDIR, ProtocolVersion(1), e))?;
+		let token_kernel_pmmr_h = PMMRHandle::open_readonly(
+			&root_dir,
+			TXHASHSET_SUBDIR,
+			TOKEN_KERNEL_SUBDIR,
+			false, // not prunable
+			ProtocolVersion(1),
+		)
+		.map_err(|e| open_mmr_error(TOKEN_KERNEL_SUBDIR, ProtocolVersion(1), e))?;
+
+		// Probing the kernel PMMR version only reads the first kernel.
+		let kernel_pmmr_h = TxHashSet::open_kernel_pmmr(|version| {
+			PMMRHandle::open_readonly(
+				&root_dir,
+				TXHASHSET_SUBDIR,
+				KERNEL_SUBDIR,
+				false, // not prunable
+				version,
+			)
+		})?;
+
+		// The node may be mid-sync, so never fail on a parity mismatch here.
+		check_mmr_parity(
+			"output",
+			output_pmmr_h.last_pos,
+			rproof_pmmr_h.last_pos,
+			ParityCheck::Warn,
+		)?;
+		check_mmr_parity(
+			"token_output",
+			token_output_pmmr_h.last_pos,
+			token_rproof_pmmr_h.last_pos,
+			ParityCheck::Warn,
+		)?;
+
+		let bitmap_accumulator = TxHashSet::bitmap_accumulator(&output_pmmr_h)
+			.map_err(|e| ErrorKind::BitmapAccumulatorInit(format!("{}", e.kind())))?;
+
+		let utxo_count = output_pmmr_h.backend.n_unpruned_leaves();
+		let token_utxo_count = token_output_pmmr_h.backend.n_unpruned_leaves();
+		Ok(ReadonlyTxHashSet(TxHashSet {
+			output_pmmr_h,
+			rproof_pmmr_h,
+			kernel_pmmr_h,
+			token_output_pmmr_h,
+			token_rproof_pmmr_h,
+			token_issue_proof_pmmr_h,
+			token_kernel_pmmr_h,
+			bitmap_accumulator,
+			utxo_count,
+			token_utxo_count,
+			durability_mode: DurabilityMode::default(),
+			kernel_pos_index: None,
+			commit_index,
+		}))
+	}
+
+	// Open the kernel PMMR, trying each supported protocol version in turn until
+	// the first kernel deserializes and verifies successfully.
+	// Errors with OpenMMR listing every version attempted and why each failed.
+	fn open_kernel_pmmr<F>(open: F) -> Result<PMMRHandle<TxKernel>, Error>
+	where
+		F: Fn(ProtocolVersion) -> Result<PMMRHandle<TxKernel>, Error>,
+	{
+		let versions = vec![ProtocolVersion(2), ProtocolVersion(1)];
+		let mut failures = vec![];
+		for version in versions.iter().cloned() {
+			let handle = match open(version) {
+				Ok(handle) => handle,
+				Err(e) => {
+					debug!(
+						"attempting to open kernel PMMR using {:?} - FAIL ({})",
+						version,
+						e.kind()
+					);
+					failures.push(format!("version {}: {}", version.value(), e.kind()));
+					continue;
+				}
+			};
 			if handle.last_pos == 0 {
 				debug!(
 					"attempting to open (empty) kernel PMMR using {:?} - SUCCESS",
 					version
 				);
-				maybe_kernel_handle = Some(handle);
-				break;
+				return Ok(handle);
 			}
 			let kernel: Option<TxKernel> = ReadonlyPMMR::at(&handle.backend, 1).get_data(1);
 			if let Some(kernel) = kernel {
-				if kernel.verify().is_ok() {
+				if let Err(e) = kernel.verify() {
 					debug!(
-						"attempting to open kernel PMMR using {:?} - SUCCESS",
+						"attempting to open kernel PMMR using {:?} - FAIL (verify failed)",
 						version
 					);
-					maybe_kernel_handle = Some(handle);
-					break;
+					failures.push(format!(
+						"version {}: verify failed: {:?}",
+						version.value(),
+						e
+					));
 				} else {
 					debug!(
-						"attempting to open kernel PMMR using {:?} - FAIL (verify failed)",
+						"attempting to open kernel PMMR using {:?} - SUCCESS",
 						version
 					);
+					return Ok(handle);
 				}
 			} else {
 				debug!(
 					"attempting to open kernel PMMR using {:?} - FAIL (read failed)",
 					version
 				);
+				failures.push(format!("version {}: read failed", version.value()));
 			}
 		}
-		if let Some(kernel_pmmr_h) = maybe_kernel_handle {
-			Ok(TxHashSet {
-				output_pmmr_h,
-				rproof_pmmr_h,
-				kernel_pmmr_h,
-				token_output_pmmr_h,
-				token_rproof_pmmr_h,
-				token_issue_proof_pmmr_h,
-				token_kernel_pmmr_h,
-				bitmap_accumulator,
-				commit_index,
-			})
-		} else {
-			Err(ErrorKind::TxHashSetErr("failed to open kernel PMMR".to_string()).into())
+		let last_version = versions.last().map(|v| v.value()).unwrap_or(0);
+		Err(ErrorKind::OpenMMR {
+			subdir: KERNEL_SUBDIR.to_owned(),
+			version: last_version,
+			reason: failures.join("; "),
 		}
+		.into())
 	}
 
 	// Build a new bitmap accumulator for the provided output PMMR.
@@ -263,6 +702,200 @@ impl TxHashSet {
 		Ok(bitmap_accumulator)
 	}
 
+	/// Rebuild the bitmap accumulator from the current output PMMR,
+	/// replacing the existing one.
+	pub fn rebuild_bitmap_accumulator(&mut self) -> Result<(), Error> {
+		self.bitmap_accumulator = TxHashSet::bitmap_accumulator(&self.output_pmmr_h)?;
+		Ok(())
+	}
+
+	/// Rebuild a fresh bitmap accumulator from the current output PMMR and confirm its root
+	/// matches our (incrementally maintained) bitmap accumulator.
+	/// A cheap integrity check to run after a reorg, errors with BitmapAccumulatorMismatch
+	/// if the two have drifted apart.
+	pub fn verify_bitmap_accumulator(&self) -> Result<(), Error> {
+		check_bitmap_accumulator(&self.bitmap_accumulator, &self.output_pmmr_h)
+	}
+
+	/// Set the durability mode used when committing extensions.
+	/// See DurabilityMode for the crash recovery implications of DurabilityMode::Buffered.
+	/// Switching back to DurabilityMode::Durable only affects subsequent commits.
+	pub fn set_durability_mode(&mut self, mode: DurabilityMode) {
+		self.durability_mode = mode;
+	}
+
+	/// The durability mode used when committing extensions.
+	pub fn durability_mode(&self) -> DurabilityMode {
+		self.durability_mode
+	}
+
+	/// Number of unspent outputs, maintained as outputs are added and spent.
+	pub fn utxo_count(&self) -> u64 {
+		self.utxo_count
+	}
+
+	/// Number of unspent token outputs, maintained as token outputs are added and spent.
+	pub fn token_utxo_count(&self) -> u64 {
+		self.token_utxo_count
+	}
+
+	/// Number of unspent outputs, see utxo_count.
+	/// This is the unpruned leaf count of the output MMR (as logged by validate_mmrs).
+	pub fn output_count(&self) -> u64 {
+		self.utxo_count()
+	}
+
+	/// Number of unspent token outputs, see token_utxo_count.
+	pub fn token_output_count(&self) -> u64 {
+		self.token_utxo_count()
+	}
+
+	/// Total number of kernels (kernels are never pruned so this includes all kernels ever confirmed).
+	pub fn kernel_count(&self) -> u64 {
+		pmmr::n_leaves(self.kernel_pmmr_h.last_pos)
+	}
+
+	/// Total number of token kernels (token kernels are never pruned).
+	pub fn token_kernel_count(&self) -> u64 {
+		pmmr::n_leaves(self.token_kernel_pmmr_h.last_pos)
+	}
+
+	/// Total number of token issue proofs (token issue proofs are never pruned).
+	pub fn token_issue_proof_count(&self) -> u64 {
+		pmmr::n_leaves(self.token_issue_proof_pmmr_h.last_pos)
+	}
+
+	/// The outputs spent by the given block, read from the spent index.
+	/// Falls back to the legacy block input bitmap (as rewind does) if the block has no
+	/// spent index. Heights are not available from the legacy bitmap so are 0 in this case.
+	/// Returns an empty vec for a block that spent nothing.
+	pub fn spent_outputs(&self, block_hash: &Hash) -> Result<Vec<CommitPos>, Error> {
+		let batch = self.commit_index.batch()?;
+		if let Ok(spent) = batch.get_spent_index(block_hash) {
+			return Ok(spent);
+		}
+		let block = batch.get_block(block_hash)?;
+		if block.inputs().is_empty() {
+			return Ok(vec![]);
+		}
+		warn!(
+			"spent_outputs: fallback to legacy input bitmap for block {} at {}",
+			block_hash, block.header.height
+		);
+		let bitmap = batch.get_block_input_bitmap(block_hash)?;
+		Ok(bitmap
+			.iter()
+			.map(|pos| CommitPos {
+				pos: pos.into(),
+				height: 0,
+			})
+			.collect())
+	}
+
+	/// The token outputs spent by the given block (see spent_outputs).
+	/// No token spent index is saved for blocks without token inputs.
+	pub fn spent_token_outputs(&self, block_hash: &Hash) -> Result<Vec<CommitPos>, Error> {
+		let batch = self.commit_index.batch()?;
+		if let Ok(spent) = batch.get_token_spent_index(block_hash) {
+			return Ok(spent);
+		}
+		let block = batch.get_block(block_hash)?;
+		if block.token_inputs().is_empty() {
+			return Ok(vec![]);
+		}
+		warn!(
+			"spent_token_outputs: fallback to legacy token input bitmap for block {} at {}",
+			block_hash, block.header.height
+		);
+		let bitmap = batch.get_block_token_input_bitmap(block_hash)?;
+		Ok(bitmap
+			.iter()
+			.map(|pos| CommitPos {
+				pos: pos.into(),
+				height: 0,
+			})
+			.collect())
+	}
+
+	/// Has the commitment ever been an output on the chain (spent or unspent)?
+	/// Checks the UTXO set and then the spent indexes of blocks within the cut-through
+	/// horizon. Beyond the horizon we cannot be sure so UnknownBeyondHorizon is returned
+	/// if the commitment is not found and the chain is longer than the horizon.
+	pub fn commitment_ever_seen(
+		&self,
+		commit: &Commitment,
+		batch: &Batch<'_>,
+	) -> Result<CommitmentSeen, Error> {
+		let output_pmmr =
+			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
+		if let Some((pos, _)) = batch.get_output_pos_height(commit)? {
+			if output_pmmr.get_data(pos).map(|out| out.commit) == Some(*commit) {
+				return Ok(CommitmentSeen::Unspent);
+			}
+		}
+		commitment_spent_within_horizon(
+			batch,
+			|bh| batch.get_spent_index(bh),
+			|pos| {
+				self.output_pmmr_h
+					.backend
+					.get_data_from_file(pos)
+					.map(|out| out.commit)
+			},
+			commit,
+		)
+	}
+
+	/// Has the commitment ever been a token output on the chain (see commitment_ever_seen).
+	pub fn token_commitment_ever_seen(
+		&self,
+		commit: &Commitment,
+		batch: &Batch<'_>,
+	) -> Result<CommitmentSeen, Error> {
+		let token_output_pmmr = ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		);
+		if let Some((pos, _)) = batch.get_token_output_pos_height(commit)? {
+			if token_output_pmmr.get_data(pos).map(|out| out.commit) == Some(*commit) {
+				return Ok(CommitmentSeen::Unspent);
+			}
+		}
+		// Blocks without token inputs may not have a token spent index (treat as empty).
+		commitment_spent_within_horizon(
+			batch,
+			|bh| match batch.get_token_spent_index(bh) {
+				Err(grin_store::Error::NotFoundErr(_)) => Ok(vec![]),
+				res => res,
+			},
+			|pos| {
+				self.token_output_pmmr_h
+					.backend
+					.get_data_from_file(pos)
+					.map(|out| out.commit)
+			},
+			commit,
+		)
+	}
+
+	/// MMR pos of the most recently added output (the last leaf pos), 0 if the MMR is empty.
+	/// Outputs added later will be at positions beyond this.
+	/// Note: the output at this pos may since have been spent.
+	pub fn latest_output_pos(&self) -> u64 {
+		if self.output_pmmr_h.last_pos == 0 {
+			return 0;
+		}
+		pmmr::bintree_rightmost(self.output_pmmr_h.last_pos)
+	}
+
+	/// MMR pos of the most recently added token output, 0 if the MMR is empty.
+	pub fn latest_token_output_pos(&self) -> u64 {
+		if self.token_output_pmmr_h.last_pos == 0 {
+			return 0;
+		}
+		pmmr::bintree_rightmost(self.token_output_pmmr_h.last_pos)
+	}
+
 	/// Close all backend file handles
 	pub fn release_backend_files(&mut self) {
 		self.output_pmmr_h.backend.release_files();
@@ -274,26 +907,41 @@ impl TxHashSet {
 		self.token_kernel_pmmr_h.backend.release_files();
 	}
 
+	/// Check if the output at the provided leaf idx (0-based insertion index into the
+	/// output MMR) is unspent, consulting the bitmap accumulator directly.
+	/// This avoids both the index lookup and the output MMR read of get_unspent().
+	/// Like the output MMR itself (and unlike the index) this is authoritative, but only
+	/// for the current (committed) tip state.
+	pub fn is_unspent_at_idx(&self, leaf_idx: u64) -> bool {
+		self.bitmap_accumulator.is_set(leaf_idx)
+	}
+
 	/// Check if an output is unspent.
 	/// We look in the index to find the output MMR pos.
 	/// Then we check the entry in the output MMR and confirm the hash matches.
 	pub fn get_unspent(&self, output_id: &OutputIdentifier) -> Result<Option<CommitPos>, Error> {
+		match self.lookup_output(output_id)? {
+			OutputStatus::Unspent(commit_pos) => Ok(Some(commit_pos)),
+			OutputStatus::NotFound | OutputStatus::Spent => Ok(None),
+		}
+	}
+
+	/// Look up an output, distinguishing an output that was never indexed (NotFound)
+	/// from one that is indexed but no longer matches the entry in the output MMR (Spent).
+	pub fn lookup_output(&self, output_id: &OutputIdentifier) -> Result<OutputStatus, Error> {
 		let commit = output_id.commit;
 		match self.commit_index.get_output_pos_height(&commit) {
 			Ok(Some((pos, height))) => {
 				let output_pmmr: ReadonlyPMMR<'_, Output, _> =
 					ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
-				if let Some(out) = output_pmmr.get_data(pos) {
-					if OutputIdentifier::from(out) == *output_id {
-						Ok(Some(CommitPos { pos, height }))
-					} else {
-						Ok(None)
+				match output_pmmr.get_data(pos) {
+					Some(out) if OutputIdentifier::from(out) == *output_id => {
+						Ok(OutputStatus::Unspent(CommitPos { pos, height }))
 					}
-				} else {
-					Ok(None)
+					_ => Ok(OutputStatus::Spent),
 				}
 			}
-			Ok(None) => Ok(None),
+			Ok(None) => Ok(OutputStatus::NotFound),
 			Err(e) => Err(ErrorKind::StoreErr(e, "txhashset unspent check".to_string()).into()),
 		}
 	}
@@ -305,6 +953,17 @@ impl TxHashSet {
 		&self,
 		output_id: &TokenOutputIdentifier,
 	) -> Result<Option<CommitPos>, Error> {
+		match self.lookup_token_output(output_id)? {
+			OutputStatus::Unspent(commit_pos) => Ok(Some(commit_pos)),
+			OutputStatus::NotFound | OutputStatus::Spent => Ok(None),
+		}
+	}
+
+	/// Look up a token output (see lookup_output).
+	pub fn lookup_token_output(
+		&self,
+		output_id: &TokenOutputIdentifier,
+	) -> Result<OutputStatus, Error> {
 		let commit = output_id.commit;
 		match self.commit_index.get_token_output_pos_height(&commit) {
 			Ok(Some((pos, height))) => {
@@ -312,45 +971,223 @@ impl TxHashSet {
 					&self.token_output_pmmr_h.backend,
 					self.token_output_pmmr_h.last_pos,
 				);
-				if let Some(out) = output_pmmr.get_data(pos) {
-					if TokenOutputIdentifier::from(out) == *output_id {
-						Ok(Some(CommitPos { pos, height }))
-					} else {
-						Ok(None)
+				match output_pmmr.get_data(pos) {
+					Some(out) if TokenOutputIdentifier::from(out) == *output_id => {
+						Ok(OutputStatus::Unspent(CommitPos { pos, height }))
 					}
-				} else {
-					Ok(None)
+					_ => Ok(OutputStatus::Spent),
 				}
 			}
-			Ok(None) => Ok(None),
+			Ok(None) => Ok(OutputStatus::NotFound),
 			Err(e) => Err(ErrorKind::StoreErr(e, "txhashset unspent check".to_string()).into()),
 		}
 	}
 
-	/// returns the last N nodes inserted into the tree (i.e. the 'bottom'
-	/// nodes at level 0
-	/// TODO: These need to return the actual data from the flat-files instead
-	/// of hashes now
-	pub fn last_n_output(&self, distance: u64) -> Vec<(Hash, OutputIdentifier)> {
-		ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos)
-			.get_last_n_insertions(distance)
+	/// Check if each of the given outputs is unspent (see get_unspent).
+	/// Resolves all the output_pos index entries first, then reads the output MMR
+	/// in pos order to amortize IO. Results are aligned with the provided ids.
+	pub fn get_unspent_batch(
+		&self,
+		ids: &[OutputIdentifier],
+	) -> Result<Vec<Option<CommitPos>>, Error> {
+		let mut indexed = Vec::with_capacity(ids.len());
+		for (idx, id) in ids.iter().enumerate() {
+			match self.commit_index.get_output_pos_height(&id.commit) {
+				Ok(Some((pos, height))) => indexed.push((pos, height, idx)),
+				Ok(None) => {}
+				Err(e) => {
+					return Err(
+						ErrorKind::StoreErr(e, "txhashset unspent check".to_string()).into(),
+					)
+				}
+			}
+		}
+		indexed.sort_unstable();
+
+		let output_pmmr: ReadonlyPMMR<'_, Output, _> =
+			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
+		let mut res: Vec<Option<CommitPos>> = ids.iter().map(|_| None).collect();
+		for (pos, height, idx) in indexed {
+			let unspent = output_pmmr
+				.get_data(pos)
+				.map_or(false, |out| OutputIdentifier::from(out) == ids[idx]);
+			if unspent {
+				res[idx] = Some(CommitPos { pos, height });
+			}
+		}
+		Ok(res)
 	}
 
-	/// returns the last N nodes inserted into the tree (i.e. the 'bottom'
-	/// nodes at level 0
-	/// TODO: These need to return the actual data from the flat-files instead
-	/// of hashes now
-	pub fn last_n_token_output(&self, distance: u64) -> Vec<(Hash, TokenOutputIdentifier)> {
-		ReadonlyPMMR::at(
+	/// Check if each of the given token outputs is unspent (see get_unspent_batch).
+	pub fn get_token_unspent_batch(
+		&self,
+		ids: &[TokenOutputIdentifier],
+	) -> Result<Vec<Option<CommitPos>>, Error> {
+		let mut indexed = Vec::with_capacity(ids.len());
+		for (idx, id) in ids.iter().enumerate() {
+			match self.commit_index.get_token_output_pos_height(&id.commit) {
+				Ok(Some((pos, height))) => indexed.push((pos, height, idx)),
+				Ok(None) => {}
+				Err(e) => {
+					return Err(
+						ErrorKind::StoreErr(e, "txhashset unspent check".to_string()).into(),
+					)
+				}
+			}
+		}
+		indexed.sort_unstable();
+
+		let output_pmmr: ReadonlyPMMR<'_, TokenOutput, _> = ReadonlyPMMR::at(
 			&self.token_output_pmmr_h.backend,
 			self.token_output_pmmr_h.last_pos,
-		)
-		.get_last_n_insertions(distance)
+		);
+		let mut res: Vec<Option<CommitPos>> = ids.iter().map(|_| None).collect();
+		for (pos, height, idx) in indexed {
+			let unspent = output_pmmr
+				.get_data(pos)
+				.map_or(false, |out| TokenOutputIdentifier::from(out) == ids[idx]);
+			if unspent {
+				res[idx] = Some(CommitPos { pos, height });
+			}
+		}
+		Ok(res)
 	}
 
-	/// as above, for range proofs
-	pub fn last_n_rangeproof(&self, distance: u64) -> Vec<(Hash, RangeProof)> {
-		ReadonlyPMMR::at(&self.rproof_pmmr_h.backend, self.rproof_pmmr_h.last_pos)
+	/// The commitment of the (unspent) output at the given output MMR pos.
+	/// None if pos is not a leaf or the output has been spent (pruned).
+	/// The reverse of get_output_pos().
+	pub fn commitment_at_pos(&self, pos: u64) -> Option<Commitment> {
+		if pos == 0 {
+			return None;
+		}
+		ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos)
+			.get_data(pos)
+			.map(|out| out.commit)
+	}
+
+	/// The commitment of the (unspent) token output at the given token output MMR pos.
+	/// None if pos is not a leaf or the token output has been spent (pruned).
+	pub fn token_commitment_at_pos(&self, pos: u64) -> Option<Commitment> {
+		if pos == 0 {
+			return None;
+		}
+		ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		)
+		.get_data(pos)
+		.map(|out| out.commit)
+	}
+
+	/// Verify the rangeproof of a single (unspent) token output.
+	/// We look in the index to find the token output MMR pos and then read the
+	/// token output and corresponding token rangeproof at that pos.
+	pub fn verify_token_output_rangeproof(&self, commit: &Commitment) -> Result<(), Error> {
+		let pos = self
+			.commit_index
+			.get_token_output_pos(commit)
+			.map_err(|_| ErrorKind::OutputNotFound)?;
+		let output_pmmr: ReadonlyPMMR<'_, TokenOutput, _> = ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		);
+		let out = match output_pmmr.get_data(pos) {
+			Some(out) if out.commit == *commit => out,
+			_ => return Err(ErrorKind::OutputNotFound.into()),
+		};
+		let proof = ReadonlyPMMR::at(
+			&self.token_rproof_pmmr_h.backend,
+			self.token_rproof_pmmr_h.last_pos,
+		)
+		.get_data(pos)
+		.ok_or_else(|| ErrorKind::RangeproofNotFound)?;
+		Output::batch_verify_proofs(&[out.commit], &[proof])?;
+		Ok(())
+	}
+
+	/// All unspent coinbase outputs along with the height of the block that created them
+	/// (via the output_pos index), allowing callers to apply the coinbase maturity rule.
+	pub fn coinbase_utxos(&self) -> Result<Vec<(u64, OutputIdentifier)>, Error> {
+		let output_pmmr =
+			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
+		let mut res = vec![];
+		for pos in output_pmmr.leaf_pos_iter() {
+			if let Some(out) = output_pmmr.get_data(pos) {
+				if out.features.is_coinbase() {
+					let (_, height) = self
+						.commit_index
+						.get_output_pos_height(&out.commit)?
+						.ok_or_else(|| ErrorKind::OutputNotFound)?;
+					res.push((height, out));
+				}
+			}
+		}
+		Ok(res)
+	}
+
+	/// Iterate over the unspent outputs yielding each output along with its MMR pos
+	/// and the height of the block that created it (via the output_pos index).
+	/// Note: this is slower than iterating over the output MMR alone as it requires
+	/// an index lookup per output. Outputs without a (consistent) index entry are skipped.
+	pub fn utxo_iter_with_height(&self) -> impl Iterator<Item = (OutputIdentifier, u64, u64)> + '_ {
+		let last_pos = self.output_pmmr_h.last_pos;
+		let backend = &self.output_pmmr_h.backend;
+		let commit_index = &self.commit_index;
+		backend
+			.leaf_pos_iter()
+			.take_while(move |pos| *pos <= last_pos)
+			.filter_map(move |pos| {
+				let out = backend.get_data(pos)?;
+				match commit_index.get_output_pos_height(&out.commit) {
+					Ok(Some((index_pos, height))) if index_pos == pos => Some((out, pos, height)),
+					_ => None,
+				}
+			})
+	}
+
+	/// Lazily iterate over the unspent outputs in the output MMR as (pos, output).
+	/// Outputs are read one at a time so the full UTXO set is never buffered in memory.
+	pub fn unspent_outputs_iter(&self) -> impl Iterator<Item = (u64, OutputIdentifier)> + '_ {
+		let last_pos = self.output_pmmr_h.last_pos;
+		let backend = &self.output_pmmr_h.backend;
+		backend
+			.leaf_pos_iter()
+			.take_while(move |pos| *pos <= last_pos)
+			.filter_map(move |pos| backend.get_data(pos).map(|out| (pos, out)))
+	}
+
+	/// Lazily iterate over the unspent token outputs in the token output MMR as (pos, output).
+	pub fn unspent_token_outputs_iter(
+		&self,
+	) -> impl Iterator<Item = (u64, TokenOutputIdentifier)> + '_ {
+		let last_pos = self.token_output_pmmr_h.last_pos;
+		let backend = &self.token_output_pmmr_h.backend;
+		backend
+			.leaf_pos_iter()
+			.take_while(move |pos| *pos <= last_pos)
+			.filter_map(move |pos| backend.get_data(pos).map(|out| (pos, out)))
+	}
+
+	/// returns the last N nodes inserted into the tree (i.e. the 'bottom'
+	/// nodes at level 0), most recent first, along with the data read from the data file.
+	/// Leaves with no data (spent outputs) are skipped but still count towards N.
+	pub fn last_n_output(&self, distance: u64) -> Vec<(Hash, OutputIdentifier)> {
+		ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos)
+			.get_last_n_insertions(distance)
+	}
+
+	/// as above, for token outputs
+	pub fn last_n_token_output(&self, distance: u64) -> Vec<(Hash, TokenOutputIdentifier)> {
+		ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		)
+		.get_last_n_insertions(distance)
+	}
+
+	/// as above, for range proofs
+	pub fn last_n_rangeproof(&self, distance: u64) -> Vec<(Hash, RangeProof)> {
+		ReadonlyPMMR::at(&self.rproof_pmmr_h.backend, self.rproof_pmmr_h.last_pos)
 			.get_last_n_insertions(distance)
 	}
 
@@ -378,11 +1215,249 @@ impl TxHashSet {
 		.get_last_n_insertions(distance)
 	}
 
+	/// All distinct token types in the txhashset, in order of issuance.
+	/// Every token must have an issue proof so the (non-prunable) token issue proof MMR
+	/// is the source of truth here.
+	pub fn token_keys(&self) -> Result<Vec<TokenKey>, Error> {
+		let mut seen = HashSet::new();
+		Ok(self
+			.token_issue_proofs()
+			.map(|proof| proof.token_type)
+			.filter(|token_type| seen.insert(*token_type))
+			.collect())
+	}
+
+	// Iterate over all token issue proofs, read via a ReadonlyPMMR at the last committed pos.
+	fn token_issue_proofs(&self) -> impl Iterator<Item = TokenIssueProof> + '_ {
+		let last_pos = self.token_issue_proof_pmmr_h.last_pos;
+		let issue_proof_pmmr = ReadonlyPMMR::at(&self.token_issue_proof_pmmr_h.backend, last_pos);
+		(1..=last_pos)
+			.filter(|&pos| pmmr::is_leaf(pos))
+			.filter_map(move |pos| issue_proof_pmmr.get_data(pos))
+	}
+
+	/// Supply of the given token type (see TokenSupply for what "supply" means
+	/// given confidential amounts).
+	pub fn token_supply(&self, token_type: &TokenKey) -> Result<TokenSupply, Error> {
+		let issue_commit = self
+			.token_issue_proofs()
+			.find(|proof| proof.token_type == *token_type)
+			.map(|proof| proof.commit);
+
+		let output_pmmr = ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		);
+		let unspent_outputs = output_pmmr
+			.leaf_pos_iter()
+			.filter_map(|pos| output_pmmr.get_data(pos))
+			.filter(|out| out.token_type == *token_type)
+			.count() as u64;
+
+		Ok(TokenSupply {
+			token_type: *token_type,
+			issue_commit,
+			unspent_outputs,
+		})
+	}
+
+	/// Hash of the block that created the given unspent output.
+	/// Resolves the creation height from the output_pos index to the header hash at
+	/// that height via the header MMR. Returns None for unknown or spent commitments.
+	pub fn output_creation_block(
+		&self,
+		commit: &Commitment,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+	) -> Result<Option<Hash>, Error> {
+		if let Some((pos, height)) = batch.get_output_pos_height(commit)? {
+			let output_pmmr =
+				ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
+			if let Some(out) = output_pmmr.get_data(pos) {
+				if out.commitment() == *commit {
+					return Ok(Some(header_pmmr.get_header_hash_by_height(height)?));
+				}
+			}
+		}
+		Ok(None)
+	}
+
 	/// Convenience function to query the db for a header by its hash.
 	pub fn get_block_header(&self, hash: &Hash) -> Result<BlockHeader, Error> {
 		Ok(self.commit_index.get_block_header(&hash)?)
 	}
 
+	/// Check all inputs (and token inputs) in the block spend outputs that are
+	/// currently unspent in the committed UTXO set, without modifying any MMR.
+	/// All offending inputs are reported via ErrorKind::UnspendableInputs, not just the first.
+	pub fn check_inputs_spendable(
+		&self,
+		b: &Block,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+	) -> Result<(), Error> {
+		let utxo = UTXOView::new(
+			ReadonlyPMMR::at(&header_pmmr.backend, header_pmmr.last_pos),
+			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos),
+			ReadonlyPMMR::at(
+				&self.token_output_pmmr_h.backend,
+				self.token_output_pmmr_h.last_pos,
+			),
+			ReadonlyPMMR::at(
+				&self.token_issue_proof_pmmr_h.backend,
+				self.token_issue_proof_pmmr_h.last_pos,
+			),
+			ReadonlyPMMR::at(&self.rproof_pmmr_h.backend, self.rproof_pmmr_h.last_pos),
+			ReadonlyPMMR::at(
+				&self.token_rproof_pmmr_h.backend,
+				self.token_rproof_pmmr_h.last_pos,
+			),
+		);
+		let unspendable = utxo.unspendable_inputs(b, batch);
+		if unspendable.is_empty() {
+			Ok(())
+		} else {
+			Err(ErrorKind::UnspendableInputs(unspendable).into())
+		}
+	}
+
+	/// Find the header corresponding to the current state of the content MMRs,
+	/// i.e. the header whose output_mmr_size and kernel_mmr_size match the current
+	/// output and kernel MMR sizes. The content MMRs may lag the header MMR during sync.
+	/// Errors if no header matches, indicating the content MMRs are mid-block (corrupt).
+	pub fn content_head_header(
+		&self,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+	) -> Result<BlockHeader, Error> {
+		let output_pos = self.output_pmmr_h.last_pos;
+		let kernel_pos = self.kernel_pmmr_h.last_pos;
+		let header = find_header_by_mmr_pos(header_pmmr, batch, output_pos, |h| h.output_mmr_size)?;
+		match header {
+			Some(header)
+				if header.output_mmr_size == output_pos && header.kernel_mmr_size == kernel_pos =>
+			{
+				Ok(header)
+			}
+			_ => Err(ErrorKind::NoHeaderForMMRSizes(output_pos, kernel_pos).into()),
+		}
+	}
+
+	/// Migrate legacy blocks (lacking a spent index) in the range [from_height, to_height]
+	/// by reconstructing the spent index (and token spent index) from the legacy block
+	/// input bitmap. Avoids falling back to the legacy input bitmap when rewinding these
+	/// blocks in the future.
+	/// The spent positions come from the bitmap, the commitment at each pos from the
+	/// MMR data file (to order the index like the block inputs) and the height from
+	/// the header MMR. Note: the output_pos index cannot be used here, it no longer has
+	/// entries for the spent outputs.
+	/// Blocks without a legacy bitmap, or with spent outputs already compacted away,
+	/// are skipped and left to the legacy fallback.
+	/// Returns the number of blocks migrated.
+	pub fn migrate_spent_indexes(
+		&self,
+		from_height: u64,
+		to_height: u64,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+	) -> Result<u64, Error> {
+		let mut migrated = 0;
+		for height in from_height.max(1)..=to_height {
+			let hash = header_pmmr.get_header_hash_by_height(height)?;
+			let missing_spent = batch.get_spent_index(&hash).is_err();
+			let missing_token_spent = batch.get_token_spent_index(&hash).is_err();
+			if !missing_spent && !missing_token_spent {
+				continue;
+			}
+
+			let block = match batch.get_block(&hash) {
+				Ok(block) => block,
+				Err(_) => continue,
+			};
+			// Blocks without token inputs do not need a token spent index.
+			let missing_token_spent = missing_token_spent && !block.token_inputs().is_empty();
+			if !missing_spent && !missing_token_spent {
+				continue;
+			}
+
+			let mut updated = false;
+			if missing_spent {
+				if let Ok(bitmap) = batch.get_block_input_bitmap(&hash) {
+					let inputs: Vec<_> = block.inputs().iter().map(|x| x.commitment()).collect();
+					let spent = spent_index_from_bitmap(
+						&bitmap,
+						&inputs,
+						|pos| {
+							self.output_pmmr_h
+								.backend
+								.get_data_from_file(pos)
+								.map(|x| x.commitment())
+						},
+						|h| h.output_mmr_size,
+						header_pmmr,
+						batch,
+					)?;
+					if let Some(spent) = spent {
+						batch.save_spent_index(&hash, &spent)?;
+						updated = true;
+					}
+				}
+			}
+			if missing_token_spent {
+				if let Ok(bitmap) = batch.get_block_token_input_bitmap(&hash) {
+					let inputs: Vec<_> = block
+						.token_inputs()
+						.iter()
+						.map(|x| x.commitment())
+						.collect();
+					let token_spent = spent_index_from_bitmap(
+						&bitmap,
+						&inputs,
+						|pos| {
+							self.token_output_pmmr_h
+								.backend
+								.get_data_from_file(pos)
+								.map(|x| x.commitment())
+						},
+						|h| h.token_output_mmr_size,
+						header_pmmr,
+						batch,
+					)?;
+					if let Some(token_spent) = token_spent {
+						batch.save_spent_token_index(&hash, &token_spent)?;
+						updated = true;
+					}
+				}
+			}
+
+			if updated {
+				migrated += 1;
+			} else {
+				debug!(
+					"migrate_spent_indexes: unable to migrate block {} at {}",
+					hash, height
+				);
+			}
+		}
+		debug!(
+			"migrate_spent_indexes: migrated {} blocks between {} and {}",
+			migrated, from_height, to_height
+		);
+		Ok(migrated)
+	}
+
+	/// Is the content of the txhashset caught up with the header chain?
+	/// Compares the current content MMR sizes against the MMR sizes committed to
+	/// in the head header of the provided header MMR.
+	pub fn is_content_synced(&self, header_pmmr: &PMMRHandle<BlockHeader>) -> Result<bool, Error> {
+		let head_header = self.get_block_header(&header_pmmr.head_hash()?)?;
+		Ok(self.output_pmmr_h.last_pos == head_header.output_mmr_size
+			&& self.kernel_pmmr_h.last_pos == head_header.kernel_mmr_size
+			&& self.token_output_pmmr_h.last_pos == head_header.token_output_mmr_size
+			&& self.token_issue_proof_pmmr_h.last_pos == head_header.token_issue_proof_mmr_size
+			&& self.token_kernel_pmmr_h.last_pos == head_header.token_kernel_mmr_size)
+	}
+
 	/// returns outputs from the given pmmr index up to the
 	/// specified limit. Also returns the last index actually populated
 	/// max index is the last PMMR index to consider, not leaf index
@@ -457,6 +1532,18 @@ impl TxHashSet {
 		let max_index = max_index.unwrap_or(self.kernel_pmmr_h.last_pos);
 
 		let pmmr = ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos);
+
+		// Check our excess index (if enabled) first, falling back to a scan on a miss.
+		if let Some(pos) = self.kernel_pos(excess) {
+			if pos >= min_index && pos <= max_index {
+				if let Some(kernel) = pmmr.get_data(pos) {
+					if &kernel.excess == excess {
+						return Some((kernel, pos));
+					}
+				}
+			}
+		}
+
 		let mut index = max_index + 1;
 		while index > min_index {
 			index -= 1;
@@ -469,6 +1556,52 @@ impl TxHashSet {
 		None
 	}
 
+	/// Enable (or disable) the in-memory index of kernel excess to kernel MMR pos
+	/// used by find_kernel. Disabled by default, the index holds an entry for every
+	/// kernel so is only worth the memory on nodes doing frequent kernel lookups
+	/// (wallets, explorers). Enabling builds the index from the full kernel MMR.
+	pub fn set_kernel_pos_index(&mut self, enabled: bool) {
+		self.kernel_pos_index = if enabled {
+			let mut index = HashMap::new();
+			self.extend_kernel_pos_index(&mut index, 0);
+			Some(index)
+		} else {
+			None
+		};
+	}
+
+	/// Lookup the kernel MMR pos for the given excess in the in-memory excess index.
+	/// Returns None if the excess is not indexed or the index is not enabled.
+	pub fn kernel_pos(&self, excess: &Commitment) -> Option<u64> {
+		self.kernel_pos_index
+			.as_ref()
+			.and_then(|index| index.get(excess).cloned())
+	}
+
+	// Add entries to the excess index for all kernels after from_pos.
+	// Later kernels take precedence, consistent with the backwards scan in find_kernel.
+	fn extend_kernel_pos_index(&self, index: &mut HashMap<Commitment, u64>, from_pos: u64) {
+		let pmmr = ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos);
+		for pos in (from_pos + 1..=self.kernel_pmmr_h.last_pos).filter(|x| pmmr::is_leaf(*x)) {
+			if let Some(kernel) = pmmr.get_data(pos) {
+				index.insert(kernel.excess, pos);
+			}
+		}
+	}
+
+	// Update the excess index (if enabled) after committing an extension.
+	// Entries beyond rewind_pos (the smallest kernel MMR size seen during the extension)
+	// are removed and entries for kernels applied after rewind_pos are added.
+	fn update_kernel_pos_index(&mut self, rewind_pos: u64) {
+		let mut index = match self.kernel_pos_index.take() {
+			Some(index) => index,
+			None => return,
+		};
+		index.retain(|_, pos| *pos <= rewind_pos);
+		self.extend_kernel_pos_index(&mut index, rewind_pos);
+		self.kernel_pos_index = Some(index);
+	}
+
 	/// Find a token kernel with a given excess. Work backwards from `max_index` to `min_index`
 	pub fn find_token_kernel(
 		&self,
@@ -495,112 +1628,769 @@ impl TxHashSet {
 		None
 	}
 
-	/// Get MMR roots.
-	pub fn roots(&self) -> TxHashSetRoots {
-		let output_pmmr =
-			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
-		let rproof_pmmr =
-			ReadonlyPMMR::at(&self.rproof_pmmr_h.backend, self.rproof_pmmr_h.last_pos);
-		let kernel_pmmr =
-			ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos);
+	/// Height of the block containing the kernel with the given excess.
+	/// A single lookup in the kernel height index (no kernel MMR scan).
+	/// Returns None if no kernel with this excess is indexed.
+	pub fn kernel_height(&self, excess: &Commitment) -> Result<Option<u64>, Error> {
+		Ok(self.commit_index.get_kernel_height(excess)?)
+	}
 
-		let token_output_pmmr = ReadonlyPMMR::at(
-			&self.token_output_pmmr_h.backend,
-			self.token_output_pmmr_h.last_pos,
-		);
-		let token_rproof_pmmr = ReadonlyPMMR::at(
-			&self.token_rproof_pmmr_h.backend,
-			self.token_rproof_pmmr_h.last_pos,
-		);
-		let token_issue_proof_pmmr = ReadonlyPMMR::at(
-			&self.token_issue_proof_pmmr_h.backend,
-			self.token_issue_proof_pmmr_h.last_pos,
+	/// Height of the block containing the token kernel with the given excess.
+	/// Returns None if no token kernel with this excess is indexed.
+	pub fn token_kernel_height(&self, excess: &Commitment) -> Result<Option<u64>, Error> {
+		Ok(self.commit_index.get_token_kernel_height(excess)?)
+	}
+
+	/// Build the kernel height index if it is missing, e.g. after upgrading or after
+	/// a txhashset write. The first kernel and the last kernel as of the head are
+	/// checked and if either is not indexed every kernel is (re)indexed by walking the
+	/// header MMR. Later kernels take precedence for a duplicate excess.
+	pub fn init_kernel_height_index(
+		&self,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+	) -> Result<(), Error> {
+		let now = Instant::now();
+		let head = batch.head_header()?;
+		let pmmr = ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos);
+
+		let last_pos = (1..=head.kernel_mmr_size)
+			.rev()
+			.find(|x| pmmr::is_leaf(*x))
+			.unwrap_or(1);
+		let is_indexed = |pos| match pmmr.get_data(pos) {
+			Some(kernel) => batch
+				.get_kernel_height(&kernel.excess)
+				.map(|x| x.is_some())
+				.unwrap_or(false),
+			None => true,
+		};
+		if is_indexed(1) && is_indexed(last_pos) {
+			return Ok(());
+		}
+
+		let mut count = 0;
+		let mut prev_size = 0;
+		for (_, hash) in header_pmmr.iter_header_hashes(0, head.height) {
+			let h = batch.get_block_header(&hash)?;
+			for pos in (prev_size + 1..=h.kernel_mmr_size).filter(|x| pmmr::is_leaf(*x)) {
+				if let Some(kernel) = pmmr.get_data(pos) {
+					batch.save_kernel_height(&kernel.excess, h.height)?;
+					count += 1;
+				}
+			}
+			prev_size = h.kernel_mmr_size;
+		}
+		debug!(
+			"init_kernel_height_index: indexed {} kernels, took {}s",
+			count,
+			now.elapsed().as_secs(),
 		);
-		let token_kernel_pmmr = ReadonlyPMMR::at(
+		Ok(())
+	}
+
+	/// Build the token kernel height index if it is missing (see init_kernel_height_index).
+	pub fn init_token_kernel_height_index(
+		&self,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+	) -> Result<(), Error> {
+		let now = Instant::now();
+		let head = batch.head_header()?;
+		let pmmr = ReadonlyPMMR::at(
 			&self.token_kernel_pmmr_h.backend,
 			self.token_kernel_pmmr_h.last_pos,
 		);
 
-		TxHashSetRoots {
-			output_roots: OutputRoots {
-				pmmr_root: output_pmmr.root(),
-				bitmap_root: self.bitmap_accumulator.root(),
-			},
-			rproof_root: rproof_pmmr.root(),
-			kernel_root: kernel_pmmr.root(),
-			token_output_root: token_output_pmmr.root(),
-			token_rproof_root: token_rproof_pmmr.root(),
-			token_issue_proof_root: token_issue_proof_pmmr.root(),
-			token_kernel_root: token_kernel_pmmr.root(),
+		let last_pos = (1..=head.token_kernel_mmr_size)
+			.rev()
+			.find(|x| pmmr::is_leaf(*x))
+			.unwrap_or(1);
+		let is_indexed = |pos| match pmmr.get_data(pos) {
+			Some(kernel) => batch
+				.get_token_kernel_height(&kernel.excess)
+				.map(|x| x.is_some())
+				.unwrap_or(false),
+			None => true,
+		};
+		if is_indexed(1) && is_indexed(last_pos) {
+			return Ok(());
 		}
-	}
 
-	/// Return Commit's MMR position
-	pub fn get_output_pos(&self, commit: &Commitment) -> Result<u64, Error> {
-		Ok(self.commit_index.get_output_pos(&commit)?)
+		let mut count = 0;
+		let mut prev_size = 0;
+		for (_, hash) in header_pmmr.iter_header_hashes(0, head.height) {
+			let h = batch.get_block_header(&hash)?;
+			for pos in (prev_size + 1..=h.token_kernel_mmr_size).filter(|x| pmmr::is_leaf(*x)) {
+				if let Some(kernel) = pmmr.get_data(pos) {
+					batch.save_token_kernel_height(&kernel.excess, h.height)?;
+					count += 1;
+				}
+			}
+			prev_size = h.token_kernel_mmr_size;
+		}
+		debug!(
+			"init_token_kernel_height_index: indexed {} token kernels, took {}s",
+			count,
+			now.elapsed().as_secs(),
+		);
+		Ok(())
 	}
 
-	/// Return Commit's MMR position
-	pub fn get_token_output_pos(&self, commit: &Commitment) -> Result<u64, Error> {
-		Ok(self.commit_index.get_token_output_pos(&commit)?)
+	/// Get the kernel at the given kernel MMR pos.
+	/// Returns None if pos is beyond the MMR or is not a leaf.
+	pub fn get_kernel_by_pos(&self, pos: u64) -> Option<TxKernel> {
+		ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos).get_data(pos)
 	}
 
-	/// build a new merkle proof for the given position.
-	pub fn merkle_proof(&mut self, commit: Commitment) -> Result<MerkleProof, Error> {
-		let pos = self.commit_index.get_output_pos(&commit)?;
-		PMMR::at(&mut self.output_pmmr_h.backend, self.output_pmmr_h.last_pos)
-			.merkle_proof(pos)
-			.map_err(|_| ErrorKind::MerkleProof.into())
+	/// Get the token kernel at the given token kernel MMR pos.
+	/// Returns None if pos is beyond the MMR or is not a leaf.
+	pub fn get_token_kernel_by_pos(&self, pos: u64) -> Option<TokenTxKernel> {
+		ReadonlyPMMR::at(
+			&self.token_kernel_pmmr_h.backend,
+			self.token_kernel_pmmr_h.last_pos,
+		)
+		.get_data(pos)
 	}
 
-	/// build a new merkle proof for the given position.
-	pub fn token_merkle_proof(&mut self, commit: Commitment) -> Result<MerkleProof, Error> {
-		let pos = self.commit_index.get_token_output_pos(&commit)?;
-		PMMR::at(
-			&mut self.token_output_pmmr_h.backend,
-			self.token_output_pmmr_h.last_pos,
-		)
-		.merkle_proof(pos)
-		.map_err(|_| ErrorKind::MerkleProof.into())
+	/// Get the outputs at the given output MMR positions.
+	/// Positions are read in sorted order (a single forward pass over the data file)
+	/// and results returned in the original order. Returns None for positions beyond
+	/// the MMR, non-leaf positions and spent or pruned outputs.
+	pub fn get_data_batch(&self, positions: &[u64]) -> Vec<Option<OutputIdentifier>> {
+		data_batch(&self.output_pmmr_h, positions)
 	}
 
-	/// Compact the MMR data files and flush the rm logs
-	pub fn compact(
-		&mut self,
-		horizon_header: &BlockHeader,
-		batch: &Batch<'_>,
-	) -> Result<(), Error> {
-		debug!("txhashset: starting compaction...");
+	/// Get the kernels at the given kernel MMR positions (see get_data_batch).
+	pub fn get_kernel_data_batch(&self, positions: &[u64]) -> Vec<Option<TxKernel>> {
+		data_batch(&self.kernel_pmmr_h, positions)
+	}
 
-		let head_header = batch.head_header()?;
+	/// Get the token outputs at the given token output MMR positions (see get_data_batch).
+	pub fn get_token_data_batch(&self, positions: &[u64]) -> Vec<Option<TokenOutputIdentifier>> {
+		data_batch(&self.token_output_pmmr_h, positions)
+	}
 
-		let rewind_rm_pos = input_pos_to_rewind(&horizon_header, &head_header, batch)?;
-		let token_rewind_rm_pos = token_input_pos_to_rewind(&horizon_header, &head_header, batch)?;
+	/// Get the token kernels at the given token kernel MMR positions (see get_data_batch).
+	pub fn get_token_kernel_data_batch(&self, positions: &[u64]) -> Vec<Option<TokenTxKernel>> {
+		data_batch(&self.token_kernel_pmmr_h, positions)
+	}
 
-		debug!("txhashset: check_compact output mmr backend...");
-		self.output_pmmr_h
-			.backend
-			.check_compact(horizon_header.output_mmr_size, &rewind_rm_pos)?;
+	/// Get the token kernels included in the block with the given header, based on
+	/// the token kernel MMR positions between the previous header token_kernel_mmr_size
+	/// and this header token_kernel_mmr_size.
+	/// Each kernel is returned along with whether it is a plain token kernel.
+	pub fn token_kernels_in_block(
+		&self,
+		header: &BlockHeader,
+	) -> Result<Vec<(TokenTxKernel, bool)>, Error> {
+		if header.token_kernel_mmr_size > self.token_kernel_pmmr_h.last_pos {
+			return Err(ErrorKind::InvalidMMRSize.into());
+		}
+		let prev_size = if header.height == 0 {
+			0
+		} else {
+			self.commit_index
+				.get_previous_header(header)?
+				.token_kernel_mmr_size
+		};
 
-		debug!("txhashset: check_compact rangeproof mmr backend...");
-		self.rproof_pmmr_h
-			.backend
-			.check_compact(horizon_header.output_mmr_size, &rewind_rm_pos)?;
+		let pmmr = ReadonlyPMMR::at(
+			&self.token_kernel_pmmr_h.backend,
+			self.token_kernel_pmmr_h.last_pos,
+		);
+		let mut kernels = vec![];
+		for pos in (prev_size + 1..=header.token_kernel_mmr_size).filter(|x| pmmr::is_leaf(*x)) {
+			let kernel = pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::TxKernelNotFound)?;
+			let is_plain = kernel.is_plain_token();
+			kernels.push((kernel, is_plain));
+		}
+		Ok(kernels)
+	}
+
+	/// Find the block containing the kernel with the given excess.
+	/// Returns the block hash and height, based on the header whose kernel_mmr_size
+	/// is the first to include the kernel MMR pos.
+	/// The kernel height index is checked first (and confirmed against the kernels in
+	/// the indexed block), falling back to find_kernel and a header MMR search on a miss.
+	pub fn kernel_block(
+		&self,
+		excess: &Commitment,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+	) -> Result<Option<(Hash, u64)>, Error> {
+		let pmmr = ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos);
+		let indexed = block_from_kernel_height_index(
+			batch.get_kernel_height(excess)?,
+			header_pmmr,
+			batch,
+			|h| h.kernel_mmr_size,
+			|pos| pmmr.get_data(pos).map(|k| &k.excess == excess),
+		)?;
+		if indexed.is_some() {
+			return Ok(indexed);
+		}
+
+		if let Some((_, pos)) = self.find_kernel(excess, None, None) {
+			let header = find_header_by_mmr_pos(header_pmmr, batch, pos, |h| h.kernel_mmr_size)?;
+			Ok(header.map(|h| (h.hash(), h.height)))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Find the block containing the token kernel with the given excess.
+	/// Returns the block hash and height, based on the header whose token_kernel_mmr_size
+	/// is the first to include the token kernel MMR pos.
+	/// The token kernel height index is checked first, as in kernel_block.
+	pub fn token_kernel_block(
+		&self,
+		excess: &Commitment,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+	) -> Result<Option<(Hash, u64)>, Error> {
+		let pmmr = ReadonlyPMMR::at(
+			&self.token_kernel_pmmr_h.backend,
+			self.token_kernel_pmmr_h.last_pos,
+		);
+		let indexed = block_from_kernel_height_index(
+			batch.get_token_kernel_height(excess)?,
+			header_pmmr,
+			batch,
+			|h| h.token_kernel_mmr_size,
+			|pos| pmmr.get_data(pos).map(|k| &k.excess == excess),
+		)?;
+		if indexed.is_some() {
+			return Ok(indexed);
+		}
+
+		if let Some((_, pos)) = self.find_token_kernel(excess, None, None) {
+			let header =
+				find_header_by_mmr_pos(header_pmmr, batch, pos, |h| h.token_kernel_mmr_size)?;
+			Ok(header.map(|h| (h.hash(), h.height)))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Export all kernels and token kernels to a file at the provided path.
+	/// The kernel and token kernel MMR roots are written at the start of the export
+	/// so it can be checked for completeness against a known header.
+	/// See verify_kernel_export() for the corresponding verification.
+	pub fn export_kernels(&self, path: &Path) -> Result<(), Error> {
+		let kernel_pmmr =
+			ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos);
+		let token_kernel_pmmr = ReadonlyPMMR::at(
+			&self.token_kernel_pmmr_h.backend,
+			self.token_kernel_pmmr_h.last_pos,
+		);
+
+		let version = ProtocolVersion::local();
+		let mut writer = BufWriter::new(File::create(path)?);
+		ser::serialize(&mut writer, version, &kernel_pmmr.root()).map_err(ErrorKind::SerErr)?;
+		ser::serialize(&mut writer, version, &token_kernel_pmmr.root())
+			.map_err(ErrorKind::SerErr)?;
+
+		let n_kernels = pmmr::n_leaves(self.kernel_pmmr_h.last_pos);
+		ser::serialize(&mut writer, version, &n_kernels).map_err(ErrorKind::SerErr)?;
+		for pos in (1..=self.kernel_pmmr_h.last_pos).filter(|x| pmmr::is_leaf(*x)) {
+			let kernel = kernel_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::TxKernelNotFound)?;
+			ser::serialize(&mut writer, version, &kernel).map_err(ErrorKind::SerErr)?;
+		}
+
+		let n_token_kernels = pmmr::n_leaves(self.token_kernel_pmmr_h.last_pos);
+		ser::serialize(&mut writer, version, &n_token_kernels).map_err(ErrorKind::SerErr)?;
+		for pos in (1..=self.token_kernel_pmmr_h.last_pos).filter(|x| pmmr::is_leaf(*x)) {
+			let kernel = token_kernel_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::TxKernelNotFound)?;
+			ser::serialize(&mut writer, version, &kernel).map_err(ErrorKind::SerErr)?;
+		}
+
+		writer.flush()?;
+		debug!(
+			"export_kernels: exported {} kernels, {} token kernels to {:?}",
+			n_kernels, n_token_kernels, path
+		);
+		Ok(())
+	}
+
+	// Commitments of all unspent outputs, sorted (by commitment bytes).
+	fn sorted_utxo_commitments(&self) -> Vec<Commitment> {
+		let mut commits: Vec<_> = self
+			.unspent_outputs_iter()
+			.map(|(_, out)| out.commit)
+			.collect();
+		commits.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+		commits
+	}
+
+	/// Export the commitments of all unspent outputs to a file at the provided path,
+	/// sorted by commitment so exports from different nodes can be streamed and compared.
+	/// See diff_utxo_set() for the corresponding comparison.
+	pub fn export_utxo_set(&self, path: &Path) -> Result<(), Error> {
+		let commits = self.sorted_utxo_commitments();
+
+		let version = ProtocolVersion::local();
+		let mut writer = BufWriter::new(File::create(path)?);
+		ser::serialize(&mut writer, version, &(commits.len() as u64)).map_err(ErrorKind::SerErr)?;
+		for commit in &commits {
+			ser::serialize(&mut writer, version, commit).map_err(ErrorKind::SerErr)?;
+		}
+		writer.flush()?;
+		debug!(
+			"export_utxo_set: exported {} utxos to {:?}",
+			commits.len(),
+			path
+		);
+		Ok(())
+	}
+
+	/// Compare the local UTXO set against a UTXO set exported by another node
+	/// (see export_utxo_set()), reporting commitments present on one side only.
+	/// The export is streamed and must be sorted by commitment, an error is returned
+	/// if it is not. Only the local commitments are held in memory (not the outputs).
+	pub fn diff_utxo_set(&self, other_export: File) -> Result<UtxoDiff, Error> {
+		let local = self.sorted_utxo_commitments();
+
+		let version = ProtocolVersion::local();
+		let mut reader = BufReader::new(other_export);
+		let n_other: u64 = ser::deserialize(&mut reader, version).map_err(ErrorKind::SerErr)?;
+
+		let mut diff = UtxoDiff::default();
+		let mut local_iter = local.into_iter().peekable();
+		let mut prev: Option<Commitment> = None;
+		for _ in 0..n_other {
+			let other: Commitment =
+				ser::deserialize(&mut reader, version).map_err(ErrorKind::SerErr)?;
+			if let Some(prev) = prev {
+				if prev.0 >= other.0 {
+					return Err(ErrorKind::UtxoExportNotSorted.into());
+				}
+			}
+			prev = Some(other);
+
+			// Everything local before this commitment is missing from the export.
+			while let Some(local) = local_iter.peek() {
+				if local.0 < other.0 {
+					diff.local_only.push(*local);
+					local_iter.next();
+				} else {
+					break;
+				}
+			}
+			match local_iter.peek() {
+				Some(local) if local.0 == other.0 => {
+					local_iter.next();
+				}
+				_ => diff.other_only.push(other),
+			}
+		}
+		diff.local_only.extend(local_iter);
+
+		debug!(
+			"diff_utxo_set: {} local only, {} other only",
+			diff.local_only.len(),
+			diff.other_only.len()
+		);
+		Ok(diff)
+	}
+
+	/// Snapshot the set of output positions pruned (compacted away) from the output MMR.
+	/// Take a snapshot before and after compaction and diff them to see exactly which
+	/// outputs were removed (see PruneSnapshot::diff()).
+	pub fn prune_state_snapshot(&self) -> PruneSnapshot {
+		PruneSnapshot::new(
+			self.output_pmmr_h.last_pos,
+			self.output_pmmr_h.backend.pruned_leaves(),
+		)
+	}
+
+	/// Get MMR roots.
+	pub fn roots(&self) -> TxHashSetRoots {
+		self.roots_inner(true)
+	}
+
+	/// Get MMR roots, skipping the (potentially expensive) bitmap accumulator root.
+	/// The output_roots.bitmap_root is left as ZERO_HASH so only the output PMMR root
+	/// (pmmr_root) is meaningful, output_roots.root() must not be used with headers
+	/// committing to the merged root.
+	pub fn roots_without_bitmap(&self) -> TxHashSetRoots {
+		self.roots_inner(false)
+	}
+
+	fn roots_inner(&self, include_bitmap_root: bool) -> TxHashSetRoots {
+		let output_pmmr =
+			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
+		let rproof_pmmr =
+			ReadonlyPMMR::at(&self.rproof_pmmr_h.backend, self.rproof_pmmr_h.last_pos);
+		let kernel_pmmr =
+			ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos);
+
+		let token_output_pmmr = ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		);
+		let token_rproof_pmmr = ReadonlyPMMR::at(
+			&self.token_rproof_pmmr_h.backend,
+			self.token_rproof_pmmr_h.last_pos,
+		);
+		let token_issue_proof_pmmr = ReadonlyPMMR::at(
+			&self.token_issue_proof_pmmr_h.backend,
+			self.token_issue_proof_pmmr_h.last_pos,
+		);
+		let token_kernel_pmmr = ReadonlyPMMR::at(
+			&self.token_kernel_pmmr_h.backend,
+			self.token_kernel_pmmr_h.last_pos,
+		);
+
+		let bitmap_root = if include_bitmap_root {
+			self.bitmap_accumulator.root()
+		} else {
+			ZERO_HASH
+		};
+
+		TxHashSetRoots {
+			output_roots: OutputRoots {
+				pmmr_root: output_pmmr.root(),
+				bitmap_root,
+			},
+			rproof_root: rproof_pmmr.root(),
+			kernel_root: kernel_pmmr.root(),
+			token_output_root: token_output_pmmr.root(),
+			token_rproof_root: token_rproof_pmmr.root(),
+			token_issue_proof_root: token_issue_proof_pmmr.root(),
+			token_kernel_root: token_kernel_pmmr.root(),
+		}
+	}
+
+	/// Validate the current MMR roots against an explicitly supplied set of expected roots
+	/// (from a checkpoint file for example, rather than a header).
+	/// All mismatched roots are reported, not just the first.
+	pub fn validate_against_roots(&self, expected: TxHashSetRoots) -> Result<(), Error> {
+		let roots = self.roots();
+		let checks = vec![
+			(
+				"output_pmmr_root",
+				roots.output_roots.pmmr_root,
+				expected.output_roots.pmmr_root,
+			),
+			(
+				"output_bitmap_root",
+				roots.output_roots.bitmap_root,
+				expected.output_roots.bitmap_root,
+			),
+			("rproof_root", roots.rproof_root, expected.rproof_root),
+			("kernel_root", roots.kernel_root, expected.kernel_root),
+			(
+				"token_output_root",
+				roots.token_output_root,
+				expected.token_output_root,
+			),
+			(
+				"token_rproof_root",
+				roots.token_rproof_root,
+				expected.token_rproof_root,
+			),
+			(
+				"token_issue_proof_root",
+				roots.token_issue_proof_root,
+				expected.token_issue_proof_root,
+			),
+			(
+				"token_kernel_root",
+				roots.token_kernel_root,
+				expected.token_kernel_root,
+			),
+		];
+		let mismatches: Vec<String> = checks
+			.into_iter()
+			.filter(|(_, actual, expected)| actual != expected)
+			.map(|(name, actual, expected)| {
+				format!("{}: expected {}, got {}", name, expected, actual)
+			})
+			.collect();
+		if mismatches.is_empty() {
+			Ok(())
+		} else {
+			Err(ErrorKind::RootsMismatch(mismatches).into())
+		}
+	}
+
+	/// Readonly view of all MMRs pinned to the provided sizes (typically corresponding to
+	/// a past header within the horizon) for consistent point-in-time reads of MMR
+	/// structure (hashes, peaks and roots) without a full rewind.
+	/// Note: the leaf set is not rewound so outputs spent since will appear spent.
+	/// Errors if any size exceeds the current size, is not a valid MMR size or if the
+	/// peaks at the requested size have been compacted away.
+	pub fn readonly_view_at(&self, sizes: MmrSizes) -> Result<ReadonlyView<'_>, Error> {
+		let view = ReadonlyView {
+			output_pmmr: readonly_pmmr_at(&self.output_pmmr_h, sizes.output)?,
+			rproof_pmmr: readonly_pmmr_at(&self.rproof_pmmr_h, sizes.rproof)?,
+			kernel_pmmr: readonly_pmmr_at(&self.kernel_pmmr_h, sizes.kernel)?,
+			token_output_pmmr: readonly_pmmr_at(&self.token_output_pmmr_h, sizes.token_output)?,
+			token_rproof_pmmr: readonly_pmmr_at(&self.token_rproof_pmmr_h, sizes.token_rproof)?,
+			token_issue_proof_pmmr: readonly_pmmr_at(
+				&self.token_issue_proof_pmmr_h,
+				sizes.token_issue_proof,
+			)?,
+			token_kernel_pmmr: readonly_pmmr_at(&self.token_kernel_pmmr_h, sizes.token_kernel)?,
+		};
+		Ok(view)
+	}
+
+	/// Peak positions of each MMR at the current size.
+	/// Together with the peak hashes these fully specify the root computation
+	/// (peaks are bagged right to left).
+	pub fn mmr_peak_positions(&self) -> MmrPeakPositions {
+		MmrPeakPositions {
+			output: pmmr::peaks(self.output_pmmr_h.last_pos),
+			rproof: pmmr::peaks(self.rproof_pmmr_h.last_pos),
+			kernel: pmmr::peaks(self.kernel_pmmr_h.last_pos),
+			token_output: pmmr::peaks(self.token_output_pmmr_h.last_pos),
+			token_rproof: pmmr::peaks(self.token_rproof_pmmr_h.last_pos),
+			token_issue_proof: pmmr::peaks(self.token_issue_proof_pmmr_h.last_pos),
+			token_kernel: pmmr::peaks(self.token_kernel_pmmr_h.last_pos),
+		}
+	}
+
+	/// Check the current (committed) output PMMR root and bitmap root against the
+	/// output_root of the provided header, without touching the other MMRs.
+	pub fn output_root_matches(&self, header: &BlockHeader) -> Result<bool, Error> {
+		let output_pmmr =
+			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
+		let output_roots = OutputRoots {
+			pmmr_root: output_pmmr.root(),
+			bitmap_root: self.bitmap_accumulator.root(),
+		};
+		Ok(output_roots.root(header) == header.output_root)
+	}
+
+	/// Check the current (committed) kernel PMMR root against the kernel_root
+	/// of the provided header.
+	pub fn kernel_root_matches(&self, header: &BlockHeader) -> Result<bool, Error> {
+		let kernel_pmmr =
+			ReadonlyPMMR::at(&self.kernel_pmmr_h.backend, self.kernel_pmmr_h.last_pos);
+		Ok(kernel_pmmr.root() == header.kernel_root)
+	}
+
+	/// Check the current (committed) token output PMMR root against the
+	/// token_output_root of the provided header.
+	pub fn token_output_root_matches(&self, header: &BlockHeader) -> Result<bool, Error> {
+		let token_output_pmmr = ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		);
+		Ok(token_output_pmmr.root() == header.token_output_root)
+	}
+
+	/// Check the current (committed) token kernel PMMR root against the
+	/// token_kernel_root of the provided header.
+	pub fn token_kernel_root_matches(&self, header: &BlockHeader) -> Result<bool, Error> {
+		let token_kernel_pmmr = ReadonlyPMMR::at(
+			&self.token_kernel_pmmr_h.backend,
+			self.token_kernel_pmmr_h.last_pos,
+		);
+		Ok(token_kernel_pmmr.root() == header.token_kernel_root)
+	}
+
+	/// Return Commit's MMR position
+	pub fn get_output_pos(&self, commit: &Commitment) -> Result<u64, Error> {
+		Ok(self.commit_index.get_output_pos(&commit)?)
+	}
+
+	/// Return Commit's MMR position
+	pub fn get_token_output_pos(&self, commit: &Commitment) -> Result<u64, Error> {
+		Ok(self.commit_index.get_token_output_pos(&commit)?)
+	}
+
+	/// build a new merkle proof for the given position.
+	pub fn merkle_proof(&self, commit: Commitment) -> Result<MerkleProof, Error> {
+		let pos = self.commit_index.get_output_pos(&commit)?;
+		ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos)
+			.merkle_proof(pos)
+			.map_err(|_| ErrorKind::MerkleProof.into())
+	}
+
+	/// build a new merkle proof for the given position.
+	pub fn token_merkle_proof(&self, commit: Commitment) -> Result<MerkleProof, Error> {
+		let pos = self.commit_index.get_token_output_pos(&commit)?;
+		ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		)
+		.merkle_proof(pos)
+		.map_err(|_| ErrorKind::MerkleProof.into())
+	}
+
+	/// build a new merkle proof for the given token output commitment, along with
+	/// the token output MMR pos and the current token output root the proof is against.
+	pub fn token_merkle_proof_with_context(
+		&self,
+		commit: Commitment,
+	) -> Result<(MerkleProof, u64, Hash), Error> {
+		let pos = self.commit_index.get_token_output_pos(&commit)?;
+		let pmmr = ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		);
+		let merkle_proof = pmmr
+			.merkle_proof(pos)
+			.map_err(|_| Error::from(ErrorKind::MerkleProof))?;
+		Ok((merkle_proof, pos, pmmr.root()))
+	}
+
+	/// Compact the MMR data files and flush the rm logs.
+	/// Returns the space reclaimed and the number of MMR positions removed.
+	pub fn compact(
+		&mut self,
+		horizon_header: &BlockHeader,
+		batch: &Batch<'_>,
+	) -> Result<CompactionStats, Error> {
+		debug!("txhashset: starting compaction...");
+
+		let head_header = batch.head_header()?;
+
+		let rewind_rm_pos = input_pos_to_rewind(&horizon_header, &head_header, batch)?;
+		let token_rewind_rm_pos = token_input_pos_to_rewind(&horizon_header, &head_header, batch)?;
+
+		debug!("txhashset: check_compact output mmr backend...");
+		let (output_bytes_freed, output_pos_removed) = check_compact_with_stats(
+			&mut self.output_pmmr_h.backend,
+			horizon_header.output_mmr_size,
+			&rewind_rm_pos,
+		)?;
+
+		debug!("txhashset: check_compact rangeproof mmr backend...");
+		let (rproof_bytes_freed, rproof_pos_removed) = check_compact_with_stats(
+			&mut self.rproof_pmmr_h.backend,
+			horizon_header.output_mmr_size,
+			&rewind_rm_pos,
+		)?;
 
 		debug!("txhashset: check_compact token_output mmr backend...");
-		self.token_output_pmmr_h
-			.backend
-			.check_compact(horizon_header.token_output_mmr_size, &token_rewind_rm_pos)?;
+		let (token_output_bytes_freed, token_output_pos_removed) = check_compact_with_stats(
+			&mut self.token_output_pmmr_h.backend,
+			horizon_header.token_output_mmr_size,
+			&token_rewind_rm_pos,
+		)?;
 
 		debug!("txhashset: check_compact token_rangeproof mmr backend...");
-		self.token_rproof_pmmr_h.backend.check_compact(
-			horizon_header.token_issue_proof_mmr_size,
+		let (token_rproof_bytes_freed, token_rproof_pos_removed) = check_compact_with_stats(
+			&mut self.token_rproof_pmmr_h.backend,
+			horizon_header.token_output_mmr_size,
 			&token_rewind_rm_pos,
 		)?;
 
-		debug!("txhashset: ... compaction finished");
+		let stats = CompactionStats {
+			output_bytes_freed,
+			rproof_bytes_freed,
+			token_output_bytes_freed,
+			token_rproof_bytes_freed,
+			positions_removed: output_pos_removed
+				+ rproof_pos_removed
+				+ token_output_pos_removed
+				+ token_rproof_pos_removed,
+		};
 
-		Ok(())
+		debug!("txhashset: ... compaction finished: {:?}", stats);
+
+		Ok(stats)
+	}
+
+	/// Estimate what compact() would do for the given horizon_header without rewriting any
+	/// files. Uses the same rewind pos calculation and cutoff positions as compact().
+	/// Bytes freed are estimated from the number of hashes removed and the (fixed) element
+	/// size of each removed leaf.
+	pub fn compact_dry_run(
+		&self,
+		horizon_header: &BlockHeader,
+		batch: &Batch<'_>,
+	) -> Result<CompactionStats, Error> {
+		let head_header = batch.head_header()?;
+
+		let rewind_rm_pos = input_pos_to_rewind(horizon_header, &head_header, batch)?;
+		let token_rewind_rm_pos = token_input_pos_to_rewind(horizon_header, &head_header, batch)?;
+
+		let (output_bytes_freed, output_pos_removed) = compact_estimate(
+			&self.output_pmmr_h.backend,
+			horizon_header.output_mmr_size,
+			&rewind_rm_pos,
+		);
+		let (rproof_bytes_freed, rproof_pos_removed) = compact_estimate(
+			&self.rproof_pmmr_h.backend,
+			horizon_header.output_mmr_size,
+			&rewind_rm_pos,
+		);
+		let (token_output_bytes_freed, token_output_pos_removed) = compact_estimate(
+			&self.token_output_pmmr_h.backend,
+			horizon_header.token_output_mmr_size,
+			&token_rewind_rm_pos,
+		);
+		let (token_rproof_bytes_freed, token_rproof_pos_removed) = compact_estimate(
+			&self.token_rproof_pmmr_h.backend,
+			horizon_header.token_output_mmr_size,
+			&token_rewind_rm_pos,
+		);
+
+		let stats = CompactionStats {
+			output_bytes_freed,
+			rproof_bytes_freed,
+			token_output_bytes_freed,
+			token_rproof_bytes_freed,
+			positions_removed: output_pos_removed
+				+ rproof_pos_removed
+				+ token_output_pos_removed
+				+ token_rproof_pos_removed,
+		};
+
+		debug!("txhashset: compaction dry run: {:?}", stats);
+
+		Ok(stats)
+	}
+
+	/// Verify compaction has not pruned any output (or token output) that is still
+	/// required within the horizon. Any output spent after the horizon_header was still
+	/// unspent at the horizon and must remain in the output and rangeproof MMRs so we can
+	/// rewind back to the horizon. Errors reporting every such pos that has been pruned.
+	pub fn verify_prune_horizon(
+		&self,
+		horizon_header: &BlockHeader,
+		batch: &Batch<'_>,
+	) -> Result<(), Error> {
+		let head_header = batch.head_header()?;
+
+		let rewind_rm_pos = input_pos_to_rewind(horizon_header, &head_header, batch)?;
+		let pruned: Vec<u64> = rewind_rm_pos
+			.iter()
+			.map(|x| x as u64)
+			.filter(|pos| {
+				let output = self.output_pmmr_h.backend.get_data_from_file(*pos);
+				let proof = self.rproof_pmmr_h.backend.get_data_from_file(*pos);
+				output.is_none() || proof.is_none()
+			})
+			.collect();
+
+		let token_rewind_rm_pos = token_input_pos_to_rewind(horizon_header, &head_header, batch)?;
+		let token_pruned: Vec<u64> = token_rewind_rm_pos
+			.iter()
+			.map(|x| x as u64)
+			.filter(|pos| {
+				let output = self.token_output_pmmr_h.backend.get_data_from_file(*pos);
+				let proof = self.token_rproof_pmmr_h.backend.get_data_from_file(*pos);
+				output.is_none() || proof.is_none()
+			})
+			.collect();
+
+		if pruned.is_empty() && token_pruned.is_empty() {
+			Ok(())
+		} else {
+			Err(ErrorKind::PrunedWithinHorizon(horizon_header.height, pruned, token_pruned).into())
+		}
 	}
 
 	/// (Re)build the output_pos index to be consistent with the current UTXO set.
@@ -610,9 +2400,39 @@ impl TxHashSet {
 		&self,
 		header_pmmr: &PMMRHandle<BlockHeader>,
 		batch: &Batch<'_>,
+	) -> Result<(), Error> {
+		self.reconcile_output_pos_index(header_pmmr, batch, 0)
+	}
+
+	/// Incremental version of init_output_pos_index, for use after a (small) reorg.
+	/// Only reconciles index entries for outputs created at or after from_height, i.e. at
+	/// positions beyond the output_mmr_size of the header at from_height - 1.
+	/// Index entries for earlier outputs are left untouched.
+	pub fn update_output_pos_index_from_height(
+		&self,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+		from_height: u64,
+	) -> Result<(), Error> {
+		self.reconcile_output_pos_index(header_pmmr, batch, from_height)
+	}
+
+	fn reconcile_output_pos_index(
+		&self,
+		header_pmmr: &PMMRHandle<BlockHeader>,
+		batch: &Batch<'_>,
+		from_height: u64,
 	) -> Result<(), Error> {
 		let now = Instant::now();
 
+		// Outputs at or below the cutoff pos were created before from_height.
+		let cutoff_pos = if from_height == 0 {
+			0
+		} else {
+			let hash = header_pmmr.get_header_hash_by_height(from_height - 1)?;
+			batch.get_block_header(&hash)?.output_mmr_size
+		};
+
 		let output_pmmr =
 			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
 
@@ -620,6 +2440,9 @@ impl TxHashSet {
 		// do not point to to the expected output.
 		let mut removed_count = 0;
 		for (key, (pos, _)) in batch.output_pos_iter()? {
+			if pos <= cutoff_pos {
+				continue;
+			}
 			if let Some(out) = output_pmmr.get_data(pos) {
 				if let Ok(pos_via_mmr) = batch.get_output_pos(&out.commitment()) {
 					// If the pos matches and the index key matches the commitment
@@ -634,18 +2457,23 @@ impl TxHashSet {
 			removed_count += 1;
 		}
 		debug!(
-			"init_output_pos_index: removed {} stale index entries",
-			removed_count
+			"output_pos_index (from height {}): removed {} stale index entries",
+			from_height, removed_count
 		);
 
 		let mut outputs_pos: Vec<(Commitment, u64)> = vec![];
-		for pos in output_pmmr.leaf_pos_iter() {
+		for idx in output_pmmr.leaf_idx_iter(pmmr::n_leaves(cutoff_pos)) {
+			let pos = pmmr::insertion_to_pmmr_index(idx + 1);
 			if let Some(out) = output_pmmr.get_data(pos) {
 				outputs_pos.push((out.commit, pos));
 			}
 		}
 
-		debug!("init_output_pos_index: {} utxos", outputs_pos.len());
+		debug!(
+			"output_pos_index (from height {}): {} utxos",
+			from_height,
+			outputs_pos.len()
+		);
 
 		outputs_pos.retain(|x| {
 			batch
@@ -655,7 +2483,8 @@ impl TxHashSet {
 		});
 
 		debug!(
-			"init_output_pos_index: {} utxos with missing index entries",
+			"output_pos_index (from height {}): {} utxos with missing index entries",
+			from_height,
 			outputs_pos.len()
 		);
 
@@ -666,9 +2495,15 @@ impl TxHashSet {
 		let total_outputs = outputs_pos.len();
 		let max_height = batch.head()?.height;
 
+		// Walk the header MMR once (outputs_pos is sorted by pos), using each header
+		// output_mmr_size as the boundary for outputs in that block.
+		// Stop as soon as every output has been assigned a height.
+		// All writes go to the provided batch (a single db transaction).
 		let mut i = 0;
-		for search_height in 0..max_height {
-			let hash = header_pmmr.get_header_hash_by_height(search_height + 1)?;
+		for (_, hash) in header_pmmr.iter_header_hashes(cmp::max(from_height, 1), max_height) {
+			if i >= total_outputs {
+				break;
+			}
 			let h = batch.get_block_header(&hash)?;
 			while i < total_outputs {
 				let (commit, pos) = outputs_pos[i];
@@ -680,8 +2515,17 @@ impl TxHashSet {
 				i += 1;
 			}
 		}
+		if i < total_outputs {
+			return Err(ErrorKind::OutputPosIndexUncovered(format!(
+				"output_pos_index (from height {}): header mmr does not cover {} utxos",
+				from_height,
+				total_outputs - i
+			))
+			.into());
+		}
 		debug!(
-			"init_height_pos_index: added entries for {} utxos, took {}s",
+			"output_pos_index (from height {}): added entries for {} utxos, took {}s",
+			from_height,
 			total_outputs,
 			now.elapsed().as_secs(),
 		);
@@ -754,9 +2598,15 @@ impl TxHashSet {
 		let total_outputs = outputs_pos.len();
 		let max_height = batch.head()?.height;
 
+		// Walk the header MMR once (outputs_pos is sorted by pos), using each header
+		// token_output_mmr_size as the boundary for outputs in that block.
+		// Stop as soon as every output has been assigned a height.
+		// All writes go to the provided batch (a single db transaction).
 		let mut i = 0;
-		for search_height in 0..max_height {
-			let hash = header_pmmr.get_header_hash_by_height(search_height + 1)?;
+		for (_, hash) in header_pmmr.iter_header_hashes(1, max_height) {
+			if i >= total_outputs {
+				break;
+			}
 			let h = batch.get_block_header(&hash)?;
 			while i < total_outputs {
 				let (commit, pos) = outputs_pos[i];
@@ -768,6 +2618,13 @@ impl TxHashSet {
 				i += 1;
 			}
 		}
+		if i < total_outputs {
+			return Err(ErrorKind::OutputPosIndexUncovered(format!(
+				"init_token_output_pos_index: header mmr does not cover {} utxos",
+				total_outputs - i
+			))
+			.into());
+		}
 		debug!(
 			"init_token_output_pos_index: added entries for {} utxos, took {}s",
 			total_outputs,
@@ -775,6 +2632,189 @@ impl TxHashSet {
 		);
 		Ok(())
 	}
+
+	/// Check the output_pos index is consistent with the output MMR without modifying
+	/// either (unlike init_output_pos_index which deletes stale entries).
+	/// Each index entry must point to an unspent output whose commitment matches the
+	/// index key and each unspent output must have an index entry pointing to it.
+	/// A read only diagnostic, to decide if the index needs rebuilding.
+	pub fn check_output_pos_index(&self, batch: &Batch<'_>) -> Result<IndexCheckReport, Error> {
+		let output_pmmr =
+			ReadonlyPMMR::at(&self.output_pmmr_h.backend, self.output_pmmr_h.last_pos);
+
+		let mut report = IndexCheckReport::default();
+		for (key, (pos, _)) in batch.output_pos_iter()? {
+			report.checked += 1;
+			match output_pmmr.get_data(pos) {
+				Some(out) => {
+					if !batch.is_match_output_pos_key(&key, &out.commitment()) {
+						report.mismatched += 1;
+					}
+				}
+				None => report.stale += 1,
+			}
+		}
+
+		for pos in output_pmmr.leaf_pos_iter() {
+			if let Some(out) = output_pmmr.get_data(pos) {
+				match batch.get_output_pos_height(&out.commitment())? {
+					Some((index_pos, _)) if index_pos == pos => {}
+					_ => report.missing += 1,
+				}
+			}
+		}
+
+		debug!("check_output_pos_index: {:?}", report);
+		Ok(report)
+	}
+
+	/// Check the token_output_pos index is consistent with the token output MMR
+	/// without modifying either, see check_output_pos_index().
+	pub fn check_token_output_pos_index(
+		&self,
+		batch: &Batch<'_>,
+	) -> Result<IndexCheckReport, Error> {
+		let output_pmmr = ReadonlyPMMR::at(
+			&self.token_output_pmmr_h.backend,
+			self.token_output_pmmr_h.last_pos,
+		);
+
+		let mut report = IndexCheckReport::default();
+		for (key, (pos, _)) in batch.token_output_pos_iter()? {
+			report.checked += 1;
+			match output_pmmr.get_data(pos) {
+				Some(out) => {
+					if !batch.is_match_token_output_pos_key(&key, &out.commitment()) {
+						report.mismatched += 1;
+					}
+				}
+				None => report.stale += 1,
+			}
+		}
+
+		for pos in output_pmmr.leaf_pos_iter() {
+			if let Some(out) = output_pmmr.get_data(pos) {
+				match batch.get_token_output_pos_height(&out.commitment())? {
+					Some((index_pos, _)) if index_pos == pos => {}
+					_ => report.missing += 1,
+				}
+			}
+		}
+
+		debug!("check_token_output_pos_index: {:?}", report);
+		Ok(report)
+	}
+
+	/// Quick check of output_pos index coverage.
+	/// Returns (utxo count, output_pos index entry count).
+	/// Diverging counts indicate an incomplete (or stale) index, i.e. init_output_pos_index
+	/// was interrupted and the index needs rebuilding.
+	pub fn output_pos_index_coverage(&self, batch: &Batch<'_>) -> Result<(u64, u64), Error> {
+		let index_count = batch.output_pos_iter()?.count() as u64;
+		Ok((self.utxo_count, index_count))
+	}
+
+	/// Quick check of token_output_pos index coverage.
+	/// Returns (token utxo count, token_output_pos index entry count).
+	pub fn token_output_pos_index_coverage(&self, batch: &Batch<'_>) -> Result<(u64, u64), Error> {
+		let index_count = batch.token_output_pos_iter()?.count() as u64;
+		Ok((self.token_utxo_count, index_count))
+	}
+
+	/// Run the cheap structural consistency checks and repair what can be safely
+	/// repaired, i.e. after an unclean shutdown.
+	/// * output and rangeproof MMR sizes (and token equivalents) must match. If they
+	///   diverge beyond the head header (an interrupted sync of the backends) the MMRs
+	///   are rewound to the head header and the roots are validated against it.
+	/// * output_pos (and token_output_pos) index coverage. An incomplete index is rebuilt.
+	/// Corruption that cannot be repaired (missing data up to the head header, roots not
+	/// matching the head header after repair) results in an error, the txhashset needs
+	/// to be resynced.
+	pub fn verify_and_repair(
+		&mut self,
+		header_pmmr: &mut PMMRHandle<BlockHeader>,
+		batch: &mut Batch<'_>,
+	) -> Result<RepairReport, Error> {
+		let mut report = RepairReport::default();
+		let head_header = batch.head_header()?;
+
+		let parity = vec![
+			(
+				"output",
+				self.output_pmmr_h.last_pos,
+				self.rproof_pmmr_h.last_pos,
+				head_header.output_mmr_size,
+			),
+			(
+				"token_output",
+				self.token_output_pmmr_h.last_pos,
+				self.token_rproof_pmmr_h.last_pos,
+				head_header.token_output_mmr_size,
+			),
+		];
+		let mut needs_rewind = false;
+		for (name, output_size, rproof_size, header_size) in parity {
+			report.checked.push(format!(
+				"{} mmr size {} vs rangeproof mmr size {}",
+				name, output_size, rproof_size
+			));
+			if output_size == rproof_size {
+				continue;
+			}
+			if cmp::min(output_size, rproof_size) < header_size {
+				return Err(ErrorKind::MMRParityMismatch(format!(
+					"{} mmr size {} vs rangeproof mmr size {}, missing data below head \
+					 header size {}, unable to repair, txhashset needs to be resynced",
+					name, output_size, rproof_size, header_size
+				))
+				.into());
+			}
+			needs_rewind = true;
+		}
+
+		if needs_rewind {
+			extending(header_pmmr, self, batch, |ext, batch| {
+				let extension = &mut ext.extension;
+				extension.rewind(&head_header, batch)?;
+				extension.validate_sizes(&head_header)?;
+				extension.validate_roots(&head_header)
+			})
+			.map_err(|e| {
+				let kind = ErrorKind::RepairFailed(head_header.hash().to_hex(), Box::new(e.kind()));
+				Error::from(e.context(kind))
+			})?;
+			report.repaired.push(format!(
+				"rewound mmrs to head {} at {}",
+				head_header.hash(),
+				head_header.height
+			));
+		}
+
+		let (utxo_count, index_count) = self.output_pos_index_coverage(batch)?;
+		report.checked.push(format!(
+			"output_pos index {} entries vs {} utxos",
+			index_count, utxo_count
+		));
+		if index_count != utxo_count {
+			self.init_output_pos_index(header_pmmr, batch)?;
+			report.repaired.push("rebuilt output_pos index".to_string());
+		}
+
+		let (token_utxo_count, token_index_count) = self.token_output_pos_index_coverage(batch)?;
+		report.checked.push(format!(
+			"token_output_pos index {} entries vs {} token utxos",
+			token_index_count, token_utxo_count
+		));
+		if token_index_count != token_utxo_count {
+			self.init_token_output_pos_index(header_pmmr, batch)?;
+			report
+				.repaired
+				.push("rebuilt token_output_pos index".to_string());
+		}
+
+		info!("verify_and_repair: {:?}", report);
+		Ok(report)
+	}
 }
 
 /// Starts a new unit of work to extend (or rewind) the chain with additional
@@ -881,6 +2921,41 @@ where
 	res
 }
 
+/// Readonly view on the UTXO set as it existed at the provided (historical) header.
+/// The header must be on the current chain (at or below the current head).
+/// Rewinds a readonly extension to the header, builds a UTXOView on the rewound
+/// MMRs and passes it to the closure. The extension is always rolled back.
+///
+/// Note: this is expensive as the rewind walks back (and undoes) every block
+/// between the current head and the header.
+pub fn utxo_view_at<F, T>(
+	handle: &mut PMMRHandle<BlockHeader>,
+	trees: &mut TxHashSet,
+	header: &BlockHeader,
+	inner: F,
+) -> Result<T, Error>
+where
+	F: FnOnce(&UTXOView<'_>, &Batch<'_>) -> Result<T, Error>,
+{
+	extending_readonly(handle, trees, |ext, batch| {
+		// Rewind the header extension to the current (body) head so we can check
+		// the header is on the chain the txhashset can be rewound along.
+		let head = batch.head()?;
+		let head_header = batch.get_block_header(&head.last_block_h)?;
+		ext.header_extension.rewind(&head_header)?;
+		ext.header_extension.is_on_current_chain(header, batch)?;
+
+		// The header may be arbitrarily old, the extension is always rolled back.
+		ext.extension.set_max_rewind_depth(None);
+		ext.header_extension.rewind(header)?;
+		ext.extension.rewind(header, batch)?;
+
+		let extension = &ext.extension;
+		let header_extension = &ext.header_extension;
+		inner(&extension.utxo_view(header_extension), batch)
+	})
+}
+
 /// Rewindable (but still readonly) view on the kernel MMR.
 /// The underlying backend is readonly. But we permit the PMMR to be "rewound"
 /// via last_pos.
@@ -926,10 +3001,12 @@ pub fn extending<'a, F, T>(
 where
 	F: FnOnce(&mut ExtensionPair<'_>, &Batch<'_>) -> Result<T, Error>,
 {
-	let sizes: (u64, u64, u64, u64, u64, u64, u64);
+	let sizes: MmrSizes;
 	let res: Result<T, Error>;
 	let rollback: bool;
 	let bitmap_accumulator: BitmapAccumulator;
+	let utxo_counts: (u64, u64);
+	let kernel_rewind_pos: u64;
 
 	let head = batch.head()?;
 
@@ -958,6 +3035,11 @@ where
 		rollback = extension_pair.extension.rollback;
 		sizes = extension_pair.extension.sizes();
 		bitmap_accumulator = extension_pair.extension.bitmap_accumulator.clone();
+		utxo_counts = (
+			extension_pair.extension.utxo_count,
+			extension_pair.extension.token_utxo_count,
+		);
+		kernel_rewind_pos = extension_pair.extension.kernel_rewind_pos;
 	}
 
 	// During an extension we do not want to modify the header_extension (and only read from it).
@@ -989,26 +3071,43 @@ where
 			} else {
 				trace!("Committing txhashset extension. sizes {:?}", sizes);
 				child_batch.commit()?;
-				trees.output_pmmr_h.backend.sync()?;
-				trees.rproof_pmmr_h.backend.sync()?;
-				trees.kernel_pmmr_h.backend.sync()?;
-				trees.output_pmmr_h.last_pos = sizes.0;
-				trees.rproof_pmmr_h.last_pos = sizes.1;
-				trees.kernel_pmmr_h.last_pos = sizes.2;
-				trees.token_output_pmmr_h.backend.sync()?;
-				trees.token_rproof_pmmr_h.backend.sync()?;
-				trees.token_issue_proof_pmmr_h.backend.sync()?;
-				trees.token_kernel_pmmr_h.backend.sync()?;
-				trees.output_pmmr_h.last_pos = sizes.0;
-				trees.rproof_pmmr_h.last_pos = sizes.1;
-				trees.kernel_pmmr_h.last_pos = sizes.2;
-				trees.token_output_pmmr_h.last_pos = sizes.3;
-				trees.token_rproof_pmmr_h.last_pos = sizes.4;
-				trees.token_issue_proof_pmmr_h.last_pos = sizes.5;
-				trees.token_kernel_pmmr_h.last_pos = sizes.6;
+				let durable = trees.durability_mode == DurabilityMode::Durable;
+				trees.output_pmmr_h.backend.sync_with_durability(durable)?;
+				trees.rproof_pmmr_h.backend.sync_with_durability(durable)?;
+				trees.kernel_pmmr_h.backend.sync_with_durability(durable)?;
+				trees
+					.token_output_pmmr_h
+					.backend
+					.sync_with_durability(durable)?;
+				trees
+					.token_rproof_pmmr_h
+					.backend
+					.sync_with_durability(durable)?;
+				trees
+					.token_issue_proof_pmmr_h
+					.backend
+					.sync_with_durability(durable)?;
+				trees
+					.token_kernel_pmmr_h
+					.backend
+					.sync_with_durability(durable)?;
+				trees.output_pmmr_h.last_pos = sizes.output;
+				trees.rproof_pmmr_h.last_pos = sizes.rproof;
+				trees.kernel_pmmr_h.last_pos = sizes.kernel;
+				trees.token_output_pmmr_h.last_pos = sizes.token_output;
+				trees.token_rproof_pmmr_h.last_pos = sizes.token_rproof;
+				trees.token_issue_proof_pmmr_h.last_pos = sizes.token_issue_proof;
+				trees.token_kernel_pmmr_h.last_pos = sizes.token_kernel;
 
 				// Update our bitmap_accumulator based on our extension
 				trees.bitmap_accumulator = bitmap_accumulator;
+
+				// Update our cached utxo counts based on our extension
+				trees.utxo_count = utxo_counts.0;
+				trees.token_utxo_count = utxo_counts.1;
+
+				// Update our kernel excess index based on our extension
+				trees.update_kernel_pos_index(kernel_rewind_pos);
 			}
 
 			trace!("TxHashSet extension done.");
@@ -1066,6 +3165,7 @@ where
 				child_batch.commit()?;
 				handle.backend.sync()?;
 				handle.last_pos = size;
+				handle.clear_header_hash_cache();
 			}
 			Ok(r)
 		}
@@ -1117,7 +3217,7 @@ impl<'a> HeaderExtension<'a> {
 		if let Some(hash) = self.get_header_hash(pos) {
 			Ok(batch.get_block_header(&hash)?)
 		} else {
-			Err(ErrorKind::Other("get header by height".to_string()).into())
+			Err(ErrorKind::HeaderByHeight.into())
 		}
 	}
 
@@ -1129,13 +3229,13 @@ impl<'a> HeaderExtension<'a> {
 		batch: &Batch<'_>,
 	) -> Result<(), Error> {
 		if header.height > self.head.height {
-			return Err(ErrorKind::Other("not on current chain, out beyond".to_string()).into());
+			return Err(ErrorKind::NotOnCurrentChainBeyondHead.into());
 		}
 		let chain_header = self.get_header_by_height(header.height, batch)?;
 		if chain_header.hash() == header.hash() {
 			Ok(())
 		} else {
-			Err(ErrorKind::Other("not on current chain".to_string()).into())
+			Err(ErrorKind::NotOnCurrentChain.into())
 		}
 	}
 
@@ -1175,6 +3275,16 @@ impl<'a> HeaderExtension<'a> {
 		Ok(())
 	}
 
+	/// Rewind the header extension to the header at the specified height on the current
+	/// header chain. Height 0 rewinds to genesis. Errors if height is beyond the head.
+	pub fn rewind_to_height(&mut self, height: u64, batch: &Batch<'_>) -> Result<(), Error> {
+		if height > self.head.height {
+			return Err(ErrorKind::RewindBeyondHead(height, self.head.height).into());
+		}
+		let header = self.get_header_by_height(height, batch)?;
+		self.rewind(&header)
+	}
+
 	/// The size of the header MMR.
 	pub fn size(&self) -> u64 {
 		self.pmmr.unpruned_size()
@@ -1226,6 +3336,15 @@ pub struct Extension<'a> {
 
 	bitmap_accumulator: BitmapAccumulator,
 
+	utxo_count: u64,
+	token_utxo_count: u64,
+
+	// smallest kernel MMR size seen (via rewind) during this extension
+	kernel_rewind_pos: u64,
+
+	// max number of blocks we will rewind in a single call to rewind (None for no limit)
+	max_rewind_depth: Option<u64>,
+
 	/// Rollback flag.
 	rollback: bool,
 }
@@ -1237,12 +3356,53 @@ impl<'a> Committed for Extension<'a> {
 
 	fn outputs_committed(&self) -> Vec<Commitment> {
 		let mut commitments = vec![];
-		for pos in self.output_pmmr.leaf_pos_iter() {
-			if let Some(out) = self.output_pmmr.get_data(pos) {
-				commitments.push(out.commit);
+		self.for_each_output_commitment(|commit| commitments.push(commit));
+		commitments
+	}
+
+	// Sum the output commitments in bounded size chunks rather than materializing
+	// the full vec of commitments via outputs_committed().
+	fn sum_commitments(&self, overage: i64) -> Result<Commitment, committed::Error> {
+		const CHUNK_SIZE: usize = 1_000;
+
+		let mut sums = vec![];
+		let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+		let mut res = Ok(());
+		self.for_each_output_commitment(|commit| {
+			if res.is_err() {
+				return;
+			}
+			chunk.push(commit);
+			if chunk.len() >= CHUNK_SIZE {
+				match committed::sum_commits(chunk.split_off(0), vec![]) {
+					Ok(sum) => sums.push(sum),
+					Err(e) => res = Err(e),
+				}
+			}
+		});
+		res?;
+		sums.push(committed::sum_commits(chunk, vec![])?);
+
+		// add the overage as output commitment if positive,
+		// or as an input commitment if negative
+		let mut negative = vec![];
+		if overage != 0 {
+			let over_commit = {
+				let secp = static_secp_instance();
+				let secp = secp.lock();
+				let overage_abs = overage
+					.checked_abs()
+					.ok_or_else(|| committed::Error::InvalidValue)? as u64;
+				secp.commit_value(overage_abs)?
+			};
+			if overage < 0 {
+				negative.push(over_commit);
+			} else {
+				sums.push(over_commit);
 			}
 		}
-		commitments
+
+		committed::sum_commits(sums, negative)
 	}
 
 	fn kernels_committed(&self) -> Vec<Commitment> {
@@ -1333,6 +3493,10 @@ impl<'a> Extension<'a> {
 				trees.token_kernel_pmmr_h.last_pos,
 			),
 			bitmap_accumulator: trees.bitmap_accumulator.clone(),
+			utxo_count: trees.utxo_count,
+			token_utxo_count: trees.token_utxo_count,
+			kernel_rewind_pos: trees.kernel_pmmr_h.last_pos,
+			max_rewind_depth: None,
 			rollback: false,
 		}
 	}
@@ -1342,6 +3506,18 @@ impl<'a> Extension<'a> {
 		self.head.clone()
 	}
 
+	/// Limit the max number of blocks a single rewind can rewind.
+	/// Defaults to None (no limit). Block processing sets this to protect against
+	/// pathologically deep reorgs, see Chain::set_max_reorg_depth.
+	pub fn set_max_rewind_depth(&mut self, max_rewind_depth: Option<u64>) {
+		self.max_rewind_depth = max_rewind_depth;
+	}
+
+	/// The max number of blocks a single rewind can rewind (None if unlimited).
+	pub fn max_rewind_depth(&self) -> Option<u64> {
+		self.max_rewind_depth
+	}
+
 	/// Build a view of the current UTXO set based on the output PMMR
 	/// and the provided header extension.
 	pub fn utxo_view(&'a self, header_ext: &'a HeaderExtension<'a>) -> UTXOView<'a> {
@@ -1359,13 +3535,52 @@ impl<'a> Extension<'a> {
 	/// Returns a vec of commit_pos representing the pos and height of the outputs spent
 	/// by this block.
 	pub fn apply_block(&mut self, b: &Block, batch: &Batch<'_>) -> Result<(), Error> {
+		let affected_pos = self.apply_block_to_mmrs(b, batch)?;
+
+		// Update our BitmapAccumulator based on affected outputs (both spent and created).
+		self.apply_to_bitmap_accumulator(&affected_pos)?;
+
+		// Update the head of the extension to reflect the block we just applied.
+		self.head = Tip::from_header(&b.header);
+
+		Ok(())
+	}
+
+	/// Apply multiple (consecutive) blocks to the current txhashset extension,
+	/// i.e. when bulk importing blocks.
+	/// Equivalent to calling apply_block for each block in turn but the bitmap accumulator
+	/// is updated once, over the outputs affected by all the blocks, rather than once per block.
+	pub fn apply_blocks(&mut self, blocks: &[Block], batch: &Batch<'_>) -> Result<(), Error> {
+		let last = match blocks.last() {
+			Some(last) => last,
+			None => return Ok(()),
+		};
+
+		let mut affected_pos = vec![];
+		for b in blocks {
+			affected_pos.extend(self.apply_block_to_mmrs(b, batch)?);
+		}
+
+		// Update our BitmapAccumulator based on outputs affected across all the blocks.
+		self.apply_to_bitmap_accumulator(&affected_pos)?;
+
+		// Update the head of the extension to reflect the last block we just applied.
+		self.head = Tip::from_header(&last.header);
+
+		Ok(())
+	}
+
+	// Apply the block outputs, inputs and kernels (and token equivalents) to the MMRs and
+	// indexes, returning the output MMR pos of each output created and spent.
+	// The bitmap accumulator and extension head are left for the caller to update.
+	fn apply_block_to_mmrs(&mut self, b: &Block, batch: &Batch<'_>) -> Result<Vec<u64>, Error> {
 		let mut affected_pos = vec![];
 
 		// Apply the output to the output and rangeproof MMRs.
 		// Add pos to affected_pos to update the accumulator later on.
 		// Add the new output to the output_pos index.
 		for out in b.outputs() {
-			let pos = self.apply_output(out, batch)?;
+			let pos = self.apply_output(out, b.header.height, batch)?;
 			affected_pos.push(pos);
 			batch.save_output_pos_height(&out.commitment(), pos, b.header.height)?;
 		}
@@ -1382,39 +3597,71 @@ impl<'a> Extension<'a> {
 		}
 		batch.save_spent_index(&b.hash(), &spent)?;
 
-		for out in b.token_outputs() {
-			let pos = self.apply_token_output(out, batch)?;
-			batch.save_token_output_pos_height(&out.commitment(), pos, b.header.height)?;
-
-			if out.is_tokenissue() {
-				let pos = self.apply_token_issue_output(out, batch)?;
-				batch.save_token_issue_proof_pos(&out.token_type, pos)?;
-			}
+		for kernel in b.kernels() {
+			self.apply_kernel(kernel, b.header.height, batch)?;
 		}
 
-		let mut token_spent = vec![];
-		for input in b.token_inputs() {
-			let spent_pos = self.apply_token_input(input, batch)?;
-			batch.delete_token_output_pos_height(&input.commitment())?;
-			token_spent.push(spent_pos);
-		}
-		batch.save_spent_token_index(&b.hash(), &token_spent)?;
+		// Most blocks have no token activity so skip the token MMRs and indexes entirely.
+		// Note: no token spent index is saved for a block with no token inputs,
+		// rewind_single_block treats a missing entry for such a block as empty.
+		let has_token_data = !b.token_outputs().is_empty()
+			|| !b.token_inputs().is_empty()
+			|| !b.token_kernels().is_empty();
+		if has_token_data {
+			for out in b.token_outputs() {
+				let pos = self.apply_token_output(out, b.header.height, batch)?;
+				batch.save_token_output_pos_height(&out.commitment(), pos, b.header.height)?;
+
+				if out.is_tokenissue() {
+					let pos = self.apply_token_issue_output(out, batch)?;
+					batch.save_token_issue_proof_pos(&out.token_type, pos)?;
+				}
+			}
 
-		for kernel in b.kernels() {
-			self.apply_kernel(kernel)?;
-		}
+			if !b.token_inputs().is_empty() {
+				let mut token_spent = vec![];
+				for input in b.token_inputs() {
+					let spent_pos = self.apply_token_input(input, batch)?;
+					batch.delete_token_output_pos_height(&input.commitment())?;
+					token_spent.push(spent_pos);
+				}
+				batch.save_spent_token_index(&b.hash(), &token_spent)?;
+			}
 
-		for token_kernel in b.token_kernels() {
-			self.apply_token_kernel(token_kernel)?;
+			for token_kernel in b.token_kernels() {
+				self.apply_token_kernel(token_kernel, b.header.height, batch)?;
+			}
 		}
 
-		// Update our BitmapAccumulator based on affected outputs (both spent and created).
-		self.apply_to_bitmap_accumulator(&affected_pos)?;
-
-		// Update the head of the extension to reflect the block we just applied.
-		self.head = Tip::from_header(&b.header);
+		Ok(affected_pos)
+	}
 
-		Ok(())
+	/// Apply a new block to the current txhashset extension after first running the
+	/// provided validator against a view of the current (pre-apply) UTXO set.
+	/// The validator can veto the block by returning an error, in which case the
+	/// block is not applied and the error is returned (the extension is then discarded).
+	pub fn apply_block_with_validator<F>(
+		&mut self,
+		b: &Block,
+		header_ext: &HeaderExtension<'_>,
+		batch: &Batch<'_>,
+		validator: F,
+	) -> Result<(), Error>
+	where
+		F: FnOnce(&UTXOView<'_>, &Block) -> Result<(), Error>,
+	{
+		{
+			let utxo = UTXOView::new(
+				header_ext.pmmr.readonly_pmmr(),
+				self.output_pmmr.readonly_pmmr(),
+				self.token_output_pmmr.readonly_pmmr(),
+				self.token_issue_proof_pmmr.readonly_pmmr(),
+				self.rproof_pmmr.readonly_pmmr(),
+				self.token_rproof_pmmr.readonly_pmmr(),
+			);
+			validator(&utxo, b)?;
+		}
+		self.apply_block(b, batch)
 	}
 
 	fn apply_to_bitmap_accumulator(&mut self, output_pos: &[u64]) -> Result<(), Error> {
@@ -1451,6 +3698,7 @@ impl<'a> Extension<'a> {
 					self.rproof_pmmr
 						.prune(pos)
 						.map_err(ErrorKind::TxHashSetErr)?;
+					self.utxo_count = self.utxo_count.saturating_sub(1);
 					Ok(CommitPos { pos, height })
 				}
 				Ok(false) => Err(ErrorKind::AlreadySpent(commit).into()),
@@ -1485,6 +3733,7 @@ impl<'a> Extension<'a> {
 					self.token_rproof_pmmr
 						.prune(pos)
 						.map_err(ErrorKind::TxHashSetErr)?;
+					self.token_utxo_count = self.token_utxo_count.saturating_sub(1);
 					Ok(CommitPos { pos, height })
 				}
 				Ok(false) => Err(ErrorKind::AlreadySpent(commit).into()),
@@ -1495,7 +3744,7 @@ impl<'a> Extension<'a> {
 		}
 	}
 
-	fn apply_output(&mut self, out: &Output, batch: &Batch<'_>) -> Result<u64, Error> {
+	fn apply_output(&mut self, out: &Output, height: u64, batch: &Batch<'_>) -> Result<u64, Error> {
 		let commit = out.commitment();
 
 		if let Ok(pos) = batch.get_output_pos(&commit) {
@@ -1521,23 +3770,25 @@ impl<'a> Extension<'a> {
 		// and we should have inserted to both in exactly the same pos.
 		{
 			if self.output_pmmr.unpruned_size() != self.rproof_pmmr.unpruned_size() {
-				return Err(
-					ErrorKind::Other("output vs rproof MMRs different sizes".to_string()).into(),
-				);
+				return Err(ErrorKind::OutputRproofSizeMismatch.into());
 			}
 
 			if output_pos != rproof_pos {
-				return Err(
-					ErrorKind::Other("output vs rproof MMRs different pos".to_string()).into(),
-				);
+				return Err(ErrorKind::OutputRproofPosMismatch.into());
 			}
 		}
+
+		// Record what was created at this pos so rewind can find it without the block.
+		batch.save_output_created(output_pos, &commit, height)?;
+
+		self.utxo_count += 1;
 		Ok(output_pos)
 	}
 
 	fn apply_token_output(
 		&mut self,
 		token_out: &TokenOutput,
+		height: u64,
 		batch: &Batch<'_>,
 	) -> Result<u64, Error> {
 		let commit = token_out.commitment();
@@ -1565,20 +3816,19 @@ impl<'a> Extension<'a> {
 		// and we should have inserted to both in exactly the same pos.
 		{
 			if self.token_output_pmmr.unpruned_size() != self.token_rproof_pmmr.unpruned_size() {
-				return Err(ErrorKind::Other(format!(
-					"token_output vs token_rproof MMRs different sizes"
-				))
-				.into());
+				return Err(ErrorKind::TokenOutputRproofSizeMismatch.into());
 			}
 
 			if output_pos != rproof_pos {
-				return Err(ErrorKind::Other(format!(
-					"token_output vs token_rproof MMRs different pos"
-				))
-				.into());
+				return Err(ErrorKind::TokenOutputRproofPosMismatch.into());
 			}
 		}
 
+		// Record what was created at this pos so rewind can find it without the block.
+		batch.save_token_output_created(output_pos, &commit, height)?;
+
+		self.token_utxo_count += 1;
+
 		Ok(output_pos)
 	}
 
@@ -1588,7 +3838,7 @@ impl<'a> Extension<'a> {
 		batch: &Batch<'_>,
 	) -> Result<u64, Error> {
 		if token_out.is_token() {
-			return Err(ErrorKind::Other(format!("token_output is not a token issue")).into());
+			return Err(ErrorKind::NotTokenIssue.into());
 		}
 
 		let token_key = token_out.token_type();
@@ -1609,19 +3859,31 @@ impl<'a> Extension<'a> {
 		Ok(issue_pos)
 	}
 
-	/// Push kernel onto MMR (hash and data files).
-	fn apply_kernel(&mut self, kernel: &TxKernel) -> Result<(), Error> {
+	/// Push kernel onto MMR (hash and data files) and index its block height.
+	fn apply_kernel(
+		&mut self,
+		kernel: &TxKernel,
+		height: u64,
+		batch: &Batch<'_>,
+	) -> Result<(), Error> {
 		self.kernel_pmmr
 			.push(kernel)
 			.map_err(&ErrorKind::TxHashSetErr)?;
+		batch.save_kernel_height(&kernel.excess, height)?;
 		Ok(())
 	}
 
-	/// Push kernel onto MMR (hash and data files).
-	fn apply_token_kernel(&mut self, token_kernel: &TokenTxKernel) -> Result<(), Error> {
+	/// Push kernel onto MMR (hash and data files) and index its block height.
+	fn apply_token_kernel(
+		&mut self,
+		token_kernel: &TokenTxKernel,
+		height: u64,
+		batch: &Batch<'_>,
+	) -> Result<(), Error> {
 		self.token_kernel_pmmr
 			.push(token_kernel)
 			.map_err(&ErrorKind::TxHashSetErr)?;
+		batch.save_token_kernel_height(&token_kernel.excess, height)?;
 		Ok(())
 	}
 
@@ -1676,16 +3938,22 @@ impl<'a> Extension<'a> {
 		let header = batch.get_block_header(&self.head.last_block_h)?;
 		self.output_pmmr
 			.snapshot(&header)
-			.map_err(ErrorKind::Other)?;
+			.map_err(ErrorKind::Snapshot)?;
 		self.rproof_pmmr
 			.snapshot(&header)
-			.map_err(|e| ErrorKind::Other(e))?;
+			.map_err(ErrorKind::Snapshot)?;
 		self.token_output_pmmr
 			.snapshot(&header)
-			.map_err(|e| ErrorKind::Other(e))?;
+			.map_err(ErrorKind::Snapshot)?;
 		self.token_rproof_pmmr
 			.snapshot(&header)
-			.map_err(ErrorKind::Other)?;
+			.map_err(ErrorKind::Snapshot)?;
+		// Note: the token issue proof MMR is non-prunable (issue proofs are never spent)
+		// so, like the kernel MMR, it is rewound by size alone and no "rewound" leaf file
+		// is written or required in the txhashset zip (see file_list).
+		self.token_issue_proof_pmmr
+			.snapshot(&header)
+			.map_err(ErrorKind::Snapshot)?;
 		Ok(())
 	}
 
@@ -1708,6 +3976,14 @@ impl<'a> Extension<'a> {
 		// Rewound input (spent) pos will be added back to the MMR.
 		let head_header = batch.get_block_header(&self.head.hash())?;
 
+		// Protect against pathological rewinds (a bug or a malicious peer).
+		if let Some(max_rewind_depth) = self.max_rewind_depth {
+			let depth = head_header.height.saturating_sub(header.height);
+			if depth > max_rewind_depth {
+				return Err(ErrorKind::RewindTooDeep(depth, max_rewind_depth).into());
+			}
+		}
+
 		if head_header.height <= header.height {
 			// Nothing to rewind but we do want to truncate the MMRs at header for consistency.
 			self.rewind_mmrs_to_pos(
@@ -1721,6 +3997,8 @@ impl<'a> Extension<'a> {
 			)?;
 			self.apply_to_bitmap_accumulator(&[header.output_mmr_size])?;
 		} else {
+			let now = Instant::now();
+			let depth = head_header.height - header.height;
 			let mut affected_pos = vec![];
 			let mut current = head_header;
 			while header.height < current.height {
@@ -1730,22 +4008,61 @@ impl<'a> Extension<'a> {
 			}
 			// Now apply a single aggregate "affected_pos" to our bitmap accumulator.
 			self.apply_to_bitmap_accumulator(&affected_pos)?;
+			debug!(
+				"rewind: rewound {} blocks to {} at {}, took {}ms",
+				depth,
+				header.hash(),
+				header.height,
+				now.elapsed().as_millis(),
+			);
 		}
 
+		// Rewind may both remove outputs and restore previously spent outputs
+		// so simply reset our cached utxo counts from the rewound MMRs.
+		self.utxo_count = self.output_pmmr.n_unpruned_leaves();
+		self.token_utxo_count = self.token_output_pmmr.n_unpruned_leaves();
+
 		// Update our head to reflect the header we rewound to.
 		self.head = Tip::from_header(header);
 
 		Ok(())
 	}
 
+	/// Rewind the extension to the block at the specified height on the chain of the
+	/// current extension head (resolved by walking back from the head).
+	/// Height 0 rewinds to genesis. Errors if height is beyond the head.
+	pub fn rewind_to_height(&mut self, height: u64, batch: &Batch<'_>) -> Result<(), Error> {
+		if height > self.head.height {
+			return Err(ErrorKind::RewindBeyondHead(height, self.head.height).into());
+		}
+		let mut header = batch.get_block_header(&self.head.hash())?;
+		while header.height > height {
+			header = batch.get_previous_header(&header)?;
+		}
+		self.rewind(&header, batch)
+	}
+
 	// Rewind the MMRs and the output_pos index.
 	// Returns a vec of "affected_pos" so we can apply the necessary updates to the bitmap
 	// accumulator in a single pass for all rewound blocks.
+	//
+	// The full block is not read in the common case. The outputs created by the block are
+	// found via the output_created index (keyed by MMR pos) and the spent outputs are
+	// read back from the output MMR once unspent. The block is only read as a fallback
+	// for blocks applied before the output_created index existed.
+	// This saves one block read (and deserialization) per rewound block, see
+	// test_rewind_without_block_bodies. To measure the speedup on a deep reorg run
+	// bench_rewind_deep_reorg (chain/tests/store_indices.rs), which times rewinding
+	// the same blocks with and without the output_created index:
+	//   cargo test --release -p grin_chain --test store_indices -- --ignored --nocapture
 	fn rewind_single_block(
 		&mut self,
 		header: &BlockHeader,
 		batch: &Batch<'_>,
 	) -> Result<Vec<u64>, Error> {
+		// Only read (at most once) if one of the indexes below is missing.
+		let mut block: Option<Block> = None;
+
 		// The spent index allows us to conveniently "unspend" everything in a block.
 		let spent = batch.get_spent_index(&header.hash());
 		let token_spent = batch.get_token_spent_index(&header.hash());
@@ -1762,8 +4079,27 @@ impl<'a> Extension<'a> {
 			bitmap.iter().map(|x| x.into()).collect()
 		};
 
+		let prev = if header.height == 0 {
+			None
+		} else {
+			Some(batch.get_previous_header(&header)?)
+		};
+
+		// No token spent index is saved for blocks without token inputs.
+		// A block without token kernels cannot have token inputs (the token kernel sums
+		// are verified per token type) so we know this from the headers alone.
+		// Otherwise we check the block itself and fallback to the legacy token input bitmap
+		// if the block has token inputs, failing if this is missing (as for outputs above).
+		let no_token_kernels = prev.as_ref().map(|x| x.token_kernel_mmr_size).unwrap_or(0)
+			== header.token_kernel_mmr_size;
 		let token_spent_pos: Vec<_> = if let Ok(ref token_spent) = token_spent {
 			token_spent.iter().map(|x| x.pos).collect()
+		} else if no_token_kernels
+			|| read_block_once(&mut block, &header.hash(), batch)?
+				.token_inputs()
+				.is_empty()
+		{
+			vec![]
 		} else {
 			warn!(
 				"rewind_single_block: fallback to legacy token input bitmap for block {} at {}",
@@ -1774,10 +4110,33 @@ impl<'a> Extension<'a> {
 			bitmap.iter().map(|x| x.into()).collect()
 		};
 
-		if header.height == 0 {
-			self.rewind_mmrs_to_pos(0, 0, 0, 0, 0, &spent_pos, &token_spent_pos)?;
-		} else {
-			let prev = batch.get_previous_header(&header)?;
+		// Remove the kernel height index entries for the kernels in the block being rewound.
+		// Only entries pointing at this block, a duplicate excess may be indexed at a later
+		// block (already rewound) or an earlier block (see init_kernel_height_index).
+		let (prev_kernel_pos, prev_token_kernel_pos) = prev
+			.as_ref()
+			.map(|x| (x.kernel_mmr_size, x.token_kernel_mmr_size))
+			.unwrap_or((0, 0));
+		for pos in (prev_kernel_pos + 1)..=header.kernel_mmr_size {
+			if let Some(kernel) = self.kernel_pmmr.get_data(pos) {
+				if let Ok(Some(height)) = batch.get_kernel_height(&kernel.excess) {
+					if height == header.height {
+						batch.delete_kernel_height(&kernel.excess)?;
+					}
+				}
+			}
+		}
+		for pos in (prev_token_kernel_pos + 1)..=header.token_kernel_mmr_size {
+			if let Some(kernel) = self.token_kernel_pmmr.get_data(pos) {
+				if let Ok(Some(height)) = batch.get_token_kernel_height(&kernel.excess) {
+					if height == header.height {
+						batch.delete_token_kernel_height(&kernel.excess)?;
+					}
+				}
+			}
+		}
+
+		let (prev_output_pos, prev_token_output_pos) = if let Some(prev) = prev {
 			self.rewind_mmrs_to_pos(
 				prev.output_mmr_size,
 				prev.kernel_mmr_size,
@@ -1787,7 +4146,11 @@ impl<'a> Extension<'a> {
 				&spent_pos,
 				&token_spent_pos,
 			)?;
-		}
+			(prev.output_mmr_size, prev.token_output_mmr_size)
+		} else {
+			self.rewind_mmrs_to_pos(0, 0, 0, 0, 0, &spent_pos, &token_spent_pos)?;
+			(0, 0)
+		};
 
 		// Update our BitmapAccumulator based on affected outputs.
 		// We want to "unspend" every rewound spent output.
@@ -1796,34 +4159,59 @@ impl<'a> Extension<'a> {
 		affected_pos.push(self.output_pmmr.last_pos);
 
 		// Remove any entries from the output_pos created by the block being rewound.
-		let block = batch.get_block(&header.hash())?;
+		let created = take_created_commits(
+			prev_output_pos,
+			header.output_mmr_size,
+			|pos| batch.get_output_created(pos),
+			|pos| batch.delete_output_created(pos),
+		)?;
+		let created = match created {
+			Some(created) => created,
+			None => read_block_once(&mut block, &header.hash(), batch)?
+				.outputs()
+				.iter()
+				.map(|x| x.commitment())
+				.collect(),
+		};
 		let mut missing_count = 0;
-		for out in block.outputs() {
-			if batch.delete_output_pos_height(&out.commitment()).is_err() {
+		for commit in created {
+			if batch.delete_output_pos_height(&commit).is_err() {
 				missing_count += 1;
 			}
 		}
 		if missing_count > 0 {
 			warn!(
-				"rewind_single_block: {} output_pos entries missing for: {} at {}",
+				"rewind_single_block (outputs): {} output_pos entries missing for: {} at {}",
 				missing_count,
 				header.hash(),
 				header.height,
 			);
 		}
+
+		let token_created = take_created_commits(
+			prev_token_output_pos,
+			header.token_output_mmr_size,
+			|pos| batch.get_token_output_created(pos),
+			|pos| batch.delete_token_output_created(pos),
+		)?;
+		let token_created = match token_created {
+			Some(token_created) => token_created,
+			None => read_block_once(&mut block, &header.hash(), batch)?
+				.token_outputs()
+				.iter()
+				.map(|x| x.commitment())
+				.collect(),
+		};
 		let mut token_missing_count = 0;
-		for token_out in block.token_outputs() {
-			if batch
-				.delete_token_output_pos_height(&token_out.commitment())
-				.is_err()
-			{
+		for commit in token_created {
+			if batch.delete_token_output_pos_height(&commit).is_err() {
 				token_missing_count += 1;
 			}
 		}
 		if token_missing_count > 0 {
 			warn!(
-				"rewind_single_block: {} token_output_pos entries missing for: {} at {}",
-				missing_count,
+				"rewind_single_block (token outputs): {} token_output_pos entries missing for: {} at {}",
+				token_missing_count,
 				header.hash(),
 				header.height,
 			);
@@ -1833,14 +4221,43 @@ impl<'a> Extension<'a> {
 		// This is necessary to ensure the output_pos index correclty reflects a
 		// reused output commitment. For example an output at pos 1, spent, reused at pos 2.
 		// The output_pos index should be updated to reflect the old pos 1 when unspent.
+		// The spent outputs are unspent in the MMR by now so we read the commitments from there.
 		if let Ok(spent) = spent {
-			for (x, y) in block.inputs().into_iter().zip(spent) {
-				batch.save_output_pos_height(&x.commitment(), y.pos, y.height)?;
+			let commits: Option<Vec<_>> = spent
+				.iter()
+				.map(|x| self.output_pmmr.get_data(x.pos).map(|out| out.commitment()))
+				.collect();
+			let commits = match commits {
+				Some(commits) => commits,
+				None => read_block_once(&mut block, &header.hash(), batch)?
+					.inputs()
+					.iter()
+					.map(|x| x.commitment())
+					.collect(),
+			};
+			for (commit, y) in commits.iter().zip(spent) {
+				batch.save_output_pos_height(commit, y.pos, y.height)?;
 			}
 		}
 		if let Ok(token_spent) = token_spent {
-			for (x, y) in block.token_inputs().into_iter().zip(token_spent) {
-				batch.save_token_output_pos_height(&x.commitment(), y.pos, y.height)?;
+			let commits: Option<Vec<_>> = token_spent
+				.iter()
+				.map(|x| {
+					self.token_output_pmmr
+						.get_data(x.pos)
+						.map(|out| out.commitment())
+				})
+				.collect();
+			let commits = match commits {
+				Some(commits) => commits,
+				None => read_block_once(&mut block, &header.hash(), batch)?
+					.token_inputs()
+					.iter()
+					.map(|x| x.commitment())
+					.collect(),
+			};
+			for (commit, y) in commits.iter().zip(token_spent) {
+				batch.save_token_output_pos_height(commit, y.pos, y.height)?;
 			}
 		}
 
@@ -1870,6 +4287,7 @@ impl<'a> Extension<'a> {
 		self.kernel_pmmr
 			.rewind(kernel_pos, &Bitmap::create())
 			.map_err(&ErrorKind::TxHashSetErr)?;
+		self.kernel_rewind_pos = self.kernel_rewind_pos.min(kernel_pos);
 		self.token_output_pmmr
 			.rewind(token_output_pos, &token_bitmap)
 			.map_err(&ErrorKind::TxHashSetErr)?;
@@ -1889,13 +4307,28 @@ impl<'a> Extension<'a> {
 	/// Current root hashes and sums (if applicable) for the Output, range proof
 	/// and kernel MMRs.
 	pub fn roots(&self) -> Result<TxHashSetRoots, Error> {
+		self.roots_inner(true)
+	}
+
+	/// Current roots as above, skipping the bitmap accumulator root.
+	/// See TxHashSet::roots_without_bitmap().
+	pub fn roots_without_bitmap(&self) -> Result<TxHashSetRoots, Error> {
+		self.roots_inner(false)
+	}
+
+	fn roots_inner(&self, include_bitmap_root: bool) -> Result<TxHashSetRoots, Error> {
+		let bitmap_root = if include_bitmap_root {
+			self.bitmap_accumulator.root()
+		} else {
+			ZERO_HASH
+		};
 		Ok(TxHashSetRoots {
 			output_roots: OutputRoots {
 				pmmr_root: self
 					.output_pmmr
 					.root()
 					.map_err(|_| ErrorKind::InvalidRoot)?,
-				bitmap_root: self.bitmap_accumulator.root(),
+				bitmap_root,
 			},
 			rproof_root: self
 				.rproof_pmmr
@@ -1932,52 +4365,246 @@ impl<'a> Extension<'a> {
 		self.roots()?.validate(header)
 	}
 
-	/// Validate the header, output and kernel MMR sizes against the block header.
-	pub fn validate_sizes(&self, header: &BlockHeader) -> Result<(), Error> {
+	/// Compute the individual terms used when validating the kernel sums against the
+	/// header (see validate_kernel_sums), without checking they balance.
+	pub fn kernel_sum_components(
+		&self,
+		genesis: &BlockHeader,
+		header: &BlockHeader,
+	) -> Result<KernelSumComponents, Error> {
+		let overage = header.total_overage(genesis.kernel_mmr_size > 0);
+		let total_kernel_offset = header.total_kernel_offset();
+		let utxo_sum = self.sum_commitments(overage)?;
+		let (kernel_sum, kernel_sum_plus_offset) =
+			self.sum_kernel_excesses(&total_kernel_offset)?;
+		Ok(KernelSumComponents {
+			utxo_sum,
+			kernel_sum,
+			kernel_sum_plus_offset,
+			overage,
+			total_kernel_offset,
+		})
+	}
+
+	/// Validate just the token issue proof MMR root against the header,
+	/// without computing any of the other roots.
+	pub fn validate_token_issue_proof_root(&self, header: &BlockHeader) -> Result<(), Error> {
 		if header.height == 0 {
 			return Ok(());
 		}
-		if (
-			header.output_mmr_size,
-			header.output_mmr_size,
-			header.kernel_mmr_size,
-			header.token_output_mmr_size,
-			header.token_output_mmr_size,
-			header.token_issue_proof_mmr_size,
-			header.token_kernel_mmr_size,
-		) != self.sizes()
-		{
-			Err(ErrorKind::InvalidMMRSize.into())
+		let root = self
+			.token_issue_proof_pmmr
+			.root()
+			.map_err(|_| ErrorKind::InvalidRoot)?;
+		if root != header.token_issue_proof_root {
+			Err(ErrorKind::InvalidRoot.into())
 		} else {
 			Ok(())
 		}
 	}
 
-	fn validate_mmrs(&self) -> Result<(), Error> {
-		let now = Instant::now();
+	/// Repair the rangeproof MMR so it is once again consistent with the output MMR,
+	/// i.e. after a partial write left the two diverging on disk.
+	/// The rangeproof MMR is rewound to the first output MMR leaf pos where it diverges
+	/// (missing, corrupt or not spent in lockstep with the output MMR) and rebuilt from
+	/// there using the rangeproofs of the outputs in the corresponding full blocks.
+	/// Errors if any of these blocks are not available (pruned beyond the horizon).
+	/// The rangeproof and output MMRs are the same size on success.
+	pub fn repair_rproof_mmr(&mut self, batch: &Batch<'_>) -> Result<(), Error> {
+		let output_size = self.output_pmmr.unpruned_size();
+		let rproof_size = self.rproof_pmmr.unpruned_size();
+
+		// Find the first leaf pos where the rangeproof MMR diverges from the output MMR.
+		let first_bad_pos = (1..=output_size)
+			.filter(|x| pmmr::is_leaf(*x))
+			.find(|&pos| {
+				if pos > rproof_size {
+					return true;
+				}
+				let unspent = self.output_pmmr.get_data(pos).is_some();
+				match self.rproof_pmmr.get_data(pos) {
+					Some(proof) => {
+						!unspent
+							|| self.rproof_pmmr.get_hash(pos)
+								!= Some(proof.hash_with_index(pos - 1))
+					}
+					None => unspent,
+				}
+			});
 
-		// validate all hashes and sums within the trees
-		if let Err(e) = self.output_pmmr.validate() {
-			return Err(ErrorKind::InvalidTxHashSet(e).into());
+		let first_bad_pos = match first_bad_pos {
+			Some(pos) => pos,
+			None => {
+				if rproof_size > output_size {
+					self.rproof_pmmr
+						.rewind(output_size, &Bitmap::create())
+						.map_err(&ErrorKind::TxHashSetErr)?;
+					debug!(
+						"repair_rproof_mmr: rewound rproof mmr from {} to {}",
+						rproof_size, output_size
+					);
+				}
+				return Ok(());
+			}
+		};
+
+		// Collect the headers of the blocks that created the outputs from first_bad_pos.
+		let mut headers = vec![];
+		let mut header = batch.get_block_header(&self.head.last_block_h)?;
+		while header.output_mmr_size >= first_bad_pos {
+			headers.push(header.clone());
+			if header.height == 0 {
+				break;
+			}
+			header = batch.get_previous_header(&header)?;
+		}
+		let mut prev_size = if header.output_mmr_size < first_bad_pos {
+			header.output_mmr_size
+		} else {
+			0
+		};
+
+		self.rproof_pmmr
+			.rewind(first_bad_pos - 1, &Bitmap::create())
+			.map_err(&ErrorKind::TxHashSetErr)?;
+
+		let mut repaired = 0;
+		for header in headers.iter().rev() {
+			let block = batch.get_block(&header.hash())?;
+			let first_idx = pmmr::n_leaves(prev_size);
+			for (i, out) in block.outputs().iter().enumerate() {
+				let pos = pmmr::insertion_to_pmmr_index(first_idx + i as u64 + 1);
+				if pos < first_bad_pos {
+					continue;
+				}
+				let unspent = match self.output_pmmr.get_data(pos) {
+					Some(out_mmr) => {
+						if out_mmr.commitment() != out.commitment() {
+							return Err(
+								ErrorKind::OutputMismatch(pos, header.hash().to_hex()).into()
+							);
+						}
+						true
+					}
+					None => false,
+				};
+				let rproof_pos = self
+					.rproof_pmmr
+					.push(&out.proof)
+					.map_err(&ErrorKind::TxHashSetErr)?;
+				if rproof_pos != pos {
+					return Err(ErrorKind::MMRParityMismatch(format!(
+						"repair_rproof_mmr: rproof pushed at {}, expected {}",
+						rproof_pos, pos
+					))
+					.into());
+				}
+				if !unspent {
+					self.rproof_pmmr
+						.prune(pos)
+						.map_err(&ErrorKind::TxHashSetErr)?;
+				}
+				repaired += 1;
+			}
+			prev_size = header.output_mmr_size;
+		}
+
+		if self.rproof_pmmr.unpruned_size() != output_size {
+			return Err(ErrorKind::MMRParityMismatch(format!(
+				"repair_rproof_mmr: output mmr size {} vs rangeproof mmr size {}",
+				output_size,
+				self.rproof_pmmr.unpruned_size()
+			))
+			.into());
 		}
-		if let Err(e) = self.rproof_pmmr.validate() {
-			return Err(ErrorKind::InvalidTxHashSet(e).into());
+
+		debug!(
+			"repair_rproof_mmr: rebuilt {} rangeproofs from pos {}",
+			repaired, first_bad_pos
+		);
+		Ok(())
+	}
+
+	/// Validate the header, output and kernel MMR sizes against the block header.
+	pub fn validate_sizes(&self, header: &BlockHeader) -> Result<(), Error> {
+		if header.height == 0 {
+			return Ok(());
 		}
-		if let Err(e) = self.kernel_pmmr.validate() {
-			return Err(ErrorKind::InvalidTxHashSet(e).into());
+		let sizes = self.sizes();
+
+		// Headers do not commit to the rangeproof MMR sizes so check these explicitly
+		// against the corresponding output MMR sizes, the MMRs must stay in lockstep.
+		if sizes.rproof != sizes.output {
+			error!(
+				"validate_sizes: rproof mmr size {} does not match output mmr size {}",
+				sizes.rproof, sizes.output
+			);
+			return Err(ErrorKind::InvalidMMRSize.into());
 		}
-		if let Err(e) = self.token_output_pmmr.validate() {
-			return Err(ErrorKind::InvalidTxHashSet(e).into());
+		if sizes.token_rproof != sizes.token_output {
+			error!(
+				"validate_sizes: token rproof mmr size {} does not match token output mmr size {}",
+				sizes.token_rproof, sizes.token_output
+			);
+			return Err(ErrorKind::InvalidMMRSize.into());
 		}
-		if let Err(e) = self.token_rproof_pmmr.validate() {
-			return Err(ErrorKind::InvalidTxHashSet(e).into());
+
+		// With the rangeproof MMRs in lockstep with the output MMRs every field
+		// can be compared against the sizes committed to in the header.
+		if sizes != MmrSizes::from_header(header) {
+			Err(ErrorKind::InvalidMMRSize.into())
+		} else {
+			Ok(())
 		}
-		if let Err(e) = self.token_issue_proof_pmmr.validate() {
-			return Err(ErrorKind::InvalidTxHashSet(e).into());
+	}
+
+	/// Validate each token type appears at most once in the token issue proof MMR.
+	/// A duplicate indicates a token was issued twice (see apply_token_issue_output).
+	/// Reports every duplicated token type along with both MMR positions.
+	pub fn validate_issue_proof_uniqueness(&self) -> Result<(), Error> {
+		let mut seen: HashMap<TokenKey, u64> = HashMap::new();
+		let mut duplicates = vec![];
+		let size = self.token_issue_proof_pmmr.unpruned_size();
+		for pos in (1..=size).filter(|x| pmmr::is_leaf(*x)) {
+			if let Some(issue_proof) = self.token_issue_proof_pmmr.get_data(pos) {
+				let token_key = issue_proof.token_type();
+				if let Some(first_pos) = seen.insert(token_key, pos) {
+					duplicates.push((token_key, first_pos, pos));
+				}
+			}
 		}
-		if let Err(e) = self.token_kernel_pmmr.validate() {
-			return Err(ErrorKind::InvalidTxHashSet(e).into());
+		if !duplicates.is_empty() {
+			error!(
+				"validate_issue_proof_uniqueness: duplicate token issue proofs: {:?}",
+				duplicates
+			);
+			return Err(ErrorKind::DuplicateIssueProof(duplicates).into());
 		}
+		Ok(())
+	}
+
+	fn validate_mmrs(&self) -> Result<(), Error> {
+		let now = Instant::now();
+
+		// validate all hashes and sums within the trees
+		// these are independent so may be validated concurrently (see validate_all)
+		let output_pmmr = &self.output_pmmr;
+		let rproof_pmmr = &self.rproof_pmmr;
+		let kernel_pmmr = &self.kernel_pmmr;
+		let token_output_pmmr = &self.token_output_pmmr;
+		let token_rproof_pmmr = &self.token_rproof_pmmr;
+		let token_issue_proof_pmmr = &self.token_issue_proof_pmmr;
+		let token_kernel_pmmr = &self.token_kernel_pmmr;
+		let validations: [&MMRValidation<'_>; 7] = [
+			&|| output_pmmr.validate(),
+			&|| rproof_pmmr.validate(),
+			&|| kernel_pmmr.validate(),
+			&|| token_output_pmmr.validate(),
+			&|| token_rproof_pmmr.validate(),
+			&|| token_issue_proof_pmmr.validate(),
+			&|| token_kernel_pmmr.validate(),
+		];
+		validate_all(&validations).map_err(ErrorKind::InvalidTxHashSet)?;
 
 		debug!(
 			"txhashset: validated the output {}, rproof {}, kernel {}, token_output {}, token_rproof {}, token_issue_prrof {}, token_kernel {}  mmrs, took {}s",
@@ -1990,6 +4617,14 @@ impl<'a> Extension<'a> {
 			self.token_kernel_pmmr.unpruned_size(),
 			now.elapsed().as_secs(),
 		);
+		debug!(
+			"txhashset: validated {} outputs, {} kernels, {} token outputs, {} token issue proofs, {} token kernels",
+			self.output_pmmr.n_unpruned_leaves(),
+			pmmr::n_leaves(self.kernel_pmmr.unpruned_size()),
+			self.token_output_pmmr.n_unpruned_leaves(),
+			pmmr::n_leaves(self.token_issue_proof_pmmr.unpruned_size()),
+			pmmr::n_leaves(self.token_kernel_pmmr.unpruned_size()),
+		);
 
 		Ok(())
 	}
@@ -2018,6 +4653,70 @@ impl<'a> Extension<'a> {
 		Ok((utxo_sum, kernel_sum))
 	}
 
+	/// Validate kernel sums incrementally, starting from the block sums of a prior
+	/// checkpoint (prev_header) rather than summing the full UTXO set.
+	/// Only the outputs created, inputs spent (taken from the blocks) and kernels added
+	/// (taken from the kernel MMR) between prev_header and header are accounted for.
+	/// The extension is expected to be at header.
+	/// Returns the (utxo_sum, kernel_sum) at header, to be used as the next checkpoint.
+	pub fn validate_kernel_sums_incremental(
+		&self,
+		prev_sums: &BlockSums,
+		prev_header: &BlockHeader,
+		header: &BlockHeader,
+		batch: &Batch<'_>,
+	) -> Result<(Commitment, Commitment), Error> {
+		let now = Instant::now();
+
+		if prev_header.height > header.height
+			|| header.kernel_mmr_size > self.kernel_pmmr.unpruned_size()
+		{
+			return Err(ErrorKind::InvalidMMRSize.into());
+		}
+
+		// Walk back from header to prev_header collecting the block inputs and outputs.
+		// Outputs created and spent within the range are in both and cancel out.
+		let mut delta = CommittedDelta::default();
+		let mut overage: i64 = 0;
+		let mut current = header.clone();
+		while current.height > prev_header.height {
+			let block = batch.get_block(&current.hash())?;
+			delta.inputs.extend(block.inputs_committed());
+			delta.outputs.extend(block.outputs_committed());
+			overage += current.overage();
+			current = batch.get_previous_header(&current)?;
+		}
+		if current.hash() != prev_header.hash() {
+			return Err(ErrorKind::NotAncestor(
+				prev_header.hash().to_string(),
+				header.hash().to_string(),
+			)
+			.into());
+		}
+
+		for pos in
+			(prev_header.kernel_mmr_size + 1..=header.kernel_mmr_size).filter(|x| pmmr::is_leaf(*x))
+		{
+			let kernel = self
+				.kernel_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::TxKernelNotFound)?;
+			delta.kernels.push(kernel.excess());
+		}
+
+		let (utxo_sum, kernel_sum) = (prev_sums.clone(), &delta as &dyn Committed)
+			.verify_kernel_sums(overage, header.total_kernel_offset())?;
+
+		debug!(
+			"txhashset: validated incremental kernel sums from {} to {}, took {}s",
+			prev_header.height,
+			header.height,
+			now.elapsed().as_secs(),
+		);
+
+		Ok((utxo_sum, kernel_sum))
+	}
+
 	/// Validate full token kernel sums against the provided header.
 	pub fn validate_token_kernel_sums(&self) -> Result<BlockTokenSums, Error> {
 		let now = Instant::now();
@@ -2032,14 +4731,88 @@ impl<'a> Extension<'a> {
 		Ok(token_kernel_sum_map)
 	}
 
+	/// Validate token kernel sums against the provided header, reusing the
+	/// block_token_sums persisted in the db for its parent (keyed by block hash,
+	/// see ChainStore::get_block_token_sums) and applying only this block on top.
+	/// Falls back to the full validate_token_kernel_sums() if either the parent
+	/// block_token_sums or the block itself are not available.
+	/// The token UTXO set itself is never read so this is only valid where the extension
+	/// state was just produced by applying this same block on top of its parent.
+	/// Never use this to validate a txhashset we did not build ourselves (i.e. one received
+	/// from a peer), use the full validate_token_kernel_sums() instead.
+	pub fn validate_token_kernel_sums_cached(
+		&self,
+		header: &BlockHeader,
+		batch: &Batch<'_>,
+	) -> Result<BlockTokenSums, Error> {
+		if header.height == 0 {
+			return self.validate_token_kernel_sums();
+		}
+
+		let prev_sums = batch.get_block_token_sums(&header.prev_hash);
+		let block = batch.get_block(&header.hash());
+		match (prev_sums, block) {
+			(Ok(prev_sums), Ok(block)) => {
+				let now = Instant::now();
+
+				let block_token_sums =
+					(prev_sums, &block as &dyn Committed).verify_token_kernel_sum()?;
+
+				debug!(
+					"txhashset: validated token kernel sums from cached parent sums at {}, took {}s",
+					header.height,
+					now.elapsed().as_secs(),
+				);
+
+				Ok(block_token_sums)
+			}
+			_ => self.validate_token_kernel_sums(),
+		}
+	}
+
 	/// Validate the txhashset state against the provided block header.
 	/// A "fast validation" will skip rangeproof verification and kernel signature verification.
+	/// Setting "skip_token_verification" skips the token rangeproof and token kernel signature
+	/// verification independently of "fast validation". Token roots, sizes and token kernel sums
+	/// are still validated but the integrity of the token proofs and signatures is *not* asserted.
+	/// Rangeproofs and kernel signatures are verified in batches sized by the provided config.
+	/// Token kernel sums are always validated in full against the token UTXO set.
 	pub fn validate(
 		&self,
 		genesis: &BlockHeader,
 		fast_validation: bool,
+		skip_token_verification: bool,
+		config: &ValidationConfig,
+		status: &dyn TxHashsetWriteStatus,
+		header: &BlockHeader,
+	) -> Result<(Commitment, Commitment, BlockTokenSums), Error> {
+		self.validate_with_progress(
+			genesis,
+			fast_validation,
+			skip_token_verification,
+			config,
+			status,
+			header,
+			None,
+		)
+	}
+
+	/// Validate the txhashset state against the provided block header, as validate() does,
+	/// persisting rangeproof and kernel signature verification progress to the file at
+	/// progress_path (see verification_progress_path()).
+	/// Any existing progress at progress_path recorded against this header is loaded and
+	/// verification resumes from it. Progress recorded against a different header
+	/// (or different MMR sizes) is ignored. The progress file is removed once
+	/// validation completes successfully.
+	pub fn validate_with_progress(
+		&self,
+		genesis: &BlockHeader,
+		fast_validation: bool,
+		skip_token_verification: bool,
+		config: &ValidationConfig,
 		status: &dyn TxHashsetWriteStatus,
 		header: &BlockHeader,
+		progress_path: Option<&Path>,
 	) -> Result<(Commitment, Commitment, BlockTokenSums), Error> {
 		self.validate_mmrs()?;
 		self.validate_roots(header)?;
@@ -2050,76 +4823,498 @@ impl<'a> Extension<'a> {
 			return Ok((zero_commit, zero_commit, BlockTokenSums::default()));
 		}
 
-		// The real magicking happens here. Sum of kernel excesses should equal
-		// sum of unspent outputs minus total supply.
-		let (output_sum, kernel_sum) = self.validate_kernel_sums(genesis, header)?;
-		let block_token_sums = self.validate_token_kernel_sums()?;
+		// The real magicking happens here. Sum of kernel excesses should equal
+		// sum of unspent outputs minus total supply.
+		let (output_sum, kernel_sum) = self.validate_kernel_sums(genesis, header)?;
+		let block_token_sums = self.validate_token_kernel_sums()?;
+
+		// These are expensive verification step (skipped for "fast validation").
+		// The rangeproofs and kernel signatures (and token equivalents) are in disjoint MMRs
+		// so we verify these concurrently (see verify_all).
+		if !fast_validation {
+			let progress = Mutex::new(
+				progress_path
+					.and_then(|path| load_verification_progress(path, header))
+					.unwrap_or_else(|| VerificationProgress::new(header)),
+			);
+			let save = |progress: &VerificationProgress| match progress_path {
+				Some(path) => save_verification_progress(path, progress),
+				None => Ok(()),
+			};
+
+			// Verify the rangeproof associated with each unspent output.
+			let rproofs = || {
+				let start_pos = progress.lock().rproof_pos;
+				self.verify_rangeproofs(
+					status,
+					config.rangeproof_batch_size,
+					start_pos,
+					&mut |pos| {
+						let mut progress = progress.lock();
+						progress.rproof_pos = pos;
+						save(&progress)
+					},
+				)
+			};
+			let token_rproofs = || {
+				let start_pos = progress.lock().token_rproof_pos;
+				self.verify_token_rangeproofs(
+					status,
+					config.rangeproof_batch_size,
+					start_pos,
+					&mut |pos| {
+						let mut progress = progress.lock();
+						progress.token_rproof_pos = pos;
+						save(&progress)
+					},
+				)
+			};
+
+			// Verify all the kernel signatures.
+			let kernels = || {
+				let start_pos = progress.lock().kernel_pos;
+				self.verify_kernel_signatures(
+					status,
+					config.kernel_batch_size,
+					start_pos,
+					&mut |pos| {
+						let mut progress = progress.lock();
+						progress.kernel_pos = pos;
+						save(&progress)
+					},
+				)
+			};
+			let token_kernels = || {
+				let start_pos = progress.lock().token_kernel_pos;
+				self.verify_token_kernel_signatures(
+					status,
+					config.kernel_batch_size,
+					start_pos,
+					&mut |pos| {
+						let mut progress = progress.lock();
+						progress.token_kernel_pos = pos;
+						save(&progress)
+					},
+				)
+			};
+
+			if skip_token_verification {
+				verify_all(&[&rproofs, &kernels])?;
+			} else {
+				verify_all(&[&rproofs, &token_rproofs, &kernels, &token_kernels])?;
+			}
+
+			if let Some(path) = progress_path {
+				if path.exists() {
+					fs::remove_file(path)?;
+				}
+			}
+		}
+
+		Ok((output_sum, kernel_sum, block_token_sums))
+	}
+
+	/// Force the rollback of this extension, no matter the result
+	pub fn force_rollback(&mut self) {
+		self.rollback = true;
+	}
+
+	/// Call the provided fn with the commitment of every unspent output in turn.
+	/// Avoids materializing the full vec of commitments for large UTXO sets.
+	pub fn for_each_output_commitment<F>(&self, mut f: F)
+	where
+		F: FnMut(Commitment),
+	{
+		for pos in self.output_pmmr.leaf_pos_iter() {
+			if let Some(out) = self.output_pmmr.get_data(pos) {
+				f(out.commit);
+			}
+		}
+	}
+
+	/// Dumps the output MMR.
+	/// We use this after compacting for visual confirmation that it worked.
+	pub fn dump_output_pmmr(&self) {
+		debug!("-- outputs --");
+		self.output_pmmr.dump_from_file(false);
+		debug!("--");
+		self.output_pmmr.dump_stats();
+		debug!("-- end of outputs --");
+	}
+
+	/// Dumps the token output MMR to the log for debugging.
+	/// Reads from the underlying hash file and so ignores the remove log.
+	pub fn dump_token_outputs(&self) {
+		debug!("-- token outputs --");
+		self.token_output_pmmr.dump_from_file(false);
+		debug!("--");
+		self.token_output_pmmr.dump_stats();
+		debug!("-- end of token outputs --");
+	}
+
+	/// Dumps the token rangeproof MMR to the log for debugging.
+	/// Reads from the underlying hash file and so ignores the remove log.
+	pub fn dump_token_rproofs(&self) {
+		debug!("-- token range proofs --");
+		self.token_rproof_pmmr.dump_from_file(false);
+		debug!("--");
+		self.token_rproof_pmmr.dump_stats();
+		debug!("-- end of token range proofs --");
+	}
+
+	/// Dumps the token issue proof MMR to the log for debugging.
+	/// Reads from the underlying hash file and so ignores the remove log.
+	pub fn dump_token_issue_proofs(&self) {
+		debug!("-- token issue proofs --");
+		self.token_issue_proof_pmmr.dump_from_file(false);
+		debug!("--");
+		self.token_issue_proof_pmmr.dump_stats();
+		debug!("-- end of token issue proofs --");
+	}
+
+	/// Dumps the token kernel MMR to the log for debugging.
+	/// Reads from the underlying hash file and so ignores the remove log.
+	pub fn dump_token_kernels(&self) {
+		debug!("-- token kernels --");
+		self.token_kernel_pmmr.dump_from_file(false);
+		debug!("--");
+		self.token_kernel_pmmr.dump_stats();
+		debug!("-- end of token kernels --");
+	}
+
+	/// Dumps the state of the 3 MMRs to stdout for debugging. Short
+	/// version only prints the Output tree.
+	pub fn dump(&self, short: bool) {
+		debug!("-- outputs --");
+		self.output_pmmr.dump(short);
+		if !short {
+			debug!("-- range proofs --");
+			self.rproof_pmmr.dump(short);
+			debug!("-- kernels --");
+			self.kernel_pmmr.dump(short);
+		}
+	}
+
+	/// Writes every unspent output to the file at path, one tab-separated line per leaf:
+	/// pos, hash, features, commitment. Positions are in ascending order so dumps taken
+	/// from two nodes can be diffed directly.
+	pub fn dump_output_to_file(&self, path: &Path) -> Result<(), Error> {
+		dump_leaves_to_file(path, self.output_pmmr.leaf_pos_iter(), |pos| {
+			let hash = self.output_pmmr.get_hash(pos)?;
+			let out = self.output_pmmr.get_data(pos)?;
+			Some((
+				hash,
+				format!("{:?}\t{}", out.features, to_hex(out.commit.0.to_vec())),
+			))
+		})
+	}
+
+	/// Writes every kernel to the file at path (see dump_output_to_file):
+	/// pos, hash, features, excess.
+	pub fn dump_kernel_to_file(&self, path: &Path) -> Result<(), Error> {
+		let positions = (1..=self.kernel_pmmr.unpruned_size()).filter(|pos| pmmr::is_leaf(*pos));
+		dump_leaves_to_file(path, positions, |pos| {
+			let hash = self.kernel_pmmr.get_hash(pos)?;
+			let kernel = self.kernel_pmmr.get_data(pos)?;
+			Some((
+				hash,
+				format!(
+					"{:?}\t{}",
+					kernel.features,
+					to_hex(kernel.excess.0.to_vec())
+				),
+			))
+		})
+	}
+
+	/// Writes every unspent token output to the file at path (see dump_output_to_file):
+	/// pos, hash, features, token type, commitment.
+	pub fn dump_token_output_to_file(&self, path: &Path) -> Result<(), Error> {
+		dump_leaves_to_file(path, self.token_output_pmmr.leaf_pos_iter(), |pos| {
+			let hash = self.token_output_pmmr.get_hash(pos)?;
+			let out = self.token_output_pmmr.get_data(pos)?;
+			Some((
+				hash,
+				format!(
+					"{:?}\t{}\t{}",
+					out.features,
+					out.token_type.to_hex(),
+					to_hex(out.commit.0.to_vec())
+				),
+			))
+		})
+	}
+
+	/// Writes every token kernel to the file at path (see dump_output_to_file):
+	/// pos, hash, features, token type, excess.
+	pub fn dump_token_kernel_to_file(&self, path: &Path) -> Result<(), Error> {
+		let positions =
+			(1..=self.token_kernel_pmmr.unpruned_size()).filter(|pos| pmmr::is_leaf(*pos));
+		dump_leaves_to_file(path, positions, |pos| {
+			let hash = self.token_kernel_pmmr.get_hash(pos)?;
+			let kernel = self.token_kernel_pmmr.get_data(pos)?;
+			Some((
+				hash,
+				format!(
+					"{:?}\t{}\t{}",
+					kernel.features,
+					kernel.token_type.to_hex(),
+					to_hex(kernel.excess.0.to_vec())
+				),
+			))
+		})
+	}
+
+	/// Sizes of each of the MMRs
+	pub fn sizes(&self) -> MmrSizes {
+		MmrSizes {
+			output: self.output_pmmr.unpruned_size(),
+			rproof: self.rproof_pmmr.unpruned_size(),
+			kernel: self.kernel_pmmr.unpruned_size(),
+			token_output: self.token_output_pmmr.unpruned_size(),
+			token_rproof: self.token_rproof_pmmr.unpruned_size(),
+			token_issue_proof: self.token_issue_proof_pmmr.unpruned_size(),
+			token_kernel: self.token_kernel_pmmr.unpruned_size(),
+		}
+	}
+
+	/// Summary of the current (uncommitted) extension state, the head along with
+	/// the roots and sizes of all the MMRs. Useful for diagnostics at intermediate
+	/// points within an extension.
+	pub fn state_summary(&self) -> Result<ExtensionSummary, Error> {
+		Ok(ExtensionSummary {
+			head: self.head.clone(),
+			roots: self.roots()?,
+			sizes: self.sizes(),
+		})
+	}
+
+	/// Verify rangeproofs and kernel signatures for only those outputs and kernels added
+	/// between prev_header and header (token outputs and token kernels included).
+	/// Positions in (prev_header.output_mmr_size, header.output_mmr_size] are verified
+	/// for unspent outputs and (prev_header.kernel_mmr_size, header.kernel_mmr_size]
+	/// for kernels. Avoids a full revalidation when only a single block changed.
+	pub fn validate_block_range(
+		&self,
+		prev_header: &BlockHeader,
+		header: &BlockHeader,
+		status: &dyn TxHashsetWriteStatus,
+	) -> Result<(), Error> {
+		let now = Instant::now();
+
+		let mut commits = vec![];
+		let mut proofs = vec![];
+		for pos in self
+			.output_pmmr
+			.leaf_pos_iter()
+			.skip_while(|pos| *pos <= prev_header.output_mmr_size)
+			.take_while(|pos| *pos <= header.output_mmr_size)
+		{
+			let output = self
+				.output_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::OutputNotFound)?;
+			let proof = self
+				.rproof_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::RangeproofNotFound)?;
+			commits.push(output.commit);
+			proofs.push(proof);
+		}
+		if !proofs.is_empty() {
+			Output::batch_verify_proofs(&commits, &proofs)?;
+		}
+		status.on_validation_rproofs(proofs.len() as u64, proofs.len() as u64);
+
+		commits.clear();
+		proofs.clear();
+		for pos in self
+			.token_output_pmmr
+			.leaf_pos_iter()
+			.skip_while(|pos| *pos <= prev_header.token_output_mmr_size)
+			.take_while(|pos| *pos <= header.token_output_mmr_size)
+		{
+			let output = self
+				.token_output_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::OutputNotFound)?;
+			let proof = self
+				.token_rproof_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::RangeproofNotFound)?;
+			commits.push(output.commit);
+			proofs.push(proof);
+		}
+		if !proofs.is_empty() {
+			Output::batch_verify_proofs(&commits, &proofs)?;
+		}
+		status.on_validation_token_rproofs(proofs.len() as u64, proofs.len() as u64);
+
+		let mut kernels = vec![];
+		for pos in
+			(prev_header.kernel_mmr_size + 1..=header.kernel_mmr_size).filter(|x| pmmr::is_leaf(*x))
+		{
+			let kernel = self
+				.kernel_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::TxKernelNotFound)?;
+			kernels.push(kernel);
+		}
+		if !kernels.is_empty() {
+			TxKernel::batch_sig_verify(&kernels)?;
+		}
+		status.on_validation_kernels(kernels.len() as u64, kernels.len() as u64);
+
+		let mut token_kernels = vec![];
+		for pos in (prev_header.token_kernel_mmr_size + 1..=header.token_kernel_mmr_size)
+			.filter(|x| pmmr::is_leaf(*x))
+		{
+			let kernel = self
+				.token_kernel_pmmr
+				.get_data(pos)
+				.ok_or_else(|| ErrorKind::TxKernelNotFound)?;
+			token_kernels.push(kernel);
+		}
+		if !token_kernels.is_empty() {
+			TokenTxKernel::batch_sig_verify(&token_kernels)?;
+		}
+		status.on_validation_token_kernels(token_kernels.len() as u64, token_kernels.len() as u64);
+
+		debug!(
+			"txhashset: validate_block_range: validated {} to {}, {} kernels, {} token kernels, took {}s",
+			prev_header.height,
+			header.height,
+			kernels.len(),
+			token_kernels.len(),
+			now.elapsed().as_secs(),
+		);
 
-		// These are expensive verification step (skipped for "fast validation").
-		if !fast_validation {
-			// Verify the rangeproof associated with each unspent output.
-			self.verify_rangeproofs(status)?;
+		Ok(())
+	}
 
-			self.verify_token_rangeproofs(status)?;
+	/// Verify all rangeproofs and kernel signatures, including those in the token MMRs.
+	/// With collect_all_failures disabled this stops at the first failure (as validate() does).
+	/// With collect_all_failures enabled verification continues and every failing pos is
+	/// returned. This is intended for offline diagnosis of a corrupt txhashset only,
+	/// a single failure is still sufficient to reject the txhashset.
+	pub fn verify_proofs_and_signatures(
+		&self,
+		config: &ValidationConfig,
+		collect_all_failures: bool,
+		status: &dyn TxHashsetWriteStatus,
+	) -> Result<Vec<VerificationFailure>, Error> {
+		let rangeproof_batch_size = cmp::max(config.rangeproof_batch_size, 1);
+		let kernel_batch_size = cmp::max(config.kernel_batch_size, 1);
+		if !collect_all_failures {
+			self.verify_rangeproofs(status, config.rangeproof_batch_size, 0, &mut |_| Ok(()))?;
+			self.verify_token_rangeproofs(
+				status,
+				config.rangeproof_batch_size,
+				0,
+				&mut |_| Ok(()),
+			)?;
+			self.verify_kernel_signatures(status, config.kernel_batch_size, 0, &mut |_| Ok(()))?;
+			self.verify_token_kernel_signatures(status, config.kernel_batch_size, 0, &mut |_| {
+				Ok(())
+			})?;
+			return Ok(vec![]);
+		}
 
-			// Verify all the kernel signatures.
-			self.verify_kernel_signatures(status)?;
+		let mut failures = vec![];
 
-			self.verify_token_kernel_signatures(status)?;
+		let mut batch = vec![];
+		for pos in self.output_pmmr.leaf_pos_iter() {
+			match (
+				self.output_pmmr.get_data(pos),
+				self.rproof_pmmr.get_data(pos),
+			) {
+				(None, _) => {
+					failures.push(VerificationFailure::OutputNotFound { token: false, pos })
+				}
+				(_, None) => {
+					failures.push(VerificationFailure::RangeproofNotFound { token: false, pos })
+				}
+				(Some(output), Some(proof)) => batch.push((pos, output.commit, proof)),
+			}
+			if batch.len() >= rangeproof_batch_size {
+				failures.extend(rangeproof_failures(&batch, false));
+				batch.clear();
+			}
 		}
+		failures.extend(rangeproof_failures(&batch, false));
+		batch.clear();
 
-		Ok((output_sum, kernel_sum, block_token_sums))
-	}
-
-	/// Force the rollback of this extension, no matter the result
-	pub fn force_rollback(&mut self) {
-		self.rollback = true;
-	}
+		for pos in self.token_output_pmmr.leaf_pos_iter() {
+			match (
+				self.token_output_pmmr.get_data(pos),
+				self.token_rproof_pmmr.get_data(pos),
+			) {
+				(None, _) => {
+					failures.push(VerificationFailure::OutputNotFound { token: true, pos })
+				}
+				(_, None) => {
+					failures.push(VerificationFailure::RangeproofNotFound { token: true, pos })
+				}
+				(Some(output), Some(proof)) => batch.push((pos, output.commit, proof)),
+			}
+			if batch.len() >= rangeproof_batch_size {
+				failures.extend(rangeproof_failures(&batch, true));
+				batch.clear();
+			}
+		}
+		failures.extend(rangeproof_failures(&batch, true));
 
-	/// Dumps the output MMR.
-	/// We use this after compacting for visual confirmation that it worked.
-	pub fn dump_output_pmmr(&self) {
-		debug!("-- outputs --");
-		self.output_pmmr.dump_from_file(false);
-		debug!("--");
-		self.output_pmmr.dump_stats();
-		debug!("-- end of outputs --");
-	}
+		let mut kernels = vec![];
+		for pos in (1..=self.kernel_pmmr.unpruned_size()).filter(|x| pmmr::is_leaf(*x)) {
+			match self.kernel_pmmr.get_data(pos) {
+				Some(kernel) => kernels.push((pos, kernel)),
+				None => failures.push(VerificationFailure::KernelNotFound { token: false, pos }),
+			}
+			if kernels.len() >= kernel_batch_size {
+				failures.extend(kernel_failures(&kernels));
+				kernels.clear();
+			}
+		}
+		failures.extend(kernel_failures(&kernels));
 
-	/// Dumps the state of the 3 MMRs to stdout for debugging. Short
-	/// version only prints the Output tree.
-	pub fn dump(&self, short: bool) {
-		debug!("-- outputs --");
-		self.output_pmmr.dump(short);
-		if !short {
-			debug!("-- range proofs --");
-			self.rproof_pmmr.dump(short);
-			debug!("-- kernels --");
-			self.kernel_pmmr.dump(short);
+		let mut token_kernels = vec![];
+		for pos in (1..=self.token_kernel_pmmr.unpruned_size()).filter(|x| pmmr::is_leaf(*x)) {
+			match self.token_kernel_pmmr.get_data(pos) {
+				Some(kernel) => token_kernels.push((pos, kernel)),
+				None => failures.push(VerificationFailure::KernelNotFound { token: true, pos }),
+			}
+			if token_kernels.len() >= kernel_batch_size {
+				failures.extend(token_kernel_failures(&token_kernels));
+				token_kernels.clear();
+			}
 		}
-	}
+		failures.extend(token_kernel_failures(&token_kernels));
 
-	/// Sizes of each of the MMRs
-	pub fn sizes(&self) -> (u64, u64, u64, u64, u64, u64, u64) {
-		(
-			self.output_pmmr.unpruned_size(),
-			self.rproof_pmmr.unpruned_size(),
-			self.kernel_pmmr.unpruned_size(),
-			self.token_output_pmmr.unpruned_size(),
-			self.token_rproof_pmmr.unpruned_size(),
-			self.token_issue_proof_pmmr.unpruned_size(),
-			self.token_kernel_pmmr.unpruned_size(),
-		)
+		if !failures.is_empty() {
+			warn!(
+				"txhashset: verify_proofs_and_signatures: found {} failures",
+				failures.len()
+			);
+		}
+		Ok(failures)
 	}
 
-	fn verify_kernel_signatures(&self, status: &dyn TxHashsetWriteStatus) -> Result<(), Error> {
+	// Verify kernel signatures for all kernels after start_pos.
+	// The provided on_batch fn is called with the last verified pos after each batch.
+	fn verify_kernel_signatures(
+		&self,
+		status: &dyn TxHashsetWriteStatus,
+		batch_size: usize,
+		start_pos: u64,
+		on_batch: &mut dyn FnMut(u64) -> Result<(), Error>,
+	) -> Result<(), Error> {
 		let now = Instant::now();
-		const KERNEL_BATCH_SIZE: usize = 5_000;
+		let batch_size = cmp::max(batch_size, 1);
 
-		let mut kern_count = 0;
+		let mut kern_count = pmmr::n_leaves(start_pos);
 		let total_kernels = pmmr::n_leaves(self.kernel_pmmr.unpruned_size());
-		let mut tx_kernels: Vec<TxKernel> = Vec::with_capacity(KERNEL_BATCH_SIZE);
-		for n in 1..self.kernel_pmmr.unpruned_size() + 1 {
+		let mut tx_kernels: Vec<TxKernel> = Vec::with_capacity(batch_size);
+		for n in start_pos + 1..self.kernel_pmmr.unpruned_size() + 1 {
 			if pmmr::is_leaf(n) {
 				let kernel = self
 					.kernel_pmmr
@@ -2128,10 +5323,11 @@ impl<'a> Extension<'a> {
 				tx_kernels.push(kernel);
 			}
 
-			if tx_kernels.len() >= KERNEL_BATCH_SIZE || n >= self.kernel_pmmr.unpruned_size() {
+			if tx_kernels.len() >= batch_size || n >= self.kernel_pmmr.unpruned_size() {
 				TxKernel::batch_sig_verify(&tx_kernels)?;
 				kern_count += tx_kernels.len() as u64;
 				tx_kernels.clear();
+				on_batch(n)?;
 				status.on_validation_kernels(kern_count, total_kernels);
 				debug!(
 					"txhashset: verify_kernel_signatures: verified {} signatures",
@@ -2153,14 +5349,17 @@ impl<'a> Extension<'a> {
 	fn verify_token_kernel_signatures(
 		&self,
 		status: &dyn TxHashsetWriteStatus,
+		batch_size: usize,
+		start_pos: u64,
+		on_batch: &mut dyn FnMut(u64) -> Result<(), Error>,
 	) -> Result<(), Error> {
 		let now = Instant::now();
-		const KERNEL_BATCH_SIZE: usize = 5_000;
+		let batch_size = cmp::max(batch_size, 1);
 
-		let mut kern_count = 0;
+		let mut kern_count = pmmr::n_leaves(start_pos);
 		let total_kernels = pmmr::n_leaves(self.token_kernel_pmmr.unpruned_size());
-		let mut tx_kernels: Vec<TokenTxKernel> = Vec::with_capacity(KERNEL_BATCH_SIZE);
-		for n in 1..self.token_kernel_pmmr.unpruned_size() + 1 {
+		let mut tx_kernels: Vec<TokenTxKernel> = Vec::with_capacity(batch_size);
+		for n in start_pos + 1..self.token_kernel_pmmr.unpruned_size() + 1 {
 			if pmmr::is_leaf(n) {
 				let kernel = self
 					.token_kernel_pmmr
@@ -2169,11 +5368,11 @@ impl<'a> Extension<'a> {
 				tx_kernels.push(kernel);
 			}
 
-			if tx_kernels.len() >= KERNEL_BATCH_SIZE || n >= self.token_kernel_pmmr.unpruned_size()
-			{
+			if tx_kernels.len() >= batch_size || n >= self.token_kernel_pmmr.unpruned_size() {
 				TokenTxKernel::batch_sig_verify(&tx_kernels)?;
 				kern_count += tx_kernels.len() as u64;
 				tx_kernels.clear();
+				on_batch(n)?;
 				status.on_validation_token_kernels(kern_count, total_kernels);
 				debug!(
 					"txhashset: verify_token_kernel_signatures: verified {} signatures",
@@ -2192,16 +5391,41 @@ impl<'a> Extension<'a> {
 		Ok(())
 	}
 
-	fn verify_rangeproofs(&self, status: &dyn TxHashsetWriteStatus) -> Result<(), Error> {
+	// Verify rangeproofs for all unspent outputs after start_pos.
+	// The provided on_batch fn is called with the last verified pos after each batch.
+	fn verify_rangeproofs(
+		&self,
+		status: &dyn TxHashsetWriteStatus,
+		batch_size: usize,
+		start_pos: u64,
+		on_batch: &mut dyn FnMut(u64) -> Result<(), Error>,
+	) -> Result<(), Error> {
 		let now = Instant::now();
+		let chunk_size = cmp::max(batch_size, 1);
+		let batch_size = rangeproof_batch_size(batch_size);
 
-		let mut commits: Vec<Commitment> = Vec::with_capacity(1_000);
-		let mut proofs: Vec<RangeProof> = Vec::with_capacity(1_000);
+		let mut commits: Vec<Commitment> = Vec::with_capacity(batch_size);
+		let mut proofs: Vec<RangeProof> = Vec::with_capacity(batch_size);
 
-		let mut proof_count = 0;
+		// Rangeproofs at or below start_pos were verified previously (see VerificationProgress),
+		// count them so progress is reported against the total.
+		let mut proof_count = self
+			.output_pmmr
+			.leaf_pos_iter()
+			.take_while(|pos| *pos <= start_pos)
+			.count() as u64;
+		let mut last_pos = start_pos;
 		let total_rproofs = self.output_pmmr.n_unpruned_leaves();
+		if proof_count > 0 {
+			status.on_validation_rproofs(proof_count, total_rproofs);
+		}
 
-		for pos in self.output_pmmr.leaf_pos_iter() {
+		for pos in self
+			.output_pmmr
+			.leaf_pos_iter()
+			.skip_while(|pos| *pos <= start_pos)
+		{
+			last_pos = pos;
 			let output = self.output_pmmr.get_data(pos);
 			let proof = self.rproof_pmmr.get_data(pos);
 
@@ -2218,29 +5442,30 @@ impl<'a> Extension<'a> {
 
 			proof_count += 1;
 
-			if proofs.len() >= 1_000 {
-				Output::batch_verify_proofs(&commits, &proofs)?;
+			if proofs.len() >= batch_size {
+				batch_verify_rangeproofs(&commits, &proofs, chunk_size)?;
 				commits.clear();
 				proofs.clear();
+				on_batch(pos)?;
 				debug!(
 					"txhashset: verify_rangeproofs: verified {} rangeproofs",
 					proof_count,
 				);
-				if proof_count % 1_000 == 0 {
-					status.on_validation_rproofs(proof_count, total_rproofs);
-				}
+				status.on_validation_rproofs(proof_count, total_rproofs);
 			}
 		}
 
-		// remaining part which not full of 1000 range proofs
+		// remaining part which not full of batch_size range proofs
 		if !proofs.is_empty() {
-			Output::batch_verify_proofs(&commits, &proofs)?;
+			batch_verify_rangeproofs(&commits, &proofs, chunk_size)?;
 			commits.clear();
 			proofs.clear();
+			on_batch(last_pos)?;
 			debug!(
 				"txhashset: verify_rangeproofs: verified {} rangeproofs",
 				proof_count,
 			);
+			status.on_validation_rproofs(proof_count, total_rproofs);
 		}
 
 		debug!(
@@ -2252,15 +5477,37 @@ impl<'a> Extension<'a> {
 		Ok(())
 	}
 
-	fn verify_token_rangeproofs(&self, status: &dyn TxHashsetWriteStatus) -> Result<(), Error> {
+	fn verify_token_rangeproofs(
+		&self,
+		status: &dyn TxHashsetWriteStatus,
+		batch_size: usize,
+		start_pos: u64,
+		on_batch: &mut dyn FnMut(u64) -> Result<(), Error>,
+	) -> Result<(), Error> {
 		let now = Instant::now();
+		let chunk_size = cmp::max(batch_size, 1);
+		let batch_size = rangeproof_batch_size(batch_size);
 
-		let mut commits: Vec<Commitment> = Vec::with_capacity(1_000);
-		let mut proofs: Vec<RangeProof> = Vec::with_capacity(1_000);
+		let mut commits: Vec<Commitment> = Vec::with_capacity(batch_size);
+		let mut proofs: Vec<RangeProof> = Vec::with_capacity(batch_size);
 
-		let mut proof_count = 0;
-		let total_rproofs = pmmr::n_leaves(self.token_output_pmmr.unpruned_size());
-		for pos in self.token_output_pmmr.leaf_pos_iter() {
+		// Rangeproofs at or below start_pos were verified previously (see VerificationProgress).
+		let mut proof_count = self
+			.token_output_pmmr
+			.leaf_pos_iter()
+			.take_while(|pos| *pos <= start_pos)
+			.count() as u64;
+		let mut last_pos = start_pos;
+		let total_rproofs = self.token_output_pmmr.n_unpruned_leaves();
+		if proof_count > 0 {
+			status.on_validation_token_rproofs(proof_count, total_rproofs);
+		}
+		for pos in self
+			.token_output_pmmr
+			.leaf_pos_iter()
+			.skip_while(|pos| *pos <= start_pos)
+		{
+			last_pos = pos;
 			let output = self.token_output_pmmr.get_data(pos);
 			let proof = self.token_rproof_pmmr.get_data(pos);
 
@@ -2277,98 +5524,707 @@ impl<'a> Extension<'a> {
 
 			proof_count += 1;
 
-			if proofs.len() >= 1_000 {
-				Output::batch_verify_proofs(&commits, &proofs)?;
+			if proofs.len() >= batch_size {
+				batch_verify_rangeproofs(&commits, &proofs, chunk_size)?;
 				commits.clear();
 				proofs.clear();
+				on_batch(pos)?;
 				debug!(
 					"txhashset: verify_token_rangeproofs: verified {} rangeproofs",
 					proof_count,
 				);
+				status.on_validation_token_rproofs(proof_count, total_rproofs);
 			}
+		}
 
-			if proof_count % 1_000 == 0 {
-				status.on_validation_token_rproofs(proof_count, total_rproofs);
+		// remaining part which not full of batch_size range proofs
+		if !proofs.is_empty() {
+			batch_verify_rangeproofs(&commits, &proofs, chunk_size)?;
+			commits.clear();
+			proofs.clear();
+			on_batch(last_pos)?;
+			debug!(
+				"txhashset: verify_token_rangeproofs: verified {} rangeproofs",
+				proof_count,
+			);
+			status.on_validation_token_rproofs(proof_count, total_rproofs);
+		}
+
+		debug!(
+			"txhashset: verified {} token rangeproofs, pmmr size {}, took {}s",
+			proof_count,
+			self.token_rproof_pmmr.unpruned_size(),
+			now.elapsed().as_secs(),
+		);
+		Ok(())
+	}
+}
+
+/// Check the provided bitmap accumulator against a fresh accumulator built from the
+/// output MMR (as TxHashSet::open does), comparing roots.
+pub fn check_bitmap_accumulator(
+	bitmap_accumulator: &BitmapAccumulator,
+	output_pmmr_h: &PMMRHandle<Output>,
+) -> Result<(), Error> {
+	let expected = TxHashSet::bitmap_accumulator(output_pmmr_h)?.root();
+	let actual = bitmap_accumulator.root();
+	if expected != actual {
+		return Err(ErrorKind::BitmapAccumulatorMismatch(format!(
+			"expected {}, got {}",
+			expected, actual
+		))
+		.into());
+	}
+	Ok(())
+}
+
+// Run check_compact on the backend, returning the bytes freed from the hash and data
+// files and the number of hash positions removed.
+fn check_compact_with_stats<T: PMMRable>(
+	backend: &mut PMMRBackend<T>,
+	cutoff_pos: u64,
+	rewind_rm_pos: &Bitmap,
+) -> Result<(u64, u64), Error> {
+	let bytes_before = backend.file_size_in_bytes()?;
+	let hashes_before = backend.hash_size();
+	backend.check_compact(cutoff_pos, rewind_rm_pos)?;
+	let bytes_freed = bytes_before.saturating_sub(backend.file_size_in_bytes()?);
+	let pos_removed = hashes_before.saturating_sub(backend.hash_size());
+	Ok((bytes_freed, pos_removed))
+}
+
+/// Read the block into the provided slot unless a previous call already did.
+fn read_block_once<'b>(
+	block: &'b mut Option<Block>,
+	hash: &Hash,
+	batch: &Batch<'_>,
+) -> Result<&'b Block, Error> {
+	if block.is_none() {
+		*block = Some(batch.get_block(hash)?);
+	}
+	Ok(block.as_ref().expect("block read above"))
+}
+
+/// Take the commitments recorded in an output_created index for the leaves in
+/// (from_pos, to_pos], deleting the entries once all of them have been found.
+/// Returns None (deleting nothing) if any entry is missing, the caller then falls back
+/// to the full block. Entries left behind are overwritten when the pos is reused.
+fn take_created_commits<G, D>(
+	from_pos: u64,
+	to_pos: u64,
+	get: G,
+	delete: D,
+) -> Result<Option<Vec<Commitment>>, Error>
+where
+	G: Fn(u64) -> Result<(Commitment, u64), grin_store::Error>,
+	D: Fn(u64) -> Result<(), grin_store::Error>,
+{
+	let positions: Vec<_> = ((from_pos + 1)..=to_pos)
+		.filter(|x| pmmr::is_leaf(*x))
+		.collect();
+	let mut commits = Vec::with_capacity(positions.len());
+	for pos in &positions {
+		match get(*pos) {
+			Ok((commit, _)) => commits.push(commit),
+			Err(grin_store::Error::NotFoundErr(_)) => return Ok(None),
+			Err(e) => return Err(e.into()),
+		}
+	}
+	for pos in positions {
+		delete(pos)?;
+	}
+	Ok(Some(commits))
+}
+
+// Estimate the bytes freed and the number of hash positions removed were we to run
+// check_compact on the backend.
+fn compact_estimate<T: PMMRable>(
+	backend: &PMMRBackend<T>,
+	cutoff_pos: u64,
+	rewind_rm_pos: &Bitmap,
+) -> (u64, u64) {
+	let (leaves_removed, pos_removed) = backend.compact_estimate(cutoff_pos, rewind_rm_pos);
+	let elmt_size = T::elmt_size().map(u64::from).unwrap_or(0);
+	let bytes_freed = pos_removed * Hash::LEN as u64 + leaves_removed * elmt_size;
+	(bytes_freed, pos_removed)
+}
+
+// Write a line per leaf pos to the file at path: pos, hash and the provided
+// (tab-separated) fields. Leaves with no hash or data are skipped.
+fn dump_leaves_to_file<I, F>(path: &Path, positions: I, entry: F) -> Result<(), Error>
+where
+	I: Iterator<Item = u64>,
+	F: Fn(u64) -> Option<(Hash, String)>,
+{
+	let mut writer = BufWriter::new(File::create(path)?);
+	for pos in positions {
+		if let Some((hash, fields)) = entry(pos) {
+			writeln!(writer, "{}\t{}\t{}", pos, hash.to_hex(), fields)?;
+		}
+	}
+	writer.flush()?;
+	Ok(())
+}
+
+// Search the spent indexes of blocks within the horizon for the provided commitment.
+// If the spent index for a block is not found we cannot be sure and stop searching.
+fn commitment_spent_within_horizon<S, C>(
+	batch: &Batch<'_>,
+	spent_index: S,
+	commit_at: C,
+	commit: &Commitment,
+) -> Result<CommitmentSeen, Error>
+where
+	S: Fn(&Hash) -> Result<Vec<CommitPos>, grin_store::Error>,
+	C: Fn(u64) -> Option<Commitment>,
+{
+	let horizon = global::cut_through_horizon() as u64;
+	let mut header = batch.head_header()?;
+	let min_height = header.height.saturating_sub(horizon);
+	loop {
+		if header.height == 0 {
+			// We searched the entire chain (genesis spends nothing).
+			return Ok(CommitmentSeen::NotSeen);
+		}
+		let spent = match spent_index(&header.hash()) {
+			Ok(spent) => spent,
+			Err(grin_store::Error::NotFoundErr(_)) => {
+				return Ok(CommitmentSeen::UnknownBeyondHorizon)
 			}
+			Err(e) => return Err(e.into()),
+		};
+		if spent
+			.iter()
+			.any(|x| commit_at(x.pos).as_ref() == Some(commit))
+		{
+			return Ok(CommitmentSeen::Spent);
+		}
+		if header.height <= min_height {
+			return Ok(CommitmentSeen::UnknownBeyondHorizon);
+		}
+		header = batch.get_previous_header(&header)?;
+	}
+}
+
+// Inputs, outputs and kernels between two headers (see validate_kernel_sums_incremental).
+#[derive(Default)]
+struct CommittedDelta {
+	inputs: Vec<Commitment>,
+	outputs: Vec<Commitment>,
+	kernels: Vec<Commitment>,
+}
+
+impl Committed for CommittedDelta {
+	fn inputs_committed(&self) -> Vec<Commitment> {
+		self.inputs.clone()
+	}
+
+	fn outputs_committed(&self) -> Vec<Commitment> {
+		self.outputs.clone()
+	}
+
+	fn kernels_committed(&self) -> Vec<Commitment> {
+		self.kernels.clone()
+	}
+
+	fn token_inputs_committed(&self) -> HashMap<TokenKey, Vec<Commitment>> {
+		HashMap::new()
+	}
+
+	fn token_outputs_committed(&self) -> HashMap<TokenKey, Vec<Commitment>> {
+		HashMap::new()
+	}
+
+	fn token_kernels_committed(&self) -> HashMap<TokenKey, Vec<Commitment>> {
+		HashMap::new()
+	}
+}
+
+// Wrap a failure to open one of the txhashset MMRs so it reports which subdir
+// (and protocol version) could not be opened.
+fn open_mmr_error(subdir: &str, version: ProtocolVersion, e: Error) -> Error {
+	ErrorKind::OpenMMR {
+		subdir: subdir.to_owned(),
+		version: version.value(),
+		reason: format!("{}", e.kind()),
+	}
+	.into()
+}
+
+// Check an output MMR and its corresponding rangeproof MMR have the same size.
+fn check_mmr_parity(
+	name: &str,
+	output_size: u64,
+	rproof_size: u64,
+	parity_check: ParityCheck,
+) -> Result<(), Error> {
+	if output_size == rproof_size {
+		return Ok(());
+	}
+	let msg = format!(
+		"{} mmr size {} vs rangeproof mmr size {}, the rangeproof mmr needs to be realigned \
+		 with the output mmr (or the txhashset resynced)",
+		name, output_size, rproof_size
+	);
+	match parity_check {
+		ParityCheck::Warn => {
+			warn!("txhashset: open: {}", msg);
+			Ok(())
+		}
+		ParityCheck::Error => Err(ErrorKind::MMRParityMismatch(msg).into()),
+	}
+}
+
+// Readonly PMMR at the provided size, checking the size is valid and not beyond the
+// current size and that the peaks at this size are still available.
+fn readonly_pmmr_at<T: PMMRable>(
+	handle: &PMMRHandle<T>,
+	size: u64,
+) -> Result<ReadonlyPMMR<'_, T, PMMRBackend<T>>, Error> {
+	if size > handle.last_pos || (size > 0 && pmmr::peaks(size).is_empty()) {
+		return Err(ErrorKind::InvalidMMRSize.into());
+	}
+	let pmmr = ReadonlyPMMR::at(&handle.backend, size);
+	if let Some(pos) = pmmr::peaks(size)
+		.into_iter()
+		.find(|pos| pmmr.get_from_file(*pos).is_none())
+	{
+		return Err(ErrorKind::PrunedPeak(pos, size).into());
+	}
+	Ok(pmmr)
+}
+
+// Read the data at the provided positions in sorted order, returning results in
+// the order provided. Reads respect last_pos of the handle.
+fn data_batch<T: PMMRable>(handle: &PMMRHandle<T>, positions: &[u64]) -> Vec<Option<T::E>> {
+	let pmmr = ReadonlyPMMR::at(&handle.backend, handle.last_pos);
+	let mut order: Vec<usize> = (0..positions.len()).collect();
+	order.sort_unstable_by_key(|i| positions[*i]);
+	let mut res: Vec<Option<T::E>> = (0..positions.len()).map(|_| None).collect();
+	for i in order {
+		res[i] = pmmr.get_data(positions[i]);
+	}
+	res
+}
+
+// A single (independent) MMR validation, see Extension::validate_mmrs().
+type MMRValidation<'a> = dyn Fn() -> Result<(), String> + Sync + 'a;
+
+// Run the MMR validations in order, stopping at the first failure.
+// This is deterministic, useful to debug which MMR fails first.
+#[cfg(feature = "single-threaded")]
+fn validate_all(validations: &[&MMRValidation<'_>]) -> Result<(), String> {
+	for validation in validations {
+		validation()?;
+	}
+	Ok(())
+}
+
+// Run the MMR validations across the rayon thread pool.
+// All validations run to completion, the first failure (in the provided order) is returned.
+#[cfg(not(feature = "single-threaded"))]
+fn validate_all(validations: &[&MMRValidation<'_>]) -> Result<(), String> {
+	use rayon::prelude::*;
+	let results: Vec<_> = validations
+		.par_iter()
+		.map(|validation| validation())
+		.collect();
+	results.into_iter().collect()
+}
+
+// A single (independent) verification pass, see Extension::validate_with_progress().
+type Verification<'a> = dyn Fn() -> Result<(), Error> + Sync + 'a;
+
+// Run the verification passes in order, stopping at the first failure.
+#[cfg(feature = "single-threaded")]
+fn verify_all(verifications: &[&Verification<'_>]) -> Result<(), Error> {
+	for verification in verifications {
+		verification()?;
+	}
+	Ok(())
+}
+
+// Run the verification passes across the rayon thread pool.
+// All passes run to completion, the first failure (in the provided order) is returned.
+#[cfg(not(feature = "single-threaded"))]
+fn verify_all(verifications: &[&Verification<'_>]) -> Result<(), Error> {
+	use rayon::prelude::*;
+	let results: Vec<_> = verifications
+		.par_iter()
+		.map(|verification| verification())
+		.collect();
+	results.into_iter().collect()
+}
+
+// Number of rangeproofs to collect before verifying them.
+#[cfg(feature = "single-threaded")]
+fn rangeproof_batch_size(batch_size: usize) -> usize {
+	cmp::max(batch_size, 1)
+}
+
+// Number of rangeproofs to collect before verifying them.
+// Large enough to give each thread in the rayon pool a batch of batch_size.
+#[cfg(not(feature = "single-threaded"))]
+fn rangeproof_batch_size(batch_size: usize) -> usize {
+	cmp::max(batch_size, 1) * rayon::current_num_threads()
+}
+
+// Batch verify the rangeproofs.
+#[cfg(feature = "single-threaded")]
+fn batch_verify_rangeproofs(
+	commits: &[Commitment],
+	proofs: &[RangeProof],
+	_chunk_size: usize,
+) -> Result<(), Error> {
+	Output::batch_verify_proofs(commits, proofs)?;
+	Ok(())
+}
+
+// Batch verify the rangeproofs, in chunks of chunk_size across the rayon thread pool.
+// Batches are independent so the order of verification does not matter.
+// Each worker verifies using its own secp context (created via try_for_each_init and
+// never shared between threads) so verification does not serialize on the mutex
+// around the shared static secp instance. Contexts are dropped once verification completes.
+#[cfg(not(feature = "single-threaded"))]
+fn batch_verify_rangeproofs(
+	commits: &[Commitment],
+	proofs: &[RangeProof],
+	chunk_size: usize,
+) -> Result<(), Error> {
+	use crate::util::secp::{ContextFlag, Secp256k1};
+	use rayon::prelude::*;
+	commits
+		.par_chunks(chunk_size)
+		.zip(proofs.par_chunks(chunk_size))
+		.try_for_each_init(
+			|| Secp256k1::with_caps(ContextFlag::Commit),
+			|secp, (commits, proofs)| Output::batch_verify_proofs_with_secp(secp, commits, proofs),
+		)?;
+	Ok(())
+}
+
+// Batch verify the rangeproofs, falling back to verifying each individually
+// to identify the failures if the batch fails to verify.
+fn rangeproof_failures(
+	batch: &[(u64, Commitment, RangeProof)],
+	token: bool,
+) -> Vec<VerificationFailure> {
+	let (commits, proofs): (Vec<_>, Vec<_>) = batch.iter().map(|(_, c, p)| (*c, *p)).unzip();
+	if batch.is_empty() || Output::batch_verify_proofs(&commits, &proofs).is_ok() {
+		return vec![];
+	}
+	batch
+		.iter()
+		.filter(|(_, commit, proof)| Output::batch_verify_proofs(&[*commit], &[*proof]).is_err())
+		.map(|(pos, commit, _)| VerificationFailure::InvalidRangeproof {
+			token,
+			pos: *pos,
+			commit: *commit,
+		})
+		.collect()
+}
+
+// Batch verify the kernel signatures, falling back to verifying each individually
+// to identify the failures if the batch fails to verify.
+fn kernel_failures(batch: &[(u64, TxKernel)]) -> Vec<VerificationFailure> {
+	let kernels: Vec<_> = batch.iter().map(|(_, k)| k.clone()).collect();
+	if kernels.is_empty() || TxKernel::batch_sig_verify(&kernels).is_ok() {
+		return vec![];
+	}
+	batch
+		.iter()
+		.filter(|(_, kernel)| kernel.verify().is_err())
+		.map(
+			|(pos, kernel)| VerificationFailure::InvalidKernelSignature {
+				token: false,
+				pos: *pos,
+				excess: kernel.excess(),
+			},
+		)
+		.collect()
+}
+
+// Token kernel equivalent of kernel_failures().
+fn token_kernel_failures(batch: &[(u64, TokenTxKernel)]) -> Vec<VerificationFailure> {
+	let kernels: Vec<_> = batch.iter().map(|(_, k)| k.clone()).collect();
+	if kernels.is_empty() || TokenTxKernel::batch_sig_verify(&kernels).is_ok() {
+		return vec![];
+	}
+	batch
+		.iter()
+		.filter(|(_, kernel)| kernel.verify().is_err())
+		.map(
+			|(pos, kernel)| VerificationFailure::InvalidKernelSignature {
+				token: true,
+				pos: *pos,
+				excess: kernel.excess(),
+			},
+		)
+		.collect()
+}
+
+/// Location of the persisted verification progress, in the txhashset dir.
+pub fn verification_progress_path(root_dir: &Path) -> PathBuf {
+	root_dir
+		.join(TXHASHSET_SUBDIR)
+		.join(VERIFICATION_PROGRESS_FILE)
+}
+
+// Load verification progress from the provided path.
+// Returns None if there is no progress or it was not recorded against this header.
+fn load_verification_progress(path: &Path, header: &BlockHeader) -> Option<VerificationProgress> {
+	let mut file = File::open(path).ok()?;
+	let progress: VerificationProgress =
+		ser::deserialize(&mut file, ProtocolVersion::local()).ok()?;
+	if progress.is_valid_for(header) {
+		debug!("load_verification_progress: resuming from {:?}", progress);
+		Some(progress)
+	} else {
+		debug!("load_verification_progress: ignoring progress for a different header");
+		None
+	}
+}
+
+// Save verification progress to the provided path.
+// Written to a tmp file first and then renamed so we never leave a partial file.
+fn save_verification_progress(path: &Path, progress: &VerificationProgress) -> Result<(), Error> {
+	let tmp_path = path.with_extension("tmp");
+	{
+		let mut file = File::create(&tmp_path)?;
+		ser::serialize(&mut file, ProtocolVersion::local(), progress).map_err(ErrorKind::SerErr)?;
+		file.sync_all()?;
+	}
+	fs::rename(tmp_path, path)?;
+	Ok(())
+}
+
+/// Verify a kernel export produced by TxHashSet::export_kernels().
+/// Batch verifies all kernel and token kernel signatures and rebuilds the kernel and
+/// token kernel MMR roots from the exported kernels, checking these against the roots
+/// included in the export.
+/// Returns the (kernel_root, token_kernel_root) so the caller can confirm completeness
+/// against the kernel_root and token_kernel_root of a known header.
+pub fn verify_kernel_export(path: &Path) -> Result<(Hash, Hash), Error> {
+	const KERNEL_BATCH_SIZE: usize = 5_000;
+
+	let version = ProtocolVersion::local();
+	let mut reader = BufReader::new(File::open(path)?);
+	let kernel_root: Hash = ser::deserialize(&mut reader, version).map_err(ErrorKind::SerErr)?;
+	let token_kernel_root: Hash =
+		ser::deserialize(&mut reader, version).map_err(ErrorKind::SerErr)?;
+
+	let mut kernel_backend: VecBackend<TxKernel> = VecBackend::new_hash_only();
+	let mut kernel_pmmr = PMMR::new(&mut kernel_backend);
+	let n_kernels: u64 = ser::deserialize(&mut reader, version).map_err(ErrorKind::SerErr)?;
+	let mut tx_kernels: Vec<TxKernel> = Vec::with_capacity(KERNEL_BATCH_SIZE);
+	for n in 0..n_kernels {
+		let kernel: TxKernel = ser::deserialize(&mut reader, version).map_err(ErrorKind::SerErr)?;
+		kernel_pmmr
+			.push(&kernel)
+			.map_err(&ErrorKind::TxHashSetErr)?;
+		tx_kernels.push(kernel);
+		if tx_kernels.len() >= KERNEL_BATCH_SIZE || n + 1 == n_kernels {
+			TxKernel::batch_sig_verify(&tx_kernels)?;
+			tx_kernels.clear();
 		}
+	}
 
-		// remaining part which not full of 1000 range proofs
-		if proofs.len() > 0 {
-			Output::batch_verify_proofs(&commits, &proofs)?;
-			commits.clear();
-			proofs.clear();
-			debug!(
-				"txhashset: verify_rangeproofs: verified {} token rangeproofs",
-				proof_count,
-			);
+	let mut token_kernel_backend: VecBackend<TokenTxKernel> = VecBackend::new_hash_only();
+	let mut token_kernel_pmmr = PMMR::new(&mut token_kernel_backend);
+	let n_token_kernels: u64 = ser::deserialize(&mut reader, version).map_err(ErrorKind::SerErr)?;
+	let mut token_kernels: Vec<TokenTxKernel> = Vec::with_capacity(KERNEL_BATCH_SIZE);
+	for n in 0..n_token_kernels {
+		let kernel: TokenTxKernel =
+			ser::deserialize(&mut reader, version).map_err(ErrorKind::SerErr)?;
+		token_kernel_pmmr
+			.push(&kernel)
+			.map_err(&ErrorKind::TxHashSetErr)?;
+		token_kernels.push(kernel);
+		if token_kernels.len() >= KERNEL_BATCH_SIZE || n + 1 == n_token_kernels {
+			TokenTxKernel::batch_sig_verify(&token_kernels)?;
+			token_kernels.clear();
 		}
+	}
 
-		debug!(
-			"txhashset: verified {} token rangeproofs, pmmr size {}, took {}s",
-			proof_count,
-			self.token_rproof_pmmr.unpruned_size(),
-			now.elapsed().as_secs(),
-		);
-		Ok(())
+	let rebuilt_kernel_root = kernel_pmmr.root().map_err(|_| ErrorKind::InvalidRoot)?;
+	let rebuilt_token_kernel_root = token_kernel_pmmr
+		.root()
+		.map_err(|_| ErrorKind::InvalidRoot)?;
+	if rebuilt_kernel_root != kernel_root || rebuilt_token_kernel_root != token_kernel_root {
+		return Err(ErrorKind::InvalidRoot.into());
 	}
+
+	debug!(
+		"verify_kernel_export: verified {} kernels, {} token kernels from {:?}",
+		n_kernels, n_token_kernels, path
+	);
+	Ok((kernel_root, token_kernel_root))
+}
+
+/// Verify a Merkle proof (as produced by Extension::merkle_proof()) for the given
+/// output against an output root. The leaf hash is rebuilt from the output
+/// identifier at the given pos. The proof must have been built against an MMR of
+/// mmr_size, i.e. the output_mmr_size of the header the root was taken from.
+pub fn verify_output_merkle_proof(
+	output: &OutputIdentifier,
+	proof: &MerkleProof,
+	root: Hash,
+	pos: u64,
+	mmr_size: u64,
+) -> Result<(), Error> {
+	verify_merkle_proof(output, proof, root, pos, mmr_size)
+}
+
+/// Verify a Merkle proof (as produced by Extension::token_merkle_proof()) for the
+/// given token output against a token_output_root.
+pub fn verify_token_output_merkle_proof(
+	output: &TokenOutputIdentifier,
+	proof: &MerkleProof,
+	root: Hash,
+	pos: u64,
+	mmr_size: u64,
+) -> Result<(), Error> {
+	verify_merkle_proof(output, proof, root, pos, mmr_size)
+}
+
+fn verify_merkle_proof(
+	element: &dyn PMMRIndexHashable,
+	proof: &MerkleProof,
+	root: Hash,
+	pos: u64,
+	mmr_size: u64,
+) -> Result<(), Error> {
+	if proof.mmr_size != mmr_size
+		|| pmmr::peaks(mmr_size).is_empty()
+		|| pos == 0
+		|| pos > mmr_size
+		|| !pmmr::is_leaf(pos)
+	{
+		return Err(ErrorKind::MerkleProof.into());
+	}
+	proof
+		.verify(root, element, pos)
+		.map_err(|_| ErrorKind::MerkleProof.into())
 }
 
+/// Default number of seconds txhashset zips are retained for before being cleaned up.
+/// Theoretically, we only need to retain zips for STATE_SYNC_THRESHOLD.
+/// But practically, these zip files are not small ones, we just keep the zips in last 24 hours.
+pub const TXHASHSET_ZIP_RETENTION_SECS: u64 = 24 * 60 * 60;
+
 /// Packages the txhashset data files into a zip and returns a Read to the
 /// resulting file
 pub fn zip_read(root_dir: String, header: &BlockHeader) -> Result<File, Error> {
+	zip_read_with_retention(root_dir, header, TXHASHSET_ZIP_RETENTION_SECS)
+}
+
+/// As zip_read, cleaning up any existing zips older than retention_secs
+/// (rather than the default TXHASHSET_ZIP_RETENTION_SECS) when creating a new zip.
+/// A longer retention trades disk space for fewer zip regenerations.
+pub fn zip_read_with_retention(
+	root_dir: String,
+	header: &BlockHeader,
+	retention_secs: u64,
+) -> Result<File, Error> {
+	zip_read_with_progress(root_dir, header, retention_secs, &mut |_, _| {})
+		.map(|(zip_file, _)| zip_file)
+}
+
+/// Packages the txhashset data files into a zip and returns a Read to the
+/// resulting file along with the blake2b hash of the zip file.
+/// The zip writer seeks back to patch entry headers so the hash is computed over the
+/// final bytes once the zip is complete (while still hot in the page cache).
+/// The hash is cached alongside the zip so reusing an existing zip does not require
+/// another read pass.
+pub fn zip_read_with_hash(root_dir: String, header: &BlockHeader) -> Result<(File, Hash), Error> {
+	zip_read_with_progress(
+		root_dir,
+		header,
+		TXHASHSET_ZIP_RETENTION_SECS,
+		&mut |_, _| {},
+	)
+}
+
+/// As zip_read_with_hash, reporting (bytes processed, total bytes) to the provided
+/// progress callback while the zip is being created.
+/// The total is the size of the files in file_list(header). No progress is reported
+/// when an existing zip file is reused.
+/// Callers within a process are expected to hold the txhashset lock. A process creating
+/// the zip holds a lock file alongside it, any other process creating the zip for the same
+/// header concurrently fails fast with ZipLocked (and can retry to reuse the zip).
+/// Existing zips older than retention_secs are cleaned up when creating a new zip.
+pub fn zip_read_with_progress(
+	root_dir: String,
+	header: &BlockHeader,
+	retention_secs: u64,
+	progress: &mut dyn FnMut(u64, u64),
+) -> Result<(File, Hash), Error> {
 	let txhashset_zip = format!("{}_{}.zip", TXHASHSET_ZIP, header.hash().to_string());
 
 	let txhashset_path = Path::new(&root_dir).join(TXHASHSET_SUBDIR);
 	let zip_path = Path::new(&root_dir).join(txhashset_zip);
 
 	// if file exist, just re-use it
-	let zip_file = File::open(zip_path.clone());
-	if let Ok(zip) = zip_file {
+	if let Some(existing) = existing_zip(&zip_path, header)? {
+		return Ok(existing);
+	}
+
+	// Only one process creates the zip for a given header, we fail fast (rather than wait)
+	// if another process is creating it.
+	let _lock = ZipLock::acquire(&zip_path)?;
+	if let Some(existing) = existing_zip(&zip_path, header)? {
+		return Ok(existing);
+	}
+
+	// clean up old zips.
+	let data_dir = Path::new(&root_dir);
+	let pattern = format!("{}_", TXHASHSET_ZIP);
+	if let Ok(n) = clean_files_by_prefix(data_dir, &pattern, retention_secs) {
 		debug!(
-			"zip_read: {} at {}: reusing existing zip file: {:?}",
-			header.hash(),
-			header.height,
-			zip_path
+			"{} zip files have been clean up in folder: {:?}",
+			n, data_dir
 		);
-		return Ok(zip);
-	} else {
-		// clean up old zips.
-		// Theoretically, we only need clean-up those zip files older than STATE_SYNC_THRESHOLD.
-		// But practically, these zip files are not small ones, we just keep the zips in last 24 hours
-		let data_dir = Path::new(&root_dir);
-		let pattern = format!("{}_", TXHASHSET_ZIP);
-		if let Ok(n) = clean_files_by_prefix(data_dir, &pattern, 24 * 60 * 60) {
-			debug!(
-				"{} zip files have been clean up in folder: {:?}",
-				n, data_dir
-			);
-		}
 	}
 
 	// otherwise, create the zip archive
-	let path_to_be_cleanup = {
-		// Temp txhashset directory
-		let temp_txhashset_path = Path::new(&root_dir).join(format!(
-			"{}_zip_{}",
-			TXHASHSET_SUBDIR,
-			header.hash().to_string()
-		));
-		// Remove temp dir if it exist
-		if temp_txhashset_path.exists() {
-			fs::remove_dir_all(&temp_txhashset_path)?;
-		}
-		// Copy file to another dir
-		file::copy_dir_to(&txhashset_path, &temp_txhashset_path)?;
-
-		let zip_file = File::create(zip_path.clone())?;
+	// The files are streamed directly from the live txhashset dir (no temp copy).
+	// Callers must hold the txhashset lock for the duration so the MMR files on disk
+	// are quiescent. Any in-memory changes (i.e. a rewind in a readonly extension) are
+	// never flushed and the rewound leaf files are written separately via snapshot().
+	let manifest_path = Path::new(&root_dir).join(format!(
+		"{}_{}.manifest",
+		TXHASHSET_ZIP,
+		header.hash().to_string()
+	));
+	// The zip is written to a tmp file first so a partially written zip is never reused.
+	let tmp_path = zip_path.with_extension("zip.tmp");
+	{
+		let zip_file = File::create(&tmp_path)?;
 
 		// Explicit list of files to add to our zip archive.
+		// Along with a manifest of checksums so the receiving side can detect corruption.
 		let files = file_list(header);
+		write_manifest(&txhashset_path, &files, &manifest_path)?;
+		let mut entries: Vec<_> = files
+			.into_iter()
+			.map(|x| (txhashset_path.join(&x), x))
+			.collect();
+		let total = entries
+			.iter()
+			.filter_map(|(path, _)| fs::metadata(path).ok())
+			.map(|meta| meta.len())
+			.sum();
+		entries.push((manifest_path.clone(), PathBuf::from(TXHASHSET_MANIFEST)));
+
+		zip::create_zip_from_entries_with_progress(&zip_file, entries, &mut |done| {
+			progress(cmp::min(done, total), total)
+		})?;
+	}
 
-		zip::create_zip(&zip_file, &temp_txhashset_path, files)?;
-
-		temp_txhashset_path
-	};
+	// hash the zip and cache the hash alongside it
+	let zip_hash = hash_file(&mut File::open(&tmp_path)?)?;
+	fs::write(zip_hash_path(&zip_path), zip_hash.to_hex())?;
+	fs::rename(&tmp_path, &zip_path)?;
 
 	debug!(
 		"zip_read: {} at {}: created zip file: {:?}",
@@ -2380,15 +6236,276 @@ pub fn zip_read(root_dir: String, header: &BlockHeader) -> Result<File, Error> {
 	// open it again to read it back
 	let zip_file = File::open(zip_path.clone())?;
 
-	// clean-up temp txhashset directory.
-	if let Err(e) = fs::remove_dir_all(&path_to_be_cleanup) {
+	// clean-up the manifest (it is in the zip).
+	if let Err(e) = fs::remove_file(&manifest_path) {
 		warn!(
-			"txhashset zip file: {:?} fail to remove, err: {}",
-			zip_path.to_str(),
+			"txhashset zip manifest: {:?} fail to remove, err: {}",
+			manifest_path.to_str(),
 			e
 		);
 	}
-	Ok(zip_file)
+	Ok((zip_file, zip_hash))
+}
+
+// The existing zip at the provided path (and its hash) if there is one.
+fn existing_zip(zip_path: &Path, header: &BlockHeader) -> Result<Option<(File, Hash)>, Error> {
+	match File::open(zip_path) {
+		Ok(zip) => {
+			debug!(
+				"zip_read: {} at {}: reusing existing zip file: {:?}",
+				header.hash(),
+				header.height,
+				zip_path
+			);
+			let zip_hash = cached_zip_hash(zip_path)?;
+			Ok(Some((zip, zip_hash)))
+		}
+		Err(_) => Ok(None),
+	}
+}
+
+// Exclusive lock on the creation of a txhashset zip (or compressed zip), held via an
+// OS advisory lock (flock) on a lock file alongside it.
+// Within a process callers are already serialized by the txhashset lock so this only
+// guards against other processes sharing the data dir. The OS releases the lock when
+// the holding process dies, a lock file left behind by a crashed node is simply reused.
+// The lock file is removed when dropped (on completion or on error).
+struct ZipLock {
+	path: PathBuf,
+	// Held for the lifetime of the lock, the flock is released when this is closed.
+	_file: File,
+}
+
+impl ZipLock {
+	// Acquire the lock for the provided zip, failing fast with ZipLocked if another
+	// process holds it (we never block here as callers hold the chain locks).
+	// The lock file is the zip path with a ".lock" suffix.
+	fn acquire(zip_path: &Path) -> Result<ZipLock, Error> {
+		let mut path = zip_path.as_os_str().to_owned();
+		path.push(".lock");
+		let path = PathBuf::from(path);
+		loop {
+			let file = OpenOptions::new()
+				.read(true)
+				.write(true)
+				.create(true)
+				.open(&path)?;
+			if let Err(e) = file.try_lock_exclusive() {
+				if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+					return Err(ErrorKind::ZipLocked(format!("{:?}", path)).into());
+				}
+				return Err(e.into());
+			}
+			// The previous holder removes the lock file on release, if it did so after we
+			// opened it we hold a lock on an orphaned file and need to start over.
+			if is_same_file(&file, &path) {
+				return Ok(ZipLock { path, _file: file });
+			}
+		}
+	}
+}
+
+// Whether the open file is (still) the file at the provided path.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+	use std::os::unix::fs::MetadataExt;
+	match (file.metadata(), fs::metadata(path)) {
+		(Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+		_ => false,
+	}
+}
+
+// An open file cannot be removed on windows so the file at path is the open file.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> bool {
+	path.exists()
+}
+
+impl Drop for ZipLock {
+	fn drop(&mut self) {
+		if let Err(e) = fs::remove_file(&self.path) {
+			warn!(
+				"zip_read: failed to remove zip lock file {:?}, err: {}",
+				self.path, e
+			);
+		}
+	}
+}
+
+/// Packages the txhashset data files into a zip (see zip_read) and compresses the
+/// zip with zstd, returning a Read to the resulting compressed file.
+/// The compressed archive is self-describing (zstd magic bytes) so zip_write
+/// detects and decompresses it transparently.
+/// A lock file guards against concurrent creation by another process, as in zip_read.
+pub fn zip_read_compressed(root_dir: String, header: &BlockHeader) -> Result<File, Error> {
+	let zst_path = Path::new(&root_dir).join(format!(
+		"{}_{}.zip.zst",
+		TXHASHSET_ZIP,
+		header.hash().to_string()
+	));
+
+	// if file exist, just re-use it
+	if let Ok(zst_file) = File::open(&zst_path) {
+		debug!(
+			"zip_read_compressed: {} at {}: reusing existing compressed zip file: {:?}",
+			header.hash(),
+			header.height,
+			zst_path
+		);
+		return Ok(zst_file);
+	}
+
+	// Only one process compresses the zip for a given header, we fail fast (rather than
+	// wait) if another process is compressing it.
+	let _lock = ZipLock::acquire(&zst_path)?;
+	if let Ok(zst_file) = File::open(&zst_path) {
+		return Ok(zst_file);
+	}
+
+	let zip_file = zip_read(root_dir, header)?;
+
+	// Compress to a tmp file first so a partially written file is never reused.
+	let tmp_path = zst_path.with_extension("zst.tmp");
+	{
+		let tmp_file = File::create(&tmp_path)?;
+		zip::compress_zstd(&zip_file, &tmp_file, TXHASHSET_ZSTD_LEVEL)?;
+	}
+	fs::rename(&tmp_path, &zst_path)?;
+
+	debug!(
+		"zip_read_compressed: {} at {}: created compressed zip file: {:?}",
+		header.hash(),
+		header.height,
+		zst_path
+	);
+	Ok(File::open(&zst_path)?)
+}
+
+// Hex encoded sha256 of the full contents of the file at the provided path.
+fn sha256_file(path: &Path) -> Result<String, Error> {
+	Ok(sha256_reader(&mut File::open(path)?)?)
+}
+
+// Hex encoded sha256 of everything read from the provided reader.
+fn sha256_reader(reader: &mut dyn Read) -> io::Result<String> {
+	let mut hasher = Sha256::default();
+	let mut buf = vec![0; 64 * 1024];
+	loop {
+		let n = reader.read(&mut buf)?;
+		if n == 0 {
+			break;
+		}
+		hasher.input(&buf[..n]);
+	}
+	Ok(to_hex(hasher.result().to_vec()))
+}
+
+// Write a manifest of "<sha256> <path>" lines for the files present in the dir
+// to the provided manifest path.
+fn write_manifest(dir: &Path, files: &[PathBuf], manifest_path: &Path) -> Result<(), Error> {
+	let mut manifest = String::new();
+	for file in files {
+		let path = dir.join(file);
+		if path.exists() {
+			manifest.push_str(&format!("{} {}\n", sha256_file(&path)?, file.display()));
+		}
+	}
+	fs::write(manifest_path, manifest)?;
+	Ok(())
+}
+
+// Verify the zip entries against the checksums in the zip manifest.
+// Entries are hashed in place, nothing is extracted.
+// Zips without a manifest (from older peers) are accepted as is.
+fn verify_manifest(
+	txhashset_data: &File,
+	entries: &HashMap<PathBuf, u64>,
+	files: &[PathBuf],
+) -> Result<(), Error> {
+	let manifest_path = PathBuf::from(TXHASHSET_MANIFEST);
+	if !entries.contains_key(&manifest_path) {
+		warn!("verify_manifest: no manifest in txhashset zip, skipping checksums");
+		return Ok(());
+	}
+	let mut manifest = String::new();
+	zip::read_files(
+		txhashset_data.try_clone()?,
+		&[manifest_path],
+		&mut |_, reader| reader.read_to_string(&mut manifest).map(|_| ()),
+	)?;
+	let checksums: HashMap<PathBuf, &str> = manifest
+		.lines()
+		.filter_map(|line| {
+			let mut parts = line.splitn(2, ' ');
+			let checksum = parts.next()?;
+			let path = parts.next()?;
+			Some((PathBuf::from(path), checksum))
+		})
+		.collect();
+	for file in files {
+		match (entries.contains_key(file), checksums.contains_key(file)) {
+			(true, false) => {
+				return Err(
+					ErrorKind::ZipChecksumMismatch(format!("{:?} not in manifest", file)).into(),
+				)
+			}
+			(false, true) => {
+				return Err(
+					ErrorKind::ZipChecksumMismatch(format!("{:?} missing from zip", file)).into(),
+				)
+			}
+			_ => {}
+		}
+	}
+
+	let mut mismatch = None;
+	zip::read_files(txhashset_data.try_clone()?, files, &mut |path, reader| {
+		let checksum = sha256_reader(reader)?;
+		if mismatch.is_none() && checksums.get(path) != Some(&checksum.as_str()) {
+			mismatch = Some(path.to_path_buf());
+		}
+		Ok(())
+	})?;
+	match mismatch {
+		Some(file) => Err(ErrorKind::ZipChecksumMismatch(format!("{:?}", file)).into()),
+		None => Ok(()),
+	}
+}
+
+// Path of the cached hash for the given zip file.
+// Shares the zip prefix so it is cleaned up along with the zip itself.
+fn zip_hash_path(zip_path: &Path) -> PathBuf {
+	zip_path.with_extension("zip.hash")
+}
+
+// Read the cached hash of the zip file if present, otherwise hash the zip
+// file contents and cache the result.
+fn cached_zip_hash(zip_path: &Path) -> Result<Hash, Error> {
+	let hash_path = zip_hash_path(zip_path);
+	if let Ok(hex) = fs::read_to_string(&hash_path) {
+		if let Ok(hash) = Hash::from_hex(hex.trim()) {
+			return Ok(hash);
+		}
+	}
+	let zip_hash = hash_file(&mut File::open(zip_path)?)?;
+	fs::write(&hash_path, zip_hash.to_hex())?;
+	Ok(zip_hash)
+}
+
+// Blake2b hash of the full contents of the provided file, read in chunks.
+fn hash_file(file: &mut File) -> Result<Hash, Error> {
+	let mut hasher = HashWriter::default();
+	let mut buf = vec![0; 64 * 1024];
+	loop {
+		let n = file.read(&mut buf)?;
+		if n == 0 {
+			break;
+		}
+		hasher
+			.write_fixed_bytes(&buf[..n])
+			.map_err(ErrorKind::SerErr)?;
+	}
+	Ok(hasher.into_hash())
 }
 
 // Explicit list of files to extract from our zip archive.
@@ -2432,28 +6549,298 @@ fn file_list(header: &BlockHeader) -> Vec<PathBuf> {
 	]
 }
 
+/// Verify the contents of a txhashset zip file against the provided header
+/// before committing to extracting it. Nothing is extracted.
+/// The pmmr_data.bin and pmmr_hash.bin files of each MMR in file_list(header) are
+/// required, the remaining files (leaf and prune files) are optional.
+/// Errors with ZipMissingFiles naming every required file missing from the zip.
+/// The MMR hash files must also have plausible sizes given the MMR sizes in the header.
+/// If the zip includes a manifest each file is hashed in place and checked against it,
+/// erroring with ZipChecksumMismatch on any difference.
+/// This is run by zip_write prior to extracting anything so a truncated or corrupt zip
+/// is rejected up front.
+pub fn verify_zip(txhashset_data: &File, header: &BlockHeader) -> Result<(), Error> {
+	let entries: HashMap<PathBuf, u64> = zip::list_files(txhashset_data.try_clone()?)?
+		.into_iter()
+		.collect();
+
+	let missing: Vec<String> = file_list(header)
+		.into_iter()
+		.filter(|path| path.ends_with("pmmr_data.bin") || path.ends_with("pmmr_hash.bin"))
+		.filter(|path| !entries.contains_key(path))
+		.map(|path| path.to_string_lossy().into_owned())
+		.collect();
+	if !missing.is_empty() {
+		return Err(ErrorKind::ZipMissingFiles(missing.join(", ")).into());
+	}
+
+	// Each hash file contains a 32 byte hash per (unpruned) MMR pos.
+	// Files may extend beyond the header (they are rewound on validation).
+	// Non-prunable MMRs (kernels, token issue proofs) contain at least one hash per pos.
+	let hash_files = vec![
+		(KERNEL_SUBDIR, header.kernel_mmr_size, false),
+		(OUTPUT_SUBDIR, header.output_mmr_size, true),
+		(RANGE_PROOF_SUBDIR, header.output_mmr_size, true),
+		(TOKEN_KERNEL_SUBDIR, header.token_kernel_mmr_size, false),
+		(TOKEN_OUTPUT_SUBDIR, header.token_output_mmr_size, true),
+		(TOKEN_RANGE_PROOF_SUBDIR, header.token_output_mmr_size, true),
+		(
+			TOKEN_ISSUE_PROOF_SUBDIR,
+			header.token_issue_proof_mmr_size,
+			false,
+		),
+	];
+	for (sub_dir, mmr_size, prunable) in hash_files {
+		let path = Path::new(sub_dir).join("pmmr_hash.bin");
+		let size = entries.get(&path).cloned().unwrap_or(0);
+		let plausible = size % Hash::LEN as u64 == 0
+			&& if prunable {
+				mmr_size == 0 || size > 0
+			} else {
+				size >= mmr_size * Hash::LEN as u64
+			};
+		if !plausible {
+			return Err(ErrorKind::ZipImplausibleSize(
+				size,
+				path.to_string_lossy().into_owned(),
+				mmr_size,
+			)
+			.into());
+		}
+	}
+
+	verify_manifest(txhashset_data, &entries, &file_list(header))?;
+
+	debug!("verify_zip: verified zip contents for {}", header.hash());
+	Ok(())
+}
+
 /// Extract the txhashset data from a zip file and writes the content into the
 /// txhashset storage dir
 pub fn zip_write(
 	root_dir: PathBuf,
 	txhashset_data: File,
 	header: &BlockHeader,
+) -> Result<(), Error> {
+	zip_write_with_progress(root_dir, txhashset_data, header, &mut |_, _| {})
+}
+
+/// As zip_write, reporting (bytes extracted, total bytes) to the provided progress
+/// callback while the zip is being extracted.
+/// The total is the (uncompressed) size of the files in file_list(header) present in the zip.
+pub fn zip_write_with_progress(
+	root_dir: PathBuf,
+	txhashset_data: File,
+	header: &BlockHeader,
+	progress: &mut dyn FnMut(u64, u64),
+) -> Result<(), Error> {
+	zip_write_with_limits(
+		root_dir,
+		txhashset_data,
+		header,
+		&ZipWriteLimits::default(),
+		progress,
+	)
+}
+
+/// Limits enforced when extracting a txhashset zip received from a peer
+/// so a maliciously crafted (highly compressed) archive cannot fill the disk.
+#[derive(Clone, Copy, Debug)]
+pub struct ZipWriteLimits {
+	/// Max uncompressed size of any single file extracted from the zip.
+	pub max_entry_size: u64,
+	/// Max uncompressed size of all files extracted from the zip combined.
+	pub max_total_size: u64,
+}
+
+impl Default for ZipWriteLimits {
+	fn default() -> ZipWriteLimits {
+		ZipWriteLimits {
+			max_entry_size: 16 * 1024 * 1024 * 1024,
+			max_total_size: 64 * 1024 * 1024 * 1024,
+		}
+	}
+}
+
+// Allowance for zip headers (and the manifest) on top of max_total_size when
+// decompressing a zstd compressed archive.
+const ZIP_OVERHEAD_ALLOWANCE: u64 = 64 * 1024 * 1024;
+
+/// As zip_write_with_progress, enforcing the provided limits.
+/// Any entry in the zip with a path escaping the txhashset dir (absolute or containing "..")
+/// causes the whole zip to be rejected, as does any file exceeding the size limits.
+/// Nothing is written to the txhashset dir on failure.
+pub fn zip_write_with_limits(
+	root_dir: PathBuf,
+	txhashset_data: File,
+	header: &BlockHeader,
+	limits: &ZipWriteLimits,
+	progress: &mut dyn FnMut(u64, u64),
 ) -> Result<(), Error> {
 	debug!("zip_write on path: {:?}", root_dir);
 	let txhashset_path = root_dir.join(TXHASHSET_SUBDIR);
 	fs::create_dir_all(&txhashset_path)?;
 
+	// Extract to a staging dir first so nothing is committed to the txhashset dir
+	// unless every file is extracted successfully (within the size limits).
+	let staging_path = root_dir.join(format!(
+		"{}_staging_{}",
+		TXHASHSET_SUBDIR,
+		header.hash().to_string()
+	));
+	if staging_path.exists() {
+		fs::remove_dir_all(&staging_path)?;
+	}
+
+	// A zstd compressed archive (see zip_read_compressed) is decompressed to a
+	// plain zip alongside the txhashset before extracting.
+	let mut txhashset_data = txhashset_data;
+	let mut decompressed_path = None;
+	let mut res = Ok(());
+	if zip::is_zstd(&mut txhashset_data)? {
+		let zip_path = root_dir.join(format!(
+			"{}_zstd_{}.zip",
+			TXHASHSET_SUBDIR,
+			header.hash().to_string()
+		));
+		res = File::create(&zip_path)
+			.and_then(|zip_file| {
+				zip::decompress_zstd_with_limit(
+					&txhashset_data,
+					&zip_file,
+					limits.max_total_size.saturating_add(ZIP_OVERHEAD_ALLOWANCE),
+				)
+			})
+			.and_then(|_| File::open(&zip_path))
+			.map(|zip_file| txhashset_data = zip_file)
+			.map_err(zip_extract_err);
+		decompressed_path = Some(zip_path);
+	}
+
+	let res = res.and_then(|_| {
+		extract_zip(
+			txhashset_data,
+			&staging_path,
+			&txhashset_path,
+			header,
+			limits,
+			progress,
+		)
+	});
+
+	if staging_path.exists() {
+		if let Err(e) = fs::remove_dir_all(&staging_path) {
+			warn!(
+				"zip_write: failed to remove staging dir {:?}, err: {}",
+				staging_path, e
+			);
+		}
+	}
+	if let Some(zip_path) = decompressed_path {
+		if let Err(e) = fs::remove_file(&zip_path) {
+			warn!(
+				"zip_write: failed to remove decompressed zip {:?}, err: {}",
+				zip_path, e
+			);
+		}
+	}
+	res
+}
+
+// Check the zip entries are safe to extract, extract them to the staging dir,
+// verify them against the manifest and finally move them to the txhashset dir.
+fn extract_zip(
+	txhashset_data: File,
+	staging_path: &Path,
+	txhashset_path: &Path,
+	header: &BlockHeader,
+	limits: &ZipWriteLimits,
+	progress: &mut dyn FnMut(u64, u64),
+) -> Result<(), Error> {
 	// Explicit list of files to extract from our zip archive.
 	let files = file_list(header);
 
 	// We expect to see *exactly* the paths listed above.
 	// No attempt is made to be permissive or forgiving with "alternative" paths.
 	// These are the *only* files we will attempt to extract from the zip file.
-	// If any of these are missing we will attempt to continue as some are potentially optional.
-	zip::extract_files(txhashset_data, &txhashset_path, files)?;
+	// The MMR data and hash files are required (see verify_zip), if any of the
+	// others are missing we will attempt to continue as they are potentially optional.
+	let mut extract_list = files.clone();
+	extract_list.push(PathBuf::from(TXHASHSET_MANIFEST));
+
+	// Reject the zip outright if it contains anything suspicious, even if we
+	// would not extract the offending entry. The sizes are those claimed by the zip,
+	// these are enforced again during extraction.
+	let entries = zip::list_files(txhashset_data.try_clone()?)?;
+	let mut total = 0;
+	let mut extract_total: u64 = 0;
+	for (path, size) in &entries {
+		let escapes = path.components().any(|c| match c {
+			Component::Normal(_) | Component::CurDir => false,
+			Component::ParentDir | Component::RootDir | Component::Prefix(_) => true,
+		});
+		if escapes {
+			return Err(ErrorKind::ZipUnsafePath(format!("{:?}", path)).into());
+		}
+		if extract_list.contains(path) {
+			if *size > limits.max_entry_size {
+				return Err(ErrorKind::ZipTooLarge(format!(
+					"{:?} is {} bytes, limit {} bytes",
+					path, size, limits.max_entry_size
+				))
+				.into());
+			}
+			extract_total = extract_total.saturating_add(*size);
+			if files.contains(path) {
+				total += size;
+			}
+		}
+	}
+	if extract_total > limits.max_total_size {
+		return Err(ErrorKind::ZipTooLarge(format!(
+			"total {} bytes, limit {} bytes",
+			extract_total, limits.max_total_size
+		))
+		.into());
+	}
+
+	// Reject a truncated or corrupt zip before extracting anything.
+	// Extraction reads the same entries (by name) that were hashed against the
+	// manifest so the extracted files are not hashed a second time.
+	verify_zip(&txhashset_data, header)?;
+
+	zip::extract_files_with_limits(
+		txhashset_data,
+		staging_path,
+		extract_list,
+		limits.max_entry_size,
+		limits.max_total_size,
+		&mut |done| progress(cmp::min(done, total), total),
+	)
+	.map_err(zip_extract_err)?;
+
+	for file in &files {
+		let src = staging_path.join(file);
+		if src.exists() {
+			let dst = txhashset_path.join(file);
+			if let Some(parent) = dst.parent() {
+				fs::create_dir_all(parent)?;
+			}
+			fs::rename(&src, &dst)?;
+		}
+	}
 	Ok(())
 }
 
+// Map size limit errors from the zip utils to ZipTooLarge.
+fn zip_extract_err(e: io::Error) -> Error {
+	if zip::is_size_limit_exceeded(&e) {
+		ErrorKind::ZipTooLarge(e.to_string()).into()
+	} else {
+		e.into()
+	}
+}
+
 /// Overwrite txhashset folders in "to" folder with "from" folder
 pub fn txhashset_replace(from: PathBuf, to: PathBuf) -> Result<(), Error> {
 	debug!("txhashset_replace: move from {:?} to {:?}", from, to);
@@ -2524,3 +6911,125 @@ fn token_input_pos_to_rewind(
 	}
 	Ok(bitmap)
 }
+
+/// Build a spent index from a legacy input bitmap, ordered like the provided block inputs.
+/// Returns None if the bitmap does not match the inputs or a pos cannot be resolved
+/// to a commitment (via commit_at) or to a header (via the header MMR).
+fn spent_index_from_bitmap<C, F>(
+	bitmap: &Bitmap,
+	inputs: &[Commitment],
+	commit_at: C,
+	mmr_size: F,
+	header_pmmr: &PMMRHandle<BlockHeader>,
+	batch: &Batch<'_>,
+) -> Result<Option<Vec<CommitPos>>, Error>
+where
+	C: Fn(u64) -> Option<Commitment>,
+	F: Fn(&BlockHeader) -> u64,
+{
+	if bitmap.cardinality() != inputs.len() as u64 {
+		return Ok(None);
+	}
+	let mut spent = HashMap::new();
+	for pos in bitmap.iter() {
+		let pos = pos as u64;
+		let commit = match commit_at(pos) {
+			Some(commit) => commit,
+			None => return Ok(None),
+		};
+		let header = match find_header_by_mmr_pos(header_pmmr, batch, pos, &mmr_size)? {
+			Some(header) => header,
+			None => return Ok(None),
+		};
+		spent.insert(
+			commit,
+			CommitPos {
+				pos,
+				height: header.height,
+			},
+		);
+	}
+	Ok(inputs
+		.iter()
+		.map(|commit| {
+			spent.get(commit).map(|x| CommitPos {
+				pos: x.pos,
+				height: x.height,
+			})
+		})
+		.collect())
+}
+
+/// Block (hash and height) at the height from a kernel height index lookup, confirmed
+/// by checking the kernels in that block (the MMR positions between the previous and
+/// this header MMR size, as returned by the provided size fn) for a match.
+/// Returns None if nothing is indexed, the height is beyond the header MMR or no kernel
+/// in the block matches (a stale index entry).
+fn block_from_kernel_height_index<F, M>(
+	height: Option<u64>,
+	header_pmmr: &PMMRHandle<BlockHeader>,
+	batch: &Batch<'_>,
+	mmr_size: F,
+	matches: M,
+) -> Result<Option<(Hash, u64)>, Error>
+where
+	F: Fn(&BlockHeader) -> u64,
+	M: Fn(u64) -> Option<bool>,
+{
+	let height = match height {
+		Some(height) if height < pmmr::n_leaves(header_pmmr.last_pos) => height,
+		_ => return Ok(None),
+	};
+	let hash = header_pmmr.get_header_hash_by_height(height)?;
+	let header = batch.get_block_header(&hash)?;
+	let prev_size = if height == 0 {
+		0
+	} else {
+		mmr_size(&batch.get_previous_header(&header)?)
+	};
+	let found = (prev_size + 1..=mmr_size(&header))
+		.filter(|x| pmmr::is_leaf(*x))
+		.any(|pos| matches(pos).unwrap_or(false));
+	if found {
+		Ok(Some((hash, height)))
+	} else {
+		Ok(None)
+	}
+}
+
+/// Binary search the header MMR for the first header whose MMR size (as returned by
+/// the provided size fn) includes the given MMR pos.
+/// Returns None if no header in the header MMR covers the pos.
+fn find_header_by_mmr_pos<F>(
+	header_pmmr: &PMMRHandle<BlockHeader>,
+	batch: &Batch<'_>,
+	pos: u64,
+	mmr_size: F,
+) -> Result<Option<BlockHeader>, Error>
+where
+	F: Fn(&BlockHeader) -> u64,
+{
+	let n_headers = pmmr::n_leaves(header_pmmr.last_pos);
+	if n_headers == 0 {
+		return Ok(None);
+	}
+	let mut low = 0;
+	let mut high = n_headers - 1;
+	while low < high {
+		let mid = low + (high - low) / 2;
+		let hash = header_pmmr.get_header_hash_by_height(mid)?;
+		let header = batch.get_block_header(&hash)?;
+		if mmr_size(&header) >= pos {
+			high = mid;
+		} else {
+			low = mid + 1;
+		}
+	}
+	let hash = header_pmmr.get_header_hash_by_height(low)?;
+	let header = batch.get_block_header(&hash)?;
+	if mmr_size(&header) >= pos {
+		Ok(Some(header))
+	} else {
+		Ok(None)
+	}
+}
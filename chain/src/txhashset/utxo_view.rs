@@ -21,7 +21,7 @@ use crate::core::core::{TokenInput, TokenIssueProof, TokenOutput, TokenOutputIde
 use crate::core::global;
 use crate::error::{Error, ErrorKind};
 use crate::store::Batch;
-use crate::util::secp::pedersen::RangeProof;
+use crate::util::secp::pedersen::{Commitment, RangeProof};
 use grin_store::pmmr::PMMRBackend;
 
 /// Readonly view of the UTXO set (based on output MMR).
@@ -100,6 +100,24 @@ impl<'a> UTXOView<'a> {
 		Ok(())
 	}
 
+	/// Check every input (and token input) in the block against the current UTXO set.
+	/// Returns the commitments of all inputs that do not spend a currently unspent output,
+	/// rather than stopping at the first failure.
+	pub fn unspendable_inputs(&self, block: &Block, batch: &Batch<'_>) -> Vec<Commitment> {
+		let mut unspendable = vec![];
+		for input in block.inputs() {
+			if self.validate_input(input, batch).is_err() {
+				unspendable.push(input.commitment());
+			}
+		}
+		for input in block.token_inputs() {
+			if self.validate_token_input(input, batch).is_err() {
+				unspendable.push(input.commitment());
+			}
+		}
+		unspendable
+	}
+
 	// Input is valid if it is spending an (unspent) output
 	// that currently exists in the output MMR.
 	// Compare against the entry in output MMR at the expected pos.
@@ -251,7 +269,7 @@ impl<'a> UTXOView<'a> {
 			let header = batch.get_block_header(&hash)?;
 			Ok(header)
 		} else {
-			Err(ErrorKind::Other("get header by height".to_string()).into())
+			Err(ErrorKind::HeaderByHeight.into())
 		}
 	}
 }
@@ -15,13 +15,16 @@
 //! Base types that the block chain pipeline requires.
 
 use chrono::prelude::{DateTime, Utc};
+use croaring::Bitmap;
 use std::sync::Arc;
 
 use crate::core::core::hash::{Hash, Hashed, ZERO_HASH};
-use crate::core::core::{Block, BlockHeader, HeaderVersion};
+use crate::core::core::{Block, BlockHeader, HeaderVersion, TokenKey};
 use crate::core::pow::Difficulty;
 use crate::core::ser::{self, PMMRIndexHashable, Readable, Reader, Writeable, Writer};
 use crate::error::{Error, ErrorKind};
+use crate::keychain::BlindingFactor;
+use crate::util::secp::pedersen::Commitment;
 use crate::util::RwLock;
 
 use crate::core::global;
@@ -207,7 +210,7 @@ impl TxHashsetWriteStatus for SyncState {
 }
 
 /// A helper for the various txhashset MMR roots.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct TxHashSetRoots {
 	/// Output roots
 	pub output_roots: OutputRoots,
@@ -264,8 +267,212 @@ impl TxHashSetRoots {
 	}
 }
 
-/// A helper for the various output roots.
+/// Breakdown of the terms involved in validating the kernel sums against a header.
+/// Useful when debugging a kernel sum mismatch to see which term is off.
+/// The sums balance when utxo_sum equals kernel_sum_plus_offset.
+#[derive(Debug, Clone)]
+pub struct KernelSumComponents {
+	/// Sum of all unspent output commitments (and the overage commitment).
+	pub utxo_sum: Commitment,
+	/// Sum of all kernel excesses.
+	pub kernel_sum: Commitment,
+	/// Sum of all kernel excesses plus the commitment to the total kernel offset.
+	pub kernel_sum_plus_offset: Commitment,
+	/// The claimed total overage (negative total reward) at the header.
+	pub overage: i64,
+	/// The total kernel offset committed to in the header.
+	pub total_kernel_offset: BlindingFactor,
+}
+
+impl KernelSumComponents {
+	/// Do the kernel sums balance?
+	pub fn is_balanced(&self) -> bool {
+		self.utxo_sum == self.kernel_sum_plus_offset
+	}
+}
+
+/// Sizes of the various txhashset MMRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmrSizes {
+	/// Output MMR size
+	pub output: u64,
+	/// Range Proof MMR size
+	pub rproof: u64,
+	/// Kernel MMR size
+	pub kernel: u64,
+	/// Token Output MMR size
+	pub token_output: u64,
+	/// Token Range Proof MMR size
+	pub token_rproof: u64,
+	/// Token Issue Proof MMR size
+	pub token_issue_proof: u64,
+	/// Token Kernel MMR size
+	pub token_kernel: u64,
+}
+
+impl MmrSizes {
+	/// MMR sizes committed to in the provided header.
+	pub fn from_header(header: &BlockHeader) -> MmrSizes {
+		MmrSizes {
+			output: header.output_mmr_size,
+			rproof: header.output_mmr_size,
+			kernel: header.kernel_mmr_size,
+			token_output: header.token_output_mmr_size,
+			token_rproof: header.token_output_mmr_size,
+			token_issue_proof: header.token_issue_proof_mmr_size,
+			token_kernel: header.token_kernel_mmr_size,
+		}
+	}
+}
+
+/// Roots, sizes and head of an extension, computed together over the same
+/// (possibly uncommitted) extension state. See Extension::state_summary().
 #[derive(Debug)]
+pub struct ExtensionSummary {
+	/// Head of the extension.
+	pub head: Tip,
+	/// MMR roots.
+	pub roots: TxHashSetRoots,
+	/// MMR sizes.
+	pub sizes: MmrSizes,
+}
+
+/// Differences between the local UTXO set and a UTXO set exported by another node.
+/// See TxHashSet::diff_utxo_set().
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UtxoDiff {
+	/// Commitments in the local UTXO set but not in the export.
+	pub local_only: Vec<Commitment>,
+	/// Commitments in the export but not in the local UTXO set.
+	pub other_only: Vec<Commitment>,
+}
+
+impl UtxoDiff {
+	/// Are the two UTXO sets identical?
+	pub fn is_empty(&self) -> bool {
+		self.local_only.is_empty() && self.other_only.is_empty()
+	}
+}
+
+/// Result of TxHashSet::commitment_ever_seen().
+/// Spent outputs can only be found within the cut-through horizon (spent indexes and
+/// data beyond the horizon are pruned), so "not seen" is only definitive on a chain
+/// shorter than the horizon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommitmentSeen {
+	/// Currently in the UTXO set.
+	Unspent,
+	/// Spent within the horizon.
+	Spent,
+	/// Never seen (the entire chain is within the horizon).
+	NotSeen,
+	/// Not seen within the horizon, it may have been spent beyond the horizon.
+	UnknownBeyondHorizon,
+}
+
+/// Result of TxHashSet::lookup_output().
+#[derive(Debug)]
+pub enum OutputStatus {
+	/// In the UTXO set at the given pos and height.
+	Unspent(CommitPos),
+	/// The commitment is not in the output_pos index.
+	NotFound,
+	/// The commitment is indexed but the output MMR no longer holds a matching output
+	/// at the indexed pos (spent, or the commitment was reused with different features).
+	Spent,
+}
+
+/// Supply of a single token type, see TxHashSet::token_supply().
+///
+/// Token amounts are hidden in Pedersen commitments so there is no plain value to sum.
+/// A token can only be issued once and token transactions must balance (no token is
+/// ever burnt), so the circulating supply is exactly the amount committed to by the
+/// issue proof. Only the issuer (who knows the blinding factor) can open it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenSupply {
+	/// The token type.
+	pub token_type: TokenKey,
+	/// Commitment to the issued (and therefore circulating) amount.
+	/// None if the token has not been issued.
+	pub issue_commit: Option<Commitment>,
+	/// Number of unspent token outputs of this type.
+	pub unspent_outputs: u64,
+}
+
+/// Report of a (read only) consistency check of an output_pos index against the
+/// corresponding output MMR, see TxHashSet::check_output_pos_index().
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexCheckReport {
+	/// Number of index entries checked.
+	pub checked: u64,
+	/// Index entries pointing to a pos that is not an unspent output in the MMR.
+	pub stale: u64,
+	/// Index entries pointing to an unspent output with a different commitment.
+	pub mismatched: u64,
+	/// Unspent outputs in the MMR with no index entry pointing to them.
+	pub missing: u64,
+}
+
+impl IndexCheckReport {
+	/// Is the index consistent with the MMR (no stale, mismatched or missing entries)?
+	pub fn is_consistent(&self) -> bool {
+		self.stale == 0 && self.mismatched == 0 && self.missing == 0
+	}
+}
+
+/// Statistics of a compaction run, see TxHashSet::compact().
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionStats {
+	/// Bytes freed from the output MMR hash and data files.
+	pub output_bytes_freed: u64,
+	/// Bytes freed from the rangeproof MMR hash and data files.
+	pub rproof_bytes_freed: u64,
+	/// Bytes freed from the token output MMR hash and data files.
+	pub token_output_bytes_freed: u64,
+	/// Bytes freed from the token rangeproof MMR hash and data files.
+	pub token_rproof_bytes_freed: u64,
+	/// Total number of MMR positions (hashes) removed across all compacted MMRs.
+	pub positions_removed: u64,
+}
+
+/// Report of the checks run (and repairs applied) by TxHashSet::verify_and_repair().
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RepairReport {
+	/// Description of each check that was run.
+	pub checked: Vec<String>,
+	/// Description of each repair that was applied.
+	pub repaired: Vec<String>,
+}
+
+impl RepairReport {
+	/// Was anything repaired?
+	pub fn is_repaired(&self) -> bool {
+		!self.repaired.is_empty()
+	}
+}
+
+/// Peak positions of the various txhashset MMRs at their current size.
+/// Peaks are listed in bagging order (left to right).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MmrPeakPositions {
+	/// Output peak positions
+	pub output: Vec<u64>,
+	/// Range Proof peak positions
+	pub rproof: Vec<u64>,
+	/// Kernel peak positions
+	pub kernel: Vec<u64>,
+	/// Token Output peak positions
+	pub token_output: Vec<u64>,
+	/// Token Range Proof peak positions
+	pub token_rproof: Vec<u64>,
+	/// Token Issue Proof peak positions
+	pub token_issue_proof: Vec<u64>,
+	/// Token Kernel peak positions
+	pub token_kernel: Vec<u64>,
+}
+
+/// A helper for the various output roots.
+#[derive(Debug, PartialEq)]
 pub struct OutputRoots {
 	/// The output PMMR root
 	pub pmmr_root: Hash,
@@ -295,7 +502,55 @@ impl OutputRoots {
 	/// together with the size of the output PMMR (for consistency with existing PMMR impl).
 	/// H(pmmr_size | pmmr_root | bitmap_root)
 	fn merged_root(&self, header: &BlockHeader) -> Hash {
-		(self.pmmr_root, self.bitmap_root).hash_with_index(header.output_mmr_size)
+		self.combined_root(header.output_mmr_size)
+	}
+
+	/// The canonical combination of the output PMMR root and the bitmap accumulator root,
+	/// exactly as committed to by headers using the merged root (see root()).
+	/// H(output_mmr_size | pmmr_root | bitmap_root), the output MMR size is big-endian.
+	/// Note: headers prior to version 3 commit to the output PMMR root alone.
+	pub fn combined_root(&self, output_mmr_size: u64) -> Hash {
+		(self.pmmr_root, self.bitmap_root).hash_with_index(output_mmr_size)
+	}
+}
+
+/// Snapshot of the pruned (compacted) output positions at a point in time.
+/// See TxHashSet::prune_state_snapshot().
+#[derive(Debug, Clone)]
+pub struct PruneSnapshot {
+	/// Output MMR size at the time of the snapshot.
+	pub output_mmr_size: u64,
+	pruned: Bitmap,
+}
+
+impl PruneSnapshot {
+	/// Create a snapshot from the bitmap of pruned output leaf positions.
+	pub fn new(output_mmr_size: u64, pruned: Bitmap) -> PruneSnapshot {
+		PruneSnapshot {
+			output_mmr_size,
+			pruned,
+		}
+	}
+
+	/// Number of pruned output positions in this snapshot.
+	pub fn pruned_count(&self) -> u64 {
+		self.pruned.cardinality()
+	}
+
+	/// Is the output at pos pruned in this snapshot?
+	pub fn is_pruned(&self, pos: u64) -> bool {
+		self.pruned.contains(pos as u32)
+	}
+
+	/// Output positions pruned in the other (later) snapshot but not in this one,
+	/// i.e. those removed by compaction between the two snapshots.
+	pub fn diff(&self, other: &PruneSnapshot) -> Vec<u64> {
+		other
+			.pruned
+			.andnot(&self.pruned)
+			.iter()
+			.map(|pos| pos as u64)
+			.collect()
 	}
 }
 
@@ -324,6 +579,114 @@ impl Writeable for CommitPos {
 	}
 }
 
+/// Batch sizes used for rangeproof and kernel signature verification during
+/// txhashset validation. Smaller batches reduce peak memory use, larger batches
+/// better amortize the cost of setting up each batch verification.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValidationConfig {
+	/// Number of kernel (and token kernel) signatures verified per batch.
+	pub kernel_batch_size: usize,
+	/// Number of rangeproofs (and token rangeproofs) verified per batch.
+	/// Unless built with the "single-threaded" feature each thread in the rayon pool
+	/// is given a batch of this size.
+	pub rangeproof_batch_size: usize,
+}
+
+impl Default for ValidationConfig {
+	fn default() -> ValidationConfig {
+		ValidationConfig {
+			kernel_batch_size: 5_000,
+			rangeproof_batch_size: 1_000,
+		}
+	}
+}
+
+/// Progress of the (expensive) rangeproof and kernel signature verification
+/// performed during txhashset validation.
+/// Persisted periodically to the txhashset dir so validation can resume after a restart
+/// without redoing completed verification work.
+/// Progress is only valid for the header (and corresponding MMR sizes) it was recorded
+/// against and must be discarded if these differ (i.e. a different txhashset was installed).
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationProgress {
+	/// Hash of the header being validated against.
+	pub header_hash: Hash,
+	/// Output MMR size of the header.
+	pub output_mmr_size: u64,
+	/// Kernel MMR size of the header.
+	pub kernel_mmr_size: u64,
+	/// Token output MMR size of the header.
+	pub token_output_mmr_size: u64,
+	/// Token kernel MMR size of the header.
+	pub token_kernel_mmr_size: u64,
+	/// Last verified rangeproof pos.
+	pub rproof_pos: u64,
+	/// Last verified kernel pos.
+	pub kernel_pos: u64,
+	/// Last verified token rangeproof pos.
+	pub token_rproof_pos: u64,
+	/// Last verified token kernel pos.
+	pub token_kernel_pos: u64,
+}
+
+impl VerificationProgress {
+	/// Fresh (nothing yet verified) progress for the provided header.
+	pub fn new(header: &BlockHeader) -> VerificationProgress {
+		VerificationProgress {
+			header_hash: header.hash(),
+			output_mmr_size: header.output_mmr_size,
+			kernel_mmr_size: header.kernel_mmr_size,
+			token_output_mmr_size: header.token_output_mmr_size,
+			token_kernel_mmr_size: header.token_kernel_mmr_size,
+			rproof_pos: 0,
+			kernel_pos: 0,
+			token_rproof_pos: 0,
+			token_kernel_pos: 0,
+		}
+	}
+
+	/// Is this progress valid for the provided header?
+	/// Both the header hash and the MMR sizes must match.
+	pub fn is_valid_for(&self, header: &BlockHeader) -> bool {
+		self.header_hash == header.hash()
+			&& self.output_mmr_size == header.output_mmr_size
+			&& self.kernel_mmr_size == header.kernel_mmr_size
+			&& self.token_output_mmr_size == header.token_output_mmr_size
+			&& self.token_kernel_mmr_size == header.token_kernel_mmr_size
+	}
+}
+
+impl Readable for VerificationProgress {
+	fn read(reader: &mut dyn Reader) -> Result<VerificationProgress, ser::Error> {
+		Ok(VerificationProgress {
+			header_hash: Hash::read(reader)?,
+			output_mmr_size: reader.read_u64()?,
+			kernel_mmr_size: reader.read_u64()?,
+			token_output_mmr_size: reader.read_u64()?,
+			token_kernel_mmr_size: reader.read_u64()?,
+			rproof_pos: reader.read_u64()?,
+			kernel_pos: reader.read_u64()?,
+			token_rproof_pos: reader.read_u64()?,
+			token_kernel_pos: reader.read_u64()?,
+		})
+	}
+}
+
+impl Writeable for VerificationProgress {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.header_hash.write(writer)?;
+		writer.write_u64(self.output_mmr_size)?;
+		writer.write_u64(self.kernel_mmr_size)?;
+		writer.write_u64(self.token_output_mmr_size)?;
+		writer.write_u64(self.token_kernel_mmr_size)?;
+		writer.write_u64(self.rproof_pos)?;
+		writer.write_u64(self.kernel_pos)?;
+		writer.write_u64(self.token_rproof_pos)?;
+		writer.write_u64(self.token_kernel_pos)?;
+		Ok(())
+	}
+}
+
 /// The tip of a fork. A handle to the fork ancestry from its leaf in the
 /// blockchain tree. References the max height and the latest and previous
 /// blocks
@@ -428,7 +791,9 @@ pub trait ChainAdapter {
 /// order defined below and can be used to provide some feedback to the
 /// caller. Functions taking arguments can be called repeatedly to update
 /// those values as the processing progresses.
-pub trait TxHashsetWriteStatus {
+/// Implementations must be Sync as txhashset validation reports progress
+/// concurrently from multiple threads.
+pub trait TxHashsetWriteStatus: Sync {
 	/// First setup of the txhashset
 	fn on_setup(&self);
 	/// Starting kernel validation
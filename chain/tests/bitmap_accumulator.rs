@@ -186,3 +186,30 @@ fn test_bitmap_accumulator() {
 	};
 	assert_eq!(accumulator.root(), expected_hash);
 }
+
+#[test]
+fn test_bitmap_accumulator_is_set() {
+	util::init_test_logger();
+
+	let mut accumulator = BitmapAccumulator::new();
+	assert!(!accumulator.is_set(0));
+
+	// 0100...0001, 1000...0000 (rebuild from 0, setting [1, 1023, 1024] true)
+	accumulator
+		.apply(vec![0], vec![1, 1023, 1024], 1025)
+		.unwrap();
+	assert!(!accumulator.is_set(0));
+	assert!(accumulator.is_set(1));
+	assert!(accumulator.is_set(1023));
+	assert!(accumulator.is_set(1024));
+	assert!(!accumulator.is_set(1025));
+
+	// Bits beyond the last chunk are unset.
+	assert!(!accumulator.is_set(4096));
+
+	// 0100...0001, 0100...0000 (rebuild from 1024, setting [1025] true)
+	accumulator.apply(vec![1024], vec![1025], 1026).unwrap();
+	assert!(accumulator.is_set(1023));
+	assert!(!accumulator.is_set(1024));
+	assert!(accumulator.is_set(1025));
+}
@@ -12,17 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// Not every test crate including this module uses every helper.
+#![allow(dead_code)]
+
+use self::chain::store::Batch;
+use self::chain::txhashset::{self, ExtensionPair, PMMRHandle};
 use self::chain::types::NoopAdapter;
 use self::chain::types::Options;
 use self::chain::Chain;
 use self::core::core::hash::Hashed;
 use self::core::core::verifier_cache::LruVerifierCache;
-use self::core::core::Block;
+use self::core::core::{Block, BlockHeader, KernelFeatures, TokenKernelFeatures, Transaction};
 use self::core::genesis;
 use self::core::global::ChainTypes;
-use self::core::libtx::{self, reward};
+use self::core::libtx::build::{self, Append};
+use self::core::libtx::{self, reward, ProofBuilder};
+use self::core::ser::ProtocolVersion;
 use self::core::{consensus, global, pow};
-use self::keychain::{ExtKeychainPath, Keychain};
+use self::keychain::{ExtKeychainPath, Identifier, Keychain};
 use self::util::RwLock;
 use chrono::Duration;
 use grin_chain as chain;
@@ -49,6 +56,65 @@ pub fn init_chain(dir_name: &str, genesis: Block) -> Chain {
 	.unwrap()
 }
 
+/// Open the header MMR of the chain in chain_dir directly (without the chain itself).
+pub fn open_header_pmmr(chain_dir: &str) -> PMMRHandle<BlockHeader> {
+	PMMRHandle::new(
+		chain_dir,
+		"header",
+		"header_head",
+		false,
+		ProtocolVersion(1),
+		None,
+	)
+	.unwrap()
+}
+
+/// Run inner against a readonly extension of the chain txhashset at the current head.
+/// Takes (and releases) the header MMR and txhashset write locks, so any headers
+/// needed inside inner must be fetched from the chain beforehand.
+pub fn extending_readonly<F, T>(chain: &Chain, inner: F) -> Result<T, chain::Error>
+where
+	F: FnOnce(&mut ExtensionPair<'_>, &Batch<'_>) -> Result<T, chain::Error>,
+{
+	let header_pmmr = chain.header_pmmr();
+	let txhashset = chain.txhashset();
+	let mut header_pmmr = header_pmmr.write();
+	let mut txhashset = txhashset.write();
+	txhashset::extending_readonly(&mut header_pmmr, &mut txhashset, inner)
+}
+
+/// Build a tx spending the coinbase output of key_id_coinbase to key_id_output
+/// (less a fee of 20000), along with the given token inputs and outputs.
+/// The tx has a plain token kernel if there are any token elements.
+pub fn spend_coinbase_tx<'a, K>(
+	keychain: &'a K,
+	key_id_coinbase: Identifier,
+	key_id_output: Identifier,
+	token_elems: Vec<Box<Append<K, ProofBuilder<'a, K>>>>,
+) -> Transaction
+where
+	K: Keychain,
+{
+	let token_features = if token_elems.is_empty() {
+		None
+	} else {
+		Some(TokenKernelFeatures::PlainToken)
+	};
+	let mut elems = vec![
+		build::coinbase_input(consensus::REWARD, key_id_coinbase),
+		build::output(consensus::REWARD - 20000, key_id_output),
+	];
+	elems.extend(token_elems);
+	build::transaction(
+		KernelFeatures::Plain { fee: 20000 },
+		token_features,
+		elems,
+		keychain,
+		&ProofBuilder::new(keychain),
+	)
+	.unwrap()
+}
+
 /// Build genesis block with reward (non-empty, like we have in mainnet).
 fn genesis_block<K>(keychain: &K) -> Block
 where
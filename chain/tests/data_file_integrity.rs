@@ -12,13 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use self::chain::store::ChainStore;
+use self::chain::txhashset::{self, DurabilityMode, TxHashSet};
+use self::chain::types::Tip;
+use self::chain::ErrorKind;
+use self::core::core::hash::Hashed;
 use self::core::genesis;
+use grin_chain as chain;
 use grin_core as core;
 use grin_util as util;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Arc;
 
 mod chain_test_helper;
 
-use self::chain_test_helper::{clean_output_dir, init_chain, mine_chain};
+use self::chain_test_helper::{clean_output_dir, init_chain, mine_chain, open_header_pmmr};
 
 #[test]
 fn data_files() {
@@ -44,3 +53,300 @@ fn data_files() {
 	// Cleanup chain directory
 	clean_output_dir(chain_dir);
 }
+
+#[test]
+fn data_files_rproof_size_mismatch() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_df_rproof";
+	clean_output_dir(chain_dir);
+
+	// Mine a few blocks on a new chain.
+	let header = {
+		let chain = mine_chain(chain_dir, 4);
+		chain.head_header().unwrap()
+	};
+
+	// Corrupt the rangeproof MMR by truncating a single hash from the hash file.
+	{
+		let hash_file = Path::new(chain_dir)
+			.join("txhashset")
+			.join("rangeproof")
+			.join("pmmr_hash.bin");
+		let file = OpenOptions::new().write(true).open(hash_file).unwrap();
+		let len = file.metadata().unwrap().len();
+		file.set_len(len - 32).unwrap();
+	}
+
+	// The output MMR still matches the header but the rangeproof MMR does not.
+	{
+		let store = Arc::new(ChainStore::new(chain_dir).unwrap());
+		let mut txhashset = TxHashSet::open(chain_dir.to_string(), store, None).unwrap();
+		let mut header_pmmr = open_header_pmmr(chain_dir);
+		let res = txhashset::extending_readonly(&mut header_pmmr, &mut txhashset, |ext, _| {
+			ext.extension.validate_sizes(&header)
+		});
+		assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidMMRSize);
+	}
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn data_files_repair_rproof_mmr() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_df_rproof_repair";
+	clean_output_dir(chain_dir);
+
+	// Mine a few blocks on a new chain.
+	let header = {
+		let chain = mine_chain(chain_dir, 4);
+		chain.head_header().unwrap()
+	};
+
+	// Delete the last rangeproof leaf by truncating its hash from the hash file.
+	{
+		let hash_file = Path::new(chain_dir)
+			.join("txhashset")
+			.join("rangeproof")
+			.join("pmmr_hash.bin");
+		let file = OpenOptions::new().write(true).open(hash_file).unwrap();
+		let len = file.metadata().unwrap().len();
+		file.set_len(len - 32).unwrap();
+	}
+
+	// Repair the rangeproof MMR from the output MMR (and full blocks).
+	{
+		let store = Arc::new(ChainStore::new(chain_dir).unwrap());
+		let mut txhashset = TxHashSet::open(chain_dir.to_string(), store.clone(), None).unwrap();
+		let mut header_pmmr = open_header_pmmr(chain_dir);
+		let mut batch = store.batch().unwrap();
+		txhashset::extending(
+			&mut header_pmmr,
+			&mut txhashset,
+			&mut batch,
+			|ext, batch| {
+				ext.extension.repair_rproof_mmr(batch)?;
+				ext.extension.validate_sizes(&header)?;
+				ext.extension.validate_roots(&header)
+			},
+		)
+		.unwrap();
+		batch.commit().unwrap();
+	}
+
+	// Now reload the chain from the repaired data files and check it is (fully) valid.
+	{
+		let chain = init_chain(chain_dir, genesis::genesis_dev());
+		chain.validate(false).unwrap();
+		assert_eq!(chain.head().unwrap().height, 3);
+	}
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn data_files_verify_and_repair() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_df_verify_repair";
+	clean_output_dir(chain_dir);
+
+	// Mine a few blocks on a new chain, the head will be moved back to height 3.
+	let (header, commits) = {
+		let chain = mine_chain(chain_dir, 5);
+		let header = chain.get_header_by_height(3).unwrap();
+		let commits: Vec<_> = (0..=3)
+			.flat_map(|height| {
+				let hash = chain.get_header_by_height(height).unwrap().hash();
+				chain.get_block(&hash).unwrap().outputs().to_vec()
+			})
+			.map(|out| out.commitment())
+			.collect();
+		(header, commits)
+	};
+
+	// The output and rangeproof MMRs disagree beyond the head (an interrupted sync).
+	{
+		let hash_file = Path::new(chain_dir)
+			.join("txhashset")
+			.join("rangeproof")
+			.join("pmmr_hash.bin");
+		let file = OpenOptions::new().write(true).open(hash_file).unwrap();
+		let len = file.metadata().unwrap().len();
+		file.set_len(len - 32).unwrap();
+	}
+	{
+		let store = ChainStore::new(chain_dir).unwrap();
+		let batch = store.batch().unwrap();
+		batch.save_body_head(&Tip::from_header(&header)).unwrap();
+		batch.commit().unwrap();
+	}
+
+	{
+		let store = Arc::new(ChainStore::new(chain_dir).unwrap());
+		let mut txhashset = TxHashSet::open(chain_dir.to_string(), store.clone(), None).unwrap();
+		let mut header_pmmr = open_header_pmmr(chain_dir);
+		let mut batch = store.batch().unwrap();
+
+		// And the output_pos index is missing every entry.
+		let keys: Vec<_> = batch.output_pos_iter().unwrap().map(|(k, _)| k).collect();
+		for key in keys {
+			batch.delete(&key).unwrap();
+		}
+
+		let report = txhashset
+			.verify_and_repair(&mut header_pmmr, &mut batch)
+			.unwrap();
+		assert!(report.is_repaired());
+		assert!(report
+			.checked
+			.iter()
+			.any(|x| x.starts_with("output mmr size")));
+		assert_eq!(
+			report.repaired,
+			vec![
+				format!(
+					"rewound mmrs to head {} at {}",
+					header.hash(),
+					header.height
+				),
+				"rebuilt output_pos index".to_string(),
+			]
+		);
+		batch.commit().unwrap();
+
+		// The MMRs are back at the head and the output_pos index covers every output.
+		txhashset.roots().validate(&header).unwrap();
+		let batch = store.batch().unwrap();
+		for commit in &commits {
+			assert!(batch.get_output_pos(commit).is_ok());
+		}
+
+		// Nothing left to repair.
+		let mut batch = store.batch().unwrap();
+		let report = txhashset
+			.verify_and_repair(&mut header_pmmr, &mut batch)
+			.unwrap();
+		assert!(!report.is_repaired());
+	}
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn data_files_buffered_durability() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_df_buffered";
+	clean_output_dir(chain_dir);
+
+	// Mine a few blocks on a new chain, keeping the blocks above height 1 to re-apply.
+	let (fork_header, header, blocks) = {
+		let chain = mine_chain(chain_dir, 4);
+		let fork_header = chain.get_header_by_height(1).unwrap();
+		let header = chain.head_header().unwrap();
+		let blocks: Vec<_> = (2..=3)
+			.map(|height| {
+				let hash = chain.get_header_by_height(height).unwrap().hash();
+				chain.get_block(&hash).unwrap()
+			})
+			.collect();
+		(fork_header, header, blocks)
+	};
+
+	// Rewind and re-apply the blocks, committing the extension without an fsync.
+	{
+		let store = Arc::new(ChainStore::new(chain_dir).unwrap());
+		let mut txhashset = TxHashSet::open(chain_dir.to_string(), store.clone(), None).unwrap();
+		assert_eq!(txhashset.durability_mode(), DurabilityMode::Durable);
+		txhashset.set_durability_mode(DurabilityMode::Buffered);
+		assert_eq!(txhashset.durability_mode(), DurabilityMode::Buffered);
+
+		let mut header_pmmr = open_header_pmmr(chain_dir);
+		let mut batch = store.batch().unwrap();
+		txhashset::extending(
+			&mut header_pmmr,
+			&mut txhashset,
+			&mut batch,
+			|ext, batch| {
+				ext.extension.rewind(&fork_header, batch)?;
+				for block in &blocks {
+					ext.extension.apply_block(block, batch)?;
+				}
+				ext.extension.validate_sizes(&header)?;
+				ext.extension.validate_roots(&header)
+			},
+		)
+		.unwrap();
+		batch.commit().unwrap();
+	}
+
+	// The buffered writes are visible once the chain is reloaded from its data files.
+	{
+		let chain = init_chain(chain_dir, genesis::genesis_dev());
+		chain.validate(false).unwrap();
+		assert_eq!(chain.head().unwrap().height, 3);
+	}
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn data_files_open_readonly_live() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_df_readonly_live";
+	clean_output_dir(chain_dir);
+
+	// The chain stays open (as a running node would) for the whole test.
+	let chain = mine_chain(chain_dir, 4);
+	let fork_header = chain.get_header_by_height(1).unwrap();
+	let header = chain.head_header().unwrap();
+	let blocks: Vec<_> = (2..=3)
+		.map(|height| {
+			let hash = chain.get_header_by_height(height).unwrap().hash();
+			chain.get_block(&hash).unwrap()
+		})
+		.collect();
+
+	let readonly = TxHashSet::open_readonly(chain_dir.to_string(), chain.store()).unwrap();
+	readonly.roots().validate(&header).unwrap();
+
+	// The node rewinds and re-applies the blocks, truncating and rewriting its files.
+	{
+		let header_pmmr = chain.header_pmmr();
+		let txhashset = chain.txhashset();
+		let mut header_pmmr = header_pmmr.write();
+		let mut txhashset = txhashset.write();
+		let mut batch = chain.store().batch().unwrap();
+		txhashset::extending(
+			&mut header_pmmr,
+			&mut txhashset,
+			&mut batch,
+			|ext, batch| {
+				ext.extension.rewind(&fork_header, batch)?;
+				for block in &blocks {
+					ext.extension.apply_block(block, batch)?;
+				}
+				ext.extension.validate_roots(&header)
+			},
+		)
+		.unwrap();
+		batch.commit().unwrap();
+	}
+
+	// The read-only view is unaffected (and reopening sees the same state).
+	readonly.roots().validate(&header).unwrap();
+	let reopened = TxHashSet::open_readonly(chain_dir.to_string(), chain.store()).unwrap();
+	reopened.roots().validate(&header).unwrap();
+	chain.validate(false).unwrap();
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use self::chain::types::{NoopAdapter, Tip};
+use self::chain::store::ChainStore;
+use self::chain::types::{CommitmentSeen, NoopAdapter, Tip};
 use self::chain::Chain;
-use self::core::core::hash::Hashed;
+use self::core::core::hash::{Hashed, ZERO_HASH};
+use self::core::core::pmmr;
 use self::core::core::verifier_cache::LruVerifierCache;
 use self::core::core::{
 	Block, BlockHeader, KernelFeatures, OutputIdentifier, TokenKernelFeatures, TokenKey,
@@ -24,19 +26,26 @@ use self::core::global::ChainTypes;
 use self::core::libtx::{self, build, ProofBuilder};
 use self::core::pow::Difficulty;
 use self::core::{consensus, global, pow};
-use self::keychain::{ExtKeychain, ExtKeychainPath, Keychain};
+use self::keychain::{ExtKeychain, ExtKeychainPath, Identifier, Keychain};
+use self::util::secp::pedersen::Commitment;
 use self::util::RwLock;
 use chrono::Duration;
 use grin_chain as chain;
 use grin_chain::{BlockStatus, ChainAdapter, Options};
 use grin_core as core;
 use grin_keychain as keychain;
+use grin_store::to_key;
 use grin_util as util;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 mod chain_test_helper;
 
-use self::chain_test_helper::{clean_output_dir, init_chain, mine_chain};
+use self::chain_test_helper::{
+	clean_output_dir, extending_readonly, init_chain, mine_chain, spend_coinbase_tx,
+};
 
 /// Adapter to retrieve last status
 pub struct StatusAdapter {
@@ -91,6 +100,61 @@ fn mine_short_chain() {
 	clean_output_dir(chain_dir);
 }
 
+#[test]
+fn mine_short_chain_mmr_sizes() {
+	let chain_dir = ".grin.mmr_sizes";
+	clean_output_dir(chain_dir);
+	let chain = mine_chain(chain_dir, 4);
+	let header = chain.head_header().unwrap();
+	{
+		// The (readonly) extension is created at the last_pos of each committed handle.
+		let sizes = extending_readonly(&chain, |ext, _| Ok(ext.extension.sizes())).unwrap();
+		assert_eq!(sizes, chain::types::MmrSizes::from_header(&header));
+		assert_eq!(sizes.rproof, header.output_mmr_size);
+		assert_eq!(sizes.token_rproof, header.token_output_mmr_size);
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn apply_blocks_matches_sequential() {
+	let chain_dir = ".grin.apply_blocks";
+	clean_output_dir(chain_dir);
+	let chain = mine_chain(chain_dir, 6);
+	let head = chain.head_header().unwrap();
+	let fork_point = chain.get_header_by_height(1).unwrap();
+	let blocks: Vec<_> = (2..=head.height)
+		.map(|height| {
+			let hash = chain.get_header_by_height(height).unwrap().hash();
+			chain.get_block(&hash).unwrap()
+		})
+		.collect();
+	{
+		// Rewind and reapply the blocks one by one.
+		let sequential = extending_readonly(&chain, |ext, batch| {
+			ext.extension.rewind(&fork_point, batch)?;
+			for b in &blocks {
+				ext.extension.apply_block(b, batch)?;
+			}
+			ext.extension.roots()
+		})
+		.unwrap();
+
+		// Rewind and reapply the blocks in a single batch.
+		let batched = extending_readonly(&chain, |ext, batch| {
+			ext.extension.rewind(&fork_point, batch)?;
+			ext.extension.apply_blocks(&blocks, batch)?;
+			ext.extension.validate_roots(&head)?;
+			ext.extension.validate_sizes(&head)?;
+			ext.extension.roots()
+		})
+		.unwrap();
+
+		assert_eq!(sequential, batched);
+	}
+	clean_output_dir(chain_dir);
+}
+
 // Convenience wrapper for processing a full block on the test chain.
 fn process_header(chain: &Chain, header: &BlockHeader) {
 	chain
@@ -212,6 +276,80 @@ fn test_block_a_block_b_block_b_fork_header_c_fork_block_c_fork() {
 	clean_output_dir(chain_dir);
 }
 
+//
+// a - b
+//  \
+//   - b' - c'
+//
+// Warm the header hash cache on a - b then reorg onto b' - c'.
+// The cached hash for height 2 must not survive the header MMR rewind.
+//
+#[test]
+fn test_header_hash_cache_after_reorg() {
+	let chain_dir = ".grin.header_hash_cache_after_reorg";
+	clean_output_dir(chain_dir);
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let kc = ExtKeychain::from_random_seed(false).unwrap();
+	let genesis = pow::mine_genesis_block().unwrap();
+	let last_status = RwLock::new(None);
+	let adapter = Arc::new(StatusAdapter::new(last_status));
+	let chain = setup_with_status_adapter(chain_dir, genesis.clone(), adapter.clone());
+
+	let block_a = prepare_block(&kc, &chain.head_header().unwrap(), &chain, 1);
+	process_block(&chain, &block_a);
+	let block_b = prepare_block(&kc, &block_a.header, &chain, 2);
+	process_block(&chain, &block_b);
+
+	// Populate the cache for every height on the original chain.
+	assert_eq!(
+		chain.get_header_by_height(1).unwrap().hash(),
+		block_a.hash()
+	);
+	assert_eq!(
+		chain.get_header_by_height(2).unwrap().hash(),
+		block_b.hash()
+	);
+	assert!(chain.get_header_by_height(3).is_err());
+
+	// Different key for b' so it cannot collide with b.
+	let block_b_fork = prepare_block_key_idx(&kc, &block_a.header, &chain, 2, 20);
+	let block_c_fork = prepare_block(&kc, &block_b_fork.header, &chain, 3);
+	process_block(&chain, &block_b_fork);
+	process_block(&chain, &block_c_fork);
+
+	assert_eq!(
+		chain.head().unwrap(),
+		Tip::from_header(&block_c_fork.header)
+	);
+
+	assert_eq!(
+		chain.get_header_by_height(1).unwrap().hash(),
+		block_a.hash()
+	);
+	assert_eq!(
+		chain.get_header_by_height(2).unwrap().hash(),
+		block_b_fork.hash()
+	);
+	assert_eq!(
+		chain.get_header_by_height(3).unwrap().hash(),
+		block_c_fork.hash()
+	);
+	{
+		let header_pmmr = chain.header_pmmr();
+		let header_pmmr = header_pmmr.read();
+		assert_eq!(
+			header_pmmr.get_header_hash_by_height(2).unwrap(),
+			block_b_fork.hash()
+		);
+		assert_eq!(
+			header_pmmr.get_header_hash_by_height(3).unwrap(),
+			block_c_fork.hash()
+		);
+	}
+
+	clean_output_dir(chain_dir);
+}
+
 //
 // a - b - c
 //  \
@@ -619,6 +757,210 @@ fn spend_rewind_spend() {
 	clean_output_dir(".grin_spend_rewind_spend");
 }
 
+#[test]
+fn kernel_pos_index_rewind() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	util::init_test_logger();
+	let chain_dir = ".grin_kernel_pos_index_rewind";
+	clean_output_dir(chain_dir);
+
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+		let mut head = chain.head_header().unwrap();
+
+		let b = prepare_block_key_idx(&kc, &head, &chain, 2, 1);
+		head = b.header.clone();
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		for n in 3..6 {
+			let b = prepare_block(&kc, &head, &chain, n);
+			head = b.header.clone();
+			chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		}
+		let fork_point = head.clone();
+
+		// The index is opt-in.
+		chain.set_kernel_pos_index(true);
+
+		let key_id_coinbase = ExtKeychainPath::new(1, 1, 0, 0, 0).to_identifier();
+		let key_id30 = ExtKeychainPath::new(1, 30, 0, 0, 0).to_identifier();
+		let tx1 = spend_coinbase_tx(&kc, key_id_coinbase, key_id30, vec![]);
+		let excess = tx1.kernels()[0].excess;
+
+		let b = prepare_block_tx(&kc, &head, &chain, 6, vec![&tx1]);
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		let pos = {
+			let txhashset = chain.txhashset();
+			let txhashset = txhashset.read();
+			let pos = txhashset.kernel_pos(&excess).unwrap();
+			assert_eq!(txhashset.find_kernel(&excess, None, None).unwrap().1, pos);
+			pos
+		};
+
+		// Reorg onto a fork without tx1, rewinding the kernel MMR past its kernel.
+		let mut fork_head = fork_point;
+		for n in 7..9 {
+			let b = prepare_block(&kc, &fork_head, &chain, n);
+			fork_head = b.header.clone();
+			chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		}
+		assert_eq!(chain.head_header().unwrap().hash(), fork_head.hash());
+		{
+			let txhashset = chain.txhashset();
+			let txhashset = txhashset.read();
+			assert_eq!(txhashset.kernel_pos(&excess), None);
+			assert!(txhashset.find_kernel(&excess, None, None).is_none());
+		}
+
+		// Including tx1 on the fork indexes its kernel at the new pos.
+		let b = prepare_block_tx(&kc, &fork_head, &chain, 9, vec![&tx1]);
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		{
+			let txhashset = chain.txhashset();
+			let txhashset = txhashset.read();
+			let new_pos = txhashset.kernel_pos(&excess).unwrap();
+			assert_ne!(new_pos, pos);
+			assert_eq!(
+				txhashset.find_kernel(&excess, None, None).unwrap().1,
+				new_pos
+			);
+		}
+	}
+
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn utxo_view_at_old_header() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_utxo_view_at";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+		let horizon = global::cut_through_horizon() as u64;
+
+		let b = prepare_block_key_idx(&kc, &chain.head_header().unwrap(), &chain, 2, 1);
+		let commit = b.outputs()[0].commitment();
+		let old_header = b.header.clone();
+		let mut head = b.header.clone();
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		let pos = chain.get_output_pos(&commit).unwrap();
+
+		for n in 3..(horizon + 5) {
+			let b = prepare_block(&kc, &head, &chain, n);
+			head = b.header.clone();
+			chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		}
+
+		// Spend the coinbase from the first block.
+		let key_id_coinbase = ExtKeychainPath::new(1, 1, 0, 0, 0).to_identifier();
+		let key_id30 = ExtKeychainPath::new(1, 30, 0, 0, 0).to_identifier();
+		let tx = spend_coinbase_tx(&kc, key_id_coinbase, key_id30, vec![]);
+		let b = prepare_block_tx(&kc, &head, &chain, horizon + 5, vec![&tx]);
+		let tip = b.header.clone();
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		assert!(tip.height - old_header.height > horizon);
+
+		let header_pmmr = chain.header_pmmr();
+		let txhashset = chain.txhashset();
+		let mut header_pmmr = header_pmmr.write();
+		let mut txhashset = txhashset.write();
+
+		// Unspent as of the old header (older than the horizon), spent as of the tip.
+		let out = chain::txhashset::utxo_view_at(
+			&mut header_pmmr,
+			&mut txhashset,
+			&old_header,
+			|utxo, _| utxo.get_unspent_output_at(pos),
+		)
+		.unwrap();
+		assert_eq!(out.commitment(), commit);
+		let res =
+			chain::txhashset::utxo_view_at(&mut header_pmmr, &mut txhashset, &tip, |utxo, _| {
+				utxo.get_unspent_output_at(pos)
+			});
+		assert!(res.is_err());
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn migrate_spent_index_and_rewind() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_migrate_spent_index";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let b = prepare_block_key_idx(&kc, &chain.head_header().unwrap(), &chain, 2, 1);
+		let commit = b.outputs()[0].commitment();
+		let mut head = b.header.clone();
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		let pos = chain.get_output_pos(&commit).unwrap();
+
+		for n in 3..6 {
+			let b = prepare_block(&kc, &head, &chain, n);
+			head = b.header.clone();
+			chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		}
+		let rewind_to = head.clone();
+
+		// Spend the coinbase then mine one more block on top.
+		let key_id_coinbase = ExtKeychainPath::new(1, 1, 0, 0, 0).to_identifier();
+		let key_id30 = ExtKeychainPath::new(1, 30, 0, 0, 0).to_identifier();
+		let tx = spend_coinbase_tx(&kc, key_id_coinbase, key_id30, vec![]);
+		let b = prepare_block_tx(&kc, &head, &chain, 6, vec![&tx]);
+		let spending = b.hash();
+		head = b.header.clone();
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		let b = prepare_block(&kc, &head, &chain, 7);
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+
+		// Turn the spending block into a legacy block (input bitmap, no spent index).
+		let spent = {
+			let batch = chain.store().batch().unwrap();
+			let spent = batch.get_spent_index(&spending).unwrap();
+			let bitmap = batch.get_block_input_bitmap(&spending).unwrap();
+			batch.save_legacy_input_bitmap(&spending, &bitmap).unwrap();
+			batch.delete_block_spent_index(&spending).unwrap();
+			batch.commit().unwrap();
+			spent
+		};
+		assert_eq!(spent.len(), 1);
+		assert_eq!(spent[0].pos, pos);
+
+		// Migrating rebuilds the spent index from the legacy bitmap.
+		{
+			let header_pmmr = chain.header_pmmr();
+			let txhashset = chain.txhashset();
+			let header_pmmr = header_pmmr.read();
+			let txhashset = txhashset.read();
+			let batch = chain.store().batch().unwrap();
+			let migrated = txhashset
+				.migrate_spent_indexes(1, 7, &header_pmmr, &batch)
+				.unwrap();
+			assert_eq!(migrated, 1);
+			let migrated = batch.get_spent_index(&spending).unwrap();
+			assert_eq!(migrated.len(), 1);
+			assert_eq!(migrated[0].pos, spent[0].pos);
+			assert_eq!(migrated[0].height, spent[0].height);
+			batch.commit().unwrap();
+		}
+
+		// Rewinding across the migrated block unspends the coinbase.
+		extending_readonly(&chain, |ext, batch| {
+			ext.extension.rewind(&rewind_to, batch)?;
+			ext.extension.validate_roots(&rewind_to)?;
+			assert_eq!(batch.get_output_pos(&commit)?, pos);
+			Ok(())
+		})
+		.unwrap();
+	}
+	clean_output_dir(chain_dir);
+}
+
 #[test]
 fn spend_in_fork_and_compact() {
 	clean_output_dir(".grin6");
@@ -793,6 +1135,24 @@ fn spend_in_fork_and_compact() {
 		}
 
 		chain.validate(false).unwrap();
+
+		// A compaction dry run removes exactly what compaction itself removes,
+		// including the token outputs and token rangeproofs spent prior to the horizon.
+		{
+			let head = chain.head_header().unwrap();
+			let horizon_height = head.height - global::cut_through_horizon() as u64;
+			let horizon_header = chain.get_header_by_height(horizon_height).unwrap();
+			let txhashset = chain.txhashset();
+			let mut txhashset = txhashset.write();
+			let batch = chain.store().batch().unwrap();
+			let dry_run = txhashset.compact_dry_run(&horizon_header, &batch).unwrap();
+			assert!(dry_run.token_output_bytes_freed > 0);
+			assert!(dry_run.token_rproof_bytes_freed > 0);
+			let stats = txhashset.compact(&horizon_header, &batch).unwrap();
+			assert_eq!(dry_run, stats);
+		}
+		chain.validate(false).unwrap();
+
 		if let Err(e) = chain.compact() {
 			panic!("Error compacting chain: {:?}", e);
 		}
@@ -899,6 +1259,860 @@ fn output_header_mappings() {
 	clean_output_dir(".grin_header_for_output");
 }
 
+/// Test the token issue proof MMR survives a txhashset zip and unzip (fast-sync).
+#[test]
+fn token_issue_proof_zip_roundtrip() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	util::init_test_logger();
+	let chain_dir = ".grin_token_zip";
+	let sandbox_dir = ".grin_token_zip_sandbox";
+	clean_output_dir(chain_dir);
+	clean_output_dir(sandbox_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		mine_token_issuance(&kc, &chain, TokenKey::new_token_key(), &key_id_token);
+
+		let mut prev = chain.head_header().unwrap();
+		for n in 7..9 {
+			let b = prepare_block(&kc, &prev, &chain, n);
+			prev = b.header.clone();
+			chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		}
+
+		let header = chain.head_header().unwrap();
+		assert!(header.token_issue_proof_mmr_size > 0);
+		let roots = chain.txhashset().read().roots();
+
+		let (_, _, _, _, zip_file) = chain.txhashset_read(header.hash()).unwrap();
+		chain::txhashset::zip_write(PathBuf::from(sandbox_dir), zip_file, &header).unwrap();
+
+		let store = Arc::new(ChainStore::new(sandbox_dir).unwrap());
+		let txhashset =
+			chain::txhashset::TxHashSet::open(sandbox_dir.to_string(), store, Some(&header))
+				.unwrap();
+		let sandbox_roots = txhashset.roots();
+
+		assert_eq!(
+			roots.output_roots.pmmr_root,
+			sandbox_roots.output_roots.pmmr_root
+		);
+		assert_eq!(roots.rproof_root, sandbox_roots.rproof_root);
+		assert_eq!(roots.kernel_root, sandbox_roots.kernel_root);
+		assert_eq!(roots.token_output_root, sandbox_roots.token_output_root);
+		assert_eq!(roots.token_rproof_root, sandbox_roots.token_rproof_root);
+		assert_eq!(
+			roots.token_issue_proof_root,
+			sandbox_roots.token_issue_proof_root
+		);
+		assert_eq!(roots.token_kernel_root, sandbox_roots.token_kernel_root);
+		assert_eq!(roots.token_issue_proof_root, header.token_issue_proof_root);
+	}
+	// Cleanup chain directories
+	clean_output_dir(chain_dir);
+	clean_output_dir(sandbox_dir);
+}
+
+#[test]
+fn output_merkle_proof_roundtrip() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_output_merkle_proof";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let mut prev = chain.head_header().unwrap();
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+		let mut outputs = vec![];
+
+		for n in 2..8 {
+			let b = prepare_block(&kc, &prev, &chain, n);
+			outputs.push(OutputIdentifier::from(&b.outputs()[0]));
+			prev = b.header.clone();
+			chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		}
+
+		let header = chain.head_header().unwrap();
+		for output in &outputs {
+			let proof = chain.get_merkle_proof(output, &header).unwrap();
+			let pos = chain.get_output_pos(&output.commit).unwrap();
+			chain::txhashset::verify_output_merkle_proof(
+				output,
+				&proof,
+				header.output_root,
+				pos,
+				header.output_mmr_size,
+			)
+			.unwrap();
+		}
+
+		let output = &outputs[0];
+		let pos = chain.get_output_pos(&output.commit).unwrap();
+		let proof = chain.get_merkle_proof(output, &header).unwrap();
+
+		// A tampered proof must not verify.
+		let mut tampered = proof.clone();
+		tampered.path[0] = ZERO_HASH;
+		assert!(chain::txhashset::verify_output_merkle_proof(
+			output,
+			&tampered,
+			header.output_root,
+			pos,
+			header.output_mmr_size,
+		)
+		.is_err());
+
+		// Nor must a valid proof for a different output or at a different pos.
+		assert!(chain::txhashset::verify_output_merkle_proof(
+			&outputs[1],
+			&proof,
+			header.output_root,
+			pos,
+			header.output_mmr_size,
+		)
+		.is_err());
+		let other_pos = chain.get_output_pos(&outputs[1].commit).unwrap();
+		assert!(chain::txhashset::verify_output_merkle_proof(
+			output,
+			&proof,
+			header.output_root,
+			other_pos,
+			header.output_mmr_size,
+		)
+		.is_err());
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn output_merkle_proof_beyond_horizon() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_output_merkle_proof_horizon";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let mut prev = chain.head_header().unwrap();
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+		let horizon = global::cut_through_horizon() as u64;
+
+		let b = prepare_block(&kc, &prev, &chain, 2);
+		let output = OutputIdentifier::from(&b.outputs()[0]);
+		let header = b.header.clone();
+		prev = b.header.clone();
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+
+		for n in 3..(horizon + 8) {
+			let b = prepare_block(&kc, &prev, &chain, n);
+			prev = b.header.clone();
+			chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		}
+		assert!(chain.head().unwrap().height - header.height > horizon);
+
+		// A proof against the (old) header of the block that created the output
+		// needs a rewind deeper than the horizon.
+		let proof = chain.get_merkle_proof(&output, &header).unwrap();
+		let pos = chain.get_output_pos(&output.commit).unwrap();
+		chain::txhashset::verify_output_merkle_proof(
+			&output,
+			&proof,
+			header.output_root,
+			pos,
+			header.output_mmr_size,
+		)
+		.unwrap();
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn rewind_beyond_max_rewind_depth() {
+	let chain_dir = ".grin_max_rewind_depth";
+	clean_output_dir(chain_dir);
+	{
+		let chain = mine_chain(chain_dir, 6);
+		let headers: Vec<_> = (0..=5)
+			.map(|height| chain.get_header_by_height(height).unwrap())
+			.collect();
+
+		// No limit by default.
+		extending_readonly(&chain, |ext, batch| {
+			assert_eq!(ext.extension.max_rewind_depth(), None);
+			ext.extension.rewind(&headers[0], batch)
+		})
+		.unwrap();
+
+		// Rewinding further than the limit fails, rewinding up to the limit is fine.
+		let res = extending_readonly(&chain, |ext, batch| {
+			ext.extension.set_max_rewind_depth(Some(2));
+			ext.extension.rewind(&headers[2], batch)
+		});
+		assert_eq!(
+			res.unwrap_err().kind(),
+			chain::ErrorKind::RewindTooDeep(3, 2)
+		);
+		extending_readonly(&chain, |ext, batch| {
+			ext.extension.set_max_rewind_depth(Some(2));
+			ext.extension.rewind(&headers[3], batch)
+		})
+		.unwrap();
+	}
+	clean_output_dir(chain_dir);
+}
+
+// Records the number of rangeproofs reported as verified during validation.
+struct RproofCountStatus(AtomicU64);
+
+impl chain::TxHashsetWriteStatus for RproofCountStatus {
+	fn on_setup(&self) {}
+	fn on_validation_kernels(&self, _ks: u64, _kts: u64) {}
+	fn on_validation_rproofs(&self, rs: u64, _rt: u64) {
+		self.0.fetch_max(rs, Ordering::Relaxed);
+	}
+	fn on_validation_token_kernels(&self, _ks: u64, _kts: u64) {}
+	fn on_validation_token_rproofs(&self, _rs: u64, _rt: u64) {}
+	fn on_save(&self) {}
+	fn on_done(&self) {}
+}
+
+#[test]
+fn validation_progress_resume() {
+	let chain_dir = ".grin_validation_progress";
+	clean_output_dir(chain_dir);
+	{
+		let chain = mine_chain(chain_dir, 4);
+		let genesis = chain.get_header_by_height(0).unwrap();
+		let other = chain.get_header_by_height(2).unwrap();
+		let head = chain.head_header().unwrap();
+		let path = chain::txhashset::verification_progress_path(Path::new(chain_dir));
+
+		// Progress claiming every rangeproof and kernel (and token equivalent) verified.
+		let done = |header: &BlockHeader| {
+			let mut progress = chain::types::VerificationProgress::new(header);
+			progress.rproof_pos = header.output_mmr_size;
+			progress.kernel_pos = header.kernel_mmr_size;
+			progress.token_rproof_pos = header.token_output_mmr_size;
+			progress.token_kernel_pos = header.token_kernel_mmr_size;
+			progress
+		};
+		let save = |progress: &chain::types::VerificationProgress| {
+			let mut file = File::create(&path).unwrap();
+			core::ser::serialize(&mut file, core::ser::ProtocolVersion::local(), progress).unwrap();
+		};
+
+		// Validate, returning the number of rangeproofs verified.
+		let validate = || {
+			extending_readonly(&chain, |ext, _| {
+				let status = RproofCountStatus(AtomicU64::new(0));
+				ext.extension.validate_with_progress(
+					&genesis,
+					false,
+					false,
+					&chain::types::ValidationConfig::default(),
+					&status,
+					&head,
+					Some(&path),
+				)?;
+				Ok(status.0.load(Ordering::Relaxed))
+			})
+			.unwrap()
+		};
+
+		// Without progress every rangeproof is verified and no progress is left behind.
+		let total = validate();
+		assert!(total > 0);
+		assert!(!path.exists());
+
+		// Progress for this header is resumed from (nothing left to verify),
+		// previously verified rangeproofs still count towards the total reported.
+		// The progress file is removed on success.
+		save(&done(&head));
+		assert_eq!(validate(), total);
+		assert!(!path.exists());
+
+		// Same when resuming part way through the rangeproofs.
+		let mut progress = done(&head);
+		progress.rproof_pos = other.output_mmr_size;
+		save(&progress);
+		assert_eq!(validate(), total);
+		assert!(!path.exists());
+
+		// Progress for a different header is ignored.
+		save(&done(&other));
+		assert_eq!(validate(), total);
+		assert!(!path.exists());
+
+		// As is progress for this header with different MMR sizes.
+		let mut progress = done(&head);
+		progress.output_mmr_size += 1;
+		save(&progress);
+		assert_eq!(validate(), total);
+		assert!(!path.exists());
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn token_output_merkle_proof_roundtrip() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_token_output_merkle_proof";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		let issue_token_tx =
+			mine_token_issuance(&kc, &chain, TokenKey::new_token_key(), &key_id_token);
+		let output = TokenOutputIdentifier::from(&issue_token_tx.token_outputs()[0]);
+
+		let header = chain.head_header().unwrap();
+		let pos = chain.get_token_output_pos(&output.commit).unwrap();
+		let proof = chain.get_token_merkle_proof_for_pos(output.commit).unwrap();
+		chain::txhashset::verify_token_output_merkle_proof(
+			&output,
+			&proof,
+			header.token_output_root,
+			pos,
+			header.token_output_mmr_size,
+		)
+		.unwrap();
+
+		// A proof against a different root must not verify.
+		assert!(chain::txhashset::verify_token_output_merkle_proof(
+			&output,
+			&proof,
+			header.output_root,
+			pos,
+			header.token_output_mmr_size,
+		)
+		.is_err());
+
+		// Nor must a proof built against a different mmr size.
+		assert!(chain::txhashset::verify_token_output_merkle_proof(
+			&output,
+			&proof,
+			header.token_output_root,
+			pos,
+			header.token_output_mmr_size + 1,
+		)
+		.is_err());
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn token_supply_after_issue_and_spend() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_token_supply";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let token_key = TokenKey::new_token_key();
+		let supply = chain.token_supply(&token_key).unwrap();
+		assert_eq!(supply.issue_commit, None);
+		assert_eq!(supply.unspent_outputs, 0);
+
+		let key_id2 = ExtKeychainPath::new(1, 2, 0, 0, 0).to_identifier();
+		let key_id30 = ExtKeychainPath::new(1, 30, 0, 0, 0).to_identifier();
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		let key_id_token1 = ExtKeychainPath::new(1, 10001, 0, 0, 0).to_identifier();
+		let key_id_token2 = ExtKeychainPath::new(1, 10002, 0, 0, 0).to_identifier();
+		let issue_token_tx = mine_token_issuance(&kc, &chain, token_key, &key_id_token);
+		let prev = chain.head_header().unwrap();
+
+		let issue_commit = issue_token_tx.token_outputs()[0].commit;
+		let supply = chain.token_supply(&token_key).unwrap();
+		assert_eq!(supply.token_type, token_key);
+		assert_eq!(supply.issue_commit, Some(issue_commit));
+		assert_eq!(supply.unspent_outputs, 1);
+
+		// Spend the issued output, splitting it in two.
+		let tx = spend_coinbase_tx(
+			&kc,
+			key_id2,
+			key_id30,
+			vec![
+				build::token_input(10000, token_key, true, key_id_token),
+				build::token_output(4000, token_key, false, key_id_token1),
+				build::token_output(6000, token_key, false, key_id_token2),
+			],
+		);
+		let b = prepare_block_tx(&kc, &prev, &chain, 7, vec![&tx]);
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+
+		// The issued supply is unchanged, now held in two unspent outputs.
+		let supply = chain.token_supply(&token_key).unwrap();
+		assert_eq!(supply.issue_commit, Some(issue_commit));
+		assert_eq!(supply.unspent_outputs, 2);
+		assert_eq!(
+			chain.txhashset().read().token_keys().unwrap(),
+			vec![token_key]
+		);
+
+		// Other token types are unaffected.
+		let supply = chain.token_supply(&TokenKey::new_token_key()).unwrap();
+		assert_eq!(supply.issue_commit, None);
+		assert_eq!(supply.unspent_outputs, 0);
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn rewind_token_inputs_without_token_spent_index() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_rewind_token_inputs";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let token_key = TokenKey::new_token_key();
+		let key_id2 = ExtKeychainPath::new(1, 2, 0, 0, 0).to_identifier();
+		let key_id30 = ExtKeychainPath::new(1, 30, 0, 0, 0).to_identifier();
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		let key_id_token1 = ExtKeychainPath::new(1, 10001, 0, 0, 0).to_identifier();
+		mine_token_issuance(&kc, &chain, token_key, &key_id_token);
+		let prev = chain.head_header().unwrap();
+
+		// Spend the issued token output at height 6.
+		let tx = spend_coinbase_tx(
+			&kc,
+			key_id2,
+			key_id30,
+			vec![
+				build::token_input(10000, token_key, true, key_id_token),
+				build::token_output(10000, token_key, false, key_id_token1),
+			],
+		);
+		let b = prepare_block_tx(&kc, &prev, &chain, 7, vec![&tx]);
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+
+		let fork_point = chain.get_header_by_height(4).unwrap();
+		let spent = chain.head_header().unwrap();
+
+		// The issuing block (height 5) has token kernels but no token inputs so has no
+		// token spent index, the block itself tells us there is nothing to unspend.
+		extending_readonly(&chain, |ext, batch| {
+			assert!(batch.get_token_spent_index(&spent.prev_hash).is_err());
+			ext.extension.rewind(&fork_point, batch)?;
+			ext.extension.validate_roots(&fork_point)
+		})
+		.unwrap();
+
+		// The spending block has token inputs, with neither a token spent index nor
+		// a legacy token input bitmap we cannot rewind it.
+		let res = extending_readonly(&chain, |ext, batch| {
+			batch.delete(&to_key(b'Z', &mut spent.hash().to_vec()))?;
+			ext.extension.rewind(&fork_point, batch)
+		});
+		assert!(res.is_err());
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn token_kernel_sums_cached_matches_full() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_token_sums_cached";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		mine_token_issuance(&kc, &chain, TokenKey::new_token_key(), &key_id_token);
+
+		let prev = chain.head_header().unwrap();
+		let b = prepare_block(&kc, &prev, &chain, 7);
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+
+		// Check the cached path (parent block_token_sums plus the block) agrees with
+		// the full path (summing the entire token UTXO set) before, at and after the
+		// block issuing the token (height 5).
+		// Headers are fetched before locking, get_header_by_height takes the header MMR lock.
+		let headers: Vec<_> = (4..=6)
+			.map(|height| chain.get_header_by_height(height).unwrap())
+			.collect();
+		for header in headers {
+			let (full, cached) = extending_readonly(&chain, |ext, batch| {
+				ext.extension.rewind(&header, batch)?;
+				let full = ext.extension.validate_token_kernel_sums()?;
+				let cached = ext
+					.extension
+					.validate_token_kernel_sums_cached(&header, batch)?;
+				Ok((full, cached))
+			})
+			.unwrap();
+			assert_eq!(full.token_issue_commit_map, cached.token_issue_commit_map);
+			assert_eq!(full.token_utxo_sum_map, cached.token_utxo_sum_map);
+			assert_eq!(full.token_kernel_sum_map, cached.token_kernel_sum_map);
+		}
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn token_kernel_sums_mismatch_fails_validation() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_token_sums_mismatch";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		let issue_token_tx =
+			mine_token_issuance(&kc, &chain, TokenKey::new_token_key(), &key_id_token);
+
+		// A block adding a token output not backed by any token kernel.
+		// The roots commit to the bad token UTXO set so only the token kernel sums catch it.
+		let prev = chain.head_header().unwrap();
+		let mut b = prepare_block(&kc, &prev, &chain, 7);
+		let mut token_output = issue_token_tx.token_outputs()[0].clone();
+		token_output.commit = b.outputs()[0].commitment();
+		b.token_outputs_mut().push(token_output);
+		chain.set_txhashset_roots(&mut b).unwrap();
+
+		let genesis = chain.get_header_by_height(0).unwrap();
+		let res = extending_readonly(&chain, |ext, batch| {
+			ext.extension.apply_block(&b, batch)?;
+			ext.extension.validate(
+				&genesis,
+				true,
+				false,
+				&chain::types::ValidationConfig::default(),
+				&chain::types::NoStatus,
+				&b.header,
+			)
+		});
+		assert!(res.is_err());
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn txhashset_counts_match_mmrs() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_txhashset_counts";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		mine_token_issuance(&kc, &chain, TokenKey::new_token_key(), &key_id_token);
+
+		let counts = {
+			let txhashset = chain.txhashset();
+			let txhashset = txhashset.read();
+
+			// The counts as scanned from the MMRs.
+			let unspent = txhashset.unspent_outputs_iter().count() as u64;
+			let token_unspent = txhashset.unspent_token_outputs_iter().count() as u64;
+			assert!(unspent > 0);
+			assert!(token_unspent > 0);
+			assert_eq!(txhashset.output_count(), unspent);
+			assert_eq!(txhashset.token_output_count(), token_unspent);
+
+			(
+				txhashset.kernel_count(),
+				txhashset.token_issue_proof_count(),
+				txhashset.token_kernel_count(),
+			)
+		};
+		assert!(counts.0 > 0);
+		assert_eq!((counts.1, counts.2), (1, 1));
+
+		// The leaf counts of the MMR sizes validate_mmrs logs.
+		extending_readonly(&chain, |ext, _| {
+			let sizes = ext.extension.sizes();
+			assert_eq!(
+				counts,
+				(
+					pmmr::n_leaves(sizes.kernel),
+					pmmr::n_leaves(sizes.token_issue_proof),
+					pmmr::n_leaves(sizes.token_kernel),
+				)
+			);
+			Ok(())
+		})
+		.unwrap();
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn kernel_export_roundtrip() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_kernel_export";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		mine_token_issuance(&kc, &chain, TokenKey::new_token_key(), &key_id_token);
+
+		let header = chain.head_header().unwrap();
+		let path = Path::new(chain_dir).join("kernels.bin");
+		chain.txhashset().read().export_kernels(&path).unwrap();
+
+		// The export is complete, the roots match the header.
+		let (kernel_root, token_kernel_root) =
+			chain::txhashset::verify_kernel_export(&path).unwrap();
+		assert_eq!(kernel_root, header.kernel_root);
+		assert_eq!(token_kernel_root, header.token_kernel_root);
+
+		let contents = fs::read(&path).unwrap();
+		let tampered_path = Path::new(chain_dir).join("kernels_tampered.bin");
+
+		// A tampered kernel (the last byte is in the last token kernel signature).
+		let mut tampered = contents.clone();
+		*tampered.last_mut().unwrap() ^= 0xff;
+		fs::write(&tampered_path, &tampered).unwrap();
+		assert!(chain::txhashset::verify_kernel_export(&tampered_path).is_err());
+
+		// A tampered root (the kernel root is at the start of the export).
+		let mut tampered = contents.clone();
+		tampered[0] ^= 0xff;
+		fs::write(&tampered_path, &tampered).unwrap();
+		assert_eq!(
+			chain::txhashset::verify_kernel_export(&tampered_path)
+				.unwrap_err()
+				.kind(),
+			chain::ErrorKind::InvalidRoot
+		);
+
+		// A truncated export.
+		fs::write(&tampered_path, &contents[..contents.len() - 1]).unwrap();
+		assert!(chain::txhashset::verify_kernel_export(&tampered_path).is_err());
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn kernel_sums_incremental_matches_full() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_kernel_sums_incremental";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		// The token issuance at height 5 spends a coinbase output.
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		mine_token_issuance(&kc, &chain, TokenKey::new_token_key(), &key_id_token);
+
+		// Spend another coinbase output at height 6.
+		let key_id2 = ExtKeychainPath::new(1, 2, 0, 0, 0).to_identifier();
+		let key_id30 = ExtKeychainPath::new(1, 30, 0, 0, 0).to_identifier();
+		let tx = spend_coinbase_tx(&kc, key_id2, key_id30, vec![]);
+		let prev = chain.head_header().unwrap();
+		let b = prepare_block_tx(&kc, &prev, &chain, 7, vec![&tx]);
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+
+		// Headers are fetched before locking, get_header_by_height takes the header MMR lock.
+		let genesis = chain.get_header_by_height(0).unwrap();
+		let headers: Vec<_> = (0..=6)
+			.map(|height| chain.get_header_by_height(height).unwrap())
+			.collect();
+
+		// Starting from each checkpoint, over one and over several blocks (with spends).
+		for (from, to) in vec![(0, 1), (1, 4), (4, 5), (4, 6), (2, 6)] {
+			let (prev_header, header) = (&headers[from], &headers[to]);
+			let (full, incremental) = extending_readonly(&chain, |ext, batch| {
+				ext.extension.rewind(header, batch)?;
+				let full = ext.extension.validate_kernel_sums(&genesis, header)?;
+				let prev_sums = batch.get_block_sums(&prev_header.hash())?;
+				let incremental = ext.extension.validate_kernel_sums_incremental(
+					&prev_sums,
+					prev_header,
+					header,
+					batch,
+				)?;
+				Ok((full, incremental))
+			})
+			.unwrap();
+			assert_eq!(full, incremental);
+		}
+
+		// The block sums of the wrong checkpoint fail to validate.
+		let res = extending_readonly(&chain, |ext, batch| {
+			let wrong_sums = batch.get_block_sums(&headers[3].hash())?;
+			ext.extension.validate_kernel_sums_incremental(
+				&wrong_sums,
+				&headers[4],
+				&headers[6],
+				batch,
+			)
+		});
+		assert!(res.is_err());
+	}
+	clean_output_dir(chain_dir);
+}
+
+// Records the counts passed to each of the validation callbacks.
+#[derive(Default)]
+struct ValidationCountStatus {
+	rproofs: AtomicU64,
+	kernels: AtomicU64,
+	token_rproofs: AtomicU64,
+	token_kernels: AtomicU64,
+}
+
+impl ValidationCountStatus {
+	fn counts(&self) -> (u64, u64, u64, u64) {
+		(
+			self.rproofs.load(Ordering::Relaxed),
+			self.kernels.load(Ordering::Relaxed),
+			self.token_rproofs.load(Ordering::Relaxed),
+			self.token_kernels.load(Ordering::Relaxed),
+		)
+	}
+}
+
+impl chain::TxHashsetWriteStatus for ValidationCountStatus {
+	fn on_setup(&self) {}
+	fn on_validation_kernels(&self, ks: u64, _kts: u64) {
+		self.kernels.store(ks, Ordering::Relaxed);
+	}
+	fn on_validation_rproofs(&self, rs: u64, _rt: u64) {
+		self.rproofs.store(rs, Ordering::Relaxed);
+	}
+	fn on_validation_token_kernels(&self, ks: u64, _kts: u64) {
+		self.token_kernels.store(ks, Ordering::Relaxed);
+	}
+	fn on_validation_token_rproofs(&self, rs: u64, _rt: u64) {
+		self.token_rproofs.store(rs, Ordering::Relaxed);
+	}
+	fn on_save(&self) {}
+	fn on_done(&self) {}
+}
+
+#[test]
+fn validate_block_range_between_headers() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_validate_block_range";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		let token_key = TokenKey::new_token_key();
+		let key_id2 = ExtKeychainPath::new(1, 2, 0, 0, 0).to_identifier();
+		let key_id30 = ExtKeychainPath::new(1, 30, 0, 0, 0).to_identifier();
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		let key_id_token1 = ExtKeychainPath::new(1, 10001, 0, 0, 0).to_identifier();
+		let key_id_token2 = ExtKeychainPath::new(1, 10002, 0, 0, 0).to_identifier();
+		mine_token_issuance(&kc, &chain, token_key, &key_id_token);
+
+		// Spend the issued token output at height 6, splitting it in two.
+		let tx = spend_coinbase_tx(
+			&kc,
+			key_id2,
+			key_id30,
+			vec![
+				build::token_input(10000, token_key, true, key_id_token),
+				build::token_output(4000, token_key, false, key_id_token1),
+				build::token_output(6000, token_key, false, key_id_token2),
+			],
+		);
+		let prev = chain.head_header().unwrap();
+		let b = prepare_block_tx(&kc, &prev, &chain, 7, vec![&tx]);
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+
+		let headers: Vec<_> = (0..=6)
+			.map(|height| chain.get_header_by_height(height).unwrap())
+			.collect();
+		let blocks: Vec<_> = (5..=6)
+			.map(|height| chain.get_block(&headers[height].hash()).unwrap())
+			.collect();
+
+		// Blocks 5 and 6: every output is unspent but the issued token output (block 5)
+		// is spent in block 6, leaving the two token outputs of block 6.
+		let expected = (
+			blocks.iter().map(|b| b.outputs().len() as u64).sum::<u64>(),
+			blocks.iter().map(|b| b.kernels().len() as u64).sum::<u64>(),
+			blocks[1].token_outputs().len() as u64,
+			blocks
+				.iter()
+				.map(|b| b.token_kernels().len() as u64)
+				.sum::<u64>(),
+		);
+		assert_eq!(expected.2, 2);
+		assert_eq!(expected.3, 2);
+
+		let validate = |prev_header: &BlockHeader, header: &BlockHeader| {
+			extending_readonly(&chain, |ext, _| {
+				let status = ValidationCountStatus::default();
+				ext.extension
+					.validate_block_range(prev_header, header, &status)?;
+				Ok(status.counts())
+			})
+			.unwrap()
+		};
+
+		assert_eq!(validate(&headers[4], &headers[6]), expected);
+
+		// A single block (coinbase only) with no token activity.
+		assert_eq!(validate(&headers[3], &headers[4]), (1, 1, 0, 0));
+
+		// Nothing between a header and itself.
+		assert_eq!(validate(&headers[6], &headers[6]), (0, 0, 0, 0));
+	}
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn commitment_ever_seen_within_horizon() {
+	global::set_mining_mode(ChainTypes::AutomatedTesting);
+	let chain_dir = ".grin_commitment_ever_seen";
+	clean_output_dir(chain_dir);
+	{
+		let chain = init_chain(chain_dir, pow::mine_genesis_block().unwrap());
+		let kc = ExtKeychain::from_random_seed(false).unwrap();
+
+		// The token issuance at height 5 spends a coinbase output.
+		let key_id_token = ExtKeychainPath::new(1, 10000, 0, 0, 0).to_identifier();
+		let issue_token_tx =
+			mine_token_issuance(&kc, &chain, TokenKey::new_token_key(), &key_id_token);
+		let spent = issue_token_tx.inputs()[0].commitment();
+		let head = chain.head_header().unwrap();
+		let unspent = chain.get_block(&head.hash()).unwrap().outputs()[0].commitment();
+		let never = Commitment::from_vec(vec![9; 33]);
+
+		let ever_seen = |commit: &Commitment| {
+			let batch = chain.store().batch().unwrap();
+			chain
+				.txhashset()
+				.read()
+				.commitment_ever_seen(commit, &batch)
+				.unwrap()
+		};
+
+		// The entire chain is within the horizon.
+		assert_eq!(ever_seen(&unspent), CommitmentSeen::Unspent);
+		assert_eq!(ever_seen(&spent), CommitmentSeen::Spent);
+		assert_eq!(ever_seen(&never), CommitmentSeen::NotSeen);
+
+		// Grow the chain beyond the horizon, the spend at height 5 is now beyond it.
+		let horizon = global::cut_through_horizon() as u64;
+		let mut prev = head;
+		for n in 0..(horizon + 2) {
+			let b = prepare_block(&kc, &prev, &chain, 7 + n);
+			prev = b.header.clone();
+			chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+		}
+		assert_eq!(ever_seen(&unspent), CommitmentSeen::Unspent);
+		assert_eq!(ever_seen(&spent), CommitmentSeen::UnknownBeyondHorizon);
+		assert_eq!(ever_seen(&never), CommitmentSeen::UnknownBeyondHorizon);
+	}
+	clean_output_dir(chain_dir);
+}
+
 // Use diff as both diff *and* key_idx for convenience (deterministic private key for test blocks)
 fn prepare_block<K>(kc: &K, prev: &BlockHeader, chain: &Chain, diff: u64) -> Block
 where
@@ -939,6 +2153,44 @@ where
 	prepare_block_tx_key_idx(kc, prev, chain, diff, key_idx, txs)
 }
 
+// Mine 4 blocks followed by a block with a tx issuing 10,000 of the given token type
+// to key_id_token, spending the coinbase from the block at height 2.
+// Returns the issue token tx, the block including it is the new chain head.
+fn mine_token_issuance<K>(
+	kc: &K,
+	chain: &Chain,
+	token_key: TokenKey,
+	key_id_token: &Identifier,
+) -> Transaction
+where
+	K: Keychain,
+{
+	let mut prev = chain.head_header().unwrap();
+	for n in 2..6 {
+		let b = prepare_block(kc, &prev, chain, n);
+		prev = b.header.clone();
+		chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+	}
+
+	let key_id3 = ExtKeychainPath::new(1, 3, 0, 0, 0).to_identifier();
+	let issue_token_tx = build::transaction(
+		KernelFeatures::Plain {
+			fee: consensus::REWARD,
+		},
+		Some(TokenKernelFeatures::IssueToken),
+		vec![
+			build::coinbase_input(consensus::REWARD, key_id3),
+			build::token_output(10000, token_key, true, key_id_token.clone()),
+		],
+		kc,
+		&ProofBuilder::new(kc),
+	)
+	.unwrap();
+	let b = prepare_block_tx(kc, &prev, chain, 6, vec![&issue_token_tx]);
+	chain.process_block(b, chain::Options::SKIP_POW).unwrap();
+	issue_token_tx
+}
+
 fn prepare_block_tx_key_idx<K>(
 	kc: &K,
 	prev: &BlockHeader,
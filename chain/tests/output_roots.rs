@@ -0,0 +1,43 @@
+// Copyright 2020 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use self::chain::types::OutputRoots;
+use self::core::core::hash::Hash;
+use self::core::core::{BlockHeader, HeaderVersion};
+use grin_chain as chain;
+use grin_core as core;
+
+#[test]
+fn test_output_roots_combined_root() {
+	let roots = OutputRoots {
+		pmmr_root: Hash::from_vec(&[1; 32]),
+		bitmap_root: Hash::from_vec(&[2; 32]),
+	};
+
+	// blake2b(be_u64(3) | pmmr_root | bitmap_root)
+	assert_eq!(
+		roots.combined_root(3),
+		Hash::from_hex("df2dcead23ee1c8c6741376fd5083968ce4f5d797bcf631b5ac0dc1f2885a847").unwrap()
+	);
+
+	// Headers from version 3 commit to the combined root.
+	let mut header = BlockHeader::default();
+	header.output_mmr_size = 3;
+	header.version = HeaderVersion(3);
+	assert_eq!(roots.root(&header), roots.combined_root(3));
+
+	// Earlier headers commit to the output PMMR root alone.
+	header.version = HeaderVersion(2);
+	assert_eq!(roots.root(&header), roots.pmmr_root);
+}
@@ -13,12 +13,17 @@
 // limitations under the License.
 
 use self::core::core::hash::Hashed;
+use self::core::core::pmmr;
+use self::util::secp::pedersen::Commitment;
+use grin_chain as chain;
 use grin_core as core;
+use grin_store::to_key;
 use grin_util as util;
+use std::time::Instant;
 
 mod chain_test_helper;
 
-use self::chain_test_helper::{clean_output_dir, mine_chain};
+use self::chain_test_helper::{clean_output_dir, extending_readonly, mine_chain};
 
 #[test]
 fn test_store_indices() {
@@ -64,3 +69,447 @@ fn test_store_indices() {
 	// Cleanup chain directory
 	clean_output_dir(chain_dir);
 }
+
+#[test]
+fn test_rebuild_output_pos_index() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_idx_2";
+	clean_output_dir(chain_dir);
+
+	let chain = mine_chain(chain_dir, 8);
+	let store = chain.store();
+	let genesis = chain.get_header_by_height(0).unwrap();
+
+	// The index as built while processing blocks.
+	let known_good: Vec<_> = store.batch().unwrap().output_pos_iter().unwrap().collect();
+	assert_eq!(
+		known_good.len() as u64,
+		chain.txhashset().read().utxo_count()
+	);
+
+	// Wipe the index and rebuild it from scratch.
+	{
+		let batch = store.batch().unwrap();
+		for (key, _) in &known_good {
+			batch.delete(key).unwrap();
+		}
+		batch.commit().unwrap();
+	}
+	assert_eq!(store.batch().unwrap().output_pos_iter().unwrap().count(), 0);
+	{
+		let header_pmmr = chain.header_pmmr();
+		let txhashset = chain.txhashset();
+		let batch = store.batch().unwrap();
+		txhashset
+			.read()
+			.init_output_pos_index(&header_pmmr.read(), &batch)
+			.unwrap();
+		batch.commit().unwrap();
+	}
+
+	// The rebuilt index matches the known-good index.
+	// Note: the rebuild has always assigned genesis outputs to height 1.
+	let rebuilt: Vec<_> = store.batch().unwrap().output_pos_iter().unwrap().collect();
+	let expected: Vec<_> = known_good
+		.into_iter()
+		.map(|(key, (pos, height))| {
+			if pos <= genesis.output_mmr_size {
+				(key, (pos, 1))
+			} else {
+				(key, (pos, height))
+			}
+		})
+		.collect();
+	assert_eq!(rebuilt, expected);
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn test_update_output_pos_index_from_height() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_idx_3";
+	clean_output_dir(chain_dir);
+
+	let chain = mine_chain(chain_dir, 8);
+	let store = chain.store();
+	let from_height = 5;
+	let cutoff_pos = chain
+		.get_header_by_height(from_height - 1)
+		.unwrap()
+		.output_mmr_size;
+
+	let known_good: Vec<_> = store.batch().unwrap().output_pos_iter().unwrap().collect();
+	assert!(known_good.iter().any(|(_, (pos, _))| *pos > cutoff_pos));
+
+	// Wipe the index entries for outputs created at or after from_height
+	// and add a stale entry in that range.
+	{
+		let batch = store.batch().unwrap();
+		for (key, (pos, _)) in &known_good {
+			if *pos > cutoff_pos {
+				batch.delete(key).unwrap();
+			}
+		}
+		batch
+			.save_output_pos_height(&Commitment::from_vec(vec![7; 33]), cutoff_pos + 1, 7)
+			.unwrap();
+		batch.commit().unwrap();
+	}
+
+	let header_pmmr = chain.header_pmmr();
+	let txhashset = chain.txhashset();
+	{
+		let batch = store.batch().unwrap();
+		txhashset
+			.read()
+			.update_output_pos_index_from_height(&header_pmmr.read(), &batch, from_height)
+			.unwrap();
+		batch.commit().unwrap();
+	}
+
+	// The targeted update restores the known-good index.
+	let updated: Vec<_> = store.batch().unwrap().output_pos_iter().unwrap().collect();
+	assert_eq!(updated, known_good);
+
+	// And matches a full rebuild for the affected range.
+	let rebuilt: Vec<_> = {
+		let batch = store.batch().unwrap();
+		txhashset
+			.read()
+			.init_output_pos_index(&header_pmmr.read(), &batch)
+			.unwrap();
+		batch.output_pos_iter().unwrap().collect()
+	};
+	let affected = |entries: &[(Vec<u8>, (u64, u64))]| -> Vec<(Vec<u8>, (u64, u64))> {
+		entries
+			.iter()
+			.filter(|(_, (pos, _))| *pos > cutoff_pos)
+			.cloned()
+			.collect()
+	};
+	assert_eq!(affected(&updated), affected(&rebuilt));
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn test_check_output_pos_index() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_idx_check";
+	clean_output_dir(chain_dir);
+
+	let chain = mine_chain(chain_dir, 4);
+	let store = chain.store();
+	let txhashset = chain.txhashset();
+
+	let known_good: Vec<_> = store.batch().unwrap().output_pos_iter().unwrap().collect();
+	assert!(known_good.len() >= 2);
+
+	// A freshly mined chain has consistent indices.
+	{
+		let batch = store.batch().unwrap();
+		let report = txhashset.read().check_output_pos_index(&batch).unwrap();
+		assert!(report.is_consistent());
+		assert_eq!(report.checked, known_good.len() as u64);
+
+		let report = txhashset
+			.read()
+			.check_token_output_pos_index(&batch)
+			.unwrap();
+		assert!(report.is_consistent());
+	}
+
+	// Seed a missing, a stale and a mismatched entry.
+	{
+		let batch = store.batch().unwrap();
+		let (missing_key, _) = &known_good[0];
+		let (_, (live_pos, _)) = known_good[1];
+		batch.delete(missing_key).unwrap();
+		batch
+			.save_output_pos_height(&Commitment::from_vec(vec![7; 33]), 10_000, 7)
+			.unwrap();
+		batch
+			.save_output_pos_height(&Commitment::from_vec(vec![8; 33]), live_pos, 8)
+			.unwrap();
+
+		let report = txhashset.read().check_output_pos_index(&batch).unwrap();
+		assert!(!report.is_consistent());
+		assert_eq!(report.checked, known_good.len() as u64 + 1);
+		assert_eq!(report.stale, 1);
+		assert_eq!(report.mismatched, 1);
+		assert_eq!(report.missing, 1);
+	}
+
+	// The check modified nothing, the uncommitted batch above was discarded.
+	let after: Vec<_> = store.batch().unwrap().output_pos_iter().unwrap().collect();
+	assert_eq!(after, known_good);
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn test_rewind_without_block_bodies() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_idx_rewind";
+	clean_output_dir(chain_dir);
+
+	let chain = mine_chain(chain_dir, 5);
+	let fork_point = chain.get_header_by_height(2).unwrap();
+	let kept = chain.get_block(&fork_point.hash()).unwrap();
+	let rewound: Vec<_> = (3..=4)
+		.map(|height| {
+			let hash = chain.get_header_by_height(height).unwrap().hash();
+			chain.get_block(&hash).unwrap()
+		})
+		.collect();
+
+	// Every output created has an output_created entry for its pos.
+	let created: Vec<_> = rewound
+		.iter()
+		.flat_map(|b| b.outputs().iter().map(|out| out.commitment()))
+		.map(|commit| (commit, chain.get_output_pos(&commit).unwrap()))
+		.collect();
+	{
+		let batch = chain.store().batch().unwrap();
+		for (commit, pos) in &created {
+			let (created_commit, _) = batch.get_output_created(*pos).unwrap();
+			assert_eq!(created_commit, *commit);
+		}
+	}
+
+	extending_readonly(&chain, |ext, batch| {
+		// Remove the bodies of the blocks being rewound, rewind must not need them.
+		for b in &rewound {
+			batch.delete(&to_key(b'b', &mut b.hash().to_vec()))?;
+		}
+		ext.extension.rewind(&fork_point, batch)?;
+
+		for (commit, pos) in &created {
+			assert!(batch.get_output_pos(commit).is_err());
+			assert!(batch.get_output_created(*pos).is_err());
+		}
+		for out in kept.outputs() {
+			assert!(batch.get_output_pos(&out.commitment()).is_ok());
+		}
+		Ok(())
+	})
+	.unwrap();
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn test_rewind_with_incomplete_created_index() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_idx_rewind_incomplete";
+	clean_output_dir(chain_dir);
+
+	let chain = mine_chain(chain_dir, 5);
+	let fork_point = chain.get_header_by_height(2).unwrap();
+	let created: Vec<Vec<_>> = (3..=4)
+		.map(|height| {
+			let hash = chain.get_header_by_height(height).unwrap().hash();
+			chain
+				.get_block(&hash)
+				.unwrap()
+				.outputs()
+				.iter()
+				.map(|out| out.commitment())
+				.map(|commit| (commit, chain.get_output_pos(&commit).unwrap()))
+				.collect()
+		})
+		.collect();
+
+	extending_readonly(&chain, |ext, batch| {
+		// The block at height 3 is missing an output_created entry.
+		let (_, missing_pos) = created[0][0];
+		batch.delete_output_created(missing_pos)?;
+
+		ext.extension.rewind(&fork_point, batch)?;
+
+		// The output_pos index is cleaned up for both blocks, via the block body
+		// for height 3 and via the output_created index for height 4.
+		for (commit, _) in created.iter().flatten() {
+			assert!(batch.get_output_pos(commit).is_err());
+		}
+		// Entries are only deleted from a complete output_created index.
+		for (_, pos) in created[0].iter().skip(1) {
+			assert!(batch.get_output_created(*pos).is_ok());
+		}
+		for (_, pos) in &created[1] {
+			assert!(batch.get_output_created(*pos).is_err());
+		}
+		Ok(())
+	})
+	.unwrap();
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn test_rewind_without_token_spent_index() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_idx_rewind_no_token";
+	clean_output_dir(chain_dir);
+
+	let chain = mine_chain(chain_dir, 5);
+	let fork_point = chain.get_header_by_height(2).unwrap();
+	let rewound: Vec<_> = (3..=4)
+		.map(|height| chain.get_header_by_height(height).unwrap().hash())
+		.collect();
+
+	// Blocks without token inputs have no (empty) token spent index saved.
+	{
+		let batch = chain.store().batch().unwrap();
+		for hash in &rewound {
+			assert!(batch.get_spent_index(hash).is_ok());
+			assert!(batch.get_token_spent_index(hash).is_err());
+		}
+	}
+
+	// Rewind treats the missing token spent index as no token inputs.
+	extending_readonly(&chain, |ext, batch| {
+		ext.extension.rewind(&fork_point, batch)?;
+		ext.extension.validate_roots(&fork_point)
+	})
+	.unwrap();
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+// Time rewinding a deep reorg via the output_created index against the fallback
+// of reading each block, see Extension::rewind_single_block.
+// Run with: cargo test --release --test store_indices -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_rewind_deep_reorg() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_idx_rewind_bench";
+	clean_output_dir(chain_dir);
+
+	let chain = mine_chain(chain_dir, 200);
+	let fork_point = chain.get_header_by_height(1).unwrap();
+	let head = chain.head_header().unwrap();
+
+	for &use_index in &[true, false] {
+		let elapsed = extending_readonly(&chain, |ext, batch| {
+			if !use_index {
+				for pos in (fork_point.output_mmr_size + 1)..=head.output_mmr_size {
+					if pmmr::is_leaf(pos) {
+						batch.delete_output_created(pos)?;
+					}
+				}
+			}
+			let now = Instant::now();
+			ext.extension.rewind(&fork_point, batch)?;
+			let elapsed = now.elapsed();
+			ext.extension.validate_roots(&fork_point)?;
+			Ok(elapsed)
+		})
+		.unwrap();
+		println!(
+			"rewind {} blocks (output_created index: {}): {}ms",
+			head.height - fork_point.height,
+			use_index,
+			elapsed.as_millis(),
+		);
+	}
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
+
+#[test]
+fn test_kernel_height_index() {
+	util::init_test_logger();
+
+	let chain_dir = ".grin_idx_kernel_height";
+	clean_output_dir(chain_dir);
+
+	let chain = mine_chain(chain_dir, 5);
+	let kernels: Vec<_> = (0..=4)
+		.flat_map(|height| {
+			let hash = chain.get_header_by_height(height).unwrap().hash();
+			let block = chain.get_block(&hash).unwrap();
+			block
+				.kernels()
+				.iter()
+				.map(|k| (k.excess, height))
+				.collect::<Vec<_>>()
+		})
+		.collect();
+
+	// Every kernel is indexed by the height of the block containing it.
+	for (excess, height) in &kernels {
+		assert_eq!(chain.kernel_height(excess).unwrap(), Some(*height));
+	}
+	assert_eq!(
+		chain
+			.kernel_height(&Commitment::from_vec(vec![9; 33]))
+			.unwrap(),
+		None
+	);
+
+	// Fetch the fork point before locking, get_header_by_height takes the header MMR lock.
+	let fork_point = chain.get_header_by_height(2).unwrap();
+
+	let header_pmmr = chain.header_pmmr();
+	let txhashset = chain.txhashset();
+	let mut header_pmmr = header_pmmr.write();
+	let mut txhashset = txhashset.write();
+
+	// Rewinding removes the entries for the rewound kernels only.
+	chain::txhashset::extending_readonly(&mut header_pmmr, &mut txhashset, |ext, batch| {
+		ext.extension.rewind(&fork_point, batch)?;
+		for (excess, height) in &kernels {
+			let expected = if *height > 2 { None } else { Some(*height) };
+			assert_eq!(batch.get_kernel_height(excess)?, expected);
+		}
+		Ok(())
+	})
+	.unwrap();
+
+	// A missing index is rebuilt.
+	// kernel_block finds the block via the index and falls back to a scan without it.
+	{
+		let batch = chain.store().batch().unwrap();
+		let kernel_block_height = |excess: &Commitment| {
+			txhashset
+				.kernel_block(excess, &header_pmmr, &batch)
+				.unwrap()
+				.map(|(_, height)| height)
+		};
+		for (excess, height) in &kernels {
+			assert_eq!(kernel_block_height(excess), Some(*height));
+		}
+		for (excess, _) in &kernels {
+			batch.delete_kernel_height(excess).unwrap();
+		}
+		for (excess, height) in &kernels {
+			assert_eq!(kernel_block_height(excess), Some(*height));
+		}
+		txhashset
+			.init_kernel_height_index(&header_pmmr, &batch)
+			.unwrap();
+		for (excess, height) in &kernels {
+			assert_eq!(batch.get_kernel_height(excess).unwrap(), Some(*height));
+		}
+	}
+
+	// Cleanup chain directory
+	clean_output_dir(chain_dir);
+}
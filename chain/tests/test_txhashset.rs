@@ -18,13 +18,20 @@ use grin_core as core;
 use grin_util as util;
 
 use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread;
 
 use crate::chain::store::ChainStore;
-use crate::chain::txhashset;
-use crate::core::core::BlockHeader;
+use crate::chain::txhashset::{self, BitmapAccumulator, PMMRHandle, TxHashSet, ZipWriteLimits};
+use crate::chain::ErrorKind;
+use crate::core::core::pmmr::{self, PMMR};
+use crate::core::core::{BlockHeader, Output, OutputFeatures};
+use crate::core::ser::ProtocolVersion;
 use crate::util::file;
+use crate::util::secp::pedersen::{Commitment, RangeProof};
+use fs2::FileExt;
 use grin_core::core::hash::Hashed;
 
 fn clean_output_dir(dir_name: &str) {
@@ -33,7 +40,7 @@ fn clean_output_dir(dir_name: &str) {
 
 #[test]
 fn test_unexpected_zip() {
-	let db_root = format!(".grin_txhashset_zip");
+	let db_root = ".grin_txhashset_zip".to_string();
 	clean_output_dir(&db_root);
 	{
 		let chain_store = ChainStore::new(&db_root).unwrap();
@@ -138,3 +145,521 @@ fn test_unexpected_zip() {
 	// Cleanup chain directory
 	clean_output_dir(&db_root);
 }
+
+#[test]
+fn test_zip_read_concurrent() {
+	let db_root = ".grin_txhashset_zip_concurrent".to_string();
+	clean_output_dir(&db_root);
+	{
+		let store = Arc::new(ChainStore::new(&db_root).unwrap());
+		txhashset::TxHashSet::open(db_root.clone(), store, None).unwrap();
+		let head = BlockHeader::default();
+
+		// All callers miss the zip, one of them creates it and any caller finding the
+		// zip locked fails fast rather than waiting.
+		let handles: Vec<_> = (0..4)
+			.map(|_| {
+				let db_root = db_root.clone();
+				let head = head.clone();
+				thread::spawn(move || txhashset::zip_read_with_hash(db_root, &head))
+			})
+			.collect();
+		let mut hashes = vec![];
+		for res in handles.into_iter().map(|h| h.join().unwrap()) {
+			match res {
+				Ok((_, hash)) => hashes.push(hash),
+				Err(e) => match e.kind() {
+					ErrorKind::ZipLocked(_) => {}
+					e => panic!("unexpected error: {:?}", e),
+				},
+			}
+		}
+		assert!(!hashes.is_empty());
+		assert!(hashes.iter().all(|h| *h == hashes[0]));
+
+		// The zip is intact and the lock file (and tmp file) cleaned up.
+		let zip_path = Path::new(&db_root).join(format!("txhashset_snapshot_{}.zip", head.hash()));
+		let zip_file = File::open(&zip_path).unwrap();
+		txhashset::verify_zip(&zip_file, &head).unwrap();
+		assert!(!zip_path.with_extension("zip.lock").exists());
+		assert!(!zip_path.with_extension("zip.tmp").exists());
+	}
+	clean_output_dir(&db_root);
+}
+
+#[test]
+fn test_zip_read_locked() {
+	let db_root = ".grin_txhashset_zip_locked".to_string();
+	clean_output_dir(&db_root);
+	{
+		let store = Arc::new(ChainStore::new(&db_root).unwrap());
+		txhashset::TxHashSet::open(db_root.clone(), store, None).unwrap();
+		let head = BlockHeader::default();
+		let zip_path = Path::new(&db_root).join(format!("txhashset_snapshot_{}.zip", head.hash()));
+		let lock_path = zip_path.with_extension("zip.lock");
+
+		// A lock file left behind by a dead process (nobody holds the lock) is reused.
+		File::create(&lock_path).unwrap();
+		let (_, hash) = txhashset::zip_read_with_hash(db_root.clone(), &head).unwrap();
+		assert!(!lock_path.exists());
+
+		// While another process holds the lock we fail fast with ZipLocked.
+		fs::remove_file(&zip_path).unwrap();
+		let holder = File::create(&lock_path).unwrap();
+		holder.try_lock_exclusive().unwrap();
+		match txhashset::zip_read_with_hash(db_root.clone(), &head) {
+			Err(e) => match e.kind() {
+				ErrorKind::ZipLocked(_) => {}
+				e => panic!("unexpected error: {:?}", e),
+			},
+			Ok(_) => panic!("expected zip to be locked"),
+		}
+		assert!(!zip_path.exists());
+
+		// Once released (the holder exits) the zip is created.
+		holder.unlock().unwrap();
+		drop(holder);
+		let (_, rehash) = txhashset::zip_read_with_hash(db_root.clone(), &head).unwrap();
+		assert_eq!(hash, rehash);
+		assert!(!lock_path.exists());
+	}
+	clean_output_dir(&db_root);
+}
+
+#[test]
+fn test_zip_read_compressed_roundtrip() {
+	let db_root = ".grin_txhashset_zip_compressed".to_string();
+	let sandbox_root = ".grin_txhashset_zip_compressed_sandbox".to_string();
+	clean_output_dir(&db_root);
+	clean_output_dir(&sandbox_root);
+	{
+		let store = Arc::new(ChainStore::new(&db_root).unwrap());
+		txhashset::TxHashSet::open(db_root.clone(), store, None).unwrap();
+		let head = BlockHeader::default();
+
+		// The compressed archive is detected as zstd, the plain zip is not.
+		let mut zst_file = txhashset::zip_read_compressed(db_root.clone(), &head).unwrap();
+		assert!(util::zip::is_zstd(&mut zst_file).unwrap());
+		let mut zip_file = txhashset::zip_read(db_root.clone(), &head).unwrap();
+		assert!(!util::zip::is_zstd(&mut zip_file).unwrap());
+
+		// zip_write detects the compressed archive and extracts the same files.
+		txhashset::zip_write(PathBuf::from(sandbox_root.clone()), zst_file, &head).unwrap();
+		let src_dir = Path::new(&db_root).join("txhashset");
+		let dst_dir = Path::new(&sandbox_root).join("txhashset");
+		let files = file::list_files(&dst_dir);
+		assert!(files.contains(&PathBuf::from("kernel/pmmr_hash.bin")));
+		assert!(files.contains(&PathBuf::from("tokenoutput/pmmr_data.bin")));
+		for file in &files {
+			assert_eq!(
+				fs::read(src_dir.join(file)).unwrap(),
+				fs::read(dst_dir.join(file)).unwrap()
+			);
+		}
+
+		// The decompressed zip is cleaned up.
+		assert!(!Path::new(&sandbox_root)
+			.join(format!("txhashset_zstd_{}.zip", head.hash()))
+			.exists());
+	}
+	clean_output_dir(&db_root);
+	clean_output_dir(&sandbox_root);
+}
+
+#[test]
+fn test_zip_progress() {
+	let db_root = ".grin_txhashset_zip_progress".to_string();
+	let sandbox_root = ".grin_txhashset_zip_progress_sandbox".to_string();
+	clean_output_dir(&db_root);
+	clean_output_dir(&sandbox_root);
+	{
+		let store = Arc::new(ChainStore::new(&db_root).unwrap());
+		txhashset::TxHashSet::open(db_root.clone(), store, None).unwrap();
+		let head = BlockHeader::default();
+
+		// Enough data for several progress updates along the way.
+		let data_path = Path::new(&db_root)
+			.join("txhashset")
+			.join("output")
+			.join("pmmr_data.bin");
+		fs::write(&data_path, vec![7u8; 20 * 1024 * 1024]).unwrap();
+
+		// Progress is reported as (done, total), never goes backwards and ends complete.
+		let check = |reports: &[(u64, u64)]| {
+			assert!(reports.len() > 1);
+			let (_, total) = reports[0];
+			assert!(total > 0);
+			assert!(reports.iter().all(|(_, t)| *t == total));
+			assert!(reports.windows(2).all(|w| w[0].0 <= w[1].0));
+			assert_eq!(reports.last(), Some(&(total, total)));
+		};
+
+		let mut reports = vec![];
+		let (zip_file, _) = txhashset::zip_read_with_progress(
+			db_root.clone(),
+			&head,
+			txhashset::TXHASHSET_ZIP_RETENTION_SECS,
+			&mut |done, total| reports.push((done, total)),
+		)
+		.unwrap();
+		check(&reports);
+
+		let mut reports = vec![];
+		txhashset::zip_write_with_progress(
+			PathBuf::from(sandbox_root.clone()),
+			zip_file,
+			&head,
+			&mut |done, total| reports.push((done, total)),
+		)
+		.unwrap();
+		check(&reports);
+		assert_eq!(
+			fs::read(&data_path).unwrap(),
+			fs::read(
+				Path::new(&sandbox_root)
+					.join("txhashset")
+					.join("output")
+					.join("pmmr_data.bin")
+			)
+			.unwrap()
+		);
+	}
+	clean_output_dir(&db_root);
+	clean_output_dir(&sandbox_root);
+}
+
+#[test]
+fn test_zip_read_compressed_concurrent() {
+	let db_root = ".grin_txhashset_zip_compressed_concurrent".to_string();
+	clean_output_dir(&db_root);
+	{
+		let store = Arc::new(ChainStore::new(&db_root).unwrap());
+		txhashset::TxHashSet::open(db_root.clone(), store, None).unwrap();
+		let head = BlockHeader::default();
+
+		// All callers miss the compressed zip, one of them creates it and any caller
+		// finding it locked fails fast rather than waiting.
+		let handles: Vec<_> = (0..4)
+			.map(|_| {
+				let db_root = db_root.clone();
+				let head = head.clone();
+				thread::spawn(move || {
+					txhashset::zip_read_compressed(db_root, &head).map(|mut file| {
+						let mut contents = vec![];
+						file.read_to_end(&mut contents).unwrap();
+						contents
+					})
+				})
+			})
+			.collect();
+		let mut contents = vec![];
+		for res in handles.into_iter().map(|h| h.join().unwrap()) {
+			match res {
+				Ok(c) => contents.push(c),
+				Err(e) => match e.kind() {
+					ErrorKind::ZipLocked(_) => {}
+					e => panic!("unexpected error: {:?}", e),
+				},
+			}
+		}
+		assert!(!contents.is_empty());
+		assert!(!contents[0].is_empty());
+		assert!(contents.iter().all(|c| *c == contents[0]));
+
+		// The lock file and tmp file are cleaned up.
+		let zst_path =
+			Path::new(&db_root).join(format!("txhashset_snapshot_{}.zip.zst", head.hash()));
+		assert!(zst_path.exists());
+		assert!(!zst_path.with_extension("zst.lock").exists());
+		assert!(!zst_path.with_extension("zst.tmp").exists());
+	}
+	clean_output_dir(&db_root);
+}
+
+// Build a zip with the given (name, content) entries, names are written as is.
+fn craft_zip<S: Into<String>>(path: &Path, entries: Vec<(S, Vec<u8>)>) -> File {
+	{
+		let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+		let options =
+			zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+		for (name, content) in entries {
+			zip.start_file(name, options).unwrap();
+			zip.write_all(&content).unwrap();
+		}
+		zip.finish().unwrap();
+	}
+	File::open(path).unwrap()
+}
+
+// Empty MMR data and hash files, the minimum required for a zip to be extracted.
+fn required_zip_entries() -> Vec<(String, Vec<u8>)> {
+	let dirs = vec![
+		"kernel",
+		"output",
+		"rangeproof",
+		"tokenkernel",
+		"tokenoutput",
+		"tokenrangeproof",
+		"tokenissueproof",
+	];
+	let mut entries = vec![];
+	for dir in dirs {
+		entries.push((format!("{}/pmmr_data.bin", dir), vec![]));
+		entries.push((format!("{}/pmmr_hash.bin", dir), vec![]));
+	}
+	entries
+}
+
+#[test]
+fn test_zip_write_path_traversal() {
+	let db_root = ".grin_txhashset_zip_traversal".to_string();
+	clean_output_dir(&db_root);
+	fs::create_dir_all(&db_root).unwrap();
+	let head = BlockHeader::default();
+
+	for name in vec!["../evil.bin", "kernel/../../evil.bin", "/tmp/evil.bin"] {
+		let zip_file = craft_zip(
+			&Path::new(&db_root).join("traversal.zip"),
+			vec![("kernel/pmmr_data.bin", vec![0; 64]), (name, vec![1; 64])],
+		);
+		let res = txhashset::zip_write(PathBuf::from(db_root.clone()), zip_file, &head);
+		match res.unwrap_err().kind() {
+			ErrorKind::ZipUnsafePath(_) => {}
+			e => panic!("unexpected error: {:?}", e),
+		}
+	}
+
+	// Nothing was extracted, neither the "good" entry nor the malicious one.
+	assert!(!Path::new(&db_root)
+		.join("txhashset/kernel/pmmr_data.bin")
+		.exists());
+	assert!(!Path::new(&db_root).join("evil.bin").exists());
+	assert!(!Path::new(&format!("{}/../evil.bin", db_root)).exists());
+	assert!(!Path::new(&db_root)
+		.join(format!("txhashset_staging_{}", head.hash()))
+		.exists());
+
+	clean_output_dir(&db_root);
+}
+
+#[test]
+fn test_zip_write_oversize() {
+	let db_root = ".grin_txhashset_zip_oversize".to_string();
+	clean_output_dir(&db_root);
+	fs::create_dir_all(&db_root).unwrap();
+	let head = BlockHeader::default();
+	let limits = ZipWriteLimits {
+		max_entry_size: 512,
+		max_total_size: 1024,
+	};
+
+	// A single entry exceeding the per entry limit.
+	let zip_file = craft_zip(
+		&Path::new(&db_root).join("oversize.zip"),
+		vec![("kernel/pmmr_data.bin", vec![0; 513])],
+	);
+	let res = txhashset::zip_write_with_limits(
+		PathBuf::from(db_root.clone()),
+		zip_file,
+		&head,
+		&limits,
+		&mut |_, _| {},
+	);
+	match res.unwrap_err().kind() {
+		ErrorKind::ZipTooLarge(_) => {}
+		e => panic!("unexpected error: {:?}", e),
+	}
+
+	// Entries individually within the limit but exceeding the aggregate limit.
+	let zip_file = craft_zip(
+		&Path::new(&db_root).join("oversize.zip"),
+		vec![
+			("kernel/pmmr_data.bin", vec![0; 512]),
+			("kernel/pmmr_hash.bin", vec![0; 512]),
+			("output/pmmr_data.bin", vec![0; 512]),
+		],
+	);
+	let res = txhashset::zip_write_with_limits(
+		PathBuf::from(db_root.clone()),
+		zip_file,
+		&head,
+		&limits,
+		&mut |_, _| {},
+	);
+	match res.unwrap_err().kind() {
+		ErrorKind::ZipTooLarge(_) => {}
+		e => panic!("unexpected error: {:?}", e),
+	}
+
+	// Entries we never extract do not count towards the limits.
+	let mut entries = required_zip_entries();
+	entries.push(("unexpected.bin".to_string(), vec![0; 4096]));
+	let zip_file = craft_zip(&Path::new(&db_root).join("oversize.zip"), entries);
+	let res = txhashset::zip_write_with_limits(
+		PathBuf::from(db_root.clone()),
+		zip_file,
+		&head,
+		&limits,
+		&mut |_, _| {},
+	);
+	assert!(res.is_ok());
+
+	// Nothing from the oversize zips made it into the txhashset dir.
+	let files = file::list_files(&Path::new(&db_root).join("txhashset"));
+	assert!(!files.contains(&PathBuf::from("kernel/pmmr_hash.bin")));
+	assert!(!files.contains(&PathBuf::from("output/pmmr_data.bin")));
+	assert!(!Path::new(&db_root)
+		.join(format!("txhashset_staging_{}", head.hash()))
+		.exists());
+
+	clean_output_dir(&db_root);
+}
+
+#[test]
+fn test_zip_write_missing_files() {
+	let db_root = ".grin_txhashset_zip_missing".to_string();
+	clean_output_dir(&db_root);
+	fs::create_dir_all(&db_root).unwrap();
+	let head = BlockHeader::default();
+
+	// A complete zip passes the check.
+	let zip_file = craft_zip(
+		&Path::new(&db_root).join("complete.zip"),
+		required_zip_entries(),
+	);
+	txhashset::verify_zip(&zip_file, &head).unwrap();
+
+	// A truncated zip is rejected up front, naming the missing files.
+	let entries = required_zip_entries()
+		.into_iter()
+		.filter(|(name, _)| !name.starts_with("output/") && *name != "kernel/pmmr_hash.bin")
+		.collect();
+	let zip_file = craft_zip(&Path::new(&db_root).join("truncated.zip"), entries);
+	match txhashset::verify_zip(&zip_file, &head).unwrap_err().kind() {
+		ErrorKind::ZipMissingFiles(missing) => {
+			assert_eq!(
+				missing,
+				"kernel/pmmr_hash.bin, output/pmmr_data.bin, output/pmmr_hash.bin"
+			);
+		}
+		e => panic!("unexpected error: {:?}", e),
+	}
+
+	let res = txhashset::zip_write(PathBuf::from(db_root.clone()), zip_file, &head);
+	match res.unwrap_err().kind() {
+		ErrorKind::ZipMissingFiles(_) => {}
+		e => panic!("unexpected error: {:?}", e),
+	}
+
+	// Nothing was extracted.
+	assert!(!Path::new(&db_root)
+		.join("txhashset/kernel/pmmr_data.bin")
+		.exists());
+
+	clean_output_dir(&db_root);
+}
+
+#[test]
+fn test_zip_write_checksum_mismatch() {
+	let db_root = ".grin_txhashset_zip_checksum".to_string();
+	clean_output_dir(&db_root);
+	fs::create_dir_all(&db_root).unwrap();
+	let head = BlockHeader::default();
+
+	// sha256 of an empty file.
+	let empty_sha = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+	let manifest = |entries: &Vec<(String, Vec<u8>)>| {
+		let lines: Vec<_> = entries
+			.iter()
+			.map(|(name, _)| format!("{} {}\n", empty_sha, name))
+			.collect();
+		(
+			"txhashset_manifest.txt".to_string(),
+			lines.concat().into_bytes(),
+		)
+	};
+
+	// Entries matching the manifest are accepted.
+	let mut entries = required_zip_entries();
+	entries.push(manifest(&entries));
+	let zip_file = craft_zip(&Path::new(&db_root).join("valid.zip"), entries);
+	txhashset::verify_zip(&zip_file, &head).unwrap();
+
+	// A corrupted entry (no longer matching the manifest) is rejected before extracting.
+	let mut entries: Vec<_> = required_zip_entries()
+		.into_iter()
+		.map(|(name, content)| match name.as_str() {
+			"kernel/pmmr_data.bin" => (name, vec![1; 64]),
+			_ => (name, content),
+		})
+		.collect();
+	entries.push(manifest(&entries));
+	let zip_file = craft_zip(&Path::new(&db_root).join("corrupt.zip"), entries);
+	match txhashset::verify_zip(&zip_file, &head).unwrap_err().kind() {
+		ErrorKind::ZipChecksumMismatch(file) => assert!(file.contains("kernel/pmmr_data.bin")),
+		e => panic!("unexpected error: {:?}", e),
+	}
+	let res = txhashset::zip_write(PathBuf::from(db_root.clone()), zip_file, &head);
+	match res.unwrap_err().kind() {
+		ErrorKind::ZipChecksumMismatch(_) => {}
+		e => panic!("unexpected error: {:?}", e),
+	}
+
+	// Nothing landed in the txhashset dir (or the staging dir).
+	let files = file::list_files(&Path::new(&db_root).join("txhashset"));
+	assert!(files.is_empty());
+	assert!(!Path::new(&db_root)
+		.join(format!("txhashset_staging_{}", head.hash()))
+		.exists());
+
+	clean_output_dir(&db_root);
+}
+
+#[test]
+fn test_verify_bitmap_accumulator() {
+	let db_root = ".grin_verify_bitmap_accumulator";
+	clean_output_dir(db_root);
+
+	// A freshly opened txhashset has an accumulator consistent with its output MMR.
+	{
+		let store = Arc::new(ChainStore::new(db_root).unwrap());
+		let txhashset = TxHashSet::open(db_root.to_string(), store, None).unwrap();
+		txhashset.verify_bitmap_accumulator().unwrap();
+	}
+
+	let mut output_pmmr_h: PMMRHandle<Output> =
+		PMMRHandle::new(db_root, "check", "output", true, ProtocolVersion(1), None).unwrap();
+	{
+		let mut pmmr = PMMR::at(&mut output_pmmr_h.backend, output_pmmr_h.last_pos);
+		for n in 0..10u8 {
+			let output = Output {
+				features: OutputFeatures::Plain,
+				commit: Commitment::from_vec(vec![n; 33]),
+				proof: RangeProof::zero(),
+			};
+			pmmr.push(&output).unwrap();
+		}
+		// Spend the output at leaf idx 3.
+		pmmr.prune(pmmr::insertion_to_pmmr_index(4)).unwrap();
+		output_pmmr_h.last_pos = pmmr.unpruned_size();
+	}
+
+	let mut bitmap_accumulator = BitmapAccumulator::new();
+	bitmap_accumulator
+		.init(vec![0, 1, 2, 4, 5, 6, 7, 8, 9], 10)
+		.unwrap();
+	txhashset::check_bitmap_accumulator(&bitmap_accumulator, &output_pmmr_h).unwrap();
+
+	// Corrupt accumulator, the spend at idx 3 was never applied.
+	let mut corrupt = BitmapAccumulator::new();
+	corrupt.init(0..10, 10).unwrap();
+	match txhashset::check_bitmap_accumulator(&corrupt, &output_pmmr_h)
+		.unwrap_err()
+		.kind()
+	{
+		ErrorKind::BitmapAccumulatorMismatch(_) => {}
+		e => panic!("unexpected error: {:?}", e),
+	}
+
+	clean_output_dir(db_root);
+}
@@ -17,7 +17,8 @@
 use std::marker;
 
 use crate::core::hash::{Hash, ZERO_HASH};
-use crate::core::pmmr::pmmr::{bintree_rightmost, peaks};
+use crate::core::merkle_proof::MerkleProof;
+use crate::core::pmmr::pmmr::{bintree_rightmost, family_branch, peaks};
 use crate::core::pmmr::{is_leaf, Backend};
 use crate::ser::{PMMRIndexHashable, PMMRable};
 
@@ -140,6 +141,75 @@ where
 			.collect()
 	}
 
+	/// Build a Merkle proof for the element at the given position.
+	/// Only reads from the backend so does not require mutable access to it.
+	pub fn merkle_proof(&self, pos: u64) -> Result<MerkleProof, String> {
+		debug!("merkle_proof  {}, last_pos {}", pos, self.last_pos);
+
+		// check this pos is actually a leaf in the MMR
+		if !is_leaf(pos) {
+			return Err(format!("not a leaf at pos {}", pos));
+		}
+
+		// check we actually have a hash in the MMR at this pos
+		self.get_hash(pos)
+			.ok_or_else(|| format!("no element at pos {}", pos))?;
+
+		let mmr_size = self.unpruned_size();
+
+		let family_branch = family_branch(pos, self.last_pos);
+
+		let mut path = family_branch
+			.iter()
+			.filter_map(|x| self.get_from_file(x.1))
+			.collect::<Vec<_>>();
+
+		let peak_pos = match family_branch.last() {
+			Some(&(x, _)) => x,
+			None => pos,
+		};
+
+		path.append(&mut self.peak_path(peak_pos));
+
+		Ok(MerkleProof { mmr_size, path })
+	}
+
+	fn peak_path(&self, peak_pos: u64) -> Vec<Hash> {
+		let rhs = self.bag_the_rhs(peak_pos);
+		let mut res = peaks(self.last_pos)
+			.into_iter()
+			.filter(|x| *x < peak_pos)
+			.filter_map(|x| self.backend.get_from_file(x))
+			.collect::<Vec<_>>();
+		if let Some(rhs) = rhs {
+			res.push(rhs);
+		}
+		res.reverse();
+
+		res
+	}
+
+	/// Takes a single peak position and hashes together
+	/// all the peaks to the right of this peak (if any).
+	/// If this return a hash then this is our peaks sibling.
+	/// If none then the sibling of our peak is the peak to the left.
+	pub fn bag_the_rhs(&self, peak_pos: u64) -> Option<Hash> {
+		let rhs = peaks(self.last_pos)
+			.into_iter()
+			.filter(|x| *x > peak_pos)
+			.filter_map(|x| self.backend.get_from_file(x))
+			.collect::<Vec<_>>();
+
+		let mut res = None;
+		for peak in rhs.into_iter().rev() {
+			res = match res {
+				None => Some(peak),
+				Some(rhash) => Some((peak, rhash).hash_with_index(self.unpruned_size())),
+			}
+		}
+		res
+	}
+
 	/// Total size of the tree, including intermediary nodes and ignoring any
 	/// pruning.
 	pub fn unpruned_size(&self) -> u64 {
@@ -2622,8 +2622,20 @@ impl Output {
 	/// Batch validates the range proofs using the commitments
 	pub fn batch_verify_proofs(commits: &[Commitment], proofs: &[RangeProof]) -> Result<(), Error> {
 		let secp = static_secp_instance();
-		secp.lock()
-			.verify_bullet_proof_multi(commits.to_vec(), proofs.to_vec(), None)?;
+		let secp = secp.lock();
+		Output::batch_verify_proofs_with_secp(&secp, commits, proofs)
+	}
+
+	/// Batch validates the range proofs using the commitments and the provided secp context
+	/// (rather than the shared static instance, which is behind a mutex).
+	/// Verification only requires a shared reference to the context so a single context
+	/// can be used by one worker for many batches. The context must have Commit capabilities.
+	pub fn batch_verify_proofs_with_secp(
+		secp: &secp::Secp256k1,
+		commits: &[Commitment],
+		proofs: &[RangeProof],
+	) -> Result<(), Error> {
+		secp.verify_bullet_proof_multi(commits.to_vec(), proofs.to_vec(), None)?;
 		Ok(())
 	}
 }
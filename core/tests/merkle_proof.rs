@@ -15,7 +15,7 @@
 mod common;
 
 use self::core::core::merkle_proof::MerkleProof;
-use self::core::core::pmmr::{VecBackend, PMMR};
+use self::core::core::pmmr::{ReadonlyPMMR, VecBackend, PMMR};
 use self::core::ser::{self, PMMRIndexHashable};
 use crate::common::TestElem;
 use grin_core as core;
@@ -187,3 +187,39 @@ fn pmmr_merkle_proof() {
 	assert_eq!(proof.path, vec![pos_9, pos_6]);
 	assert!(proof.verify(pmmr.root().unwrap(), &elems[6], 11).is_ok());
 }
+
+#[test]
+fn readonly_pmmr_merkle_proof() {
+	let mut ba = VecBackend::new();
+	let size = {
+		let mut pmmr = PMMR::new(&mut ba);
+		for x in 0..11 {
+			pmmr.push(&TestElem([0, 0, 0, x])).unwrap();
+		}
+		pmmr.unpruned_size()
+	};
+
+	let (root, expected) = {
+		let pmmr: PMMR<'_, TestElem, _> = PMMR::at(&mut ba, size);
+		let expected: Vec<_> = [1, 2, 4, 8, 16, 17, 19]
+			.iter()
+			.map(|&pos| pmmr.merkle_proof(pos).unwrap())
+			.collect();
+		(pmmr.root().unwrap(), expected)
+	};
+
+	// A readonly PMMR over the same backend builds identical proofs.
+	let readonly_pmmr: ReadonlyPMMR<'_, TestElem, _> = ReadonlyPMMR::at(&ba, size);
+	assert_eq!(readonly_pmmr.root(), root);
+	for (i, &pos) in [1, 2, 4, 8, 16, 17, 19].iter().enumerate() {
+		let proof = readonly_pmmr.merkle_proof(pos).unwrap();
+		assert_eq!(proof, expected[i]);
+		assert!(proof
+			.verify(root, &readonly_pmmr.get_data(pos).unwrap(), pos)
+			.is_ok());
+	}
+
+	// Not a leaf, or beyond the end of the MMR.
+	assert!(readonly_pmmr.merkle_proof(3).is_err());
+	assert!(readonly_pmmr.merkle_proof(size + 1).is_err());
+}
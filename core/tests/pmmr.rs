@@ -404,6 +404,35 @@ fn pmmr_get_last_n_insertions() {
 	assert!(res.len() == 7);
 }
 
+#[test]
+fn pmmr_get_last_n_insertions_data() {
+	let elems: Vec<_> = (1..10).map(|x| TestElem([0, 0, 0, x])).collect();
+
+	let mut ba = VecBackend::new();
+	let mut pmmr = PMMR::new(&mut ba);
+	for elem in &elems {
+		pmmr.push(elem).unwrap();
+	}
+
+	// Most recent insertion first, each with the leaf hash and the data as pushed.
+	let res = pmmr.readonly_pmmr().get_last_n_insertions(4);
+	let expected: Vec<_> = (6..10)
+		.rev()
+		.map(|n| {
+			let pos = pmmr::insertion_to_pmmr_index(n);
+			let elem = elems[n as usize - 1];
+			(elem.hash_with_index(pos - 1), elem)
+		})
+		.collect();
+	assert_eq!(res, expected);
+
+	// Asking for more than we have returns everything.
+	let res = pmmr.readonly_pmmr().get_last_n_insertions(20);
+	assert_eq!(res.len(), elems.len());
+	let data: Vec<_> = res.into_iter().map(|(_, elem)| elem).rev().collect();
+	assert_eq!(data, elems);
+}
+
 #[test]
 #[allow(unused_variables)]
 fn pmmr_prune() {
@@ -60,6 +60,7 @@ pub struct PMMRBackend<T: PMMRable> {
 	data_file: DataFile<T::E>,
 	leaf_set: LeafSet,
 	prune_list: PruneList,
+	readonly: bool,
 }
 
 impl<T: PMMRable> Backend<T> for PMMRBackend<T> {
@@ -210,6 +211,12 @@ impl<T: PMMRable> Backend<T> for PMMRBackend<T> {
 	}
 
 	fn snapshot(&self, header: &BlockHeader) -> Result<(), String> {
+		self.check_writable().map_err(|e| e.to_string())?;
+		// A non-prunable MMR has an empty leaf_set, the hash and data files
+		// (rewound by size) fully describe it so there is nothing to snapshot.
+		if !self.prunable {
+			return Ok(());
+		}
 		self.leaf_set
 			.snapshot(header)
 			.map_err(|_| format!("Failed to save copy of leaf_set for {}", header.hash()))?;
@@ -281,9 +288,66 @@ impl<T: PMMRable> PMMRBackend<T> {
 			data_file,
 			leaf_set,
 			prune_list,
+			readonly: false,
+		})
+	}
+
+	/// Opens an existing PMMR backend in read-only mode.
+	/// No file is created, rebuilt or modified, so this is safe to use on a data dir
+	/// concurrently in use by another process. Any attempt to sync or compact fails.
+	pub fn open_readonly<P: AsRef<Path>>(
+		data_dir: P,
+		prunable: bool,
+		version: ProtocolVersion,
+	) -> io::Result<PMMRBackend<T>> {
+		let data_dir = data_dir.as_ref();
+
+		let size_info = if let Some(fixed_size) = T::elmt_size() {
+			SizeInfo::FixedSize(fixed_size)
+		} else {
+			SizeInfo::VariableSize(Box::new(AppendOnlyFile::open_readonly(
+				data_dir.join(PMMR_SIZE_FILE),
+				SizeInfo::FixedSize(SizeEntry::LEN as u16),
+				version,
+			)?))
+		};
+
+		let hash_size_info = SizeInfo::FixedSize(Hash::LEN.try_into().unwrap());
+
+		let hash_file =
+			DataFile::open_readonly(&data_dir.join(PMMR_HASH_FILE), hash_size_info, version)?;
+		let data_file =
+			DataFile::open_readonly(&data_dir.join(PMMR_DATA_FILE), size_info, version)?;
+
+		let leaf_set = LeafSet::open(&data_dir.join(PMMR_LEAF_FILE))?;
+		let prune_list = PruneList::open(&data_dir.join(PMMR_PRUN_FILE))?;
+
+		Ok(PMMRBackend {
+			data_dir: data_dir.to_path_buf(),
+			prunable,
+			hash_file,
+			data_file,
+			leaf_set,
+			prune_list,
+			readonly: true,
 		})
 	}
 
+	/// Whether this backend was opened in read-only mode.
+	pub fn is_readonly(&self) -> bool {
+		self.readonly
+	}
+
+	fn check_writable(&self) -> io::Result<()> {
+		if self.readonly {
+			return Err(io::Error::new(
+				io::ErrorKind::PermissionDenied,
+				format!("pmmr backend opened read-only: {:?}", self.data_dir),
+			));
+		}
+		Ok(())
+	}
+
 	fn is_pruned(&self, pos: u64) -> bool {
 		self.prune_list.is_pruned(pos)
 	}
@@ -296,6 +360,11 @@ impl<T: PMMRable> PMMRBackend<T> {
 		self.is_pruned(pos) && !self.is_pruned_root(pos)
 	}
 
+	/// Bitmap of all leaf positions pruned (and compacted away) from this backend.
+	pub fn pruned_leaves(&self) -> Bitmap {
+		self.prune_list.pruned_leaves()
+	}
+
 	/// Number of hashes in the PMMR stored by this backend. Only produces the
 	/// fully sync'd size.
 	pub fn unpruned_size(&self) -> u64 {
@@ -314,12 +383,27 @@ impl<T: PMMRable> PMMRBackend<T> {
 		self.hash_file.size()
 	}
 
+	/// Combined size in bytes of the hash and data files on disk.
+	pub fn file_size_in_bytes(&self) -> io::Result<u64> {
+		let hash_bytes = fs::metadata(self.hash_file.path())?.len();
+		let data_bytes = fs::metadata(self.data_file.path())?.len();
+		Ok(hash_bytes + data_bytes)
+	}
+
 	/// Syncs all files to disk. A call to sync is required to ensure all the
 	/// data has been successfully written to disk.
 	pub fn sync(&mut self) -> io::Result<()> {
+		self.sync_with_durability(true)
+	}
+
+	/// Syncs all files to disk, as sync() does, but only issues an fsync on the hash
+	/// and data files if durable. A non-durable sync is a buffered write only and
+	/// recent writes may be lost if the OS crashes before they are written back.
+	pub fn sync_with_durability(&mut self, durable: bool) -> io::Result<()> {
+		self.check_writable()?;
 		Ok(())
-			.and(self.hash_file.flush())
-			.and(self.data_file.flush())
+			.and(self.hash_file.flush_with_durability(durable))
+			.and(self.data_file.flush_with_durability(durable))
 			.and(self.sync_leaf_set())
 			.map_err(|e| {
 				io::Error::new(
@@ -355,6 +439,7 @@ impl<T: PMMRable> PMMRBackend<T> {
 	/// after which the local node should have all the data to allow rewinding.
 	pub fn check_compact(&mut self, cutoff_pos: u64, rewind_rm_pos: &Bitmap) -> io::Result<bool> {
 		assert!(self.prunable, "Trying to compact a non-prunable PMMR");
+		self.check_writable()?;
 
 		// Calculate the sets of leaf positions and node positions to remove based
 		// on the cutoff_pos provided.
@@ -405,6 +490,14 @@ impl<T: PMMRable> PMMRBackend<T> {
 		Ok(true)
 	}
 
+	/// Estimate what check_compact would remove for the given cutoff_pos and
+	/// rewind_rm_pos without modifying anything.
+	/// Returns the number of leaves and the number of hash positions that would be removed.
+	pub fn compact_estimate(&self, cutoff_pos: u64, rewind_rm_pos: &Bitmap) -> (u64, u64) {
+		let (leaves_removed, pos_to_rm) = self.pos_to_rm(cutoff_pos, rewind_rm_pos);
+		(leaves_removed.cardinality(), pos_to_rm.cardinality())
+	}
+
 	fn clean_rewind_files(&self) -> io::Result<u32> {
 		let data_dir = self.data_dir.clone();
 		let pattern = format!("{}.", PMMR_LEAF_FILE);
@@ -26,7 +26,7 @@ use std::path::{Path, PathBuf};
 
 use croaring::Bitmap;
 
-use crate::core::core::pmmr::{bintree_postorder_height, family, path};
+use crate::core::core::pmmr::{bintree_leftmost, bintree_postorder_height, family, is_leaf, path};
 use crate::{read_bitmap, save_via_temp_file};
 
 /// Maintains a list of previously pruned nodes in PMMR, compacting the list as
@@ -265,6 +265,21 @@ impl PruneList {
 		self.bitmap.iter().map(|x| x as u64).collect()
 	}
 
+	/// Bitmap of all leaf positions beneath the pruned roots.
+	pub fn pruned_leaves(&self) -> Bitmap {
+		let mut leaves = Bitmap::create();
+		for root in self.bitmap.iter() {
+			let root = root as u64;
+			for pos in bintree_leftmost(root)..=root {
+				if is_leaf(pos) {
+					leaves.add(pos as u32);
+				}
+			}
+		}
+		leaves.run_optimize();
+		leaves
+	}
+
 	/// Is the pos pruned?
 	/// Assumes the pruned_cache is fully built and up to date.
 	pub fn is_pruned(&self, pos: u64) -> bool {
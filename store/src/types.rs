@@ -18,6 +18,7 @@ use tempfile::tempfile;
 use crate::core::ser::{
 	self, BinWriter, ProtocolVersion, Readable, Reader, StreamingReader, Writeable, Writer,
 };
+use std::borrow::Cow;
 use std::fmt::Debug;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
@@ -91,6 +92,21 @@ where
 		})
 	}
 
+	/// Open an existing file at the provided path on disk in read-only mode.
+	/// The file is never created or modified and any attempt to write to it fails.
+	pub fn open_readonly<P>(
+		path: P,
+		size_info: SizeInfo,
+		version: ProtocolVersion,
+	) -> io::Result<DataFile<T>>
+	where
+		P: AsRef<Path> + Debug,
+	{
+		Ok(DataFile {
+			file: AppendOnlyFile::open_readonly(path, size_info, version)?,
+		})
+	}
+
 	/// Append an element to the file.
 	/// Will not be written to disk until flush() is subsequently called.
 	/// Alternatively discard() may be called to discard any pending changes.
@@ -120,6 +136,11 @@ where
 		self.file.flush()
 	}
 
+	/// Flush unsynced changes to the file, only issuing an fsync if durable.
+	pub fn flush_with_durability(&mut self, durable: bool) -> io::Result<()> {
+		self.file.flush_with_durability(durable)
+	}
+
 	/// Discard any unsynced changes to the file.
 	pub fn discard(&mut self) {
 		self.file.discard()
@@ -168,11 +189,20 @@ where
 /// Despite being append-only, the file can still be pruned and truncated. The
 /// former simply happens by rewriting it, ignoring some of the data. The
 /// latter by truncating the underlying file and re-creating the mmap.
+///
+/// A file opened read-only is never memory mapped as it may be the live file of
+/// another process, which would kill us with SIGBUS if it truncated the file under
+/// the map. Reads use pread(2) instead and are bounded by the length of the file
+/// (as indexed by the size file) when it was opened, anything appended afterwards
+/// is not visible. Reads of data truncated since it was opened fail.
 pub struct AppendOnlyFile<T> {
 	path: PathBuf,
 	file: Option<File>,
 	size_info: SizeInfo,
 	version: ProtocolVersion,
+	readonly: bool,
+	// Length in bytes of a read-only file when it was opened, reads are bounded by this.
+	readonly_len: u64,
 	mmap: Option<memmap::Mmap>,
 
 	// Buffer of unsync'd bytes. These bytes will be appended to the file when flushed.
@@ -191,6 +221,16 @@ impl AppendOnlyFile<SizeEntry> {
 		}
 		Ok(sum)
 	}
+
+	// Size of the data indexed by the size file, entries are contiguous so this
+	// is the end of the last entry.
+	fn indexed_size(&self) -> io::Result<u64> {
+		if self.buffer_start_pos == 0 {
+			return Ok(0);
+		}
+		let entry = self.read_as_elmt(self.buffer_start_pos - 1)?;
+		Ok(entry.offset + entry.size as u64)
+	}
 }
 
 impl<T> AppendOnlyFile<T>
@@ -203,6 +243,33 @@ where
 		size_info: SizeInfo,
 		version: ProtocolVersion,
 	) -> io::Result<AppendOnlyFile<T>>
+	where
+		P: AsRef<Path> + Debug,
+	{
+		AppendOnlyFile::open_with_mode(path, size_info, version, false)
+	}
+
+	/// Open an existing file in read-only mode, read via pread(2) rather than a mmap.
+	/// The file is never created, the size file is never rebuilt and all writes are rejected.
+	/// Safe to use on a file concurrently written (appended, truncated or replaced) by another
+	/// process, only the data present when it was opened is visible.
+	pub fn open_readonly<P>(
+		path: P,
+		size_info: SizeInfo,
+		version: ProtocolVersion,
+	) -> io::Result<AppendOnlyFile<T>>
+	where
+		P: AsRef<Path> + Debug,
+	{
+		AppendOnlyFile::open_with_mode(path, size_info, version, true)
+	}
+
+	fn open_with_mode<P>(
+		path: P,
+		size_info: SizeInfo,
+		version: ProtocolVersion,
+		readonly: bool,
+	) -> io::Result<AppendOnlyFile<T>>
 	where
 		P: AsRef<Path> + Debug,
 	{
@@ -211,6 +278,8 @@ where
 			path: path.as_ref().to_path_buf(),
 			size_info,
 			version,
+			readonly,
+			readonly_len: 0,
 			mmap: None,
 			buffer: vec![],
 			buffer_start_pos: 0,
@@ -225,7 +294,18 @@ where
 		// And we can *only* do this after init() the data file (so we know sizes).
 		let expected_size = aof.size()?;
 		if let SizeInfo::VariableSize(ref mut size_file) = &mut aof.size_info {
-			if size_file.sum_sizes()? != expected_size {
+			if aof.readonly {
+				// The size file is flushed (and opened) before the data file, a live data file
+				// may have trailing data not in the size file yet, which we ignore.
+				let indexed_size = size_file.indexed_size()?;
+				if indexed_size > expected_size {
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						format!("size file inconsistent with data file: {:?}", aof.path),
+					));
+				}
+				aof.readonly_len = indexed_size;
+			} else if size_file.sum_sizes()? != expected_size {
 				aof.rebuild_size_file()?;
 
 				// (Re)init the entire file as we just rebuilt the size_file
@@ -247,11 +327,18 @@ where
 		self.file = Some(
 			OpenOptions::new()
 				.read(true)
-				.append(true)
-				.create(true)
+				.append(!self.readonly)
+				.create(!self.readonly)
 				.open(self.path.clone())?,
 		);
 
+		// Never mmap a read-only file, note its length to bound reads instead.
+		if self.readonly {
+			self.readonly_len = self.file.as_ref().unwrap().metadata()?.len();
+			self.buffer_start_pos = self.size_in_elmts()?;
+			return Ok(());
+		}
+
 		// If we have a non-empty file then mmap it.
 		if self.size()? == 0 {
 			self.buffer_start_pos = 0;
@@ -290,6 +377,7 @@ where
 	/// Append data to the file. Until the append-only file is synced, data is
 	/// only written to memory.
 	pub fn append(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+		self.check_writable()?;
 		if let SizeInfo::VariableSize(ref mut size_file) = &mut self.size_info {
 			let next_pos = size_file.size_unsync_in_elmts()?;
 			let offset = if next_pos == 0 {
@@ -339,9 +427,17 @@ where
 	/// Syncs all writes (fsync), reallocating the memory map to make the newly
 	/// written data accessible.
 	pub fn flush(&mut self) -> io::Result<()> {
+		self.flush_with_durability(true)
+	}
+
+	/// Writes all buffered data to the file, reallocating the memory map to make the newly
+	/// written data accessible. Only issues an fsync if durable, otherwise the written data
+	/// may be lost (and the file left truncated) if the OS crashes before it is written back.
+	pub fn flush_with_durability(&mut self, durable: bool) -> io::Result<()> {
+		self.check_writable()?;
 		if let SizeInfo::VariableSize(ref mut size_file) = &mut self.size_info {
 			// Flush the associated size_file if we have one.
-			size_file.flush()?
+			size_file.flush_with_durability(durable)?
 		}
 
 		if self.buffer_start_pos_bak > 0 {
@@ -378,7 +474,9 @@ where
 		}
 
 		self.file.as_mut().unwrap().write_all(&self.buffer[..])?;
-		self.file.as_mut().unwrap().sync_all()?;
+		if durable {
+			self.file.as_mut().unwrap().sync_all()?;
+		}
 
 		self.buffer.clear();
 		self.buffer_start_pos = self.size_in_elmts()?;
@@ -412,17 +510,19 @@ where
 	/// Read the bytes representing the element at the given position (0-indexed).
 	/// Uses the offset cache to determine the offset to read from and the size
 	/// in bytes to actually read.
-	/// Leverages the memory map.
-	pub fn read(&self, pos: u64) -> io::Result<&[u8]> {
+	/// Leverages the memory map (or pread if opened read-only).
+	pub fn read(&self, pos: u64) -> io::Result<Cow<'_, [u8]>> {
 		if pos >= self.size_unsync_in_elmts()? {
-			return Ok(<&[u8]>::default());
+			return Ok(Cow::Borrowed(<&[u8]>::default()));
 		}
 		let (offset, length) = self.offset_and_size(pos)?;
-		let res = if pos < self.buffer_start_pos {
-			self.read_from_mmap(offset, length)
+		let res = if self.readonly {
+			Cow::Owned(self.read_from_file(offset, length)?)
+		} else if pos < self.buffer_start_pos {
+			Cow::Borrowed(self.read_from_mmap(offset, length))
 		} else {
 			let (buffer_offset, _) = self.offset_and_size(self.buffer_start_pos)?;
-			self.read_from_buffer(offset.saturating_sub(buffer_offset), length)
+			Cow::Borrowed(self.read_from_buffer(offset.saturating_sub(buffer_offset), length))
 		};
 		Ok(res)
 	}
@@ -460,6 +560,20 @@ where
 		}
 	}
 
+	// Read length bytes starting at offset from the (read-only) file via pread.
+	// Return empty vec if the bytes are beyond the length of the file when opened.
+	// Errors if the file has since been truncated by another process.
+	fn read_from_file(&self, offset: u64, length: u16) -> io::Result<Vec<u8>> {
+		if offset + length as u64 > self.readonly_len {
+			return Ok(vec![]);
+		}
+		let mut buf = vec![0; length as usize];
+		if let Some(file) = &self.file {
+			read_exact_at(file, &mut buf, offset)?;
+		}
+		Ok(buf)
+	}
+
 	/// Create a new tempfile containing the contents of this append only file.
 	/// This allows callers to see a consistent view of the data without
 	/// locking the append only file.
@@ -479,6 +593,7 @@ where
 	/// Saves a copy of the current file content, skipping data at the provided
 	/// prune positions. prune_pos must be ordered.
 	pub fn save_prune(&mut self, prune_pos: &[u64]) -> io::Result<()> {
+		self.check_writable()?;
 		let tmp_path = self.path.with_extension("tmp");
 
 		// Scope the reader and writer to within the block so we can safely replace files later on.
@@ -589,7 +704,11 @@ where
 	}
 
 	/// Current size of the file in bytes.
+	/// For a read-only file this is the size when opened (as indexed by the size file).
 	pub fn size(&self) -> io::Result<u64> {
+		if self.readonly {
+			return Ok(self.readonly_len);
+		}
 		fs::metadata(&self.path).map(|md| md.len())
 	}
 
@@ -597,4 +716,50 @@ where
 	pub fn path(&self) -> &Path {
 		&self.path
 	}
+
+	/// Whether this file was opened in read-only mode.
+	pub fn is_readonly(&self) -> bool {
+		self.readonly
+	}
+
+	fn check_writable(&self) -> io::Result<()> {
+		if self.readonly {
+			return Err(io::Error::new(
+				io::ErrorKind::PermissionDenied,
+				format!("file opened read-only: {:?}", self.path),
+			));
+		}
+		Ok(())
+	}
+}
+
+// Read exactly buf.len() bytes from the file at the provided offset,
+// without moving the file cursor.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+	use std::os::unix::fs::FileExt;
+	file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+	use std::os::windows::fs::FileExt;
+	while !buf.is_empty() {
+		match file.seek_read(buf, offset) {
+			Ok(0) => {
+				return Err(io::Error::new(
+					io::ErrorKind::UnexpectedEof,
+					"failed to fill whole buffer",
+				))
+			}
+			Ok(n) => {
+				let tmp = buf;
+				buf = &mut tmp[n..];
+				offset += n as u64;
+			}
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(())
 }
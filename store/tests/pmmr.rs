@@ -813,6 +813,109 @@ fn compact_twice() {
 	teardown(data_dir);
 }
 
+#[test]
+fn pmmr_open_readonly() {
+	let (data_dir, elems) = setup("open_readonly");
+
+	// opening a missing backend read-only must not create it
+	assert!(store::pmmr::PMMRBackend::<TestElem>::open_readonly(
+		format!("{}/missing", data_dir),
+		true,
+		ProtocolVersion(1)
+	)
+	.is_err());
+	assert!(!std::path::Path::new(&format!("{}/missing", data_dir)).exists());
+
+	let (mmr_size, root) = {
+		let mut backend =
+			store::pmmr::PMMRBackend::new(data_dir.to_string(), true, ProtocolVersion(1), None)
+				.unwrap();
+		let mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		let root = {
+			let pmmr: PMMR<'_, TestElem, _> = PMMR::at(&mut backend, mmr_size);
+			pmmr.root().unwrap()
+		};
+		(mmr_size, root)
+	};
+
+	{
+		let mut backend = store::pmmr::PMMRBackend::<TestElem>::open_readonly(
+			data_dir.to_string(),
+			true,
+			ProtocolVersion(1),
+		)
+		.unwrap();
+		assert!(backend.is_readonly());
+		assert_eq!(backend.unpruned_size(), mmr_size);
+		assert_eq!(backend.get_data(1), Some(elems[0]));
+		{
+			let pmmr: PMMR<'_, TestElem, _> = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(pmmr.root().unwrap(), root);
+		}
+
+		// writes are rejected and leave the files untouched
+		{
+			let mut pmmr: PMMR<'_, TestElem, _> = PMMR::at(&mut backend, mmr_size);
+			assert!(pmmr.push(&elems[0]).is_err());
+		}
+		assert!(backend.sync().is_err());
+		assert!(backend.check_compact(mmr_size, &Bitmap::create()).is_err());
+	}
+
+	{
+		let backend = store::pmmr::PMMRBackend::<TestElem>::new(
+			data_dir.to_string(),
+			true,
+			ProtocolVersion(1),
+			None,
+		)
+		.unwrap();
+		assert_eq!(backend.unpruned_size(), mmr_size);
+	}
+
+	teardown(data_dir);
+}
+
+#[test]
+fn pmmr_open_readonly_live() {
+	let (data_dir, elems) = setup("open_readonly_live");
+	let mut backend =
+		store::pmmr::PMMRBackend::new(data_dir.to_string(), false, ProtocolVersion(1), None)
+			.unwrap();
+	let mmr_size = load(0, &elems[0..4], &mut backend);
+	backend.sync().unwrap();
+
+	let reader = store::pmmr::PMMRBackend::<TestElem>::open_readonly(
+		data_dir.to_string(),
+		false,
+		ProtocolVersion(1),
+	)
+	.unwrap();
+	assert_eq!(reader.unpruned_size(), mmr_size);
+
+	// Data appended (and flushed) by the writer after the reader was opened is not visible.
+	let mmr_size2 = load(mmr_size, &elems[4..8], &mut backend);
+	backend.sync().unwrap();
+	assert_eq!(reader.unpruned_size(), mmr_size);
+	assert_eq!(reader.get_data(4), Some(elems[2]));
+	assert_eq!(reader.get_data(8), None);
+
+	// The writer truncating the files under the reader (rewind) does not crash the reader,
+	// reads of the truncated data fail and the data still present is read as before.
+	{
+		let mut pmmr: PMMR<'_, TestElem, _> = PMMR::at(&mut backend, mmr_size2);
+		pmmr.rewind(3, &Bitmap::create()).unwrap();
+	}
+	backend.sync().unwrap();
+	assert_eq!(reader.get_data(1), Some(elems[0]));
+	assert_eq!(reader.get_data(2), Some(elems[1]));
+	assert_eq!(reader.get_data(4), None);
+	assert_eq!(reader.get_hash(4), None);
+
+	teardown(data_dir);
+}
+
 #[test]
 fn cleanup_rewind_files_test() {
 	let expected = 10;
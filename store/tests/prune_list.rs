@@ -268,3 +268,26 @@ fn test_get_shift() {
 	assert_eq!(pl.get_shift(11), 4);
 	assert_eq!(pl.get_shift(12), 4);
 }
+
+#[test]
+fn test_pruned_leaves() {
+	let mut pl = PruneList::empty();
+	assert!(pl.pruned_leaves().is_empty());
+
+	pl.add(4);
+	pl.flush().unwrap();
+	assert_eq!(pl.pruned_leaves().to_vec(), [4]);
+
+	// Pruning 1 and 2 compacts to the root at 3, leaves 1 and 2 are reported.
+	pl.add(1);
+	pl.add(2);
+	pl.flush().unwrap();
+	assert_eq!(pl.to_vec(), [3, 4]);
+	assert_eq!(pl.pruned_leaves().to_vec(), [1, 2, 4]);
+
+	// Pruning 5 compacts everything up to the root at 7.
+	pl.add(5);
+	pl.flush().unwrap();
+	assert_eq!(pl.to_vec(), [7]);
+	assert_eq!(pl.pruned_leaves().to_vec(), [1, 2, 4, 5]);
+}
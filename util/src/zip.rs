@@ -13,9 +13,12 @@
 // limitations under the License.
 
 /// Wrappers around the `zip-rs` library to compress and decompress zip archives.
+use std::cmp;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc;
 use std::thread;
 
 use self::zip_rs::write::FileOptions;
@@ -24,6 +27,58 @@ use zip as zip_rs;
 /// Create a zip archive from source dir and list of relative file paths.
 /// Permissions are set to 644 by default.
 pub fn create_zip(dst_file: &File, src_dir: &Path, files: Vec<PathBuf>) -> io::Result<()> {
+	let entries = files.into_iter().map(|x| (src_dir.join(&x), x)).collect();
+	create_zip_from_entries(dst_file, entries)
+}
+
+/// Create a zip archive from a list of (source file path, path in archive) entries.
+/// Files are streamed directly from their source paths, missing files are skipped.
+/// Permissions are set to 644 by default.
+pub fn create_zip_from_entries(
+	dst_file: &File,
+	entries: Vec<(PathBuf, PathBuf)>,
+) -> io::Result<()> {
+	create_zip_from_entries_with_progress(dst_file, entries, &mut |_| {})
+}
+
+/// Minimum number of bytes processed between two progress updates.
+const PROGRESS_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// Copy everything from reader to writer, adding the bytes copied to the running
+/// total and reporting it every PROGRESS_INTERVAL bytes and once at the end.
+fn copy_with_progress<R: Read, W: Write>(
+	reader: &mut R,
+	writer: &mut W,
+	total: &mut u64,
+	progress: &mut dyn FnMut(u64),
+) -> io::Result<()> {
+	let mut buf = vec![0u8; 64 * 1024];
+	let mut last_report = *total;
+	loop {
+		let n = match reader.read(&mut buf) {
+			Ok(0) => break,
+			Ok(n) => n,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		};
+		writer.write_all(&buf[..n])?;
+		*total += n as u64;
+		if *total - last_report >= PROGRESS_INTERVAL {
+			last_report = *total;
+			progress(*total);
+		}
+	}
+	progress(*total);
+	Ok(())
+}
+
+/// As create_zip_from_entries, reporting the total number of (uncompressed) bytes
+/// written so far to the provided progress callback.
+pub fn create_zip_from_entries_with_progress(
+	dst_file: &File,
+	entries: Vec<(PathBuf, PathBuf)>,
+	progress: &mut dyn FnMut(u64),
+) -> io::Result<()> {
 	let mut writer = {
 		let zip = zip_rs::ZipWriter::new(dst_file);
 		BufWriter::new(zip)
@@ -33,12 +88,17 @@ pub fn create_zip(dst_file: &File, src_dir: &Path, files: Vec<PathBuf>) -> io::R
 		.compression_method(zip_rs::CompressionMethod::Stored)
 		.unix_permissions(0o644);
 
-	for x in &files {
-		let file_path = src_dir.join(x);
-		if let Ok(file) = File::open(file_path.clone()) {
+	let mut written = 0;
+	for (file_path, x) in &entries {
+		if let Ok(file) = File::open(file_path) {
 			info!("compress: {:?} -> {:?}", file_path, x);
 			writer.get_mut().start_file_from_path(x, options)?;
-			io::copy(&mut BufReader::new(file), &mut writer)?;
+			copy_with_progress(
+				&mut BufReader::new(file),
+				&mut writer,
+				&mut written,
+				progress,
+			)?;
 			// Flush the BufWriter after each file so we start then next one correctly.
 			writer.flush()?;
 		}
@@ -49,20 +109,150 @@ pub fn create_zip(dst_file: &File, src_dir: &Path, files: Vec<PathBuf>) -> io::R
 	Ok(())
 }
 
+/// Magic bytes at the start of every zstd frame.
+/// Used to distinguish a zstd compressed archive from a plain zip archive.
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compress the contents of src (typically a zip archive) into dst using zstd
+/// at the provided compression level.
+pub fn compress_zstd(src: &File, dst: &File, level: i32) -> io::Result<()> {
+	let mut encoder = zstd::stream::Encoder::new(BufWriter::new(dst), level)?;
+	io::copy(&mut BufReader::new(src), &mut encoder)?;
+	encoder.finish()?.flush()?;
+	dst.sync_all()?;
+	Ok(())
+}
+
+/// Decompress the zstd compressed contents of src into dst.
+pub fn decompress_zstd(src: &File, dst: &File) -> io::Result<()> {
+	decompress_zstd_with_limit(src, dst, u64::MAX)
+}
+
+/// Decompress the zstd compressed contents of src into dst, failing with an
+/// `InvalidData` error (see is_size_limit_exceeded) if the decompressed size
+/// exceeds max_size.
+pub fn decompress_zstd_with_limit(src: &File, dst: &File, max_size: u64) -> io::Result<()> {
+	let decoder = zstd::stream::Decoder::new(BufReader::new(src))?;
+	let mut writer = BufWriter::new(dst);
+	let size = io::copy(&mut decoder.take(max_size.saturating_add(1)), &mut writer)?;
+	writer.flush()?;
+	if size > max_size {
+		return Err(too_large(Path::new("zstd stream"), size, max_size));
+	}
+	Ok(())
+}
+
+/// Is the provided file zstd compressed (based on the leading magic bytes)?
+/// The file is rewound to the start before returning.
+pub fn is_zstd(file: &mut File) -> io::Result<bool> {
+	let mut magic = [0u8; 4];
+	file.seek(SeekFrom::Start(0))?;
+	let res = match file.read_exact(&mut magic) {
+		Ok(()) => magic == ZSTD_MAGIC,
+		Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+		Err(e) => return Err(e),
+	};
+	file.seek(SeekFrom::Start(0))?;
+	Ok(res)
+}
+
+/// List the files in the provided zip archive along with their uncompressed sizes.
+/// Nothing is extracted.
+pub fn list_files(from_archive: File) -> io::Result<Vec<(PathBuf, u64)>> {
+	let mut archive = zip_rs::ZipArchive::new(from_archive)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+	let mut files = Vec::with_capacity(archive.len());
+	for i in 0..archive.len() {
+		let file = archive
+			.by_index(i)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+		files.push((PathBuf::from(file.name()), file.size()));
+	}
+	Ok(files)
+}
+
+/// Read a set of files from the provided zip archive in place, passing the path and a
+/// reader over the (uncompressed) contents of each file present to the provided fn.
+/// Files missing from the archive are skipped. Nothing is extracted.
+pub fn read_files(
+	from_archive: File,
+	files: &[PathBuf],
+	f: &mut dyn FnMut(&Path, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+	let mut archive = zip_rs::ZipArchive::new(from_archive)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+	for x in files {
+		if let Ok(mut file) = archive.by_name(x.to_str().expect("valid path")) {
+			f(x, &mut file)?;
+		}
+	}
+	Ok(())
+}
+
 /// Extract a set of files from the provided zip archive.
 pub fn extract_files(from_archive: File, dest: &Path, files: Vec<PathBuf>) -> io::Result<()> {
+	extract_files_with_progress(from_archive, dest, files, &mut |_| {})
+}
+
+/// As extract_files, reporting the total number of (uncompressed) bytes extracted
+/// so far to the provided progress callback.
+/// Extraction happens on a separate thread, progress is reported on the calling thread.
+pub fn extract_files_with_progress(
+	from_archive: File,
+	dest: &Path,
+	files: Vec<PathBuf>,
+	progress: &mut dyn FnMut(u64),
+) -> io::Result<()> {
+	extract_files_with_limits(from_archive, dest, files, u64::MAX, u64::MAX, progress)
+}
+
+/// As extract_files_with_progress, enforcing a max uncompressed size per extracted
+/// file and for all extracted files combined.
+/// The limits are enforced on the bytes actually decompressed (not on the sizes
+/// claimed by the archive). Exceeding a limit aborts the extraction with an
+/// `InvalidData` error (see is_size_limit_exceeded), any partially extracted files
+/// are left for the caller to clean up.
+pub fn extract_files_with_limits(
+	from_archive: File,
+	dest: &Path,
+	files: Vec<PathBuf>,
+	max_entry_size: u64,
+	max_total_size: u64,
+	progress: &mut dyn FnMut(u64),
+) -> io::Result<()> {
 	let dest: PathBuf = PathBuf::from(dest);
 	let files: Vec<_> = files.to_vec();
-	let res = thread::spawn(move || {
+	let (tx, rx) = mpsc::channel();
+	let handle = thread::spawn(move || -> io::Result<()> {
 		let mut archive = zip_rs::ZipArchive::new(from_archive).expect("archive file exists");
+		let mut extracted = 0;
 		for x in files {
 			if let Ok(file) = archive.by_name(x.to_str().expect("valid path")) {
+				let limit = cmp::min(max_entry_size, max_total_size - extracted);
+				if file.size() > limit {
+					return Err(too_large(&x, file.size(), limit));
+				}
 				let path = dest.join(file.sanitized_name());
 				let parent_dir = path.parent().expect("valid parent dir");
 				fs::create_dir_all(&parent_dir).expect("create parent dir");
 				let outfile = fs::File::create(&path).expect("file created");
-				io::copy(&mut BufReader::new(file), &mut BufWriter::new(outfile))
-					.expect("write to file");
+				let mut writer = BufWriter::new(outfile);
+
+				// Never trust the size in the archive, read at most one byte past the limit.
+				let start = extracted;
+				copy_with_progress(
+					&mut BufReader::new(file.take(limit.saturating_add(1))),
+					&mut writer,
+					&mut extracted,
+					&mut |n| {
+						let _ = tx.send(n);
+					},
+				)
+				.expect("write to file");
+				writer.flush().expect("write to file");
+				if extracted - start > limit {
+					return Err(too_large(&x, extracted - start, limit));
+				}
 
 				info!("extract_files: {:?} -> {:?}", x, path);
 
@@ -75,13 +265,48 @@ pub fn extract_files(from_archive: File, dest: &Path, files: Vec<PathBuf>) -> io
 				}
 			}
 		}
-	})
-	.join();
+		Ok(())
+	});
+
+	// The channel is closed once the extracting thread finishes (or panics).
+	for n in rx {
+		progress(n);
+	}
+	let res = handle.join();
 
-	// If join() above is Ok then we successfully extracted the files.
+	// If join() above is Ok then the extracting thread did not panic.
 	// If the result is Err then we failed to extract the files.
 	res.map_err(|e| {
 		error!("failed to extract files from zip: {:?}", e);
 		io::Error::new(io::ErrorKind::Other, "failed to extract files from zip")
-	})
+	})?
+}
+
+/// Payload of the `InvalidData` io errors returned when a size limit is exceeded
+/// while decompressing or extracting.
+#[derive(Debug)]
+pub struct SizeLimitExceeded(pub String);
+
+impl fmt::Display for SizeLimitExceeded {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+impl std::error::Error for SizeLimitExceeded {}
+
+/// Is the provided io error the result of exceeding a size limit?
+pub fn is_size_limit_exceeded(e: &io::Error) -> bool {
+	e.get_ref()
+		.map(|inner| inner.is::<SizeLimitExceeded>())
+		.unwrap_or(false)
+}
+
+fn too_large(path: &Path, size: u64, limit: u64) -> io::Error {
+	let msg = format!(
+		"{:?} too large, {} bytes exceeds limit of {} bytes",
+		path, size, limit
+	);
+	error!("extract_files: {}", msg);
+	io::Error::new(io::ErrorKind::InvalidData, SizeLimitExceeded(msg))
 }
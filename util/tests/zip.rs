@@ -93,3 +93,88 @@ fn zip_unzip() {
 		);
 	}
 }
+
+#[test]
+fn zstd_roundtrip_and_limit() {
+	let root = Path::new("target/tmp/zstd");
+	fs::create_dir_all(root).unwrap();
+	let src_path = root.join("src.bin");
+	let zst_path = root.join("src.bin.zst");
+	let dst_path = root.join("dst.bin");
+
+	let contents: Vec<u8> = (0..4096u32).map(|x| (x % 251) as u8).collect();
+	fs::write(&src_path, &contents).unwrap();
+
+	{
+		let src = File::open(&src_path).unwrap();
+		let zst = File::create(&zst_path).unwrap();
+		zip::compress_zstd(&src, &zst, 3).unwrap();
+	}
+
+	// A zstd stream is detected by its magic bytes, the file is rewound afterwards.
+	let mut zst = File::open(&zst_path).unwrap();
+	assert!(zip::is_zstd(&mut zst).unwrap());
+	assert!(!zip::is_zstd(&mut File::open(&src_path).unwrap()).unwrap());
+	fs::write(root.join("empty.bin"), b"").unwrap();
+	assert!(!zip::is_zstd(&mut File::open(root.join("empty.bin")).unwrap()).unwrap());
+
+	// Decompressing within the limit restores the original contents.
+	{
+		let dst = File::create(&dst_path).unwrap();
+		zip::decompress_zstd_with_limit(&zst, &dst, contents.len() as u64).unwrap();
+	}
+	assert_eq!(fs::read(&dst_path).unwrap(), contents);
+
+	// A stream decompressing to more than the limit is rejected.
+	let zst = File::open(&zst_path).unwrap();
+	let dst = File::create(&dst_path).unwrap();
+	let e = zip::decompress_zstd_with_limit(&zst, &dst, contents.len() as u64 - 1).unwrap_err();
+	assert!(zip::is_size_limit_exceeded(&e));
+	assert!(fs::metadata(&dst_path).unwrap().len() <= contents.len() as u64);
+
+	fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn zip_unzip_progress() {
+	let root = Path::new("target/tmp/progress");
+	let zip_path = root.join("zipped.zip");
+	let path = root.join("to_zip");
+	let dest_dir = root.join("unzipped");
+	fs::create_dir_all(&path).unwrap();
+	fs::create_dir_all(&dest_dir).unwrap();
+
+	// Enough data for progress updates along the way, not only at the end of each file.
+	fs::write(path.join("big.bin"), vec![7u8; 20 * 1024 * 1024]).unwrap();
+	fs::write(path.join("small.txt"), b"Hello, world!").unwrap();
+	let files = vec![PathBuf::from("big.bin"), PathBuf::from("small.txt")];
+	let total = 20 * 1024 * 1024 + 13;
+
+	// The running total never goes backwards and ends with every byte processed.
+	let check = |reports: &[u64]| {
+		assert!(reports.len() > files.len());
+		assert!(reports.windows(2).all(|w| w[0] <= w[1]));
+		assert_eq!(reports.last(), Some(&total));
+	};
+
+	let mut reports = vec![];
+	{
+		let zip_file = File::create(&zip_path).unwrap();
+		let entries = files.iter().map(|x| (path.join(x), x.clone())).collect();
+		zip::create_zip_from_entries_with_progress(&zip_file, entries, &mut |n| reports.push(n))
+			.unwrap();
+	}
+	check(&reports);
+
+	let mut reports = vec![];
+	let zip_file = File::open(&zip_path).unwrap();
+	zip::extract_files_with_progress(zip_file, &dest_dir, files.clone(), &mut |n| reports.push(n))
+		.unwrap();
+	check(&reports);
+	assert_eq!(
+		fs::read(dest_dir.join("big.bin")).unwrap(),
+		fs::read(path.join("big.bin")).unwrap()
+	);
+
+	fs::remove_dir_all(root).unwrap();
+}